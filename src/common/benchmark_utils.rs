@@ -0,0 +1,104 @@
+// 発行から受信までの遅延を測定するベンチマークモード (benchmark) のペイロード形式と集計
+use std::{sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+
+/// 発行時刻 (UNIX 時間のマイクロ秒) をペイロードの先頭に付ける際の接頭辞 (`bench:<時刻>:<ペイロード>`)
+pub const TIMESTAMP_PREFIX: &[u8] = b"bench:";
+
+fn now_micros() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as i64).unwrap_or_default()
+}
+
+/// ペイロードの先頭に現在時刻を付ける
+pub fn with_timestamp(payload: &[u8]) -> Vec<u8> {
+    let mut data = TIMESTAMP_PREFIX.to_vec();
+    data.extend_from_slice(format!("{}:", now_micros()).as_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+/// `with_timestamp` で付けた時刻と、残りのペイロードを取り出す。時刻が付いていない場合は `None` を返す
+pub fn split_timestamp(payload: &[u8]) -> Option<(i64, &[u8])> {
+    let rest = payload.strip_prefix(TIMESTAMP_PREFIX)?;
+    let end = rest.iter().position(|b| *b == b':')?;
+    let timestamp = std::str::from_utf8(&rest[..end]).ok()?.parse().ok()?;
+    Some((timestamp, &rest[end + 1..]))
+}
+
+/// 受信したメッセージの遅延 (受信時刻 - ペイロードの発行時刻) を記録する。
+///
+/// 他のホストのパブリッシャーから受信した場合は両者の時計のずれが遅延に含まれるため、
+/// 同じプロセスから発行して受信し直した (ループバック) メッセージの遅延を別に記録して比較できるようにする。
+#[derive(Debug, Default)]
+pub struct LatencyRecorder {
+    remote: Mutex<Vec<i64>>,
+    loopback: Mutex<Vec<i64>>,
+}
+
+/// 遅延の統計 (マイクロ秒)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub min: i64,
+    pub max: i64,
+    pub mean: f64,
+    pub p50: i64,
+    pub p95: i64,
+    pub p99: i64,
+}
+
+impl LatencyRecorder {
+    /// 発行時刻 `timestamp` のメッセージを受信したことを記録する
+    pub fn record(&self, timestamp: i64, loopback: bool) {
+        let latency = now_micros() - timestamp;
+        let samples = if loopback { &self.loopback } else { &self.remote };
+        samples.lock().unwrap().push(latency);
+    }
+
+    /// パブリッシャーから受信したメッセージの遅延の統計
+    pub fn remote_summary(&self) -> Option<LatencySummary> {
+        summarize(&self.remote.lock().unwrap())
+    }
+
+    /// ループバックのメッセージの遅延の統計
+    pub fn loopback_summary(&self) -> Option<LatencySummary> {
+        summarize(&self.loopback.lock().unwrap())
+    }
+}
+
+/// サンプルの統計を計算する (サンプルがない場合は `None`)
+pub fn summarize(samples: &[i64]) -> Option<LatencySummary> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    // nearest-rank 法のパーセンタイル
+    let percentile = |p: f64| sorted[((p / 100.0 * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len()) - 1];
+    Some(LatencySummary {
+        count: sorted.len(),
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        mean: sorted.iter().sum::<i64>() as f64 / sorted.len() as f64,
+        p50: percentile(50.0),
+        p95: percentile(95.0),
+        p99: percentile(99.0),
+    })
+}
+
+/// 遅延の統計を表示する
+pub fn print_report(label: &str, recorder: &LatencyRecorder) {
+    match recorder.remote_summary() {
+        Some(summary) => print_summary(label, "遅延", &summary),
+        None => crate::status!("[{}] 遅延: 発行時刻の付いたメッセージを受信しませんでした。", label),
+    }
+    if let Some(summary) = recorder.loopback_summary() {
+        print_summary(label, "ループバックの遅延", &summary);
+    }
+}
+
+fn print_summary(label: &str, name: &str, summary: &LatencySummary) {
+    let ms = |micros: i64| micros as f64 / 1000.0;
+    crate::status!("[{}] {} ({} 件): 最小 {:.3} ms, 最大 {:.3} ms, 平均 {:.3} ms, p50 {:.3} ms, p95 {:.3} ms, p99 {:.3} ms",
+        label, name, summary.count, ms(summary.min), ms(summary.max), summary.mean / 1000.0,
+        ms(summary.p50), ms(summary.p95), ms(summary.p99));
+}
@@ -0,0 +1,98 @@
+// CBOR のペイロードのデコード (payload_format: cbor、cbor フィーチャー)
+//
+// JSON で表せない値は次のように変換する: バイト列は Base64 の文字列、文字列以外のマップのキーは JSON に変換した文字列、
+// タグ付きの値は {"tag": タグ番号, "value": 値}、JSON の数値の範囲を超える整数は文字列、NaN と無限大は null。
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ciborium::Value as CborValue;
+use serde_json::{Map, Value};
+use std::io::Cursor;
+
+/// CBOR のペイロードを JSON の値に変換する。CBOR の値 1 つとして読めない場合 (後ろに余分なデータがある場合を含む) はエラーを返す
+pub fn decode(payload: &[u8]) -> Result<Value, String> {
+    let mut reader = Cursor::new(payload);
+    let value: CborValue = ciborium::from_reader(&mut reader).map_err(|e| match e {
+        ciborium::de::Error::Io(_) => "CBOR の値の途中でデータが終わっています".to_string(),
+        ciborium::de::Error::Syntax(offset) => format!("{} バイト目が CBOR として不正です", offset),
+        ciborium::de::Error::Semantic(_, message) => message,
+        ciborium::de::Error::RecursionLimitExceeded => "CBOR の値の入れ子が深すぎます".to_string(),
+    })?;
+    let consumed = reader.position() as usize;
+    if consumed != payload.len() {
+        return Err(format!("CBOR の値の後ろに {} バイトの余分なデータがあります", payload.len() - consumed));
+    }
+    Ok(to_json(value))
+}
+
+fn to_json(value: CborValue) -> Value {
+    match value {
+        CborValue::Integer(integer) => {
+            let integer = i128::from(integer);
+            match (i64::try_from(integer), u64::try_from(integer)) {
+                (Ok(signed), _) => signed.into(),
+                (_, Ok(unsigned)) => unsigned.into(),
+                _ => integer.to_string().into(),
+            }
+        }
+        CborValue::Bytes(bytes) => BASE64.encode(bytes).into(),
+        CborValue::Float(float) => serde_json::Number::from_f64(float).map_or(Value::Null, Value::Number),
+        CborValue::Text(text) => text.into(),
+        CborValue::Bool(boolean) => boolean.into(),
+        CborValue::Null => Value::Null,
+        CborValue::Tag(tag, value) => serde_json::json!({ "tag": tag, "value": to_json(*value) }),
+        CborValue::Array(items) => items.into_iter().map(to_json).collect(),
+        CborValue::Map(entries) => {
+            let mut object = Map::new();
+            for (key, value) in entries {
+                let key = match key {
+                    CborValue::Text(text) => text,
+                    other => to_json(other).to_string(),
+                };
+                object.insert(key, to_json(value));
+            }
+            Value::Object(object)
+        }
+        // ciborium の Value は non_exhaustive
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encode(value: CborValue) -> Vec<u8> {
+        let mut payload = Vec::new();
+        ciborium::into_writer(&value, &mut payload).unwrap();
+        payload
+    }
+
+    #[test]
+    fn converts_values_that_json_cannot_represent() {
+        let payload = encode(CborValue::Map(vec![
+            (CborValue::Text("temperature".into()), CborValue::Float(21.5)),
+            (CborValue::Integer(1.into()), CborValue::Bytes(vec![1, 2, 3])),
+            (CborValue::Text("time".into()), CborValue::Tag(1, Box::new(CborValue::Integer(1_700_000_000.into())))),
+            (CborValue::Text("min".into()), CborValue::Integer((-(1i128 << 64)).try_into().unwrap())),
+            (CborValue::Text("nan".into()), CborValue::Float(f64::NAN)),
+            (CborValue::Text("items".into()), CborValue::Array(vec![CborValue::Bool(true), CborValue::Null])),
+        ]));
+        assert_eq!(decode(&payload).unwrap(), json!({
+            "temperature": 21.5,
+            "1": "AQID",
+            "time": {"tag": 1, "value": 1_700_000_000},
+            "min": "-18446744073709551616",
+            "nan": null,
+            "items": [true, null],
+        }));
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_data() {
+        let payload = encode(CborValue::Text("hello".into()));
+        assert!(decode(&payload[..payload.len() - 1]).unwrap_err().contains("途中"));
+        let mut trailing = payload.clone();
+        trailing.extend_from_slice(&[0x01, 0x02]);
+        assert_eq!(decode(&trailing).unwrap_err(), "CBOR の値の後ろに 2 バイトの余分なデータがあります");
+    }
+}
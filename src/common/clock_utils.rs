@@ -0,0 +1,89 @@
+// システムの時刻 (壁時計) の変更の検出
+//
+// 再接続の待ち時間や idle_timeout_secs などの間隔は単調増加の時計 (tokio::time::Instant) で計るため、NTP による補正などで
+// システムの時刻が変わっても影響を受けない。システムの時刻は表示する受信時刻 (timestamp) にのみ使うため、
+// 時刻が飛んだ場合は前後のメッセージの受信時刻の差が実際の間隔と一致しなくなる。
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// 単調増加の時計の経過とシステムの時刻の経過の差がこれ以上の場合に、システムの時刻が変わったとみなす
+const DISCONTINUITY_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// 連続して受信したメッセージの間で、システムの時刻が単調増加の時計の経過から大きくずれた (進んだ・戻った) ことを検出する
+#[derive(Debug, Default)]
+pub struct ClockWatch {
+    // 前回のメッセージを受信したときのシステムの時刻と単調増加の時計の時刻
+    last: Option<(SystemTime, Instant)>,
+}
+
+/// 検出したシステムの時刻の変更
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockJump {
+    Forward(Duration),
+    Backward(Duration),
+}
+
+impl ClockWatch {
+    /// 現在のシステムの時刻と単調増加の時計の時刻
+    pub fn now() -> (SystemTime, Instant) {
+        (SystemTime::now(), Instant::now())
+    }
+
+    /// メッセージを受信した時刻 `now` (システムの時刻と単調増加の時計の時刻。`ClockWatch::now` で取得する) を記録し、
+    /// 前回のメッセージからシステムの時刻が変わっていればその量を返す
+    pub fn record(&mut self, now: (SystemTime, Instant)) -> Option<ClockJump> {
+        let last = self.last.replace(now)?;
+        let monotonic = now.1.duration_since(last.1);
+        let jump = match now.0.duration_since(last.0) {
+            Ok(wall) if wall > monotonic => ClockJump::Forward(wall - monotonic),
+            Ok(wall) => ClockJump::Backward(monotonic - wall),
+            Err(e) => ClockJump::Backward(monotonic + e.duration()),
+        };
+        let (ClockJump::Forward(amount) | ClockJump::Backward(amount)) = jump;
+        (amount >= DISCONTINUITY_THRESHOLD).then_some(jump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn the_first_message_only_sets_the_reference() {
+        let mut watch = ClockWatch::default();
+        assert_eq!(watch.record(ClockWatch::now()), None);
+    }
+
+    #[test]
+    fn forward_and_backward_jumps_are_measured_against_the_monotonic_clock() {
+        let (wall, monotonic) = ClockWatch::now();
+        let mut watch = ClockWatch::default();
+        watch.record((wall, monotonic));
+        // 10 秒の間にシステムの時刻が 70 秒進んだ
+        assert_eq!(watch.record((wall + secs(70), monotonic + secs(10))), Some(ClockJump::Forward(secs(60))));
+        // 次の 10 秒の間にシステムの時刻が 5 秒戻った
+        assert_eq!(watch.record((wall + secs(65), monotonic + secs(20))), Some(ClockJump::Backward(secs(15))));
+        // 次の 10 秒の間にシステムの時刻が 1 秒しか進まなかった
+        assert_eq!(watch.record((wall + secs(66), monotonic + secs(30))), Some(ClockJump::Backward(secs(9))));
+        // 同じだけ進んだ場合は変更なし
+        assert_eq!(watch.record((wall + secs(76), monotonic + secs(40))), None);
+    }
+
+    #[test]
+    fn differences_below_the_threshold_are_ignored() {
+        let (wall, monotonic) = ClockWatch::now();
+        let mut watch = ClockWatch::default();
+        watch.record((wall, monotonic));
+        let below = DISCONTINUITY_THRESHOLD - Duration::from_millis(1);
+        assert_eq!(watch.record((wall + secs(10) + below, monotonic + secs(10))), None);
+        assert_eq!(watch.record((wall + secs(20) + below, monotonic + secs(20) + below)), None);
+        assert_eq!(watch.record((wall + secs(30) + below, monotonic + secs(30) + below + DISCONTINUITY_THRESHOLD)),
+            Some(ClockJump::Backward(DISCONTINUITY_THRESHOLD)));
+        assert_eq!(watch.record((wall + secs(40) + below + DISCONTINUITY_THRESHOLD, monotonic + secs(40) + below + DISCONTINUITY_THRESHOLD)),
+            Some(ClockJump::Forward(DISCONTINUITY_THRESHOLD)));
+    }
+}
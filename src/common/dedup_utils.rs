@@ -0,0 +1,71 @@
+use std::collections::{hash_map::DefaultHasher, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// dedup_max_entries が未指定の場合に保持するハッシュの最大数
+pub const DEFAULT_DEDUP_MAX_ENTRIES: usize = 10_000;
+
+/// 一定時間内に受信した同一メッセージ (トピック + ペイロード、またはペイロードのみ) を検出する。
+///
+/// メッセージを最初に受信してから `window` の間に受信した同じ内容のメッセージを重複とみなす。
+/// 保持するハッシュは最大 `max_entries` 件で、超えた場合は最も古いものから破棄する。
+pub struct Deduplicator {
+    window: Duration,
+    include_topic: bool,
+    max_entries: usize,
+    // ハッシュと最初に受信した時刻
+    seen: HashMap<u64, Instant>,
+    // 受信順のハッシュ (古いものが先頭)
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration, include_topic: bool, max_entries: usize) -> Deduplicator {
+        Deduplicator {
+            window,
+            include_topic,
+            max_entries: max_entries.max(1),
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// メッセージが重複であれば `true` を返す。重複でなければ記録して `false` を返す
+    pub fn is_duplicate(&mut self, topic: &str, payload: &[u8], now: Instant) -> bool {
+        self.expire(now);
+
+        let key = self.key(topic, payload);
+        if self.seen.contains_key(&key) {
+            return true;
+        }
+
+        if self.seen.len() >= self.max_entries
+            && let Some((oldest, _)) = self.order.pop_front() {
+            self.seen.remove(&oldest);
+        }
+        self.seen.insert(key, now);
+        self.order.push_back((key, now));
+        false
+    }
+
+    // ウィンドウを過ぎたハッシュを破棄する
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(key, seen_at)) = self.order.front() {
+            if now.duration_since(seen_at) < self.window {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&key);
+        }
+    }
+
+    fn key(&self, topic: &str, payload: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        if self.include_topic {
+            topic.hash(&mut hasher);
+        }
+        payload.hash(&mut hasher);
+        hasher.finish()
+    }
+}
@@ -0,0 +1,102 @@
+// トピックの一覧を配信するトピック (discover) からのトピックの検出
+use super::config_utils::DiscoverConfig;
+use super::output_utils;
+use super::topic_utils::topic_matches;
+use rumqttc::QoS;
+use std::collections::HashSet;
+
+// max_topics が未指定の場合に検出して購読するトピックの最大数
+const DEFAULT_MAX_TOPICS: usize = 1000;
+
+/// トピックの一覧のペイロードからトピック名を取り出す。
+///
+/// JSON の文字列の配列、またはキーがトピック名の JSON オブジェクトの場合はその要素を、
+/// それ以外は改行またはカンマ区切りのトピック名の一覧として扱う。
+pub fn parse_topic_list(payload: &[u8]) -> Vec<String> {
+    let topics: Vec<String> = match serde_json::from_slice::<serde_json::Value>(payload) {
+        Ok(serde_json::Value::Array(items)) => items.into_iter()
+            .filter_map(|item| item.as_str().map(str::to_string))
+            .collect(),
+        Ok(serde_json::Value::Object(map)) => map.into_iter().map(|(topic, _)| topic).collect(),
+        _ => String::from_utf8_lossy(payload)
+            .split(['\n', ','])
+            .map(|topic| topic.trim().to_string())
+            .collect(),
+    };
+    topics.into_iter().filter(|topic| !topic.is_empty()).collect()
+}
+
+/// 検出したトピックと、pattern に一致して購読したトピックを管理する
+#[derive(Debug)]
+pub struct TopicDiscovery {
+    config: DiscoverConfig,
+    // 検出して購読したトピック (検出した順)
+    subscribed: Vec<String>,
+    // pattern に一致しなかった、または購読中のフィルタに含まれていたトピック (同じトピックを繰り返し表示しないため)
+    ignored: HashSet<String>,
+    limit_warned: bool,
+}
+
+impl TopicDiscovery {
+    pub fn new(config: DiscoverConfig) -> TopicDiscovery {
+        TopicDiscovery { config, subscribed: Vec::new(), ignored: HashSet::new(), limit_warned: false }
+    }
+
+    fn qos(&self) -> QoS {
+        self.config.qos.unwrap_or(QoS::AtMostOnce)
+    }
+
+    /// トピックの一覧を配信するトピックのメッセージかを判定する
+    pub fn is_discovery_topic(&self, topic: &str) -> bool {
+        topic_matches(&self.config.topic, topic)
+    }
+
+    /// 検出して購読したトピック (フィルタ) に一致するかを判定する
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscribed.iter().any(|filter| topic_matches(filter, topic))
+    }
+
+    /// 購読するフィルタ (トピックの一覧を配信するトピックと、これまでに検出して購読したトピック)。再接続時の購読に使う
+    pub fn filters(&self) -> Vec<(String, QoS)> {
+        std::iter::once(&self.config.topic)
+            .chain(&self.subscribed)
+            .map(|topic| (topic.clone(), self.qos()))
+            .collect()
+    }
+
+    /// トピックの一覧のペイロードから新しく検出したトピックのうち、購読するトピックを返す。
+    ///
+    /// pattern に一致しないトピック、`topics` のフィルタや検出済みのトピックに含まれるトピックは除く。
+    /// subscribe が false の場合は検出したトピックを表示するだけで、購読するトピックは返さない。
+    pub fn discover(&mut self, payload: &[u8], topics: &[String]) -> Vec<(String, QoS)> {
+        let pattern = self.config.pattern.as_deref().unwrap_or("#");
+        let auto_subscribe = self.config.subscribe.unwrap_or(true);
+        let max_topics = self.config.max_topics.unwrap_or(DEFAULT_MAX_TOPICS);
+        let mut discovered = Vec::new();
+        for topic in parse_topic_list(payload) {
+            if self.subscribed.contains(&topic) || self.ignored.contains(&topic) || discovered.iter().any(|(t, _)| *t == topic) {
+                continue;
+            }
+            if !topic_matches(pattern, &topic) || topics.iter().any(|filter| topic_matches(filter, &topic)) {
+                self.ignored.insert(topic);
+                continue;
+            }
+            if !auto_subscribe {
+                crate::status!("トピック '{}' を検出しました。", output_utils::status_topic(&topic));
+                self.ignored.insert(topic);
+                continue;
+            }
+            if self.subscribed.len() + discovered.len() >= max_topics {
+                if !self.limit_warned {
+                    eprintln!("警告: 検出して購読したトピックが discover.max_topics ({}) に達したため、これ以上購読しません。", max_topics);
+                    self.limit_warned = true;
+                }
+                break;
+            }
+            crate::status!("トピック '{}' を検出しました。購読します。", output_utils::status_topic(&topic));
+            discovered.push((topic, self.qos()));
+        }
+        self.subscribed.extend(discovered.iter().map(|(topic, _)| topic.clone()));
+        discovered
+    }
+}
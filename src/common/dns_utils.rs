@@ -0,0 +1,124 @@
+// ブローカーの DNS 名の名前解決の記録 (接続のたびの再解決と dns_refresh_secs)
+//
+// rumqttc は接続 (再接続) のたびに broker_address を名前解決し、解決したアドレスを順に試してから接続エラーにする。
+// プロセス内で名前解決の結果をキャッシュしないため、DNS の TTL はシステムのリゾルバーに従う。
+// ここでは同じ名前を接続の前に解決して、解決したアドレスとその変化、名前解決の失敗を表示する。
+// 接続したときに rumqttc が接続したアドレスを記録し、dns_refresh_secs で解決し直した結果からそのアドレスがなくなった場合に再接続する。
+use super::config_utils::Config;
+use super::socket_utils;
+use std::net::{IpAddr, SocketAddr};
+
+/// ブローカーの名前解決の結果を保持する
+pub struct BrokerResolver {
+    host: String,
+    port: u16,
+    // proxy_url 経由 (名前解決はプロキシが行う) や IP アドレスを指定した場合は名前解決しない。
+    // failover_brokers を指定した場合は、接続先が切り替わるため最初のブローカーの名前解決を表示しない
+    enabled: bool,
+    // 前回の名前解決で得たアドレス (未解決または失敗した場合は空)
+    addresses: Vec<SocketAddr>,
+    // 接続中のアドレス (接続したときに解決したアドレスのうち、rumqttc が接続したもの。分からない場合は `None`)
+    peer: Option<SocketAddr>,
+}
+
+impl BrokerResolver {
+    pub fn new(config: &Config) -> BrokerResolver {
+        let host = config.broker_address.trim_start_matches('[').trim_end_matches(']').to_string();
+        let enabled = config.proxy_url.is_none() && config.failover_brokers.is_none() && host.parse::<IpAddr>().is_err();
+        BrokerResolver { host, port: config.broker_port, enabled, addresses: Vec::new(), peer: None }
+    }
+
+    /// ブローカーに接続した。rumqttc が接続したアドレスを記録する
+    pub fn connected(&mut self) {
+        if self.enabled {
+            self.peer = socket_utils::connected_peer(&self.addresses);
+        }
+    }
+
+    /// ブローカーの名前を解決し直し、前回から変わった場合は表示する。
+    /// 接続中のアドレスがなくなった場合 (接続中のアドレスが分からない場合は、前回解決したアドレスがすべてなくなった場合) は
+    /// `true` を返す (接続中のアドレスが古くなっている)
+    pub async fn refresh(&mut self) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let resolved: Vec<SocketAddr> = match tokio::net::lookup_host((self.host.as_str(), self.port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                eprintln!("エラー: ブローカー '{}' の名前解決に失敗しました: {}", self.host, e);
+                return false;
+            }
+        };
+        self.update(resolved)
+    }
+
+    // 名前解決の結果を記録し、接続中のアドレスが古くなっている場合は `true` を返す
+    fn update(&mut self, resolved: Vec<SocketAddr>) -> bool {
+        if resolved.is_empty() || resolved == self.addresses {
+            return false;
+        }
+        let stale = match self.peer {
+            Some(peer) => !resolved.contains(&peer),
+            None => !self.addresses.is_empty() && !self.addresses.iter().any(|addr| resolved.contains(addr)),
+        };
+        if self.addresses.is_empty() {
+            crate::status!("ブローカー '{}' を {} に名前解決しました。", self.host, format_addresses(&resolved));
+        } else {
+            crate::status!("ブローカー '{}' のアドレスが {} から {} に変わりました。",
+                self.host, format_addresses(&self.addresses), format_addresses(&resolved));
+        }
+        self.addresses = resolved;
+        stale
+    }
+
+    /// 接続時に表示する、名前解決したアドレスの一覧 (名前解決していない場合は `None`)
+    pub fn describe(&self) -> Option<String> {
+        (!self.addresses.is_empty()).then(|| format!("{} → {}", self.host, format_addresses(&self.addresses)))
+    }
+}
+
+fn format_addresses(addresses: &[SocketAddr]) -> String {
+    addresses.iter().map(|addr| addr.ip().to_string()).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver() -> BrokerResolver {
+        let config: Config = serde_yaml::from_str("client_id: dns\nbroker_address: broker.example.com\nbroker_port: 1883\n").unwrap();
+        BrokerResolver::new(&config)
+    }
+
+    fn addresses(ips: &[&str]) -> Vec<SocketAddr> {
+        ips.iter().map(|ip| SocketAddr::new(ip.parse().unwrap(), 1883)).collect()
+    }
+
+    #[test]
+    fn reconnects_when_the_connected_address_leaves_the_resolved_set() {
+        let mut resolver = resolver();
+        assert!(!resolver.update(addresses(&["192.0.2.1"])));
+        resolver.peer = Some(addresses(&["192.0.2.1"])[0]);
+        // アドレスが追加されただけでは再接続しない
+        assert!(!resolver.update(addresses(&["192.0.2.1", "192.0.2.2"])));
+        // 前回のアドレスの一部 (192.0.2.2) は残っているが、接続中の 192.0.2.1 がなくなった
+        assert!(resolver.update(addresses(&["192.0.2.2"])));
+    }
+
+    #[test]
+    fn keeps_the_connection_while_the_connected_address_remains() {
+        let mut resolver = resolver();
+        resolver.update(addresses(&["192.0.2.1", "192.0.2.2"]));
+        resolver.peer = Some(addresses(&["192.0.2.2"])[0]);
+        assert!(!resolver.update(addresses(&["192.0.2.2", "192.0.2.3"])));
+    }
+
+    #[test]
+    fn without_the_connected_address_all_previous_addresses_must_be_gone() {
+        let mut resolver = resolver();
+        resolver.update(addresses(&["192.0.2.1"]));
+        assert!(!resolver.update(addresses(&["192.0.2.1", "192.0.2.2"])));
+        assert!(!resolver.update(addresses(&["192.0.2.2"])));
+        assert!(resolver.update(addresses(&["192.0.2.3"])));
+    }
+}
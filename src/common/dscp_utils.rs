@@ -0,0 +1,77 @@
+// ブローカーとの接続のソケットへの DSCP (IP ヘッダーの ToS / Traffic Class) の設定 (socket_dscp)
+//
+// rumqttc が作成したソケットには接続後に `socket_utils` が設定し、proxy_url の中継がプロキシに接続するソケットには
+// ここで接続する前に設定する。
+use super::config_utils::Config;
+use std::{io, net::SocketAddr, sync::atomic::{AtomicBool, Ordering}};
+use tokio::net::{TcpSocket, TcpStream};
+
+// DSCP を設定できなかった警告を 1 回だけ表示する
+static DSCP_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// DSCP の最大値 (6 ビット)
+pub const MAX_DSCP: u8 = 63;
+
+/// socket_dscp を適用するか。scheme: quic では適用しない (警告を表示する)
+pub fn socket_dscp(config: &Config) -> Option<u8> {
+    let dscp = config.socket_dscp?;
+    if config.scheme.as_deref() == Some("quic") {
+        eprintln!("警告: socket_dscp は scheme: quic では無効です (scheme: tcp / mqtts / ssl / ws / wss でのみ適用します)。");
+        return None;
+    }
+    Some(dscp)
+}
+
+/// `addr` に接続したストリームを返す。`dscp` が指定されている場合は接続する前のソケットに設定する。
+/// 設定できない場合 (権限がない、OS が対応していないなど) は警告を 1 回表示して、設定せずに接続する
+pub async fn connect_addr(addr: SocketAddr, dscp: Option<u8>) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    if let Some(dscp) = dscp
+        && let Err(e) = set_dscp(&socket, addr, dscp) {
+        warn_dscp_failure(dscp, e);
+    }
+    socket.connect(addr).await
+}
+
+/// `host:port` を名前解決し、解決したアドレスに順に接続する (すべて失敗した場合は最後のエラーを返す)
+pub async fn connect(host: &str, port: u16, dscp: Option<u8>) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("'{}' のアドレスが見つかりません", host));
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        match connect_addr(addr, dscp).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// DSCP を設定できなかった警告を 1 回だけ表示する (設定できなくても接続は続ける)
+pub(crate) fn warn_dscp_failure(dscp: u8, e: io::Error) {
+    if !DSCP_WARNED.swap(true, Ordering::Relaxed) {
+        eprintln!("警告: ソケットに DSCP {} を設定できませんでした。DSCP を設定せずに接続を続けます: {}", dscp, e);
+    }
+}
+
+/// `addr` (IPv4 / IPv6) に接続する (または接続した) ソケットに DSCP を設定する。
+/// ToS / Traffic Class のバイトの上位 6 ビットが DSCP (下位 2 ビットは ECN のため 0 のままにする)
+#[cfg(unix)]
+pub(crate) fn set_dscp(socket: &impl std::os::fd::AsRawFd, addr: SocketAddr, dscp: u8) -> io::Result<()> {
+    let tos = libc::c_int::from(dscp) << 2;
+    let (level, name) = if addr.is_ipv4() { (libc::IPPROTO_IP, libc::IP_TOS) } else { (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) };
+    // SAFETY: 有効なソケットのファイルディスクリプタに、サイズを指定した c_int の値を渡している
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&tos as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_dscp<T>(_socket: &T, _addr: SocketAddr, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "この OS では socket_dscp に対応していません"))
+}
@@ -0,0 +1,84 @@
+// 終了の原因となるエラーと終了コードの対応 (BSD の sysexits.h に準拠)
+use std::{fmt, process};
+
+/// 設定ファイルやコマンドライン引数が不正な場合の終了コード (EX_USAGE)
+pub const EXIT_CONFIG_ERROR: i32 = 64;
+/// exactly-once の検証 (verify_exactly_once) で重複や欠番が見つかった場合の終了コード (EX_DATAERR)
+pub const EXIT_DATA_ERROR: i32 = 65;
+/// ブローカーに接続できない (接続拒否・到達不能) 場合の終了コード (EX_UNAVAILABLE)
+pub const EXIT_UNAVAILABLE: i32 = 69;
+/// 再試行を繰り返しても接続できなかった場合、wait_for のメッセージや idle_timeout_secs の間にメッセージ、QoS 2 の確認応答を受信しなかった場合の終了コード (EX_TEMPFAIL)
+pub const EXIT_TEMP_FAILURE: i32 = 75;
+/// 認証・認可に失敗した場合の終了コード (EX_NOPERM)
+pub const EXIT_AUTH_ERROR: i32 = 77;
+
+/// プロセスを終了させるエラー。内容は標準エラー出力に表示するメッセージ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppError {
+    /// 設定ファイルやコマンドライン引数が不正
+    Config(String),
+    /// ブローカーに接続できない、または接続が拒否された
+    Unavailable(String),
+    /// ブローカーでの認証・認可に失敗した
+    Auth(String),
+    /// 再試行の上限に達した
+    RetriesExhausted(String),
+    /// 受信したメッセージの検証に失敗した
+    Verification(String),
+    /// wait_for で待っていたメッセージ (または idle_timeout_secs の間にメッセージ、qos2_completion_timeout_secs 以内に QoS 2 の確認応答) を受信しなかった
+    NotReceived(String),
+}
+
+impl AppError {
+    /// このエラーで終了する場合の終了コード
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Config(_) => EXIT_CONFIG_ERROR,
+            AppError::Unavailable(_) => EXIT_UNAVAILABLE,
+            AppError::Auth(_) => EXIT_AUTH_ERROR,
+            AppError::RetriesExhausted(_) => EXIT_TEMP_FAILURE,
+            AppError::Verification(_) => EXIT_DATA_ERROR,
+            AppError::NotReceived(_) => EXIT_TEMP_FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Config(message)
+            | AppError::Unavailable(message)
+            | AppError::Auth(message)
+            | AppError::RetriesExhausted(message)
+            | AppError::Verification(message)
+            | AppError::NotReceived(message) => f.write_str(message),
+        }
+    }
+}
+
+/// エラーメッセージを表示し、エラーの種類に応じた終了コードでプロセスを終了する
+pub fn exit_with(err: AppError) -> ! {
+    eprintln!("{}", err);
+    process::exit(err.exit_code());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_each_variant_to_its_sysexits_code() {
+        let message = || "終了".to_string();
+        assert_eq!(AppError::Config(message()).exit_code(), 64);
+        assert_eq!(AppError::Unavailable(message()).exit_code(), 69);
+        assert_eq!(AppError::Auth(message()).exit_code(), 77);
+        assert_eq!(AppError::RetriesExhausted(message()).exit_code(), 75);
+        assert_eq!(AppError::Verification(message()).exit_code(), 65);
+        assert_eq!(AppError::NotReceived(message()).exit_code(), 75);
+    }
+
+    #[test]
+    fn displays_only_the_message() {
+        assert_eq!(AppError::Auth("認証に失敗しました".to_string()).to_string(), "認証に失敗しました");
+    }
+}
@@ -0,0 +1,89 @@
+// 接続できない場合に切り替えるブローカー (failover_brokers)
+//
+// 最初のブローカー (url / broker_address / broker_port) と failover_brokers の各ブローカーについて、接続先と
+// クライアント証明書を置き換えた Config を作る。イベントループは接続先ごとに MqttOptions を構築しておき、
+// 接続できなかった場合に次の接続先に切り替える (`mqtt_utils::MqttEventLoop`)。
+use super::config_utils::{Config, FailoverBrokerConfig};
+use super::url_utils;
+
+/// 接続先ごとの Config (最初の要素が最初のブローカー)。failover_brokers を指定しない場合は `config` のみを返す
+pub fn broker_configs(config: &Config) -> Result<Vec<Config>, String> {
+    let mut primary = config.clone();
+    primary.failover_brokers = None;
+    let mut configs = vec![primary];
+    for (index, broker) in config.failover_brokers.iter().flatten().enumerate() {
+        let target = broker_config(&configs[0], broker).map_err(|e| format!("failover_brokers[{}]: {}", index, e))?;
+        configs.push(target);
+    }
+    Ok(configs)
+}
+
+/// 接続先の名前 (host:port。状態の表示に使う)
+pub fn broker_name(config: &Config) -> String {
+    if config.broker_address.contains(':') && !config.broker_address.starts_with('[') {
+        format!("[{}]:{}", config.broker_address, config.broker_port)
+    } else {
+        format!("{}:{}", config.broker_address, config.broker_port)
+    }
+}
+
+fn broker_config(primary: &Config, broker: &FailoverBrokerConfig) -> Result<Config, String> {
+    let mut target = primary.clone();
+    target.url = broker.url.clone();
+    if let Some(address) = &broker.broker_address {
+        target.broker_address = address.clone();
+    }
+    if let Some(port) = broker.broker_port {
+        target.broker_port = port;
+    }
+    url_utils::apply_broker_url(&mut target)?;
+    if broker.url.is_none() && broker.broker_address.is_none() {
+        return Err("url または broker_address を指定してください".to_string());
+    }
+    // 証明書を指定した場合は、最初のブローカーの証明書 (ファイル・PEM 文字列のどちらでも) の代わりに使う
+    if broker.client_combined_path.is_some() || broker.client_cert_pem.is_some() || broker.client_key_pem.is_some() {
+        target.client_combined_path = broker.client_combined_path.clone();
+        target.client_cert_pem = broker.client_cert_pem.clone();
+        target.client_key_pem = broker.client_key_pem.clone();
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("client_id: failover\nscheme: mqtts\nbroker_address: primary.example.com\nbroker_port: 8883\nclient_combined_path: primary.pem\n{}", yaml)).unwrap()
+    }
+
+    #[test]
+    fn without_failover_brokers_only_the_primary_is_used() {
+        let configs = broker_configs(&config("")).unwrap();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(broker_name(&configs[0]), "primary.example.com:8883");
+    }
+
+    #[test]
+    fn failover_brokers_inherit_the_primary_settings() {
+        let configs = broker_configs(&config("failover_brokers:\n  - broker_address: backup.example.com\n  - broker_address: \"::1\"\n    broker_port: 18883\n")).unwrap();
+        assert_eq!(configs.iter().map(broker_name).collect::<Vec<_>>(), ["primary.example.com:8883", "backup.example.com:8883", "[::1]:18883"]);
+        assert!(configs.iter().all(|config| config.scheme.as_deref() == Some("mqtts") && config.failover_brokers.is_none()));
+        assert!(configs.iter().all(|config| config.client_combined_path.as_deref() == Some("primary.pem")));
+    }
+
+    #[test]
+    fn failover_brokers_can_present_their_own_client_certificate() {
+        let configs = broker_configs(&config("failover_brokers:\n  - url: mqtts://backup.example.com:9883\n    client_cert_pem: cert\n    client_key_pem: key\n")).unwrap();
+        assert_eq!(broker_name(&configs[1]), "backup.example.com:9883");
+        assert_eq!(configs[1].client_combined_path, None);
+        assert_eq!((configs[1].client_cert_pem.as_deref(), configs[1].client_key_pem.as_deref()), (Some("cert"), Some("key")));
+        assert_eq!(configs[0].client_combined_path.as_deref(), Some("primary.pem"));
+    }
+
+    #[test]
+    fn failover_brokers_need_an_address() {
+        let e = broker_configs(&config("failover_brokers:\n  - broker_port: 1883\n")).unwrap_err();
+        assert!(e.starts_with("failover_brokers[0]:"), "{}", e);
+    }
+}
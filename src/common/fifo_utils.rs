@@ -0,0 +1,150 @@
+// 受信したメッセージの名前付きパイプ (FIFO) への出力 (output_fifo、Unix のみ)
+use super::config_utils::{Config, OutputFormat, RawDelimiter};
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils;
+use super::sink_utils::Sink;
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, os::unix::fs::{FileTypeExt, OpenOptionsExt}, os::fd::AsRawFd};
+use std::{sync::{mpsc, Arc}, thread, time::{Duration, Instant}};
+
+// 書き込み待ちの記録の最大数 (超えた分は破棄し、イベントループを待たせない)
+const FIFO_QUEUE_CAPACITY: usize = 1024;
+// 読み取り側がいない場合に、名前付きパイプを開き直すまでの間隔 (その間の記録は破棄する)
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 受信したメッセージを output_format の形式で名前付きパイプに書き込む。
+///
+/// 書き込みは専用のスレッドで行う。読み取り側のプロセスがいない場合 (開けない場合や、読み取り側が閉じて書き込みが
+/// EPIPE で失敗した場合) はその記録を破棄し、一定時間後に開き直す。SIGPIPE は Rust の標準ライブラリが起動時に
+/// 無視する設定にしているため、読み取り側が閉じてもプロセスは終了せず、書き込みのエラーとして扱える。
+pub struct FifoOutput {
+    tx: mpsc::SyncSender<Vec<u8>>,
+    path: String,
+    // output_format: raw の場合はペイロードを受信したまま raw_delimiter の区切りを付けて書き込む
+    raw_delimiter: Option<RawDelimiter>,
+}
+
+// 書き込み用のスレッドが保持する名前付きパイプの状態
+struct Writer {
+    path: String,
+    file: Option<File>,
+    // 開けなかった (または書き込みに失敗した) 場合に、次に開き直す時刻
+    retry_at: Option<Instant>,
+    // CSV の場合は開くたびに先頭でヘッダー行を書き込む
+    header: Option<&'static str>,
+}
+
+impl FifoOutput {
+    /// output_fifo が指定されていない場合は `None` を返す。パスが名前付きパイプでない場合は設定エラーで終了する
+    pub fn open(config: &Config) -> Option<Arc<FifoOutput>> {
+        let path = config.output_fifo.clone()?;
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_fifo() => {}
+            Ok(_) => exit_with(AppError::Config(format!("output_fifo '{}' は名前付きパイプではありません (mkfifo で作成してください)。", path))),
+            Err(e) => exit_with(AppError::Config(format!("output_fifo '{}' を確認できませんでした (mkfifo で作成してください): {}", path, e))),
+        }
+        let output_format = config.output_format.unwrap_or_default();
+        if output_format == OutputFormat::JsonArray {
+            eprintln!("警告: output_format: json-array の場合、output_fifo には 1 件ずつ 1 行の JSON として書き込みます。");
+        }
+        let header = (output_format == OutputFormat::Csv).then_some(output_utils::CSV_HEADER);
+
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(FIFO_QUEUE_CAPACITY);
+        let mut writer = Writer { path: path.clone(), file: None, retry_at: None, header };
+        thread::spawn(move || {
+            for record in rx {
+                writer.write(&record);
+            }
+        });
+        crate::status!("受信したメッセージを名前付きパイプ '{}' に書き込みます。", path);
+        let raw_delimiter = (output_format == OutputFormat::Raw).then(|| config.raw_delimiter.clone().unwrap_or(RawDelimiter::Newline));
+        Some(Arc::new(FifoOutput { tx, path, raw_delimiter }))
+    }
+}
+
+impl Sink for FifoOutput {
+    fn name(&self) -> String {
+        format!("名前付きパイプ '{}'", self.path)
+    }
+
+    /// 受信したメッセージ (format_message の出力と表示したペイロード) を書き込む。書き込み待ちの記録が多すぎる場合は破棄してエラーを返す
+    /// (読み取り側がいない場合は書き込み用のスレッドが表示する)
+    fn write(&self, message: &ReceivedMessage, output: &str, _config: &Config) -> Result<(), String> {
+        let record = match &self.raw_delimiter {
+            Some(delimiter) => {
+                let mut record = Vec::with_capacity(message.payload.len() + 4);
+                let _ = output_utils::write_delimited(&mut record, &message.payload, delimiter);
+                record
+            }
+            None => format!("{}\n", output).into_bytes(),
+        };
+        match self.tx.try_send(record) {
+            Err(mpsc::TrySendError::Full(_)) => Err("書き込み待ちの記録が多すぎるため破棄しました (読み取りが追いついていません)".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Writer {
+    fn write(&mut self, record: &[u8]) {
+        if self.file.is_none() {
+            if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                return;
+            }
+            self.open();
+        }
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if let Err(e) = file.write_all(record) {
+            self.file = None;
+            let message = if e.kind() == io::ErrorKind::BrokenPipe {
+                "読み取り側のプロセスが名前付きパイプを閉じました".to_string()
+            } else {
+                format!("書き込みに失敗しました: {}", e)
+            };
+            self.fail(message);
+        }
+    }
+
+    fn open(&mut self) {
+        match open_fifo(&self.path) {
+            Ok(mut file) => {
+                if let Some(header) = self.header
+                    && let Err(e) = writeln!(file, "{}", header) {
+                    self.fail(format!("書き込みに失敗しました: {}", e));
+                    return;
+                }
+                if self.retry_at.take().is_some() {
+                    crate::status!("名前付きパイプ '{}' への書き込みを再開しました。", self.path);
+                }
+                self.file = Some(file);
+            }
+            // ENXIO: 読み取り側のプロセスがまだ開いていない
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => self.fail("読み取り側のプロセスがいません".to_string()),
+            Err(e) => self.fail(format!("開けませんでした: {}", e)),
+        }
+    }
+
+    // 失敗は開き直せるまで 1 回だけ表示する
+    fn fail(&mut self, reason: String) {
+        if self.retry_at.is_none() {
+            eprintln!("警告: 名前付きパイプ '{}' に書き込めません ({})。読み取り側が開くまで {} 秒ごとに開き直します (それまでの記録は破棄します)。",
+                self.path, reason, REOPEN_INTERVAL.as_secs());
+        }
+        self.retry_at = Some(Instant::now() + REOPEN_INTERVAL);
+    }
+}
+
+// 読み取り側がいない場合に待たないよう O_NONBLOCK で開き、開けたら書き込みは読み取り側に合わせて待つようにする
+// (読み取りが追いつかない場合は書き込み待ちの記録が溜まり、上限を超えた分が破棄される)
+fn open_fifo(path: &str) -> io::Result<File> {
+    let file = OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path)?;
+    let fd = file.as_raw_fd();
+    // SAFETY: fd は開いたファイルの有効なディスクリプターで、ファイル状態フラグを読み書きするだけ
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
@@ -0,0 +1,135 @@
+// トピックごとの表示するペイロードの書式 (format_rules)
+use super::config_utils::{Config, PayloadDisplay, PayloadFormat};
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils;
+use super::topic_utils::topic_matches;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+/// format_rules のルール (トピックパターンと書式) を上から順に評価し、最初に一致したルールの書式でペイロードを変換する。
+/// どのルールにも一致しないメッセージは全体の payload_format でデコードする
+pub struct FormatRules {
+    rules: Vec<Rule>,
+    default_format: PayloadFormat,
+    // --verbose の場合に、各メッセージに適用したルールを表示する
+    verbose: bool,
+}
+
+struct Rule {
+    pattern: String,
+    display: PayloadDisplay,
+    payload_format: Option<PayloadFormat>,
+}
+
+impl FormatRules {
+    /// format_rules が指定されていない場合は `None` を返す
+    pub fn new(config: &Config) -> Option<FormatRules> {
+        let rules = config.format_rules.as_ref().filter(|rules| !rules.is_empty())?;
+        let rules = rules.iter().map(|rule| Rule {
+            pattern: rule.pattern.clone(),
+            display: rule.format.unwrap_or_default(),
+            payload_format: rule.payload_format,
+        }).collect();
+        Some(FormatRules { rules, default_format: config.payload_format.unwrap_or_default(), verbose: output_utils::is_verbose() })
+    }
+
+    /// メッセージのトピックに一致するルールの payload_format でデコードし、書式に変換したメッセージを返す
+    pub fn apply(&self, message: ReceivedMessage) -> ReceivedMessage {
+        let Some(rule) = self.rules.iter().find(|rule| topic_matches(&rule.pattern, &message.topic)) else {
+            if self.verbose {
+                eprintln!("書式のルール: トピック '{}' に一致するルールがないため、全体の書式で表示します。", output_utils::status_topic(&message.topic));
+            }
+            return output_utils::decode_payload_as(message, self.default_format);
+        };
+        if self.verbose {
+            eprintln!("書式のルール: トピック '{}' にルール '{}' ({}) を適用します。", output_utils::status_topic(&message.topic), rule.pattern, display_name(rule.display));
+        }
+        let mut message = output_utils::decode_payload_as(message, rule.payload_format.unwrap_or(self.default_format));
+        // 空のペイロード (retain メッセージの削除など) は変換しない
+        if message.payload.is_empty() {
+            return message;
+        }
+        let display = match rule.display {
+            PayloadDisplay::Text => return message,
+            PayloadDisplay::Json | PayloadDisplay::JsonPretty => match serde_json::from_slice::<serde_json::Value>(&message.payload) {
+                Ok(value) if rule.display == PayloadDisplay::JsonPretty => serde_json::to_string_pretty(&value).expect("JSON の値は文字列に変換できる"),
+                Ok(value) => value.to_string(),
+                Err(e) => {
+                    eprintln!("警告: トピック '{}' のペイロードを JSON として整形できませんでした: {}", output_utils::status_topic(&message.topic), e);
+                    return message;
+                }
+            },
+            PayloadDisplay::Hex => message.payload.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+            PayloadDisplay::Base64 => BASE64.encode(&message.payload),
+        };
+        message.payload = display.into();
+        message
+    }
+}
+
+// 設定ファイルでの書式の名前 (serde の名前を使う)
+fn display_name(display: PayloadDisplay) -> String {
+    match serde_json::to_value(display) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => format!("{:?}", display),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use rumqttc::QoS;
+
+    fn rules(yaml: &str) -> FormatRules {
+        let config: Config = serde_yaml::from_str(&format!("client_id: format\nbroker_address: localhost\nbroker_port: 1883\nformat_rules:\n{}", yaml)).unwrap();
+        FormatRules::new(&config).unwrap()
+    }
+
+    fn message(topic: &str, payload: &[u8]) -> ReceivedMessage {
+        ReceivedMessage {
+            topic: topic.to_string(),
+            payload: Bytes::copy_from_slice(payload),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pkid: 0,
+            properties: None,
+            acker: None,
+        }
+    }
+
+    fn apply(rules: &FormatRules, topic: &str, payload: &[u8]) -> Vec<u8> {
+        rules.apply(message(topic, payload)).payload.to_vec()
+    }
+
+    #[test]
+    fn formats_json_compact_and_pretty() {
+        let rules = rules("  - pattern: compact/#\n    format: json\n  - pattern: pretty/#\n    format: json-pretty\n");
+        assert_eq!(apply(&rules, "compact/a", b"{ \"a\" : [1, 2] }"), br#"{"a":[1,2]}"#);
+        assert_eq!(apply(&rules, "pretty/a", br#"{"a":1}"#), b"{\n  \"a\": 1\n}");
+        // JSON でないペイロードは受信したまま表示する
+        assert_eq!(apply(&rules, "compact/a", b"not json"), b"not json");
+    }
+
+    #[test]
+    fn formats_hex_and_base64() {
+        let rules = rules("  - pattern: bin/+/hex\n    format: hex\n  - pattern: bin/#\n    format: base64\n");
+        assert_eq!(apply(&rules, "bin/a/hex", &[0x00, 0xab, 0x10]), b"00 ab 10");
+        assert_eq!(apply(&rules, "bin/a/b64", b"hello"), b"aGVsbG8=");
+        // 空のペイロード (retain メッセージの削除など) は変換しない
+        assert_eq!(apply(&rules, "bin/a/hex", b""), b"");
+    }
+
+    #[test]
+    fn first_matching_rule_wins_and_unmatched_topics_fall_back() {
+        let rules = rules("  - pattern: a/#\n    format: hex\n  - pattern: a/b\n    format: base64\n");
+        assert_eq!(apply(&rules, "a/b", b"A"), b"41");
+        assert_eq!(apply(&rules, "other", b"A"), b"A");
+    }
+
+    #[test]
+    fn display_names_match_the_config_names() {
+        assert_eq!(display_name(PayloadDisplay::Text), "text");
+        assert_eq!(display_name(PayloadDisplay::JsonPretty), "json-pretty");
+        assert_eq!(display_name(PayloadDisplay::Base64), "base64");
+    }
+}
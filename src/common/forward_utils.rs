@@ -0,0 +1,599 @@
+// 受信したメッセージを別のブローカーに受信順に転送する (bridge) ためのキュー (forward_queue_max / forward_queue_file) と転送
+use super::config_utils::{BridgeConfig, Config, OverflowPolicy};
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::{self, MqttEvent, ReceivedMessage};
+use super::output_utils;
+use super::qos_utils;
+use super::url_utils;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use rumqttc::{Outgoing, QoS};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}};
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::time::Duration;
+use tokio::{sync::Notify, time};
+
+/// forward_queue_max の既定値
+pub const DEFAULT_FORWARD_QUEUE_MAX: usize = 1000;
+// 転送先に送信して確認応答を待つメッセージの最大数 (転送先のクライアントのチャネル容量と同じにし、try_publish が満杯で失敗しないようにする)
+const FORWARD_WINDOW: usize = 10;
+// 転送先に接続できなかった (切断された) 場合に、再接続するまで待つ時間
+const BRIDGE_RETRY_DELAY: Duration = Duration::from_secs(1);
+// forward_queue_file の記録の行数が、保持しているメッセージの件数の 2 倍にこれを加えた数を超えたらファイルを書き直して詰める
+const JOURNAL_COMPACT_SLACK: usize = 1024;
+// 転送のキューが満杯でメッセージを破棄した場合に、警告を表示する間隔 (件数)
+const DROP_REPORT_INTERVAL: u64 = 1000;
+
+/// 転送するメッセージを受信順に保持する容量制限付きのキュー。
+///
+/// 転送先に送信して確認応答 (PUBACK) を待っているメッセージもキューに残し、転送先の接続が切れた場合は
+/// (`disconnected`) それらを未送信のメッセージの前に戻す。再接続後は切断前に送信したメッセージを受信順に送り終えてから
+/// 新しく受信したメッセージを転送するため、転送先では受信順が保たれる。QoS 1 の確認応答は送信した順に届くため、
+/// `acknowledged` は最も古い送信中のメッセージを完了とする。
+///
+/// forward_queue_file を指定した場合は、追加と削除をファイルに記録し、起動時に読み込む。プロセスが終了しても
+/// 確認応答を受け取っていないメッセージは次の起動時に受信順に転送する (送信中だったメッセージは重複して届くことがある)。
+/// 記録は書き込むたびに OS に渡すが同期 (fsync) はしないため、OS ごと停止した場合は直前の記録が失われることがある。
+///
+/// スレッドモデル: 受信側 (`push`、`Bridge::forward` など) と転送側 (`next` / `acknowledged` /
+/// `disconnected`、転送先のイベントループを処理するタスク) はそれぞれ 1 つであることを前提とする。
+/// 送信中のメッセージを含めて forward_queue_max 件を超える場合は forward_queue_overflow に従って待機または破棄する。
+pub struct ForwardQueue {
+    state: Mutex<ForwardState>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // 未送信のメッセージが追加された (または戻された) ことを転送側に通知する
+    item_pushed: Notify,
+    // 確認応答を受け取ってキューに空きができたことを受信側に通知する
+    item_removed: Notify,
+}
+
+#[derive(Default)]
+struct ForwardState {
+    // 送信して確認応答を待っているメッセージ (送信した順)
+    inflight: VecDeque<ReceivedMessage>,
+    // まだ送信していないメッセージ (受信順)
+    pending: VecDeque<ReceivedMessage>,
+    // forward_queue_file (指定した場合のみ)
+    journal: Option<Journal>,
+}
+
+impl ForwardState {
+    fn len(&self) -> usize {
+        self.inflight.len() + self.pending.len()
+    }
+
+    fn push(&mut self, message: Option<ReceivedMessage>) {
+        let Some(message) = message else {
+            return;
+        };
+        let record = self.journal.is_some().then(|| JournalRecord::push(&message));
+        self.pending.push_back(message);
+        if let Some(record) = record {
+            self.record(record);
+        }
+    }
+
+    // キューの変更 (反映済み) をファイルに記録する。前回の書き込みに失敗したか記録が多くなった場合は、
+    // 保持しているメッセージ (送信中、未送信の順で、キュー全体の受信順) でファイルを書き直す
+    fn record(&mut self, record: JournalRecord) {
+        let ForwardState { inflight, pending, journal } = self;
+        let Some(journal) = journal else {
+            return;
+        };
+        let result = if journal.dirty || journal.records > (inflight.len() + pending.len()) * 2 + JOURNAL_COMPACT_SLACK {
+            journal.rewrite(inflight.iter().chain(pending.iter()))
+        } else {
+            journal.append(&record)
+        };
+        match result {
+            Ok(()) if journal.dirty => {
+                journal.dirty = false;
+                crate::status!("forward_queue_file '{}' への記録を再開しました。", journal.path.display());
+            }
+            Ok(()) => {}
+            Err(e) => {
+                if !journal.dirty {
+                    eprintln!("警告: forward_queue_file '{}' に記録できませんでした: {}。書き込めるようになるまでメモリ上のキューで転送を続けます。", journal.path.display(), e);
+                }
+                journal.dirty = true;
+            }
+        }
+    }
+}
+
+// forward_queue_file の 1 行 (キューへの追加、またはキュー全体での位置を指定した削除)
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum JournalRecord {
+    Push { topic: String, payload: String, qos: u8, retain: bool },
+    Remove { index: usize },
+}
+
+impl JournalRecord {
+    fn push(message: &ReceivedMessage) -> JournalRecord {
+        JournalRecord::Push {
+            topic: message.topic.clone(),
+            payload: BASE64.encode(&message.payload),
+            qos: qos_utils::qos_number(message.qos),
+            retain: message.retain,
+        }
+    }
+}
+
+// forward_queue_file への記録
+struct Journal {
+    path: PathBuf,
+    file: File,
+    // ファイルの行数
+    records: usize,
+    // 前回の書き込みに失敗したため、ファイルがキューの内容と一致していない
+    dirty: bool,
+}
+
+impl Journal {
+    // ファイルを読み込んで記録されているキューの内容を返す。読み込んだ内容でファイルを書き直してから追記する
+    fn open(path: &Path) -> io::Result<(Journal, VecDeque<ReceivedMessage>)> {
+        let messages = match File::open(path) {
+            Ok(file) => read_journal(file, path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e),
+        };
+        let mut journal = Journal { path: path.to_path_buf(), file: write_journal(path, messages.iter())?, records: 0, dirty: false };
+        journal.records = messages.len();
+        Ok((journal, messages))
+    }
+
+    fn append(&mut self, record: &JournalRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record).map_err(io::Error::other)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn rewrite<'a>(&mut self, messages: impl Iterator<Item = &'a ReceivedMessage> + Clone) -> io::Result<()> {
+        self.file = write_journal(&self.path, messages.clone())?;
+        self.records = messages.count();
+        Ok(())
+    }
+}
+
+// 記録を先頭から順に反映する。最後の行が途中までしか書き込まれていない場合 (書き込み中に終了した場合) などは、読めない行以降を無視する
+fn read_journal(file: File, path: &Path) -> io::Result<VecDeque<ReceivedMessage>> {
+    let mut messages = VecDeque::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let record = match serde_json::from_str::<JournalRecord>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("警告: forward_queue_file '{}' の {} 行目を読めないため、以降の記録を無視します: {}", path.display(), number + 1, e);
+                break;
+            }
+        };
+        match record {
+            JournalRecord::Push { topic, payload, qos, retain } => {
+                let (Ok(payload), Some(qos)) = (BASE64.decode(&payload), qos_utils::to_qos(qos.into())) else {
+                    eprintln!("警告: forward_queue_file '{}' の {} 行目のメッセージが不正なため、以降の記録を無視します。", path.display(), number + 1);
+                    break;
+                };
+                messages.push_back(ReceivedMessage {
+                    topic,
+                    payload: Bytes::from(payload),
+                    qos,
+                    retain,
+                    pkid: 0,
+                    properties: None,
+                    acker: None,
+                });
+            }
+            JournalRecord::Remove { index } => {
+                messages.remove(index);
+            }
+        }
+    }
+    Ok(messages)
+}
+
+// 一時ファイルに書き込んでから置き換え、追記用に開き直す (書き込み中に終了しても元のファイルが残る)
+fn write_journal<'a>(path: &Path, messages: impl Iterator<Item = &'a ReceivedMessage>) -> io::Result<File> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut contents = String::new();
+    for message in messages {
+        contents.push_str(&serde_json::to_string(&JournalRecord::push(message)).map_err(io::Error::other)?);
+        contents.push('\n');
+    }
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+impl ForwardQueue {
+    /// 容量は forward_queue_max、満杯の場合の動作は forward_queue_overflow から決める。
+    /// forward_queue_file を指定した場合は、ファイルに記録されている未転送のメッセージを読み込む
+    pub fn new(config: &Config) -> ForwardQueue {
+        let capacity = config.forward_queue_max.unwrap_or(DEFAULT_FORWARD_QUEUE_MAX);
+        let policy = config.forward_queue_overflow.unwrap_or_default();
+        let Some(path) = &config.forward_queue_file else {
+            return ForwardQueue::with_capacity(capacity, policy);
+        };
+        let queue = ForwardQueue::open(Path::new(path), capacity, policy).unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("forward_queue_file '{}' を開けませんでした: {}", path, e)));
+        });
+        if !queue.is_empty() {
+            crate::status!("forward_queue_file '{}' から未転送のメッセージを {} 件読み込みました。", path, queue.len());
+        }
+        queue
+    }
+
+    pub fn with_capacity(capacity: usize, policy: OverflowPolicy) -> ForwardQueue {
+        ForwardQueue {
+            state: Mutex::new(ForwardState::default()),
+            capacity: capacity.max(1),
+            policy,
+            item_pushed: Notify::new(),
+            item_removed: Notify::new(),
+        }
+    }
+
+    /// 追加と削除を `path` に記録するキューを作成する。ファイルに記録されているメッセージは未送信のメッセージとして読み込む
+    pub fn open(path: &Path, capacity: usize, policy: OverflowPolicy) -> io::Result<ForwardQueue> {
+        let (journal, pending) = Journal::open(path)?;
+        let queue = ForwardQueue::with_capacity(capacity, policy);
+        {
+            let mut state = queue.state.lock().unwrap();
+            state.pending = pending;
+            state.journal = Some(journal);
+        }
+        Ok(queue)
+    }
+
+    /// 受信したメッセージを転送するメッセージの末尾に追加する。満杯のためにメッセージを破棄した場合は `true` を返す。
+    /// drop-oldest の場合も、送信中のメッセージは破棄せず、最も古い未送信のメッセージを破棄する
+    pub async fn push(&self, message: ReceivedMessage) -> bool {
+        let mut message = Some(message);
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.len() < self.capacity {
+                    state.push(message.take());
+                    self.item_pushed.notify_one();
+                    return false;
+                }
+                match self.policy {
+                    OverflowPolicy::DropNewest => return true,
+                    OverflowPolicy::DropOldest if !state.pending.is_empty() => {
+                        state.pending.pop_front();
+                        let index = state.inflight.len();
+                        state.record(JournalRecord::Remove { index });
+                        state.push(message.take());
+                        self.item_pushed.notify_one();
+                        return true;
+                    }
+                    // すべて送信中の場合は破棄できるメッセージがないため、確認応答を待つ
+                    OverflowPolicy::DropOldest | OverflowPolicy::Block => {}
+                }
+            }
+            self.item_removed.notified().await;
+        }
+    }
+
+    /// 次に転送するメッセージを送信中にして返す (未送信のメッセージがない場合は `None`)
+    pub fn try_next(&self) -> Option<ReceivedMessage> {
+        let mut state = self.state.lock().unwrap();
+        let message = state.pending.pop_front()?;
+        state.inflight.push_back(message.clone());
+        Some(message)
+    }
+
+    /// 次に転送するメッセージを送信中にして返す。未送信のメッセージがない場合は追加されるまで待つ
+    pub async fn next(&self) -> ReceivedMessage {
+        loop {
+            if let Some(message) = self.try_next() {
+                return message;
+            }
+            self.item_pushed.notified().await;
+        }
+    }
+
+    /// 転送先から確認応答を受け取った。最も古い送信中のメッセージをキューから取り除いて返す
+    pub fn acknowledged(&self) -> Option<ReceivedMessage> {
+        let message = {
+            let mut state = self.state.lock().unwrap();
+            let message = state.inflight.pop_front()?;
+            state.record(JournalRecord::Remove { index: 0 });
+            message
+        };
+        self.item_removed.notify_one();
+        Some(message)
+    }
+
+    /// 転送先の接続が切れた。送信中のメッセージを送信した順のまま未送信のメッセージの前に戻し、戻した件数を返す
+    pub fn disconnected(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let returned = state.inflight.len();
+        while let Some(message) = state.inflight.pop_back() {
+            state.pending.push_front(message);
+        }
+        if returned > 0 {
+            self.item_pushed.notify_one();
+        }
+        returned
+    }
+
+    /// 送信中と未送信のメッセージの件数
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 受信したメッセージを別のブローカー (bridge) に転送する。
+///
+/// 転送先への接続と送信は専用のタスクが行い、受信側は `forward` で ForwardQueue に追加するだけのため、
+/// 転送先に接続できない間も表示や他の出力先への出力は止まらない (forward_queue_overflow: block でキューが満杯の場合を除く)。
+/// 転送先の接続が切れた場合は、rumqttc が再送のために保持したメッセージを破棄し、確認応答を受け取っていないメッセージを
+/// ForwardQueue から受信順に送り直す (rumqttc の再送に任せると、再接続後に新しく受信したメッセージと順序が入れ替わることがある)。
+pub struct Bridge {
+    queue: Arc<ForwardQueue>,
+    policy: OverflowPolicy,
+    // 転送のキューが満杯で破棄したメッセージの件数
+    dropped: AtomicU64,
+}
+
+impl Bridge {
+    /// 転送先への接続を開始する。bridge が指定されていない場合は `None` を返す
+    pub fn start(config: &Config) -> Option<Arc<Bridge>> {
+        let bridge = config.bridge.as_ref()?;
+        let target = target_config(config, bridge).unwrap_or_else(|e| exit_with(AppError::Config(format!("bridge の設定が不正です: {}", e))));
+        let queue = Arc::new(ForwardQueue::new(config));
+        crate::status!("受信したメッセージをブローカー {}:{} に転送します。", target.broker_address, target.broker_port);
+        let qos = bridge.qos.unwrap_or(QoS::AtLeastOnce);
+        tokio::spawn(run(target, bridge.topic_prefix.clone().unwrap_or_default(), qos, Arc::clone(&queue)));
+        Some(Arc::new(Bridge { queue, policy: config.forward_queue_overflow.unwrap_or_default(), dropped: AtomicU64::new(0) }))
+    }
+
+    /// 受信したメッセージ (デコードする前のペイロード) を転送のキューに追加する。
+    /// forward_queue_overflow: block でキューが満杯の場合は、転送先の確認応答を受け取って空きができるまで待つ
+    pub async fn forward(&self, message: &ReceivedMessage) {
+        let message = ReceivedMessage { properties: None, acker: None, ..message.clone() };
+        if !self.queue.push(message).await {
+            return;
+        }
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped == 1 || dropped.is_multiple_of(DROP_REPORT_INTERVAL) {
+            eprintln!("警告: 転送のキューが満杯のため、転送するメッセージを破棄しました (これまでに {} 件、forward_queue_overflow: {:?})。", dropped, self.policy);
+        }
+    }
+}
+
+// 転送先に接続する設定。bridge で指定しない接続の設定 (TLS の証明書、protocol_version など) は購読の設定を引き継ぐ
+fn target_config(config: &Config, bridge: &BridgeConfig) -> Result<Config, String> {
+    let mut target = config.clone();
+    target.url = bridge.url.clone();
+    target.scheme = bridge.scheme.clone();
+    target.broker_address = bridge.broker_address.clone().unwrap_or_default();
+    target.broker_port = bridge.broker_port.unwrap_or_default();
+    target.client_id = bridge.client_id.clone().unwrap_or_else(|| format!("{}-bridge", config.client_id));
+    target.username = bridge.username.clone();
+    target.password = bridge.password.clone();
+    // 送り直しは ForwardQueue が行うため、前回のセッションを引き継がない
+    target.clean_session = Some(true);
+    target.manual_ack = None;
+    target.connect_properties = None;
+    target.failover_brokers = None;
+    url_utils::apply_broker_url(&mut target)?;
+    if target.broker_address.is_empty() || target.broker_port == 0 {
+        return Err("url または broker_address と broker_port を指定してください".to_string());
+    }
+    Ok(target)
+}
+
+// 転送先との接続を維持し、転送のキューのメッセージを受信順に送信する
+async fn run(target: Config, topic_prefix: String, qos: QoS, queue: Arc<ForwardQueue>) {
+    let (client, mut eventloop) = mqtt_utils::create_client(&target, FORWARD_WINDOW);
+    let mut connected = false;
+    // 接続できない間は、接続できるまで同じ警告を繰り返し表示しない
+    let mut failure_reported = false;
+    // 送信して確認応答を待っているメッセージの件数
+    let mut inflight = 0;
+    loop {
+        tokio::select! {
+            event = eventloop.poll() => {
+                let acknowledged = match event {
+                    Ok(MqttEvent::ConnAck { .. }) => {
+                        connected = true;
+                        failure_reported = false;
+                        crate::status!("転送先のブローカー {}:{} に接続しました。", target.broker_address, target.broker_port);
+                        false
+                    }
+                    Ok(MqttEvent::Outgoing(Outgoing::Publish(_))) => qos == QoS::AtMostOnce,
+                    Ok(MqttEvent::PubAck(_)) => qos == QoS::AtLeastOnce,
+                    Ok(MqttEvent::PubComp(_)) => qos == QoS::ExactlyOnce,
+                    Ok(MqttEvent::Disconnect(reason)) => {
+                        eprintln!("警告: 転送先のブローカーから切断されました (理由コード: {:?})。", reason.code);
+                        connected = false;
+                        false
+                    }
+                    Ok(_) => false,
+                    Err(e) => {
+                        if connected || !failure_reported {
+                            eprintln!("警告: 転送先のブローカー {}:{} との接続でエラーが発生しました: {}", target.broker_address, target.broker_port, e);
+                            failure_reported = true;
+                        }
+                        connected = false;
+                        false
+                    }
+                };
+                if acknowledged && inflight > 0 {
+                    inflight -= 1;
+                    queue.acknowledged();
+                }
+                if !connected {
+                    eventloop.discard_pending();
+                    inflight = 0;
+                    let returned = queue.disconnected();
+                    if returned > 0 {
+                        crate::status!("転送先の確認応答を受け取っていない {} 件のメッセージを、再接続後に受信順に送り直します。", returned);
+                    }
+                    time::sleep(BRIDGE_RETRY_DELAY).await;
+                }
+            }
+            message = queue.next(), if connected && inflight < FORWARD_WINDOW => {
+                inflight += 1;
+                let topic = format!("{}{}", topic_prefix, message.topic);
+                if let Err(e) = client.try_publish(&topic, qos, message.retain, message.payload.to_vec()) {
+                    eprintln!("警告: トピック '{}' へのメッセージの転送を要求できませんでした: {}", output_utils::status_topic(&topic), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use rumqttc::QoS;
+
+    fn message(payload: &str) -> ReceivedMessage {
+        ReceivedMessage {
+            topic: "bridge/in".to_string(),
+            payload: Bytes::from(payload.to_string()),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            pkid: 0,
+            properties: None,
+            acker: None,
+        }
+    }
+
+    fn payload(message: ReceivedMessage) -> String {
+        String::from_utf8(message.payload.to_vec()).unwrap()
+    }
+
+    fn journal_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mqtt-client-forward-{}-{}.jsonl", std::process::id(), test));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn keeps_receive_order_through_a_disconnect() {
+        let queue = ForwardQueue::with_capacity(10, OverflowPolicy::Block);
+        for text in ["1", "2", "3"] {
+            assert!(!queue.push(message(text)).await);
+        }
+        // 1 と 2 を送信し、1 の確認応答だけを受け取った後に転送先の接続が切れる
+        assert_eq!(payload(queue.try_next().unwrap()), "1");
+        assert_eq!(payload(queue.try_next().unwrap()), "2");
+        assert_eq!(payload(queue.acknowledged().unwrap()), "1");
+        assert_eq!(queue.disconnected(), 1);
+        // 切断中に受信したメッセージは、切断前に送信したメッセージと未送信のメッセージの後に転送する
+        assert!(!queue.push(message("4")).await);
+        let order: Vec<String> = std::iter::from_fn(|| queue.try_next()).map(payload).collect();
+        assert_eq!(order, ["2", "3", "4"]);
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn drop_policies_never_drop_inflight_messages() {
+        let queue = ForwardQueue::with_capacity(2, OverflowPolicy::DropOldest);
+        queue.push(message("1")).await;
+        queue.push(message("2")).await;
+        queue.try_next();
+        // 送信中の 1 は残し、最も古い未送信の 2 を破棄する
+        assert!(queue.push(message("3")).await);
+        queue.disconnected();
+        let order: Vec<String> = std::iter::from_fn(|| queue.try_next()).map(payload).collect();
+        assert_eq!(order, ["1", "3"]);
+
+        let queue = ForwardQueue::with_capacity(1, OverflowPolicy::DropNewest);
+        queue.push(message("1")).await;
+        assert!(queue.push(message("2")).await);
+        assert_eq!(payload(queue.try_next().unwrap()), "1");
+        assert!(queue.try_next().is_none());
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_an_acknowledgement() {
+        let queue = std::sync::Arc::new(ForwardQueue::with_capacity(1, OverflowPolicy::Block));
+        queue.push(message("1")).await;
+        assert_eq!(payload(queue.next().await), "1");
+        let pushing = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(message("2")).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!pushing.is_finished());
+        queue.acknowledged();
+        assert!(!pushing.await.unwrap());
+        assert_eq!(payload(queue.next().await), "2");
+    }
+
+    #[tokio::test]
+    async fn forward_queue_file_keeps_unacknowledged_messages_in_order_across_restarts() {
+        let path = journal_path("restart");
+        {
+            let queue = ForwardQueue::open(&path, 10, OverflowPolicy::Block).unwrap();
+            for text in ["1", "2", "3"] {
+                queue.push(message(text)).await;
+            }
+            // 1 は確認応答を受け取り、2 は送信中のまま終了する
+            queue.try_next();
+            queue.try_next();
+            queue.acknowledged();
+        }
+        let queue = ForwardQueue::open(&path, 10, OverflowPolicy::Block).unwrap();
+        queue.push(message("4")).await;
+        let order: Vec<String> = std::iter::from_fn(|| queue.try_next()).map(payload).collect();
+        assert_eq!(order, ["2", "3", "4"]);
+        let restored = ForwardQueue::open(&path, 10, OverflowPolicy::Block).unwrap().try_next().unwrap();
+        assert_eq!((restored.topic.as_str(), restored.qos, restored.retain), ("bridge/in", QoS::AtLeastOnce, false));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn forward_queue_file_records_drop_oldest_behind_inflight_messages() {
+        let path = journal_path("drop-oldest");
+        {
+            let queue = ForwardQueue::open(&path, 2, OverflowPolicy::DropOldest).unwrap();
+            queue.push(message("1")).await;
+            queue.push(message("2")).await;
+            queue.try_next();
+            assert!(queue.push(message("3")).await);
+        }
+        let queue = ForwardQueue::open(&path, 2, OverflowPolicy::DropOldest).unwrap();
+        let order: Vec<String> = std::iter::from_fn(|| queue.try_next()).map(payload).collect();
+        assert_eq!(order, ["1", "3"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn forward_queue_file_is_compacted_and_ignores_a_truncated_last_line() {
+        let path = journal_path("compact");
+        {
+            let queue = ForwardQueue::open(&path, 10, OverflowPolicy::Block).unwrap();
+            for i in 0..JOURNAL_COMPACT_SLACK {
+                queue.push(message(&i.to_string())).await;
+                queue.try_next();
+                queue.acknowledged();
+            }
+            queue.push(message("last")).await;
+        }
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines < JOURNAL_COMPACT_SLACK, "{} 行", lines);
+        // 書き込み中に終了した場合の途中までの行
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"op\":\"pu").unwrap();
+        let queue = ForwardQueue::open(&path, 10, OverflowPolicy::Block).unwrap();
+        let order: Vec<String> = std::iter::from_fn(|| queue.try_next()).map(payload).collect();
+        assert_eq!(order, ["last"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
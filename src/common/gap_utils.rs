@@ -0,0 +1,233 @@
+// トピックごとのメッセージの到着間隔の測定 (max_gap_secs / burst_max_rate)
+use super::benchmark_utils::LatencySummary;
+use super::config_utils::Config;
+use super::histogram_utils::SizeHistogram;
+use super::output_utils::status_topic;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+// バーストの判定に使う時間の窓
+const BURST_WINDOW: Duration = Duration::from_secs(1);
+/// max_gap_secs を超えてメッセージが届いていないトピックを確認する間隔
+pub const SILENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// トピックごとに、前回のメッセージから次のメッセージが届くまでの間隔を記録する。
+///
+/// 間隔が max_gap_secs を超えた場合 (パブリッシャーが停止していた可能性がある) と、1 秒間に burst_max_rate 件を超えて
+/// 届いた場合 (バースト) に警告を表示する。どちらも表示するだけで、受信は続ける。間隔は次のメッセージが届いた時点で判定するほか、
+/// `check_silent` で次のメッセージが届く前にも判定する。到着間隔の統計はヒストグラムで数えるため、メモリの使用量は増え続けない。
+#[derive(Debug)]
+pub struct GapTracker {
+    max_gap: Option<Duration>,
+    burst_max_rate: Option<u32>,
+    topics: Mutex<HashMap<String, TopicArrivals>>,
+    // すべてのトピックの到着間隔 (マイクロ秒)
+    gaps: Mutex<GapStats>,
+}
+
+#[derive(Debug, Default)]
+struct GapStats {
+    histogram: SizeHistogram,
+    min: u64,
+    sum: u128,
+}
+
+#[derive(Debug)]
+struct TopicArrivals {
+    last: Instant,
+    // 現在のバーストの判定の窓の開始時刻と、その窓で受信した件数
+    window_start: Instant,
+    window_count: u32,
+    // 現在の窓でバーストの警告を表示したか
+    burst_warned: bool,
+    // 前回のメッセージから max_gap_secs を超えたことを、次のメッセージが届く前に警告したか
+    silence_warned: bool,
+}
+
+impl GapTracker {
+    /// max_gap_secs と burst_max_rate のどちらも指定されていない場合は `None` を返す
+    pub fn new(config: &Config) -> Option<GapTracker> {
+        if config.max_gap_secs.is_none() && config.burst_max_rate.is_none() {
+            return None;
+        }
+        Some(GapTracker {
+            max_gap: config.max_gap_secs.map(Duration::from_secs),
+            burst_max_rate: config.burst_max_rate.filter(|&rate| rate > 0),
+            topics: Mutex::new(HashMap::new()),
+            gaps: Mutex::new(GapStats::default()),
+        })
+    }
+
+    /// max_gap_secs を指定した場合に、`check_silent` を呼び出す間隔
+    pub fn silence_check_interval(&self) -> Option<Duration> {
+        self.max_gap.map(|_| SILENCE_CHECK_INTERVAL)
+    }
+
+    /// `topic` のメッセージを受信したことを記録し、間隔やバーストが閾値を超えていれば警告を表示する
+    pub fn record(&self, topic: &str) {
+        let now = Instant::now();
+        let mut topics = self.topics.lock().unwrap();
+        let Some(arrivals) = topics.get_mut(topic) else {
+            topics.insert(topic.to_string(), TopicArrivals { last: now, window_start: now, window_count: 1, burst_warned: false, silence_warned: false });
+            return;
+        };
+        let gap = now.duration_since(arrivals.last);
+        arrivals.last = now;
+        arrivals.silence_warned = false;
+        self.gaps.lock().unwrap().record(gap.as_micros() as u64);
+        if let Some(max_gap) = self.max_gap
+            && gap > max_gap {
+            eprintln!("警告: トピック '{}' のメッセージの間隔が {:.1} 秒空きました (max_gap_secs: {})。パブリッシャーが停止していた可能性があります。",
+                status_topic(topic), gap.as_secs_f64(), max_gap.as_secs());
+        }
+
+        if now.duration_since(arrivals.window_start) >= BURST_WINDOW {
+            arrivals.window_start = now;
+            arrivals.window_count = 0;
+            arrivals.burst_warned = false;
+        }
+        arrivals.window_count += 1;
+        if let Some(rate) = self.burst_max_rate
+            && arrivals.window_count > rate && !arrivals.burst_warned {
+            arrivals.burst_warned = true;
+            eprintln!("警告: トピック '{}' のメッセージが 1 秒間に {} 件を超えて届きました (burst_max_rate)。", status_topic(topic), rate);
+        }
+    }
+
+    /// 前回のメッセージから max_gap_secs を超えてメッセージが届いていないトピックの警告を表示する
+    /// (トピックごとに、次のメッセージが届くまで 1 回)
+    pub fn check_silent(&self) {
+        for (topic, silent) in self.silent_topics(Instant::now()) {
+            eprintln!("警告: トピック '{}' のメッセージが {} 秒以上届いていません (max_gap_secs: {})。パブリッシャーが停止している可能性があります。",
+                status_topic(&topic), silent.as_secs(), self.max_gap.unwrap_or_default().as_secs());
+        }
+    }
+
+    // `now` の時点で max_gap_secs を超えてメッセージが届いておらず、まだ警告していないトピックと、前回のメッセージからの時間
+    fn silent_topics(&self, now: Instant) -> Vec<(String, Duration)> {
+        let Some(max_gap) = self.max_gap else {
+            return Vec::new();
+        };
+        let mut topics = self.topics.lock().unwrap();
+        let mut silent: Vec<(String, Duration)> = topics.iter_mut()
+            .filter(|(_, arrivals)| !arrivals.silence_warned && now.duration_since(arrivals.last) > max_gap)
+            .map(|(topic, arrivals)| {
+                arrivals.silence_warned = true;
+                (topic.clone(), now.duration_since(arrivals.last))
+            })
+            .collect();
+        silent.sort();
+        silent
+    }
+
+    /// すべてのトピックの到着間隔の統計 (マイクロ秒。パーセンタイルはヒストグラムのバケットの上限で、実際の値との差は 12.5% 以内)
+    pub fn summary(&self) -> Option<LatencySummary> {
+        self.gaps.lock().unwrap().summary()
+    }
+}
+
+impl GapStats {
+    fn record(&mut self, micros: u64) {
+        self.min = if self.histogram.count() == 0 { micros } else { self.min.min(micros) };
+        self.sum += u128::from(micros);
+        self.histogram.record(micros);
+    }
+
+    fn summary(&self) -> Option<LatencySummary> {
+        let count = self.histogram.count();
+        let percentile = |p: f64| self.histogram.percentile(p).map(|micros| micros as i64);
+        Some(LatencySummary {
+            count: count as usize,
+            min: self.min as i64,
+            max: self.histogram.max() as i64,
+            mean: self.sum as f64 / count as f64,
+            p50: percentile(50.0)?,
+            p95: percentile(95.0)?,
+            p99: percentile(99.0)?,
+        })
+    }
+}
+
+/// 到着間隔の統計を表示する
+pub fn print_report(label: &str, tracker: &GapTracker) {
+    let Some(summary) = tracker.summary() else {
+        crate::status!("[{}] 到着間隔: 同じトピックのメッセージを 2 件以上受信しませんでした。", label);
+        return;
+    };
+    let secs = |micros: i64| micros as f64 / 1_000_000.0;
+    crate::status!("[{}] 到着間隔 ({} 件): 最小 {:.3} 秒, 最大 {:.3} 秒, 平均 {:.3} 秒, p50 {:.3} 秒, p95 {:.3} 秒, p99 {:.3} 秒",
+        label, summary.count, secs(summary.min), secs(summary.max), summary.mean / 1_000_000.0,
+        secs(summary.p50), secs(summary.p95), secs(summary.p99));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(yaml: &str) -> GapTracker {
+        let config: Config = serde_yaml::from_str(&format!("client_id: gap\nbroker_address: localhost\nbroker_port: 1883\n{}", yaml)).unwrap();
+        GapTracker::new(&config).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn summarizes_gaps_per_topic() {
+        let tracker = tracker("max_gap_secs: 60\n");
+        assert!(tracker.summary().is_none());
+        tracker.record("a");
+        tracker.record("b");
+        for _ in 0..3 {
+            tokio::time::advance(Duration::from_millis(100)).await;
+            tracker.record("a");
+        }
+        tokio::time::advance(Duration::from_millis(700)).await;
+        tracker.record("b");
+        let summary = tracker.summary().unwrap();
+        // a の 3 つの間隔 (100 ms) と b の 1 つの間隔 (1000 ms)
+        assert_eq!(summary.count, 4);
+        assert_eq!((summary.min, summary.max), (100_000, 1_000_000));
+        assert_eq!(summary.mean, 325_000.0);
+        assert!((100_000..=112_500).contains(&summary.p50), "{}", summary.p50);
+        assert_eq!(summary.p99, 1_000_000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gap_statistics_do_not_grow_with_the_message_count() {
+        let tracker = tracker("max_gap_secs: 60\n");
+        tracker.record("a");
+        for _ in 0..10_000 {
+            tokio::time::advance(Duration::from_millis(10)).await;
+            tracker.record("a");
+        }
+        let gaps = tracker.gaps.lock().unwrap();
+        assert_eq!(gaps.histogram.count(), 10_000);
+        assert_eq!(gaps.histogram.percentile(50.0), gaps.histogram.percentile(99.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn warns_about_silent_topics_once_before_the_next_message() {
+        let tracker = tracker("max_gap_secs: 10\n");
+        assert_eq!(tracker.silence_check_interval(), Some(SILENCE_CHECK_INTERVAL));
+        tracker.record("a");
+        tracker.record("b");
+        tokio::time::advance(Duration::from_secs(5)).await;
+        tracker.record("b");
+        tokio::time::advance(Duration::from_secs(10)).await;
+        // a は 15 秒、b は 10 秒 (max_gap_secs ちょうど) 届いていない
+        assert_eq!(tracker.silent_topics(Instant::now()), [("a".to_string(), Duration::from_secs(15))]);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(tracker.silent_topics(Instant::now()), [("b".to_string(), Duration::from_secs(11))]);
+        // 警告したトピックは、次のメッセージが届くまで繰り返し警告しない
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(tracker.silent_topics(Instant::now()).is_empty());
+        tracker.record("a");
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert_eq!(tracker.silent_topics(Instant::now()), [("a".to_string(), Duration::from_secs(11))]);
+    }
+
+    #[test]
+    fn silence_is_not_checked_without_max_gap_secs() {
+        let tracker = tracker("burst_max_rate: 10\n");
+        assert_eq!(tracker.silence_check_interval(), None);
+        assert!(tracker.silent_topics(Instant::now()).is_empty());
+    }
+}
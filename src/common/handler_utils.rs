@@ -0,0 +1,86 @@
+use super::config_utils::OverflowPolicy;
+use super::mqtt_utils::ReceivedMessage;
+use std::{collections::VecDeque, sync::Mutex};
+use tokio::sync::Notify;
+
+/// イベントループとメッセージハンドラーの実行タスクの間の容量制限付きキュー。
+///
+/// ハンドラーの処理が遅くてもイベントループが停止しない (キープアライブがタイムアウトしない) よう、
+/// キューが満杯の場合は `OverflowPolicy` に従って待機または破棄する。
+/// 送信側 (イベントループ) と受信側 (ハンドラー実行タスク) はそれぞれ 1 つであることを前提とする。
+pub struct HandlerQueue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // キューにメッセージが追加された (またはクローズされた) ことを受信側に通知する
+    item_pushed: Notify,
+    // キューに空きができたことを送信側に通知する
+    item_popped: Notify,
+}
+
+struct QueueState {
+    items: VecDeque<ReceivedMessage>,
+    closed: bool,
+}
+
+impl HandlerQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> HandlerQueue {
+        HandlerQueue {
+            state: Mutex::new(QueueState { items: VecDeque::with_capacity(capacity), closed: false }),
+            capacity: capacity.max(1),
+            policy,
+            item_pushed: Notify::new(),
+            item_popped: Notify::new(),
+        }
+    }
+
+    /// メッセージをキューに追加する。満杯のためにメッセージを破棄した場合は `true` を返す
+    pub async fn push(&self, message: ReceivedMessage) -> bool {
+        let mut message = Some(message);
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.items.len() < self.capacity {
+                    state.items.extend(message.take());
+                    self.item_pushed.notify_one();
+                    return false;
+                }
+                match self.policy {
+                    OverflowPolicy::DropNewest => return true,
+                    OverflowPolicy::DropOldest => {
+                        state.items.pop_front();
+                        state.items.extend(message.take());
+                        self.item_pushed.notify_one();
+                        return true;
+                    }
+                    OverflowPolicy::Block => {}
+                }
+            }
+            // 空きができるまで待機する (notify_one は待機前の通知も 1 回分保持する)
+            self.item_popped.notified().await;
+        }
+    }
+
+    /// キューからメッセージを取り出す。クローズ済みでキューが空の場合は `None` を返す
+    pub async fn pop(&self) -> Option<ReceivedMessage> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(message) = state.items.pop_front() {
+                    self.item_popped.notify_one();
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.item_pushed.notified().await;
+        }
+    }
+
+    /// これ以上メッセージを追加しないことを受信側に通知する (キューに残ったメッセージは取り出せる)
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.item_pushed.notify_one();
+    }
+}
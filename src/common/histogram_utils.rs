@@ -0,0 +1,292 @@
+// 受信したペイロードのサイズのヒストグラム (payload_size_histogram)
+use super::config_utils::PayloadSizeHistogramConfig;
+use super::output_utils;
+use std::{collections::HashMap, sync::Arc};
+
+// 既定のバケットでサイズをそのまま数える上限 (これ以上は 2 のべき乗の区間をそれぞれ SUB_BUCKETS 個に分ける)
+const LINEAR_LIMIT: u64 = 16;
+const SUB_BUCKETS: u64 = 8;
+// per_topic の内訳を保持するトピックの最大数 (超えた分は OTHER_TOPICS にまとめる)
+const MAX_TOPICS: usize = 1000;
+const OTHER_TOPICS: &str = "(その他のトピック)";
+
+/// ペイロードのサイズ (バイト) のヒストグラム。
+///
+/// 既定のバケットは HDR 形式で、16 バイト未満はサイズごと、それ以上は 2 のべき乗の区間を 8 つに分ける
+/// (バケットの上限と実際のサイズの差は 12.5% 以内)。buckets を指定した場合はその上限ごとに数え、最後の上限を超えた分は 1 つにまとめる。
+/// バケットの数はメッセージ数にかかわらず一定のため、メモリの使用量は増え続けない。
+#[derive(Debug, Clone)]
+pub struct SizeHistogram {
+    // 各バケットの上限 (その値を含む、昇順)。None の場合は既定のバケット
+    bounds: Option<Arc<[u64]>>,
+    counts: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+/// ヒストグラムから求めたパーセンタイル (各値はそのサイズを含むバケットの上限。最大値を超える場合は最大値)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeSummary {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Default for SizeHistogram {
+    /// 既定のバケットのヒストグラム
+    fn default() -> SizeHistogram {
+        SizeHistogram::new(None)
+    }
+}
+
+impl SizeHistogram {
+    fn new(bounds: Option<Arc<[u64]>>) -> SizeHistogram {
+        let buckets = bounds.as_ref().map_or(0, |bounds| bounds.len() + 1);
+        SizeHistogram { bounds, counts: vec![0; buckets], count: 0, max: 0 }
+    }
+
+    pub fn record(&mut self, size: u64) {
+        let index = self.index(size);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.count += 1;
+        self.max = self.max.max(size);
+    }
+
+    /// 同じバケットのヒストグラムを合算する (複数セッションの合計用)
+    pub fn merge(&mut self, other: &SizeHistogram) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+
+    /// 記録がない場合は `None` を返す
+    pub fn summary(&self) -> Option<SizeSummary> {
+        if self.count == 0 {
+            return None;
+        }
+        let percentile = |p: f64| self.percentile(p).unwrap_or(self.max);
+        Some(SizeSummary { count: self.count, p50: percentile(50.0), p90: percentile(90.0), p99: percentile(99.0), max: self.max })
+    }
+
+    /// 記録した値の件数
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// 記録した値の最大値
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// `p` パーセンタイル (nearest-rank 法) を含むバケットの上限 (最大値を超える場合は最大値)。記録がない場合は `None` を返す
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(self.upper_bound(index).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// buckets を指定した場合に、各バケットの上限 (最後のバケットは `None`) と件数を返す
+    pub fn buckets(&self) -> Option<Vec<(Option<u64>, u64)>> {
+        let bounds = self.bounds.as_ref()?;
+        Some(self.counts.iter().enumerate().map(|(index, count)| (bounds.get(index).copied(), *count)).collect())
+    }
+
+    fn index(&self, size: u64) -> usize {
+        match &self.bounds {
+            Some(bounds) => bounds.partition_point(|bound| *bound < size),
+            None if size < LINEAR_LIMIT => size as usize,
+            None => {
+                let exponent = 63 - u64::from(size.leading_zeros());
+                let sub = (size >> (exponent - 3)) & (SUB_BUCKETS - 1);
+                (LINEAR_LIMIT + (exponent - 4) * SUB_BUCKETS + sub) as usize
+            }
+        }
+    }
+
+    fn upper_bound(&self, index: usize) -> u64 {
+        let index = index as u64;
+        match &self.bounds {
+            Some(bounds) => bounds.get(index as usize).copied().unwrap_or(self.max),
+            None if index < LINEAR_LIMIT => index,
+            None => {
+                let exponent = (index - LINEAR_LIMIT) / SUB_BUCKETS + 4;
+                let sub = (index - LINEAR_LIMIT) % SUB_BUCKETS;
+                ((SUB_BUCKETS + sub + 1) << (exponent - 3)) - 1
+            }
+        }
+    }
+}
+
+/// セッションの受信したペイロードのサイズの記録 (全体と、per_topic が指定されている場合はトピックごとの内訳)
+#[derive(Debug, Clone)]
+pub struct PayloadSizes {
+    pub total: SizeHistogram,
+    pub per_topic: Option<HashMap<String, SizeHistogram>>,
+}
+
+impl PayloadSizes {
+    pub fn new(config: &PayloadSizeHistogramConfig) -> PayloadSizes {
+        let bounds = config.buckets.as_ref().map(|buckets| Arc::from(buckets.as_slice()));
+        PayloadSizes {
+            total: SizeHistogram::new(bounds),
+            per_topic: config.per_topic.map(|_| HashMap::new()),
+        }
+    }
+
+    /// `key` は per_topic に従ったトピック (または購読トピックフィルタ)。per_topic が指定されていない場合は使わない
+    pub fn record(&mut self, key: &str, size: usize) {
+        let size = size as u64;
+        self.total.record(size);
+        let Some(per_topic) = &mut self.per_topic else {
+            return;
+        };
+        let key = if per_topic.len() >= MAX_TOPICS && !per_topic.contains_key(key) { OTHER_TOPICS } else { key };
+        match per_topic.get_mut(key) {
+            Some(histogram) => histogram.record(size),
+            None => {
+                let mut histogram = SizeHistogram::new(self.total.bounds.clone());
+                histogram.record(size);
+                per_topic.insert(key.to_string(), histogram);
+            }
+        }
+    }
+
+    /// 複数セッションの記録を合算する
+    pub fn merge(&mut self, other: &PayloadSizes) {
+        self.total.merge(&other.total);
+        if let (Some(per_topic), Some(other)) = (&mut self.per_topic, &other.per_topic) {
+            for (key, histogram) in other {
+                match per_topic.get_mut(key) {
+                    Some(total) => total.merge(histogram),
+                    None => {
+                        per_topic.insert(key.clone(), histogram.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// ペイロードのサイズの分布を表示する (buckets を指定した場合はバケットごとの件数、per_topic の場合はトピックごとのパーセンタイルも表示する)
+pub fn print_payload_sizes(label: &str, sizes: &PayloadSizes) {
+    let Some(summary) = sizes.total.summary() else {
+        return;
+    };
+    crate::status!("[{}] ペイロードサイズ ({} 件): {}", label, summary.count, format_summary(&summary));
+    if let Some(buckets) = sizes.total.buckets() {
+        let mut last = 0;
+        for (bound, count) in buckets {
+            let share = count as f64 * 100.0 / summary.count as f64;
+            match bound {
+                Some(bound) => {
+                    crate::status!("  {:>12} バイト以下  {} 件 ({:.1}%)", bound, count, share);
+                    last = bound;
+                }
+                None => crate::status!("  {:>12} バイト超    {} 件 ({:.1}%)", last, count, share),
+            }
+        }
+    }
+    let Some(per_topic) = &sizes.per_topic else {
+        return;
+    };
+    let mut rows: Vec<(&String, SizeSummary)> = per_topic.iter()
+        .filter_map(|(key, histogram)| histogram.summary().map(|summary| (key, summary)))
+        .collect();
+    rows.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(b.0)));
+    let rows: Vec<_> = rows.into_iter().map(|(key, summary)| (output_utils::status_topic(key), summary)).collect();
+    let width = rows.iter().map(|(key, _)| key.chars().count()).max().unwrap_or(0);
+    crate::status!("[{}] トピックごとのペイロードサイズ:", label);
+    for (key, summary) in rows {
+        crate::status!("  {:<width$}  {} 件, {}", key, summary.count, format_summary(&summary), width = width);
+    }
+}
+
+fn format_summary(summary: &SizeSummary) -> String {
+    format!("p50 {} バイト, p90 {} バイト, p99 {} バイト, 最大 {} バイト", summary.p50, summary.p90, summary.p99, summary.max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_buckets_round_trip_between_index_and_upper_bound() {
+        let histogram = SizeHistogram::default();
+        let sizes = (0..4096).chain((12..40).flat_map(|exponent| {
+            let power = 1u64 << exponent;
+            [power - 1, power, power + 1, power + power / 3]
+        }));
+        for size in sizes {
+            let index = histogram.index(size);
+            let upper = histogram.upper_bound(index);
+            assert!(size <= upper, "{} > {} (バケット {})", size, upper, index);
+            if index > 0 {
+                assert!(histogram.upper_bound(index - 1) < size, "{} はバケット {} にも入る", size, index - 1);
+            }
+            // バケットの上限と実際のサイズの差は 12.5% 以内
+            assert!((upper - size) as f64 <= size as f64 / 8.0, "{} のバケットの上限 {}", size, upper);
+            assert_eq!(histogram.index(upper), index);
+        }
+    }
+
+    #[test]
+    fn configured_buckets_put_sizes_at_or_below_each_bound() {
+        let histogram = SizeHistogram::new(Some(Arc::from([10, 100])));
+        assert_eq!([0, 10, 11, 100, 101, 5000].map(|size| histogram.index(size)), [0, 0, 1, 1, 2, 2]);
+        assert_eq!(histogram.upper_bound(1), 100);
+    }
+
+    #[test]
+    fn percentiles_are_bucket_upper_bounds_capped_at_the_maximum() {
+        let mut histogram = SizeHistogram::new(Some(Arc::from([10, 50, 100])));
+        assert_eq!(histogram.percentile(50.0), None);
+        assert_eq!(histogram.summary(), None);
+        for size in 1..=100 {
+            histogram.record(size);
+        }
+        assert_eq!(histogram.percentile(10.0), Some(10));
+        assert_eq!(histogram.percentile(50.0), Some(50));
+        assert_eq!(histogram.percentile(51.0), Some(100));
+        assert_eq!(histogram.summary(), Some(SizeSummary { count: 100, p50: 50, p90: 100, p99: 100, max: 100 }));
+
+        // 最後の上限を超えたバケットや、最大値より大きい上限は最大値にする
+        let mut histogram = SizeHistogram::new(Some(Arc::from([10])));
+        histogram.record(5);
+        histogram.record(500);
+        assert_eq!(histogram.percentile(99.0), Some(500));
+        let mut histogram = SizeHistogram::default();
+        histogram.record(1000);
+        assert_eq!(histogram.percentile(50.0), Some(1000));
+    }
+
+    #[test]
+    fn merged_histograms_count_both_sessions() {
+        let mut first = SizeHistogram::default();
+        let mut second = SizeHistogram::default();
+        first.record(3);
+        second.record(3);
+        second.record(100_000);
+        first.merge(&second);
+        assert_eq!((first.count(), first.max()), (3, 100_000));
+        assert_eq!(first.percentile(50.0), Some(3));
+    }
+}
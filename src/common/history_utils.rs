@@ -0,0 +1,213 @@
+// 直近に受信したメッセージの保持 (history_size、readiness_port の /history で参照する)
+use super::config_utils::Config;
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils;
+use super::topic_utils::topic_matches;
+use std::{collections::{HashMap, VecDeque}, sync::Mutex};
+
+// history_max_bytes が未指定の場合に保持する最大バイト数 (10 MiB)
+const DEFAULT_HISTORY_MAX_BYTES: usize = 10 * 1024 * 1024;
+// 1 件あたりの管理用の領域として加算するバイト数 (空のペイロードのメッセージでも上限が効くようにする)
+const ENTRY_OVERHEAD: usize = 64;
+
+/// トピックごとに直近 history_size 件の受信メッセージを JSON で保持するリングバッファ。
+///
+/// すべてのトピックの合計 (JSON の長さとトピック名) が history_max_bytes を超える場合は、
+/// トピックにかかわらず古いメッセージから破棄する。
+pub struct MessageHistory {
+    per_topic: usize,
+    max_bytes: usize,
+    state: Mutex<HistoryState>,
+}
+
+#[derive(Default)]
+struct HistoryState {
+    // トピックごとの (通し番号, 受信メッセージの JSON)
+    topics: HashMap<String, VecDeque<(u64, String)>>,
+    // すべてのトピックの受信順 (トピックごとの上限で破棄済みの番号も含む)
+    order: VecDeque<(u64, String)>,
+    next_seq: u64,
+    entries: usize,
+    bytes: usize,
+}
+
+impl MessageHistory {
+    /// history_size が指定されていない場合は `None` を返す
+    pub fn new(config: &Config) -> Option<MessageHistory> {
+        let per_topic = config.history_size.filter(|size| *size > 0)?;
+        if config.readiness_port.is_none() {
+            eprintln!("警告: history_size を指定していますが readiness_port が指定されていないため、保持したメッセージを参照できません。");
+        }
+        Some(MessageHistory {
+            per_topic,
+            max_bytes: config.history_max_bytes.unwrap_or(DEFAULT_HISTORY_MAX_BYTES),
+            state: Mutex::new(HistoryState::default()),
+        })
+    }
+
+    /// 受信したメッセージを記録する
+    pub fn record(&self, message: &ReceivedMessage) {
+        let json = output_utils::format_json(message);
+        let size = entry_size(&message.topic, &json);
+        if size > self.max_bytes {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        let removed = {
+            let entries = state.topics.entry(message.topic.clone()).or_default();
+            entries.push_back((seq, json));
+            (entries.len() > self.per_topic).then(|| entries.pop_front()).flatten()
+        };
+        state.order.push_back((seq, message.topic.clone()));
+        state.entries += 1;
+        state.bytes += size;
+        if let Some((_, json)) = removed {
+            state.entries -= 1;
+            state.bytes -= entry_size(&message.topic, &json);
+        }
+        while state.bytes > self.max_bytes {
+            state.evict_oldest();
+        }
+        // トピックごとの上限で破棄した番号が溜まりすぎないよう、受信順の一覧を詰める
+        if state.order.len() > state.entries * 2 + 1024 {
+            let HistoryState { topics, order, .. } = &mut *state;
+            // トピックごとのメッセージは番号順のため、先頭の番号以降であれば保持している
+            order.retain(|(seq, topic)| topics.get(topic).and_then(VecDeque::front).is_some_and(|(front, _)| seq >= front));
+        }
+    }
+
+    /// `filter` (トピックまたはワイルドカードを含むトピックフィルタ、`None` はすべて) に一致するトピックの
+    /// 保持しているメッセージを、トピックをキーとする JSON オブジェクト (値は古い順の配列) にする
+    pub fn to_json(&self, filter: Option<&str>) -> String {
+        let state = self.state.lock().unwrap();
+        let mut topics: Vec<(&String, &VecDeque<(u64, String)>)> = state.topics.iter()
+            .filter(|(topic, _)| filter.is_none_or(|filter| topic_matches(filter, topic)))
+            .collect();
+        topics.sort_by(|a, b| a.0.cmp(b.0));
+        let fields: Vec<String> = topics.iter().map(|(topic, entries)| {
+            let messages: Vec<&str> = entries.iter().map(|(_, json)| json.as_str()).collect();
+            format!("{}:[{}]", serde_json::Value::from(topic.as_str()), messages.join(","))
+        }).collect();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+impl HistoryState {
+    fn evict_oldest(&mut self) {
+        let Some((seq, topic)) = self.order.pop_front() else {
+            return;
+        };
+        let Some(entries) = self.topics.get_mut(&topic) else {
+            return;
+        };
+        // トピックごとの上限ですでに破棄されている場合は何もしない
+        if entries.front().is_some_and(|(s, _)| *s == seq) {
+            let (_, json) = entries.pop_front().expect("先頭を確認済み");
+            self.entries -= 1;
+            self.bytes -= entry_size(&topic, &json);
+            if entries.is_empty() {
+                self.topics.remove(&topic);
+            }
+        }
+    }
+}
+
+fn entry_size(topic: &str, json: &str) -> usize {
+    topic.len() + json.len() + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use rumqttc::QoS;
+
+    fn history(per_topic: usize, max_bytes: usize) -> MessageHistory {
+        MessageHistory { per_topic, max_bytes, state: Mutex::new(HistoryState::default()) }
+    }
+
+    fn message(topic: &str, payload: &str) -> ReceivedMessage {
+        ReceivedMessage {
+            topic: topic.to_string(),
+            payload: Bytes::from(payload.to_string()),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pkid: 0,
+            properties: None,
+            acker: None,
+        }
+    }
+
+    // トピックごとに保持しているペイロード (古い順)
+    fn payloads(history: &MessageHistory, filter: Option<&str>) -> Vec<(String, Vec<String>)> {
+        let json: serde_json::Value = serde_json::from_str(&history.to_json(filter)).unwrap();
+        json.as_object().unwrap().iter().map(|(topic, messages)| {
+            let payloads = messages.as_array().unwrap().iter()
+                .map(|message| message["payload"].as_str().unwrap().to_string())
+                .collect();
+            (topic.clone(), payloads)
+        }).collect()
+    }
+
+    fn entries(topic: &str, payloads: &[&str]) -> (String, Vec<String>) {
+        (topic.to_string(), payloads.iter().map(|p| p.to_string()).collect())
+    }
+
+    #[test]
+    fn keeps_the_latest_messages_per_topic() {
+        let history = history(2, usize::MAX);
+        for payload in ["1", "2", "3"] {
+            history.record(&message("a", payload));
+        }
+        history.record(&message("b", "x"));
+        assert_eq!(payloads(&history, None), vec![entries("a", &["2", "3"]), entries("b", &["x"])]);
+        assert_eq!(history.state.lock().unwrap().entries, 3);
+    }
+
+    #[test]
+    fn evicts_the_oldest_messages_across_topics_when_over_max_bytes() {
+        let size = entry_size("a", &output_utils::format_json(&message("a", "1")));
+        let history = history(10, size * 3);
+        history.record(&message("a", "1"));
+        history.record(&message("b", "2"));
+        history.record(&message("a", "3"));
+        history.record(&message("b", "4"));
+        assert_eq!(payloads(&history, None), vec![entries("a", &["3"]), entries("b", &["2", "4"])]);
+        let state = history.state.lock().unwrap();
+        assert_eq!((state.entries, state.bytes), (3, size * 3));
+    }
+
+    #[test]
+    fn eviction_skips_messages_already_dropped_by_the_per_topic_limit() {
+        let size = entry_size("a", &output_utils::format_json(&message("a", "1")));
+        let history = history(1, size);
+        history.record(&message("a", "1"));
+        history.record(&message("a", "2"));
+        history.record(&message("b", "3"));
+        // "1" は a のトピックごとの上限で破棄済みのため、合計の上限では a の "2" を破棄する
+        assert_eq!(payloads(&history, None), vec![entries("b", &["3"])]);
+        let state = history.state.lock().unwrap();
+        assert_eq!((state.entries, state.bytes), (1, size));
+    }
+
+    #[test]
+    fn ignores_messages_larger_than_max_bytes() {
+        let history = history(10, 16);
+        history.record(&message("a", "too large"));
+        assert_eq!(history.to_json(None), "{}");
+        assert_eq!(history.state.lock().unwrap().bytes, 0);
+    }
+
+    #[test]
+    fn filters_topics_with_wildcards() {
+        let history = history(10, usize::MAX);
+        history.record(&message("sensors/1", "a"));
+        history.record(&message("sensors/2", "b"));
+        history.record(&message("other", "c"));
+        assert_eq!(payloads(&history, Some("sensors/#")), vec![entries("sensors/1", &["a"]), entries("sensors/2", &["b"])]);
+        assert_eq!(payloads(&history, Some("other")), vec![entries("other", &["c"])]);
+        assert!(payloads(&history, Some("missing")).is_empty());
+    }
+}
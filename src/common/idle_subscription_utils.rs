@@ -0,0 +1,188 @@
+// メッセージを配信しなくなった購読の自動解除 (topic_idle_unsub_secs)
+use super::config_utils::Config;
+use super::topic_utils::topic_matches;
+use rumqttc::QoS;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+// 一致するフィルタを覚えておく受信したトピックの最大数 (超えたら覚え直す)
+const MAX_CACHED_TOPICS: usize = 10_000;
+
+/// 購読したトピックフィルタごとに最後にメッセージを受信した時刻を記録し、topic_idle_unsub_secs の間メッセージを
+/// 配信しなかったフィルタを求める。
+///
+/// 受信したメッセージは、トピックが一致するすべての購読に数える (重なった購読のメッセージをブローカーが 1 回だけ配信する場合があるため)。
+/// topic_idle_resubscribe が有効な場合は、解除したフィルタに一致するメッセージを他の購読 (ワイルドカード) で受信したら購読し直す。
+#[derive(Debug)]
+pub struct IdleSubscriptions {
+    timeout: Duration,
+    resubscribe: bool,
+    // 購読中のフィルタの、購読した QoS と最後にメッセージを受信した (または購読した) 時刻
+    active: HashMap<String, (QoS, Instant)>,
+    // 自動で購読を解除したフィルタと、購読していた QoS
+    unsubscribed: HashMap<String, QoS>,
+    // 受信したトピックごとの、一致するフィルタ (購読中と解除済みのもの)。メッセージごとにすべてのフィルタと照合しないよう覚えておき、
+    // フィルタが増減したら覚え直す
+    matches: HashMap<String, Vec<String>>,
+}
+
+impl IdleSubscriptions {
+    /// topic_idle_unsub_secs が指定されていない場合は `None` を返す
+    pub fn new(config: &Config) -> Option<IdleSubscriptions> {
+        let secs = config.topic_idle_unsub_secs?;
+        Some(IdleSubscriptions {
+            timeout: Duration::from_secs(secs.max(1)),
+            resubscribe: config.topic_idle_resubscribe.unwrap_or(false),
+            active: HashMap::new(),
+            unsubscribed: HashMap::new(),
+            matches: HashMap::new(),
+        })
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// 購読した (購読し直した) フィルタを記録する。自動で解除していたフィルタも購読中に戻す
+    pub fn subscribed(&mut self, filters: &[(String, QoS)]) {
+        let now = Instant::now();
+        for (filter, qos) in filters {
+            let was_unsubscribed = self.unsubscribed.remove(filter).is_some();
+            let was_active = self.active.insert(filter.clone(), (*qos, now)).is_some();
+            if !was_unsubscribed && !was_active {
+                self.matches.clear();
+            }
+        }
+    }
+
+    /// 自動で購読を解除したフィルタか
+    pub fn is_unsubscribed(&self, filter: &str) -> bool {
+        self.unsubscribed.contains_key(filter)
+    }
+
+    /// 購読しなくなった (設定の再読み込みで削除された) フィルタの記録を取り除く
+    pub fn remove(&mut self, filters: &[String]) {
+        for filter in filters {
+            self.active.remove(filter);
+            self.unsubscribed.remove(filter);
+        }
+        if !filters.is_empty() {
+            self.matches.clear();
+        }
+    }
+
+    /// (再) 接続した。切断されていた間は配信されないため、購読中のすべてのフィルタの無配信の時間を数え直す
+    pub fn restart(&mut self) {
+        let now = Instant::now();
+        for (_, at) in self.active.values_mut() {
+            *at = now;
+        }
+    }
+
+    /// `topic` のメッセージを受信したことを記録する。topic_idle_resubscribe が有効な場合は、購読し直す解除済みのフィルタを返す
+    pub fn record(&mut self, topic: &str) -> Vec<(String, QoS)> {
+        let now = Instant::now();
+        if !self.matches.contains_key(topic) {
+            if self.matches.len() >= MAX_CACHED_TOPICS {
+                self.matches.clear();
+            }
+            let filters = self.active.keys().chain(self.unsubscribed.keys())
+                .filter(|filter| topic_matches(filter, topic))
+                .cloned()
+                .collect();
+            self.matches.insert(topic.to_string(), filters);
+        }
+        let mut resubscribe = Vec::new();
+        for filter in &self.matches[topic] {
+            if let Some((_, at)) = self.active.get_mut(filter) {
+                *at = now;
+            } else if self.resubscribe
+                && let Some(qos) = self.unsubscribed.get(filter) {
+                resubscribe.push((filter.clone(), *qos));
+            }
+        }
+        resubscribe
+    }
+
+    /// 次に購読を解除するフィルタの期限
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.active.values().map(|(_, at)| *at + self.timeout).min()
+    }
+
+    /// 期限を過ぎたフィルタを解除済みにして、そのフィルタを返す
+    pub fn expire(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired: Vec<String> = self.active.iter()
+            .filter(|(_, (_, at))| *at + self.timeout <= now)
+            .map(|(filter, _)| filter.clone())
+            .collect();
+        expired.sort();
+        for filter in &expired {
+            if let Some((qos, _)) = self.active.remove(filter) {
+                self.unsubscribed.insert(filter.clone(), qos);
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idle(resubscribe: bool) -> IdleSubscriptions {
+        let config: Config = serde_yaml::from_str(&format!(
+            "client_id: idle\nbroker_address: localhost\nbroker_port: 1883\ntopic_idle_unsub_secs: 10\ntopic_idle_resubscribe: {}\n", resubscribe)).unwrap();
+        IdleSubscriptions::new(&config).unwrap()
+    }
+
+    fn filters(names: &[&str]) -> Vec<(String, QoS)> {
+        names.iter().map(|name| (name.to_string(), QoS::AtLeastOnce)).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_keep_every_matching_filter_active() {
+        let mut idle = idle(false);
+        idle.subscribed(&filters(&["a/#", "a/b", "c"]));
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!(idle.record("a/b").is_empty());
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(idle.expire(), ["c"]);
+        assert_eq!(idle.next_deadline(), Some(Instant::now() + Duration::from_secs(6)));
+        // 解除済みのフィルタに一致しても、topic_idle_resubscribe でなければ購読し直さない
+        assert!(idle.record("c").is_empty());
+        assert!(idle.is_unsubscribed("c"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resubscribes_an_expired_filter_when_a_matching_message_arrives() {
+        let mut idle = idle(true);
+        idle.subscribed(&filters(&["a/#", "a/b"]));
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(idle.record("x").is_empty());
+        let mut expired = idle.expire();
+        expired.sort();
+        assert_eq!(expired, ["a/#", "a/b"]);
+        assert_eq!(idle.record("a/b").len(), 2);
+        idle.subscribed(&filters(&["a/b"]));
+        assert_eq!(idle.record("a/b"), filters(&["a/#"]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cached_matches_follow_added_and_removed_filters() {
+        let mut idle = idle(false);
+        idle.subscribed(&filters(&["a/#"]));
+        idle.record("b/c");
+        // 後から購読したフィルタも、一度受信したトピックに一致する
+        idle.subscribed(&filters(&["b/+"]));
+        tokio::time::advance(Duration::from_secs(9)).await;
+        idle.record("b/c");
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(idle.expire(), ["a/#"]);
+
+        idle.remove(&["b/+".to_string()]);
+        idle.record("b/c");
+        assert_eq!(idle.next_deadline(), None);
+        assert!(idle.matches["b/c"].is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils;
+use super::sink_utils::Sink;
+use flate2::{write::GzEncoder, Compression};
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, sync::Mutex};
+use std::time::SystemTime;
+
+/// log_max_files が未指定の場合に残すローテーション済みファイルの数
+pub const DEFAULT_LOG_MAX_FILES: usize = 5;
+
+/// サイズでローテーションするログファイル。
+///
+/// 書き込みによって `max_size` バイトを超える場合は、現在のファイルを `<名前>.1` (`log_compress` が有効なら
+/// `<名前>.1.gz`) に移動して新しいファイルに書き込む。古いファイルは `<名前>.2`, `<名前>.3`, ... と番号をずらし、
+/// `max_files` 個を超えたものは削除する。書き込みとローテーションは内部のロックで排他するため、
+/// 複数のタスクから同時に書き込んでも記録が混ざったり失われたりしない。
+pub struct RotatingLog {
+    path: PathBuf,
+    // None の場合はローテーションしない
+    max_size: Option<u64>,
+    max_files: usize,
+    compress: bool,
+    file: Mutex<LogFile>,
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    /// `log_directory` に `<client_id>.log` を作成する。`log_directory` が指定されていない場合は `None` を返す
+    pub fn open(config: &Config) -> Option<RotatingLog> {
+        let directory = config.log_directory.as_ref()?;
+        fs::create_dir_all(directory).unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("ログディレクトリ '{}' の作成中にエラーが発生しました: {}", directory, e)));
+        });
+        let path = Path::new(directory).join(format!("{}.log", config.client_id));
+        let file = open_append(&path).unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("ログファイル '{}' を開けませんでした: {}", path.display(), e)));
+        });
+
+        Some(RotatingLog {
+            path,
+            max_size: config.log_max_size_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+            max_files: config.log_max_files.unwrap_or(DEFAULT_LOG_MAX_FILES),
+            compress: config.log_compress.unwrap_or(false),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// 受信したメッセージを受信時刻 (UTC) とともに書き込む。書き込みに失敗しても処理は継続する
+    pub fn write_message(&self, text: &str) {
+        if let Err(e) = self.append_message(text) {
+            eprintln!("ログファイル '{}' への書き込み中にエラーが発生しました: {}", self.path.display(), e);
+        }
+    }
+
+    fn append_message(&self, text: &str) -> io::Result<()> {
+        let record = format!("受信時刻: {}\n{}\n", output_utils::format_timestamp(SystemTime::now()), text);
+        self.write(record.as_bytes())
+    }
+
+    fn write(&self, record: &[u8]) -> io::Result<()> {
+        let mut current = self.file.lock().unwrap();
+        if let Some(max_size) = self.max_size
+            && current.size > 0
+            && current.size + record.len() as u64 > max_size {
+            self.rotate()?;
+            *current = open_append(&self.path)?;
+        }
+        current.file.write_all(record)?;
+        current.size += record.len() as u64;
+        Ok(())
+    }
+
+    // ロックを保持した状態で呼び出す
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        // 最も古いファイルを削除し、残りの番号を 1 つずつずらす (圧縮の有無を途中で変更した場合も両方を扱う)
+        for ext in ["", ".gz"] {
+            remove_if_exists(&self.rotated_path(self.max_files, ext))?;
+        }
+        for n in (1..self.max_files).rev() {
+            for ext in ["", ".gz"] {
+                let from = self.rotated_path(n, ext);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1, ext))?;
+                }
+            }
+        }
+
+        let rotated = self.rotated_path(1, "");
+        fs::rename(&self.path, &rotated)?;
+        if self.compress {
+            // 圧縮が終わるまで書き込みを待たせることになるが、ローテーションの順序が入れ替わらないよう同期的に行う
+            compress_file(&rotated, &self.rotated_path(1, ".gz"))?;
+            fs::remove_file(&rotated)?;
+        }
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize, ext: &str) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}{}", n, ext));
+        PathBuf::from(name)
+    }
+}
+
+// ログファイルには output_format にかかわらずテキスト形式で書き込む
+impl Sink for RotatingLog {
+    fn name(&self) -> String {
+        format!("ログファイル '{}'", self.path.display())
+    }
+
+    fn write(&self, message: &ReceivedMessage, _output: &str, config: &Config) -> Result<(), String> {
+        self.append_message(&output_utils::format_text(message, config.payload_preview_bytes)).map_err(|e| e.to_string())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<LogFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(LogFile { file, size })
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn compress_file(from: &Path, to: &Path) -> io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
@@ -0,0 +1,228 @@
+use super::config_utils::Config;
+use super::histogram_utils::{PayloadSizes, SizeSummary};
+use super::output_utils;
+use std::collections::HashMap;
+use std::sync::{atomic::{AtomicU64, Ordering}, Mutex};
+use std::time::Duration;
+
+/// サブスクライバーの受信統計。イベントループから更新され、他のタスクからも参照できる
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    reconnects: AtomicU64,
+    handler_dropped: AtomicU64,
+    duplicates_suppressed: AtomicU64,
+    sampled_out: AtomicU64,
+    qos_rejected: AtomicU64,
+    schema_invalid: AtomicU64,
+    // トピック (または購読トピックフィルタ) ごとの受信メッセージ数 (topic_counts が指定されている場合のみ)
+    topic_counts: Mutex<HashMap<String, u64>>,
+    // throttle のパターンごとの、最大レートを超えたメッセージ数
+    throttled: Mutex<HashMap<String, ThrottleCount>>,
+    // 受信したペイロードのサイズのヒストグラム (payload_size_histogram が指定されている場合のみ)
+    payload_sizes: Option<Mutex<PayloadSizes>>,
+}
+
+/// throttle のルールで最大レートを超えたメッセージ数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThrottleCount {
+    pub dropped: u64,
+    pub delayed: u64,
+}
+
+impl ThrottleCount {
+    pub fn merge(self, other: ThrottleCount) -> ThrottleCount {
+        ThrottleCount { dropped: self.dropped + other.dropped, delayed: self.delayed + other.delayed }
+    }
+}
+
+/// ある時点の受信統計の値
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub reconnects: u64,
+    /// ハンドラーのキューが満杯のため破棄したメッセージ数
+    pub handler_dropped: u64,
+    /// 重複として表示しなかったメッセージ数
+    pub duplicates_suppressed: u64,
+    /// サンプリング (sample_rate) で表示しなかったメッセージ数
+    pub sampled_out: u64,
+    /// accept_qos にない QoS のため破棄したメッセージ数
+    pub qos_rejected: u64,
+    /// schema に適合しなかったため表示しなかったメッセージ数
+    pub schema_invalid: u64,
+}
+
+impl Metrics {
+    /// payload_size_histogram が指定されている場合は、ペイロードのサイズも記録する
+    pub fn new(config: &Config) -> Metrics {
+        Metrics {
+            payload_sizes: config.payload_size_histogram.as_ref().map(|histogram| Mutex::new(PayloadSizes::new(histogram))),
+            ..Metrics::default()
+        }
+    }
+
+    pub fn record_message(&self, payload_len: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(payload_len as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_sampled_out(&self) {
+        self.sampled_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_qos_rejected(&self) {
+        self.qos_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_schema_invalid(&self) {
+        self.schema_invalid.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_topic(&self, key: &str) {
+        let mut counts = self.topic_counts.lock().unwrap();
+        match counts.get_mut(key) {
+            Some(count) => *count += 1,
+            None => {
+                counts.insert(key.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn record_payload_size(&self, key: &str, size: usize) {
+        if let Some(sizes) = &self.payload_sizes {
+            sizes.lock().unwrap().record(key, size);
+        }
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_handler_drop(&self) {
+        self.handler_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_duplicate(&self) {
+        self.duplicates_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_throttled(&self, pattern: &str, dropped: bool) {
+        let mut counts = self.throttled.lock().unwrap();
+        let count = match counts.get_mut(pattern) {
+            Some(count) => count,
+            None => counts.entry(pattern.to_string()).or_default(),
+        };
+        if dropped {
+            count.dropped += 1;
+        } else {
+            count.delayed += 1;
+        }
+    }
+
+    /// throttle のパターンごとの、最大レートを超えたメッセージ数を返す
+    pub fn throttled_counts(&self) -> HashMap<String, ThrottleCount> {
+        self.throttled.lock().unwrap().clone()
+    }
+
+    /// ペイロードのサイズの記録を返す (payload_size_histogram が指定されていない場合は `None`)
+    pub fn payload_sizes(&self) -> Option<PayloadSizes> {
+        self.payload_sizes.as_ref().map(|sizes| sizes.lock().unwrap().clone())
+    }
+
+    /// 全体のペイロードのサイズのパーセンタイルを返す (トピックごとの内訳はコピーしない)
+    pub fn payload_size_summary(&self) -> Option<SizeSummary> {
+        self.payload_sizes.as_ref()?.lock().unwrap().total.summary()
+    }
+
+    /// トピックごとの受信メッセージ数を返す
+    pub fn topic_counts(&self) -> HashMap<String, u64> {
+        self.topic_counts.lock().unwrap().clone()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            handler_dropped: self.handler_dropped.load(Ordering::Relaxed),
+            duplicates_suppressed: self.duplicates_suppressed.load(Ordering::Relaxed),
+            sampled_out: self.sampled_out.load(Ordering::Relaxed),
+            qos_rejected: self.qos_rejected.load(Ordering::Relaxed),
+            schema_invalid: self.schema_invalid.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// 複数セッションの統計を合算する
+    pub fn merge(self, other: MetricsSnapshot) -> MetricsSnapshot {
+        MetricsSnapshot {
+            messages_received: self.messages_received + other.messages_received,
+            bytes_received: self.bytes_received + other.bytes_received,
+            reconnects: self.reconnects + other.reconnects,
+            handler_dropped: self.handler_dropped + other.handler_dropped,
+            duplicates_suppressed: self.duplicates_suppressed + other.duplicates_suppressed,
+            sampled_out: self.sampled_out + other.sampled_out,
+            qos_rejected: self.qos_rejected + other.qos_rejected,
+            schema_invalid: self.schema_invalid + other.schema_invalid,
+        }
+    }
+}
+
+/// 受信統計を表示する
+pub fn print_summary(label: &str, snapshot: &MetricsSnapshot, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { snapshot.messages_received as f64 / secs } else { 0.0 };
+    crate::status!("[{}] 受信メッセージ数: {}, 受信バイト数: {}, 再接続回数: {}, 経過時間: {:.1} 秒, 受信レート: {:.1} 件/秒",
+        label, snapshot.messages_received, snapshot.bytes_received, snapshot.reconnects, secs, rate);
+    if snapshot.handler_dropped > 0 {
+        crate::status!("[{}] ハンドラーのキューが満杯のため破棄したメッセージ数: {}", label, snapshot.handler_dropped);
+    }
+    if snapshot.duplicates_suppressed > 0 {
+        crate::status!("[{}] 重複として表示しなかったメッセージ数: {}", label, snapshot.duplicates_suppressed);
+    }
+    if snapshot.qos_rejected > 0 {
+        crate::status!("[{}] accept_qos にない QoS のため破棄したメッセージ数: {}", label, snapshot.qos_rejected);
+    }
+    if snapshot.schema_invalid > 0 {
+        crate::status!("[{}] schema に適合しなかったメッセージ数: {}", label, snapshot.schema_invalid);
+    }
+}
+
+/// サンプリング (sample_rate) の比率と、表示しなかったメッセージ数を表示する
+pub fn print_sampling(label: &str, snapshot: &MetricsSnapshot, sample_rate: u64) {
+    crate::status!("[{}] サンプリング: トピックごとに {} 件に 1 件を表示 (表示しなかったメッセージ数: {})",
+        label, sample_rate, snapshot.sampled_out);
+}
+
+/// トピックごとの受信メッセージ数を件数の多い順に表示する
+pub fn print_topic_counts(label: &str, counts: &HashMap<String, u64>) {
+    if counts.is_empty() {
+        return;
+    }
+    let mut rows: Vec<(&String, &u64)> = counts.iter().collect();
+    rows.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    let rows: Vec<_> = rows.into_iter().map(|(key, count)| (output_utils::status_topic(key), count)).collect();
+    let width = rows.iter().map(|(key, _)| key.chars().count()).max().unwrap_or(0);
+    crate::status!("[{}] トピックごとの受信メッセージ数:", label);
+    for (key, count) in rows {
+        crate::status!("  {:<width$}  {}", key, count, width = width);
+    }
+}
+
+/// throttle のパターンごとに、最大レートを超えて破棄・遅延したメッセージ数を表示する
+pub fn print_throttled(label: &str, counts: &HashMap<String, ThrottleCount>) {
+    if counts.is_empty() {
+        return;
+    }
+    let mut rows: Vec<(&String, &ThrottleCount)> = counts.iter().collect();
+    rows.sort_by(|a, b| a.0.cmp(b.0));
+    let width = rows.iter().map(|(pattern, _)| pattern.chars().count()).max().unwrap_or(0);
+    crate::status!("[{}] throttle で最大レートを超えたメッセージ数:", label);
+    for (pattern, count) in rows {
+        crate::status!("  {:<width$}  破棄: {}, 遅延: {}", pattern, count.dropped, count.delayed, width = width);
+    }
+}
@@ -1 +1,54 @@
+pub mod benchmark_utils;
+#[cfg(feature = "cbor")]
+pub mod cbor_utils;
+pub mod clock_utils;
 pub mod config_utils;
+pub mod dedup_utils;
+pub mod discover_utils;
+pub mod dns_utils;
+pub mod dscp_utils;
+pub mod error_utils;
+pub mod failover_utils;
+#[cfg(unix)]
+pub mod fifo_utils;
+pub mod format_rule_utils;
+pub mod forward_utils;
+pub mod gap_utils;
+pub mod handler_utils;
+pub mod histogram_utils;
+pub mod history_utils;
+pub mod idle_subscription_utils;
+pub mod log_utils;
+pub mod metrics_utils;
+pub mod mqtt_utils;
+#[cfg(feature = "msgpack")]
+pub mod msgpack_utils;
+pub mod output_utils;
+pub mod packet_dump_utils;
+pub mod proxy_utils;
+pub mod publisher;
+#[cfg(feature = "quic")]
+pub mod quic_utils;
+pub mod qos_utils;
+pub mod readiness_utils;
+pub mod reconnect_utils;
+pub mod replay_utils;
+pub mod schema_utils;
+pub mod selftest_utils;
+pub mod sequence_utils;
+pub mod sink_utils;
+pub mod socket_utils;
+#[cfg(feature = "sparkplug")]
+pub mod sparkplug_utils;
+pub mod statsd_utils;
+pub mod stream_utils;
+pub mod subscriber;
+pub mod syslog_utils;
+pub mod template_utils;
+pub mod throttle_utils;
+pub mod tls_utils;
+pub mod token_utils;
+pub mod topic_utils;
+pub mod url_utils;
+pub mod wait_utils;
+pub mod webhook_utils;
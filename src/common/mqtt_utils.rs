@@ -0,0 +1,1072 @@
+use super::config_utils::{Config, ServerKeepAliveAction};
+use super::error_utils::{exit_with, AppError};
+use super::failover_utils;
+use super::packet_dump_utils;
+use super::proxy_utils;
+use super::socket_utils::SocketOptions;
+use super::tls_utils;
+use bytes::Bytes;
+use std::{collections::HashSet, fmt, sync::atomic::{AtomicBool, Ordering}, time::Duration};
+use rumqttc::{v5, AsyncClient, Event, EventLoop, MqttOptions, NetworkOptions, Outgoing, Packet, QoS, SubscribeFilter, SubscribeReasonCode, Transport};
+use rumqttc::v5::mqttbytes::v5::{ConnAckProperties, ConnectProperties, DisconnectReasonCode, Filter, PublishProperties, RetainForwardRule};
+
+// max_packet_size が未指定の場合に受信するパケットの最大サイズ (rumqttc の既定値と同じ)
+const DEFAULT_MAX_PACKET_SIZE: usize = 10 * 1024;
+// websocket_path が未指定の場合に WebSocket で接続するパス
+const DEFAULT_WEBSOCKET_PATH: &str = "/mqtt";
+// dump_packets で表示するパケットの仮のパケット ID (実際の ID は rumqttc が送信時に割り当てる)
+const DUMP_PACKET_ID: u16 = 1;
+// CONNECT で要求するキープアライブの秒数
+const KEEP_ALIVE_SECS: u64 = 20;
+// min_server_keep_alive_action: reconnect で、続けてこの回数だけ Server Keep Alive を受け入れなかった場合は終了する
+const MAX_SERVER_KEEP_ALIVE_REJECTIONS: u32 = 5;
+/// MQTT のパケットの残りの長さとして表現できる最大値 (256 MB - 1)
+pub const MAX_PROTOCOL_PACKET_SIZE: usize = 268_435_455;
+// ソケットのバッファサイズを表示したか (セッションごとにクライアントを作成しても 1 回だけ表示する)
+static SOCKET_BUFFERS_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// MQTT のプロトコルバージョン
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1 (プロトコルレベル 4)
+    V311,
+    /// MQTT 5.0
+    V5,
+}
+
+/// 設定ファイルの `protocol_version` (4 または 5、未指定の場合は 4。値は `Config::validate` で検査済み) を判定する
+pub fn protocol_version(config: &Config) -> ProtocolVersion {
+    match config.protocol_version {
+        Some(5) => ProtocolVersion::V5,
+        _ => ProtocolVersion::V311,
+    }
+}
+
+/// トピック購読時のオプション
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SubscribeOptions {
+    /// 購読時点の retain メッセージを送信しないようブローカーに要求する (MQTT 5 の Retain Handling = 2)。
+    /// MQTT 3.1.1 には対応する仕組みがないため無視される。
+    pub skip_retained: bool,
+}
+
+/// プロトコルバージョンに依存しない受信メッセージ
+#[derive(Debug, Clone)]
+pub struct ReceivedMessage {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+    pub pkid: u16,
+    /// MQTT 5 の PUBLISH プロパティ (MQTT 3.1.1 の場合は常に `None`)
+    pub properties: Option<Box<PublishProperties>>,
+    // manual_ack が有効な場合に確認応答を送信するクライアント (送信済み、または自動で確認応答する場合は `None`)
+    pub(crate) acker: Option<MqttClient>,
+}
+
+impl ReceivedMessage {
+    /// manual_ack が有効な場合に、このメッセージの確認応答 (QoS 1 は PUBACK、QoS 2 は PUBREC) を送信する。
+    ///
+    /// ハンドラーがメッセージを永続的に処理し終えてから呼び出す。確認応答する前にプロセスが終了した場合は、
+    /// ブローカーが再接続後にメッセージを再送する。manual_ack が無効な場合や QoS 0 のメッセージでは何もしない。
+    pub async fn ack(&self) -> Result<(), ClientError> {
+        match &self.acker {
+            Some(client) => client.ack(self).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// ブローカーから受信した DISCONNECT (MQTT 5) の理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectReason {
+    pub code: DisconnectReasonCode,
+    /// ブローカーが付加した人が読める形式の理由 (Reason String プロパティ)
+    pub reason_string: Option<String>,
+}
+
+impl DisconnectReason {
+    /// この理由で切断された後に再接続すべきかどうか。
+    /// 同じクライアント ID の別の接続にセッションが引き継がれた場合は、再接続すると奪い合いになるため再接続しない。
+    pub fn should_reconnect(&self) -> bool {
+        !matches!(self.code, DisconnectReasonCode::SessionTakenOver)
+    }
+}
+
+/// イベントループで発生したイベントのうち、サブスクライバー・パブリッシャーが扱うもの
+#[derive(Debug)]
+pub enum MqttEvent {
+    /// CONNACK を受信した。`session_present` はブローカーに前回のセッション (購読を含む) が残っていたか。
+    /// `properties` はブローカーが通知した機能や制限 (MQTT 5 のみ)
+    ConnAck { session_present: bool, properties: Option<Box<ConnAckProperties>> },
+    Publish(ReceivedMessage),
+    /// SUBACK を受信した。`granted` は SUBSCRIBE のトピックフィルターの順に、許可された QoS (拒否された場合は `None`)
+    SubAck { pkid: u16, granted: Vec<Option<QoS>> },
+    PubAck(u16),
+    /// QoS 2 の発行に対する PUBREC を受信した (rumqttc は続けて PUBREL を送信する)
+    PubRec(u16),
+    PubComp(u16),
+    PingResp,
+    /// ブローカーからの DISCONNECT (MQTT 5 のみ)
+    Disconnect(DisconnectReason),
+    Outgoing(Outgoing),
+    Other,
+}
+
+/// リクエストをイベントループに送るクライアント
+#[derive(Clone, Debug)]
+pub enum MqttClient {
+    V311(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+/// ブローカーとの通信を行うイベントループ
+pub struct MqttEventLoop {
+    inner: EventLoopKind,
+    // 次の poll で rumqttc が (再) 接続するか (接続時に送信する CONNECT のダンプに使う)
+    connecting: bool,
+    // 接続するたびにブローカーとの接続のソケットに設定するオプション (tcp_nodelay / socket_dscp)
+    socket: Option<SocketOptions>,
+    // failover_brokers を指定した場合の、切り替え先の接続先
+    failover: Option<Failover>,
+}
+
+// 接続できなかった場合に順に切り替える接続先。rumqttc の MqttOptions には接続先を変更する手段がないため、
+// 接続先ごとに MqttOptions を構築しておき、イベントループの MqttOptions と入れ替える
+struct Failover {
+    brokers: Vec<FailoverBroker>,
+    // イベントループが使用している接続先 (`brokers[current].options` は `None`)
+    current: usize,
+    // 接続できなかったため、次の poll で次の接続先に切り替える
+    failed: bool,
+}
+
+struct FailoverBroker {
+    name: String,
+    options: Option<BrokerOptions>,
+    socket: Option<SocketOptions>,
+}
+
+enum BrokerOptions {
+    V311(Box<MqttOptions>),
+    V5(Box<v5::MqttOptions>),
+}
+
+enum EventLoopKind {
+    V311(Box<EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+/// クライアントからイベントループへのリクエスト送信に失敗した
+#[derive(Debug)]
+pub enum ClientError {
+    V311(Box<rumqttc::ClientError>),
+    V5(Box<v5::ClientError>),
+}
+
+/// ブローカーが CONNACK で接続を拒否した理由の分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectRefusal {
+    /// クライアント ID が拒否された (Identifier rejected / Client Identifier not valid)
+    IdentifierRejected,
+    /// ブローカーが接続を受け付けられない (Server unavailable / Server busy)
+    ServerUnavailable,
+    /// ユーザー名またはパスワードが正しくない (Bad user name or password)
+    BadCredentials,
+    /// 接続が認可されていない (Not authorized / Banned / Bad authentication method)
+    NotAuthorized,
+    /// その他の理由 (プロトコルバージョンの非対応など)
+    Other,
+}
+
+impl ConnectRefusal {
+    /// 認証・認可の失敗の場合は `true` (設定を変えない限り再接続しても成功しない)
+    pub fn is_auth_failure(self) -> bool {
+        matches!(self, ConnectRefusal::BadCredentials | ConnectRefusal::NotAuthorized)
+    }
+
+    /// 利用者が取るべき対処を含む説明
+    pub fn describe(self, config: &Config, code: &str) -> String {
+        match self {
+            ConnectRefusal::IdentifierRejected => format!(
+                "ブローカーがクライアント ID '{}' を拒否しました ({})。client_id の長さや使用できる文字を確認してください。\
+                 永続セッションに対応していないブローカーでは、clean_session: false の場合にも拒否されることがあります (clean_session: true を試してください)。",
+                config.client_id, code),
+            ConnectRefusal::ServerUnavailable => format!(
+                "ブローカーが現在接続を受け付けていません ({})。ブローカーの状態や接続数の上限を確認してください。", code),
+            ConnectRefusal::BadCredentials => format!(
+                "ユーザー名またはパスワードが正しくないため、ブローカーが接続を拒否しました ({})。username / password (または auth_token_url) を確認してください。", code),
+            ConnectRefusal::NotAuthorized => format!(
+                "このクライアントの接続はブローカーに認可されていません ({})。ブローカーの ACL や認証方式の設定を確認してください。", code),
+            ConnectRefusal::Other => format!("ブローカーが接続を拒否しました ({})。", code),
+        }
+    }
+}
+
+/// イベントループで発生した接続エラー
+#[derive(Debug)]
+pub enum ConnectionError {
+    V311(rumqttc::ConnectionError),
+    V5(v5::ConnectionError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::V311(e) => e.fmt(f),
+            ClientError::V5(e) => e.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::V311(e) => e.fmt(f),
+            ConnectionError::V5(e) => e.fmt(f),
+        }
+    }
+}
+
+impl ConnectionError {
+    /// クライアントのハンドルがすべて破棄され、イベントループへの要求チャネルが閉じられた場合は `true`。
+    /// この状態は再接続しても回復しないため、再試行せずに終了する
+    pub fn is_requests_done(&self) -> bool {
+        matches!(self,
+            ConnectionError::V311(rumqttc::ConnectionError::RequestsDone)
+                | ConnectionError::V5(v5::ConnectionError::RequestsDone))
+    }
+
+    /// 受信したパケットが受信できる最大サイズを超えていた場合は、そのパケットのサイズ (固定ヘッダーの残りの長さ) を返す。
+    /// パケットは解析される前に破棄されるため、トピックなどの内容は分からない
+    pub fn oversized_packet(&self) -> Option<usize> {
+        match self {
+            ConnectionError::V311(rumqttc::ConnectionError::MqttState(rumqttc::StateError::Deserialization(
+                rumqttc::Error::PayloadSizeLimitExceeded(size)))) => Some(*size),
+            ConnectionError::V5(v5::ConnectionError::MqttState(v5::StateError::IncomingPacketTooLarge { pkt_size, .. })) => Some(*pkt_size),
+            _ => None,
+        }
+    }
+
+    /// ブローカーが購読・発行を拒否した (MQTT 5 の SUBACK / PUBACK / PUBREC の理由コードが失敗だった) 場合は、その説明を返す。
+    ///
+    /// rumqttc は失敗の理由コードを受信すると接続エラーとして接続を切り、パケットを破棄するため、
+    /// ブローカーが付加した Reason String は参照できず、理由コードのみを表示する
+    pub fn rejection(&self) -> Option<String> {
+        let ConnectionError::V5(v5::ConnectionError::MqttState(state)) = self else {
+            return None;
+        };
+        match state {
+            v5::StateError::SubFail { reason } => Some(format!("ブローカーが購読を拒否しました (SUBACK の理由コード: {:?})", reason)),
+            v5::StateError::UnsubFail { reason } => Some(format!("ブローカーが購読の解除を拒否しました (UNSUBACK の理由コード: {:?})", reason)),
+            v5::StateError::PubAckFail { reason } => Some(format!("ブローカーが QoS 1 の発行を拒否しました (PUBACK の理由コード: {:?})", reason)),
+            v5::StateError::PubRecFail { reason } => Some(format!("ブローカーが QoS 2 の発行を拒否しました (PUBREC の理由コード: {:?})", reason)),
+            _ => None,
+        }
+    }
+
+    /// 購読がブローカーに拒否された場合は `true`
+    pub fn is_subscribe_rejected(&self) -> bool {
+        matches!(self, ConnectionError::V5(v5::ConnectionError::MqttState(v5::StateError::SubFail { .. })))
+    }
+
+    /// 購読の拒否が一時的な失敗 (再試行で許可される可能性がある理由コード) の場合は `true`。
+    /// Not authorized やトピックフィルタの不正などの恒久的な拒否は `false`
+    pub fn is_transient_subscribe_failure(&self) -> bool {
+        use v5::mqttbytes::v5::SubscribeReasonCode as Reason;
+        matches!(self, ConnectionError::V5(v5::ConnectionError::MqttState(v5::StateError::SubFail {
+            reason: Reason::Failure | Reason::Unspecified | Reason::ImplementationSpecific | Reason::PkidInUse | Reason::QuotaExceeded,
+        })))
+    }
+
+    /// QoS 1 / 2 の発行がブローカーに拒否された場合は `true` (拒否されたメッセージは再送されない)
+    pub fn is_publish_rejected(&self) -> bool {
+        self.rejected_publish_qos().is_some()
+    }
+
+    /// 発行がブローカーに拒否された場合は、拒否されたメッセージの QoS (PUBACK の場合は 1、PUBREC の場合は 2) を返す
+    pub fn rejected_publish_qos(&self) -> Option<QoS> {
+        match self {
+            ConnectionError::V5(v5::ConnectionError::MqttState(v5::StateError::PubAckFail { .. })) => Some(QoS::AtLeastOnce),
+            ConnectionError::V5(v5::ConnectionError::MqttState(v5::StateError::PubRecFail { .. })) => Some(QoS::ExactlyOnce),
+            _ => None,
+        }
+    }
+
+    /// ブローカーが CONNACK で接続を拒否した場合は、その理由の分類と理由コードを返す
+    pub fn connect_refusal(&self) -> Option<(ConnectRefusal, String)> {
+        use v5::mqttbytes::v5::ConnectReturnCode as V5Code;
+        match self {
+            ConnectionError::V311(rumqttc::ConnectionError::ConnectionRefused(code)) => {
+                let refusal = match code {
+                    rumqttc::ConnectReturnCode::BadClientId => ConnectRefusal::IdentifierRejected,
+                    rumqttc::ConnectReturnCode::ServiceUnavailable => ConnectRefusal::ServerUnavailable,
+                    rumqttc::ConnectReturnCode::BadUserNamePassword => ConnectRefusal::BadCredentials,
+                    rumqttc::ConnectReturnCode::NotAuthorized => ConnectRefusal::NotAuthorized,
+                    _ => ConnectRefusal::Other,
+                };
+                Some((refusal, format!("{:?}", code)))
+            }
+            ConnectionError::V5(v5::ConnectionError::ConnectionRefused(code)) => {
+                let refusal = match code {
+                    V5Code::BadClientId | V5Code::ClientIdentifierNotValid => ConnectRefusal::IdentifierRejected,
+                    V5Code::ServiceUnavailable | V5Code::ServerUnavailable | V5Code::ServerBusy => ConnectRefusal::ServerUnavailable,
+                    V5Code::BadUserNamePassword => ConnectRefusal::BadCredentials,
+                    V5Code::NotAuthorized | V5Code::Banned | V5Code::BadAuthenticationMethod => ConnectRefusal::NotAuthorized,
+                    _ => ConnectRefusal::Other,
+                };
+                Some((refusal, format!("{:?}", code)))
+            }
+            _ => None,
+        }
+    }
+
+    /// 接続エラーを終了時のエラーに変換する。ブローカーが認証・認可の失敗で接続を拒否した場合は `AppError::Auth` になる
+    pub fn to_app_error(&self, message: String) -> AppError {
+        let auth_failure = self.connect_refusal().is_some_and(|(refusal, _)| refusal.is_auth_failure());
+        if auth_failure {
+            AppError::Auth(message)
+        } else {
+            AppError::Unavailable(message)
+        }
+    }
+}
+
+/// MQTT 5 の CONNACK の Server Keep Alive (ブローカーが指定したキープアライブ) を確認する。
+///
+/// rumqttc はその接続の PINGREQ の間隔にブローカーの値を使うため、要求した値と異なる場合は表示する。
+/// min_server_keep_alive_secs より短い場合は min_server_keep_alive_action に従って終了するか、`false` を返す (呼び出し側で再接続する)。
+/// `rejections` は続けて受け入れなかった回数で、受け入れると 0 に戻す。MAX_SERVER_KEEP_ALIVE_REJECTIONS 回に達すると終了する
+pub fn check_server_keep_alive(config: &Config, properties: Option<&ConnAckProperties>, rejections: &mut u32) -> bool {
+    let Err(message) = accept_server_keep_alive(config, properties) else {
+        *rejections = 0;
+        return true;
+    };
+    match config.min_server_keep_alive_action.unwrap_or_default() {
+        ServerKeepAliveAction::Exit => exit_with(AppError::Unavailable(format!("{}。終了します。", message))),
+        ServerKeepAliveAction::Reconnect => {
+            *rejections += 1;
+            if *rejections >= MAX_SERVER_KEEP_ALIVE_REJECTIONS {
+                exit_with(AppError::RetriesExhausted(format!("{}。{} 回続けて再接続しても変わらなかったため終了します。",
+                    message, MAX_SERVER_KEEP_ALIVE_REJECTIONS)));
+            }
+            eprintln!("警告: {}。再接続します ({}/{} 回目)。", message, rejections, MAX_SERVER_KEEP_ALIVE_REJECTIONS);
+            false
+        }
+    }
+}
+
+// Server Keep Alive を受け入れる場合は変更後の値などを表示し、受け入れない場合はその理由を返す
+fn accept_server_keep_alive(config: &Config, properties: Option<&ConnAckProperties>) -> Result<(), String> {
+    let Some(server_keep_alive) = properties.and_then(|p| p.server_keep_alive) else {
+        return Ok(());
+    };
+    if u64::from(server_keep_alive) == KEEP_ALIVE_SECS {
+        return Ok(());
+    }
+    if server_keep_alive == 0 {
+        crate::status!("ブローカーがキープアライブを無効にしました (Server Keep Alive: 0)。PINGREQ は要求した {} 秒ごとに送信します。", KEEP_ALIVE_SECS);
+        return Ok(());
+    }
+    if let Some(min) = config.min_server_keep_alive_secs
+        && server_keep_alive < min {
+        return Err(format!("ブローカーが指定したキープアライブ {} 秒 (Server Keep Alive) は min_server_keep_alive_secs ({} 秒) より短いため、接続を受け入れません",
+            server_keep_alive, min));
+    }
+    crate::status!("ブローカーがキープアライブを {} 秒に変更しました (要求 {} 秒、Server Keep Alive)。PINGREQ はこの間隔で送信します。",
+        server_keep_alive, KEEP_ALIVE_SECS);
+    Ok(())
+}
+
+/// ブローカーが CONNACK で接続を拒否した場合に、理由と対処を表示して `true` を返す。
+/// 認証・認可の失敗は再接続しても成功しないため、retry_on_auth_failure が有効でなければ終了する
+pub fn report_connect_refusal(e: &ConnectionError, config: &Config) -> bool {
+    let Some((refusal, code)) = e.connect_refusal() else {
+        return false;
+    };
+    eprintln!("エラー: {}", refusal.describe(config, &code));
+    if refusal.is_auth_failure() && !config.retry_on_auth_failure.unwrap_or(false) {
+        exit_with(AppError::Auth("認証・認可に失敗したため、再接続せずに終了します (再接続を続ける場合は retry_on_auth_failure: true を指定してください)。".to_string()));
+    }
+    true
+}
+
+/// 設定ファイルのプロトコルバージョンに応じたクライアントとイベントループを生成する
+pub fn create_client(config: &Config, cap: usize) -> (MqttClient, MqttEventLoop) {
+    packet_dump_utils::configure(config);
+    let network_options = network_options(config);
+    // failover_brokers は `Config::validate` で検査済み
+    let brokers = failover_utils::broker_configs(config).unwrap_or_else(|e| exit_with(AppError::Config(e)));
+    let (client, inner) = match protocol_version(config) {
+        ProtocolVersion::V311 => {
+            let (client, mut eventloop) = AsyncClient::new(build_mqtt_options(&brokers[0]), cap);
+            eventloop.set_network_options(network_options.clone());
+            (MqttClient::V311(client), EventLoopKind::V311(Box::new(eventloop)))
+        }
+        ProtocolVersion::V5 => {
+            let mut mqtt_options = build_v5_mqtt_options(&brokers[0]);
+            mqtt_options.set_network_options(network_options.clone());
+            let (client, eventloop) = v5::AsyncClient::new(mqtt_options, cap);
+            (MqttClient::V5(client), EventLoopKind::V5(Box::new(eventloop)))
+        }
+    };
+    let failover = (brokers.len() > 1).then(|| Failover {
+        brokers: brokers.iter().enumerate().map(|(index, broker)| FailoverBroker {
+            name: failover_utils::broker_name(broker),
+            // 最初の接続先の MqttOptions はイベントループが使用している
+            options: (index > 0).then(|| match protocol_version(config) {
+                ProtocolVersion::V311 => BrokerOptions::V311(Box::new(build_mqtt_options(broker))),
+                ProtocolVersion::V5 => {
+                    let mut mqtt_options = build_v5_mqtt_options(broker);
+                    mqtt_options.set_network_options(network_options.clone());
+                    BrokerOptions::V5(Box::new(mqtt_options))
+                }
+            }),
+            socket: (index > 0).then(|| SocketOptions::new(broker)).flatten(),
+        }).collect(),
+        current: 0,
+        failed: false,
+    });
+    (client, MqttEventLoop { inner, connecting: true, socket: SocketOptions::new(config), failover })
+}
+
+// ソケットのバッファサイズ (socket_recv_buffer_bytes / socket_send_buffer_bytes)。rumqttc が接続のたびに作成するソケットに、
+// 接続する前 (MQTT の接続より前) に設定する。scheme: tcp / mqtts / ssl / ws / wss で有効
+fn network_options(config: &Config) -> NetworkOptions {
+    let mut network_options = NetworkOptions::new();
+    let (recv, send) = (config.socket_recv_buffer_bytes, config.socket_send_buffer_bytes);
+    if recv.is_none() && send.is_none() {
+        return network_options;
+    }
+    if config.scheme.as_deref() == Some("quic") {
+        eprintln!("警告: socket_recv_buffer_bytes / socket_send_buffer_bytes は scheme: quic では無効です (rumqttc との中継用のローカルの接続にのみ適用します)。");
+    } else if proxy_utils::is_relayed(config) {
+        eprintln!("警告: proxy_url を指定した場合、socket_recv_buffer_bytes / socket_send_buffer_bytes は rumqttc とブローカーへの中継用のローカルの接続にのみ適用します。");
+    }
+    if let Some(size) = recv {
+        network_options.set_tcp_recv_buffer_size(size);
+    }
+    if let Some(size) = send {
+        network_options.set_tcp_send_buffer_size(size);
+    }
+    if !SOCKET_BUFFERS_REPORTED.swap(true, Ordering::Relaxed) {
+        report_socket_buffers(recv, send);
+    }
+    network_options
+}
+
+// rumqttc は接続したソケットを公開していないため、同じ設定をした未接続のソケットで OS が実際に設定するサイズを確認して表示する
+// (OS は要求どおりの値にするとは限らない。Linux では管理用の領域を含めて要求の 2 倍の値になり、
+// net.core.rmem_max / net.core.wmem_max を上限に切り詰められる)
+fn report_socket_buffers(recv: Option<u32>, send: Option<u32>) {
+    let socket = match tokio::net::TcpSocket::new_v4() {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("警告: ソケットのバッファサイズを確認できませんでした: {}", e);
+            return;
+        }
+    };
+    if let Some(requested) = recv {
+        report_granted("受信", requested, socket.set_recv_buffer_size(requested).and_then(|_| socket.recv_buffer_size()));
+    }
+    if let Some(requested) = send {
+        report_granted("送信", requested, socket.set_send_buffer_size(requested).and_then(|_| socket.send_buffer_size()));
+    }
+}
+
+// 設定できない場合は rumqttc が接続のたびに失敗する (パニックする) ため、接続する前に設定エラーとする
+fn report_granted(name: &str, requested: u32, granted: std::io::Result<u32>) {
+    match granted {
+        Ok(granted) => crate::status!("ソケットの{}バッファ: 要求 {} バイト、OS が設定したサイズ {} バイト", name, requested, granted),
+        Err(e) => exit_with(AppError::Config(format!("ソケットの{}バッファを {} バイトに設定できませんでした: {}", name, requested, e))),
+    }
+}
+
+// rumqttc が接続するアドレスとポート。scheme: quic と proxy_url の場合は、ブローカーに中継するローカルのアドレスに接続する
+// (scheme: quic と proxy_url の組み合わせや quic フィーチャーの有無は `Config::validate` で検査済み)
+fn broker_endpoint(config: &Config) -> (String, u16) {
+    #[cfg(feature = "quic")]
+    if config.scheme.as_deref() == Some("quic") {
+        return super::quic_utils::quic_endpoint(config);
+    }
+    proxy_utils::broker_endpoint(config)
+}
+
+// WebSocket (scheme: ws / wss) の場合は、rumqttc には接続先を URL で渡す
+fn broker_address(config: &Config) -> (String, u16) {
+    let (address, port) = broker_endpoint(config);
+    match config.scheme.as_deref() {
+        Some(scheme @ ("ws" | "wss")) => {
+            let host = if address.contains(':') { format!("[{}]", address) } else { address };
+            let path = config.websocket_path.as_deref().unwrap_or(DEFAULT_WEBSOCKET_PATH);
+            let separator = if path.starts_with('/') { "" } else { "/" };
+            (format!("{}://{}:{}{}{}", scheme, host, port, separator, path), port)
+        }
+        _ => (address, port),
+    }
+}
+
+// 接続に使うトランスポート (TCP の場合は `None`)。中継する場合は、中継の中でブローカーとの間で TLS 接続するため、
+// rumqttc は中継用のローカルのアドレスに TLS なしで接続する
+fn transport(config: &Config) -> Option<Transport> {
+    match config.scheme.as_deref() {
+        Some("ws") => Some(Transport::Ws),
+        _ if proxy_utils::is_relayed(config) => None,
+        _ => tls_utils::build_tls_transport(config),
+    }
+}
+
+// 受信するパケットの最大サイズ (max_packet_size。範囲は `Config::validate` で検査済み)
+fn max_packet_size(config: &Config) -> usize {
+    config.max_packet_size.unwrap_or(DEFAULT_MAX_PACKET_SIZE)
+}
+
+/// 発行できるメッセージのパケットの最大サイズ。MQTT 3.1.1 では max_packet_size が送信にも適用される
+/// (MQTT 5 ではブローカーが CONNACK で通知した上限に従うため `None`)
+pub fn max_outgoing_packet_size(config: &Config) -> Option<usize> {
+    (protocol_version(config) == ProtocolVersion::V311).then(|| max_packet_size(config))
+}
+
+// 設定ファイルの内容から MqttOptions を構築する
+pub fn build_mqtt_options(config: &Config) -> MqttOptions {
+    let (address, port) = broker_address(config);
+    let mut mqtt_options = MqttOptions::new(&config.client_id, address, port);
+    mqtt_options.set_keep_alive(Duration::from_secs(KEEP_ALIVE_SECS));
+    mqtt_options.set_clean_session(config.clean_session.unwrap_or(true));
+    mqtt_options.set_manual_acks(config.manual_ack.unwrap_or(false));
+    // MQTT 3.1.1 では発行するメッセージのペイロードの上限にも使われる
+    let max_packet_size = max_packet_size(config);
+    mqtt_options.set_max_packet_size(max_packet_size, max_packet_size);
+
+    // ユーザー名とパスワードが指定されていれば設定
+    if let Some(username) = &config.username {
+        let password = config.password.as_deref().unwrap_or("");
+        mqtt_options.set_credentials(username, password);
+    }
+
+    if config.connect_properties.is_some() {
+        eprintln!("警告: connect_properties は MQTT 5 (protocol_version: 5) でのみ有効です。MQTT 3.1.1 では無視します。");
+    }
+
+    // SSL/TLS 設定
+    if let Some(transport) = transport(config) {
+        mqtt_options.set_transport(transport);
+    }
+
+    mqtt_options
+}
+
+// 設定ファイルの内容から MQTT 5 用の MqttOptions を構築する
+pub fn build_v5_mqtt_options(config: &Config) -> v5::MqttOptions {
+    let (address, port) = broker_address(config);
+    let mut mqtt_options = v5::MqttOptions::new(&config.client_id, address, port);
+    mqtt_options.set_keep_alive(Duration::from_secs(KEEP_ALIVE_SECS));
+    // MQTT 5 では clean_session に相当するのは Clean Start
+    mqtt_options.set_clean_start(config.clean_session.unwrap_or(true));
+    mqtt_options.set_manual_acks(config.manual_ack.unwrap_or(false));
+    if let Some(properties) = &config.connect_properties {
+        let mut connect_properties = ConnectProperties::new();
+        connect_properties.session_expiry_interval = properties.session_expiry_interval;
+        connect_properties.receive_maximum = properties.receive_maximum;
+        connect_properties.max_packet_size = properties.maximum_packet_size;
+        connect_properties.request_response_info = properties.request_response_information.map(u8::from);
+        mqtt_options.set_connect_properties(connect_properties);
+    }
+    // 受信できる最大サイズをブローカーに通知し、それを超えるメッセージを送らないようにする。
+    // connect_properties.maximum_packet_size が指定されている場合はそちらを優先する
+    if mqtt_options.max_packet_size().is_none() {
+        mqtt_options.set_max_packet_size(Some(max_packet_size(config) as u32));
+    }
+
+    if let Some(username) = &config.username {
+        let password = config.password.as_deref().unwrap_or("");
+        mqtt_options.set_credentials(username, password);
+    }
+
+    if let Some(transport) = transport(config) {
+        mqtt_options.set_transport(transport);
+    }
+
+    mqtt_options
+}
+
+fn to_v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn from_v5_qos(qos: v5::mqttbytes::QoS) -> QoS {
+    match qos {
+        v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+        v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+        v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+impl MqttClient {
+    pub async fn subscribe(&self, topic: &str, qos: QoS, options: SubscribeOptions) -> Result<(), ClientError> {
+        match self {
+            MqttClient::V311(client) => {
+                if packet_dump_utils::enabled() {
+                    let mut subscribe = rumqttc::Subscribe::new(topic, qos);
+                    subscribe.pkid = DUMP_PACKET_ID;
+                    packet_dump_utils::subscribe(&subscribe);
+                }
+                client.subscribe(topic, qos).await.map_err(|e| ClientError::V311(Box::new(e)))
+            }
+            MqttClient::V5(client) => {
+                let mut filter = Filter::new(topic, to_v5_qos(qos));
+                if options.skip_retained {
+                    filter.retain_forward_rule = RetainForwardRule::Never;
+                }
+                if packet_dump_utils::enabled() {
+                    let mut subscribe = v5::mqttbytes::v5::Subscribe::new(filter.clone(), None);
+                    subscribe.pkid = DUMP_PACKET_ID;
+                    packet_dump_utils::subscribe_v5(&subscribe);
+                }
+                client.subscribe_many(vec![filter]).await.map_err(|e| ClientError::V5(Box::new(e)))
+            }
+        }
+    }
+
+    /// 複数のトピックフィルターを 1 つの SUBSCRIBE パケットで購読する
+    pub async fn subscribe_many(&self, filters: &[(String, QoS)], options: SubscribeOptions) -> Result<(), ClientError> {
+        match self {
+            MqttClient::V311(client) => {
+                let filters: Vec<SubscribeFilter> = filters.iter().map(|(topic, qos)| SubscribeFilter::new(topic.clone(), *qos)).collect();
+                if packet_dump_utils::enabled() {
+                    let mut subscribe = rumqttc::Subscribe::new_many(filters.clone());
+                    subscribe.pkid = DUMP_PACKET_ID;
+                    packet_dump_utils::subscribe(&subscribe);
+                }
+                client.subscribe_many(filters).await.map_err(|e| ClientError::V311(Box::new(e)))
+            }
+            MqttClient::V5(client) => {
+                let filters: Vec<Filter> = filters.iter().map(|(topic, qos)| {
+                    let mut filter = Filter::new(topic, to_v5_qos(*qos));
+                    if options.skip_retained {
+                        filter.retain_forward_rule = RetainForwardRule::Never;
+                    }
+                    filter
+                }).collect();
+                if packet_dump_utils::enabled() {
+                    let mut subscribe = v5::mqttbytes::v5::Subscribe::new_many(filters.clone(), None);
+                    subscribe.pkid = DUMP_PACKET_ID;
+                    packet_dump_utils::subscribe_v5(&subscribe);
+                }
+                client.subscribe_many(filters).await.map_err(|e| ClientError::V5(Box::new(e)))
+            }
+        }
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<(), ClientError> {
+        match self {
+            MqttClient::V311(client) => client.unsubscribe(topic).await.map_err(|e| ClientError::V311(Box::new(e))),
+            MqttClient::V5(client) => client.unsubscribe(topic).await.map_err(|e| ClientError::V5(Box::new(e))),
+        }
+    }
+
+    pub async fn publish(&self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), ClientError> {
+        self.dump_publish(topic, qos, retain, &payload);
+        match self {
+            MqttClient::V311(client) => client.publish(topic, qos, retain, payload).await.map_err(|e| ClientError::V311(Box::new(e))),
+            MqttClient::V5(client) => client.publish(topic, to_v5_qos(qos), retain, payload).await.map_err(|e| ClientError::V5(Box::new(e))),
+        }
+    }
+
+    /// チャネルに空きがない場合に待機せずエラーを返す publish。イベントループの処理中に使用する
+    pub fn try_publish(&self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), ClientError> {
+        self.dump_publish(topic, qos, retain, &payload);
+        match self {
+            MqttClient::V311(client) => client.try_publish(topic, qos, retain, payload).map_err(|e| ClientError::V311(Box::new(e))),
+            MqttClient::V5(client) => client.try_publish(topic, to_v5_qos(qos), retain, payload).map_err(|e| ClientError::V5(Box::new(e))),
+        }
+    }
+
+    // dump_packets が有効な場合に、発行する PUBLISH を表示する
+    fn dump_publish(&self, topic: &str, qos: QoS, retain: bool, payload: &[u8]) {
+        if !packet_dump_utils::enabled() {
+            return;
+        }
+        match self {
+            MqttClient::V311(_) => {
+                let mut publish = rumqttc::Publish::new(topic, qos, payload.to_vec());
+                publish.retain = retain;
+                publish.pkid = if qos == QoS::AtMostOnce { 0 } else { DUMP_PACKET_ID };
+                packet_dump_utils::publish(&publish);
+            }
+            MqttClient::V5(_) => {
+                let mut publish = v5::mqttbytes::v5::Publish::new(topic, to_v5_qos(qos), payload.to_vec(), None);
+                publish.retain = retain;
+                publish.pkid = if qos == QoS::AtMostOnce { 0 } else { DUMP_PACKET_ID };
+                packet_dump_utils::publish_v5(&publish);
+            }
+        }
+    }
+
+    /// 受信したメッセージの確認応答を送信する (manual_ack が有効な場合のみ使用する)
+    pub async fn ack(&self, message: &ReceivedMessage) -> Result<(), ClientError> {
+        // 確認応答の送信に必要なのは QoS とパケット ID のみ
+        match self {
+            MqttClient::V311(client) => {
+                let mut publish = rumqttc::Publish::new(message.topic.as_str(), message.qos, Vec::new());
+                publish.pkid = message.pkid;
+                client.ack(&publish).await.map_err(|e| ClientError::V311(Box::new(e)))
+            }
+            MqttClient::V5(client) => {
+                let mut publish = v5::mqttbytes::v5::Publish::new(message.topic.as_str(), to_v5_qos(message.qos), Bytes::new(), None);
+                publish.pkid = message.pkid;
+                client.ack(&publish).await.map_err(|e| ClientError::V5(Box::new(e)))
+            }
+        }
+    }
+
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        match self {
+            MqttClient::V311(client) => client.disconnect().await.map_err(|e| ClientError::V311(Box::new(e))),
+            MqttClient::V5(client) => client.disconnect().await.map_err(|e| ClientError::V5(Box::new(e))),
+        }
+    }
+}
+
+impl MqttEventLoop {
+    /// 次回の (再) 接続で使用するユーザー名とパスワードを設定する
+    pub fn set_credentials(&mut self, username: &str, password: &str) {
+        match &mut self.inner {
+            EventLoopKind::V311(eventloop) => { eventloop.mqtt_options.set_credentials(username, password); }
+            EventLoopKind::V5(eventloop) => { eventloop.options.set_credentials(username, password); }
+        }
+    }
+
+    /// 受信するパケットの最大サイズ
+    pub fn max_packet_size(&self) -> usize {
+        match &self.inner {
+            EventLoopKind::V311(eventloop) => eventloop.mqtt_options.max_packet_size(),
+            EventLoopKind::V5(eventloop) => eventloop.options.max_packet_size().map_or(DEFAULT_MAX_PACKET_SIZE, |size| size as usize),
+        }
+    }
+
+    /// 次回の (再) 接続から受信するパケットの最大サイズを変更する (MQTT 5 ではブローカーにも通知する)
+    pub fn set_max_packet_size(&mut self, size: usize) {
+        match &mut self.inner {
+            EventLoopKind::V311(eventloop) => { eventloop.mqtt_options.set_max_packet_size(size, size); }
+            EventLoopKind::V5(eventloop) => { eventloop.options.set_max_packet_size(Some(size as u32)); }
+        }
+    }
+
+    /// 現在の接続を破棄する。次の `poll` で再接続が行われる (確認応答を受け取っていないメッセージは再送される)
+    pub fn reconnect(&mut self) {
+        self.connecting = true;
+        match &mut self.inner {
+            EventLoopKind::V311(eventloop) => eventloop.clean(),
+            EventLoopKind::V5(eventloop) => eventloop.clean(),
+        }
+    }
+
+    /// 切断時に rumqttc が再送のために保持した、確認応答を受け取っていないリクエストを破棄し、破棄した件数を返す。
+    /// 送り直しを呼び出し側 (ForwardQueue など) が行う場合に、rumqttc の再送と重複しないようにする
+    pub fn discard_pending(&mut self) -> usize {
+        match &mut self.inner {
+            EventLoopKind::V311(eventloop) => eventloop.pending.drain(..).count(),
+            EventLoopKind::V5(eventloop) => eventloop.pending.drain(..).count(),
+        }
+    }
+
+    /// 確認応答を待っているか、受信した PUBACK / PUBREC をまだ `poll` で返していない QoS 1 / 2 の PUBLISH のパケット ID。
+    /// rumqttc はエラーで接続を破棄したとき (と `reconnect`) に、確認応答を待っているメッセージを再送の待ち行列に移すため、
+    /// その直後に呼ぶと、そのエラーの原因になったメッセージ (発行を拒否されたメッセージなど) 以外が分かる
+    pub fn unacked_publishes(&self) -> HashSet<u16> {
+        use v5::mqttbytes::v5::Packet as V5Packet;
+        match &self.inner {
+            EventLoopKind::V311(eventloop) => {
+                let pending = eventloop.pending.iter().filter_map(|request| match request {
+                    rumqttc::Request::Publish(publish) if publish.pkid != 0 => Some(publish.pkid),
+                    _ => None,
+                });
+                let buffered = eventloop.state.events.iter().filter_map(|event| match event {
+                    Event::Incoming(Packet::PubAck(ack)) => Some(ack.pkid),
+                    Event::Incoming(Packet::PubRec(rec)) => Some(rec.pkid),
+                    _ => None,
+                });
+                pending.chain(buffered).collect()
+            }
+            EventLoopKind::V5(eventloop) => {
+                let pending = eventloop.pending.iter().filter_map(|request| match request {
+                    v5::Request::Publish(publish) if publish.pkid != 0 => Some(publish.pkid),
+                    _ => None,
+                });
+                let buffered = eventloop.state.events.iter().filter_map(|event| match event {
+                    v5::Event::Incoming(V5Packet::PubAck(ack)) => Some(ack.pkid),
+                    v5::Event::Incoming(V5Packet::PubRec(rec)) => Some(rec.pkid),
+                    _ => None,
+                });
+                pending.chain(buffered).collect()
+            }
+        }
+    }
+
+    /// 受信した SUBACK のうち、まだ `poll` で返していないもののパケット ID。
+    /// rumqttc は拒否を含む SUBACK をエラーにして破棄するため、エラーの直後に呼ぶと、拒否された SUBSCRIBE より前に
+    /// 受信した SUBACK が分かる (ブローカーは SUBACK を SUBSCRIBE を受信した順に返す)
+    pub fn buffered_subacks(&self) -> HashSet<u16> {
+        use v5::mqttbytes::v5::Packet as V5Packet;
+        match &self.inner {
+            EventLoopKind::V311(eventloop) => eventloop.state.events.iter().filter_map(|event| match event {
+                Event::Incoming(Packet::SubAck(ack)) => Some(ack.pkid),
+                _ => None,
+            }).collect(),
+            EventLoopKind::V5(eventloop) => eventloop.state.events.iter().filter_map(|event| match event {
+                v5::Event::Incoming(V5Packet::SubAck(ack)) => Some(ack.pkid),
+                _ => None,
+            }).collect(),
+        }
+    }
+
+    /// 再送の待ち行列から、パケット ID が `pkid` の PUBLISH を取り除く (再接続しても再送しない)。取り除いた場合は true を返す。
+    /// rumqttc が確認応答を待っているメッセージは `reconnect` で再送の待ち行列に移してから取り除く
+    pub fn discard_pending_publish(&mut self, pkid: u16) -> bool {
+        match &mut self.inner {
+            EventLoopKind::V311(eventloop) => {
+                let len = eventloop.pending.len();
+                eventloop.pending.retain(|request| !matches!(request, rumqttc::Request::Publish(publish) if publish.pkid == pkid));
+                eventloop.pending.len() < len
+            }
+            EventLoopKind::V5(eventloop) => {
+                let len = eventloop.pending.len();
+                eventloop.pending.retain(|request| !matches!(request, v5::Request::Publish(publish) if publish.pkid == pkid));
+                eventloop.pending.len() < len
+            }
+        }
+    }
+
+    /// 次のイベントを待機する。接続されていない場合はこの中で (再) 接続が行われる。
+    pub async fn poll(&mut self) -> Result<MqttEvent, ConnectionError> {
+        if self.failover.as_ref().is_some_and(|failover| failover.failed) {
+            self.switch_broker();
+        }
+        if self.connecting {
+            match &mut self.inner {
+                EventLoopKind::V311(eventloop) => packet_dump_utils::connect(&eventloop.mqtt_options),
+                EventLoopKind::V5(eventloop) => {
+                    // rumqttc は CONNACK の Server Keep Alive で MqttOptions のキープアライブを書き換えるため、
+                    // 再接続の CONNECT では元の値を要求し直す
+                    eventloop.options.set_keep_alive(Duration::from_secs(KEEP_ALIVE_SECS));
+                    packet_dump_utils::connect_v5(&eventloop.options);
+                }
+            }
+        }
+        let result = self.poll_event().await;
+        match &result {
+            Ok(MqttEvent::ConnAck { properties, .. }) => {
+                self.connecting = false;
+                if let Some(socket) = &self.socket {
+                    socket.apply().await;
+                }
+                // Server Keep Alive: 0 (キープアライブなし) を rumqttc はそのまま PINGREQ の間隔に使い、PINGREQ を送り続けてしまう。
+                // ブローカーが無通信で切断しないことを示す値のため、要求した間隔で PINGREQ を送る
+                if let EventLoopKind::V5(eventloop) = &mut self.inner
+                    && properties.as_ref().and_then(|p| p.server_keep_alive) == Some(0) {
+                    eventloop.options.set_keep_alive(Duration::from_secs(KEEP_ALIVE_SECS));
+                }
+            }
+            // rumqttc はエラーを返すと接続を破棄し、次の poll で再接続する
+            Err(e) => {
+                // 接続できなかった場合は、次の poll で次の接続先に切り替えてから再接続する
+                if self.connecting && !e.is_requests_done()
+                    && let Some(failover) = &mut self.failover {
+                    failover.failed = true;
+                }
+                self.connecting = true;
+            }
+            _ => {}
+        }
+        result
+    }
+
+    // failover_brokers の次の接続先に切り替える。実行中に変更したユーザー名・パスワード (auth_token_url のトークン) と
+    // 受信するパケットの最大サイズは切り替え先にも引き継ぐ
+    fn switch_broker(&mut self) {
+        let Some(failover) = &mut self.failover else {
+            return;
+        };
+        failover.failed = false;
+        let next = (failover.current + 1) % failover.brokers.len();
+        let Some(options) = failover.brokers[next].options.take() else {
+            return;
+        };
+        let previous = match (&mut self.inner, options) {
+            (EventLoopKind::V311(eventloop), BrokerOptions::V311(mut options)) => {
+                if let Some((username, password)) = eventloop.mqtt_options.credentials() {
+                    options.set_credentials(username, password);
+                }
+                let size = eventloop.mqtt_options.max_packet_size();
+                options.set_max_packet_size(size, size);
+                BrokerOptions::V311(Box::new(std::mem::replace(&mut eventloop.mqtt_options, *options)))
+            }
+            (EventLoopKind::V5(eventloop), BrokerOptions::V5(mut options)) => {
+                if let Some((username, password)) = eventloop.options.credentials() {
+                    options.set_credentials(username, password);
+                }
+                options.set_max_packet_size(eventloop.options.max_packet_size());
+                BrokerOptions::V5(Box::new(std::mem::replace(&mut eventloop.options, *options)))
+            }
+            // 接続先の MqttOptions はすべて同じプロトコルバージョンで構築している
+            (_, options) => {
+                failover.brokers[next].options = Some(options);
+                return;
+            }
+        };
+        let current = failover.current;
+        failover.brokers[current].options = Some(previous);
+        failover.brokers[current].socket = std::mem::replace(&mut self.socket, failover.brokers[next].socket.take());
+        failover.current = next;
+        crate::status!("ブローカー {} に接続できなかったため、{} に切り替えて接続します。", failover.brokers[current].name, failover.brokers[next].name);
+    }
+
+    async fn poll_event(&mut self) -> Result<MqttEvent, ConnectionError> {
+        match &mut self.inner {
+            EventLoopKind::V311(eventloop) => {
+                let event = eventloop.poll().await.map_err(ConnectionError::V311)?;
+                Ok(match event {
+                    Event::Incoming(Packet::ConnAck(ack)) => MqttEvent::ConnAck { session_present: ack.session_present, properties: None },
+                    Event::Incoming(Packet::Publish(p)) => MqttEvent::Publish(ReceivedMessage {
+                        topic: p.topic,
+                        payload: p.payload,
+                        qos: p.qos,
+                        retain: p.retain,
+                        pkid: p.pkid,
+                        properties: None,
+                        acker: None,
+                    }),
+                    Event::Incoming(Packet::SubAck(ack)) => MqttEvent::SubAck {
+                        pkid: ack.pkid,
+                        granted: ack.return_codes.iter().map(|c| match c {
+                            SubscribeReasonCode::Success(qos) => Some(*qos),
+                            SubscribeReasonCode::Failure => None,
+                        }).collect(),
+                    },
+                    Event::Incoming(Packet::PubAck(ack)) => MqttEvent::PubAck(ack.pkid),
+                    Event::Incoming(Packet::PubRec(rec)) => MqttEvent::PubRec(rec.pkid),
+                    Event::Incoming(Packet::PubComp(comp)) => MqttEvent::PubComp(comp.pkid),
+                    Event::Incoming(Packet::PingResp) => MqttEvent::PingResp,
+                    Event::Outgoing(outgoing) => MqttEvent::Outgoing(outgoing),
+                    _ => MqttEvent::Other,
+                })
+            }
+            EventLoopKind::V5(eventloop) => {
+                use v5::mqttbytes::v5::Packet as V5Packet;
+                let event = match eventloop.poll().await {
+                    Ok(event) => event,
+                    Err(e @ v5::ConnectionError::MqttState(v5::StateError::IncomingPacketTooLarge { .. })) => {
+                        // rumqttc はプロトコルエラーの DISCONNECT を送信せずに書き込みバッファに残し、送信したイベントも残す。
+                        // 再接続後の接続に DISCONNECT が送られ、利用者が切断を要求したとも扱われるため、どちらも取り除く
+                        eventloop.state.write.clear();
+                        eventloop.state.events.retain(|event| !matches!(event, v5::Event::Outgoing(Outgoing::Disconnect)));
+                        return Err(ConnectionError::V5(e));
+                    }
+                    // rumqttc はブローカーからの DISCONNECT を接続エラーとして返す (イベントにはならない)。
+                    // 接続は破棄済みのため、次の poll で再接続する
+                    Err(v5::ConnectionError::MqttState(v5::StateError::ServerDisconnect { reason_code, reason_string })) => {
+                        self.connecting = true;
+                        return Ok(MqttEvent::Disconnect(DisconnectReason { code: reason_code, reason_string }));
+                    }
+                    Err(e) => return Err(ConnectionError::V5(e)),
+                };
+                Ok(match event {
+                    v5::Event::Incoming(V5Packet::ConnAck(ack)) => MqttEvent::ConnAck {
+                        session_present: ack.session_present,
+                        properties: ack.properties.map(Box::new),
+                    },
+                    v5::Event::Incoming(V5Packet::Publish(p)) => MqttEvent::Publish(ReceivedMessage {
+                        topic: String::from_utf8_lossy(&p.topic).into_owned(),
+                        payload: p.payload,
+                        qos: from_v5_qos(p.qos),
+                        retain: p.retain,
+                        pkid: p.pkid,
+                        properties: p.properties.map(Box::new),
+                        acker: None,
+                    }),
+                    v5::Event::Incoming(V5Packet::SubAck(ack)) => MqttEvent::SubAck {
+                        pkid: ack.pkid,
+                        granted: ack.return_codes.iter().map(|c| match c {
+                            v5::mqttbytes::v5::SubscribeReasonCode::Success(qos) => Some(from_v5_qos(*qos)),
+                            _ => None,
+                        }).collect(),
+                    },
+                    v5::Event::Incoming(V5Packet::PubAck(ack)) => MqttEvent::PubAck(ack.pkid),
+                    v5::Event::Incoming(V5Packet::PubRec(rec)) => MqttEvent::PubRec(rec.pkid),
+                    v5::Event::Incoming(V5Packet::PubComp(comp)) => MqttEvent::PubComp(comp.pkid),
+                    v5::Event::Incoming(V5Packet::PingResp(_)) => MqttEvent::PingResp,
+                    v5::Event::Incoming(V5Packet::Disconnect(disconnect)) => MqttEvent::Disconnect(DisconnectReason {
+                        code: disconnect.reason_code,
+                        reason_string: disconnect.properties.and_then(|p| p.reason_string),
+                    }),
+                    v5::Event::Outgoing(outgoing) => MqttEvent::Outgoing(outgoing),
+                    _ => MqttEvent::Other,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_keep_alive_rejections_are_counted_until_accepted() {
+        let config: Config = serde_yaml::from_str("client_id: x\nbroker_address: localhost\nbroker_port: 1883\n\
+            min_server_keep_alive_secs: 10\nmin_server_keep_alive_action: reconnect\n").unwrap();
+        let connack = |server_keep_alive| ConnAckProperties { server_keep_alive: Some(server_keep_alive), ..super::super::output_utils::empty_connack_properties() };
+        let mut rejections = 0;
+        assert!(!check_server_keep_alive(&config, Some(&connack(5)), &mut rejections));
+        assert!(!check_server_keep_alive(&config, Some(&connack(5)), &mut rejections));
+        assert_eq!(rejections, 2);
+        assert!(check_server_keep_alive(&config, Some(&connack(10)), &mut rejections));
+        assert_eq!(rejections, 0);
+        assert!(!check_server_keep_alive(&config, Some(&connack(5)), &mut rejections));
+        // キープアライブを無効にした場合と、Server Keep Alive がない場合は受け入れる
+        assert!(check_server_keep_alive(&config, Some(&connack(0)), &mut rejections));
+        assert_eq!(rejections, 0);
+        assert!(check_server_keep_alive(&config, None, &mut rejections));
+    }
+
+    #[test]
+    fn requests_done_is_not_a_reconnectable_error() {
+        for e in [ConnectionError::V311(rumqttc::ConnectionError::RequestsDone), ConnectionError::V5(v5::ConnectionError::RequestsDone)] {
+            assert!(e.is_requests_done());
+            assert!(e.connect_refusal().is_none());
+            assert!(e.rejection().is_none());
+            assert!(e.oversized_packet().is_none());
+            assert!(matches!(e.to_app_error(e.to_string()), AppError::Unavailable(_)));
+        }
+    }
+
+    #[test]
+    fn network_errors_are_not_requests_done() {
+        let io = || std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "接続が拒否されました");
+        assert!(!ConnectionError::V311(rumqttc::ConnectionError::Io(io())).is_requests_done());
+        assert!(!ConnectionError::V5(v5::ConnectionError::Io(io())).is_requests_done());
+    }
+
+    #[test]
+    fn discarded_publishes_are_not_resent() {
+        let config: Config = serde_yaml::from_str("client_id: x\nbroker_address: localhost\nbroker_port: 1883\n").unwrap();
+        let (_client, mut eventloop) = create_client(&config, 10);
+        let EventLoopKind::V311(inner) = &mut eventloop.inner else { unreachable!() };
+        for pkid in [0, 1, 2] {
+            let mut publish = rumqttc::Publish::new("t", QoS::ExactlyOnce, Vec::new());
+            publish.pkid = pkid;
+            inner.pending.push_back(rumqttc::Request::Publish(publish));
+        }
+        inner.state.events.push_back(Event::Incoming(Packet::PubAck(rumqttc::PubAck::new(3))));
+        // パケット ID 0 はまだ送信していない発行の要求
+        assert_eq!(eventloop.unacked_publishes(), HashSet::from([1, 2, 3]));
+        assert!(eventloop.discard_pending_publish(2));
+        assert!(!eventloop.discard_pending_publish(2));
+        assert_eq!(eventloop.unacked_publishes(), HashSet::from([1, 3]));
+    }
+}
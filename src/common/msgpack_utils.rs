@@ -0,0 +1,96 @@
+// MessagePack のペイロードのデコード (payload_format: msgpack、msgpack フィーチャー)
+//
+// JSON で表せない値は次のように変換する: バイナリは Base64 の文字列、文字列以外のマップのキーは JSON に変換した文字列、
+// 拡張型は {"ext": 型番号, "data": Base64 の文字列}、UTF-8 として正しくない文字列は Base64 の文字列、NaN と無限大は null。
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rmp_serde::decode::Error as DecodeError;
+use rmpv::Value as MsgpackValue;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::io::Cursor;
+
+/// MessagePack のペイロードを JSON の値に変換する。MessagePack の値 1 つとして読めない場合 (後ろに余分なデータがある場合を含む) はエラーを返す
+pub fn decode(payload: &[u8]) -> Result<Value, String> {
+    let mut deserializer = rmp_serde::Deserializer::new(Cursor::new(payload));
+    let value = MsgpackValue::deserialize(&mut deserializer).map_err(|e| match e {
+        DecodeError::InvalidMarkerRead(_) | DecodeError::InvalidDataRead(_) => "MessagePack の値の途中でデータが終わっています".to_string(),
+        DecodeError::DepthLimitExceeded => "MessagePack の値の入れ子が深すぎます".to_string(),
+        other => other.to_string(),
+    })?;
+    let consumed = deserializer.get_ref().position() as usize;
+    if consumed != payload.len() {
+        return Err(format!("MessagePack の値の後ろに {} バイトの余分なデータがあります", payload.len() - consumed));
+    }
+    Ok(to_json(value))
+}
+
+fn to_json(value: MsgpackValue) -> Value {
+    match value {
+        MsgpackValue::Nil => Value::Null,
+        MsgpackValue::Boolean(boolean) => boolean.into(),
+        MsgpackValue::Integer(integer) => match (integer.as_i64(), integer.as_u64()) {
+            (Some(signed), _) => signed.into(),
+            (_, Some(unsigned)) => unsigned.into(),
+            _ => Value::Null,
+        },
+        MsgpackValue::F32(float) => serde_json::Number::from_f64(float.into()).map_or(Value::Null, Value::Number),
+        MsgpackValue::F64(float) => serde_json::Number::from_f64(float).map_or(Value::Null, Value::Number),
+        MsgpackValue::String(text) if text.is_str() => text.into_str().unwrap_or_default().into(),
+        MsgpackValue::String(text) => BASE64.encode(text.into_bytes()).into(),
+        MsgpackValue::Binary(bytes) => BASE64.encode(bytes).into(),
+        MsgpackValue::Array(items) => items.into_iter().map(to_json).collect(),
+        MsgpackValue::Map(entries) => {
+            let mut object = Map::new();
+            for (key, value) in entries {
+                let key = match key {
+                    MsgpackValue::String(text) if text.is_str() => text.into_str().unwrap_or_default(),
+                    other => to_json(other).to_string(),
+                };
+                object.insert(key, to_json(value));
+            }
+            Value::Object(object)
+        }
+        MsgpackValue::Ext(kind, data) => serde_json::json!({ "ext": kind, "data": BASE64.encode(data) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encode(value: &MsgpackValue) -> Vec<u8> {
+        let mut payload = Vec::new();
+        rmpv::encode::write_value(&mut payload, value).unwrap();
+        payload
+    }
+
+    #[test]
+    fn converts_values_that_json_cannot_represent() {
+        let payload = encode(&MsgpackValue::Map(vec![
+            (MsgpackValue::from("temperature"), MsgpackValue::F64(21.5)),
+            (MsgpackValue::from(1), MsgpackValue::Binary(vec![1, 2, 3])),
+            (MsgpackValue::from("ext"), MsgpackValue::Ext(5, vec![0xff])),
+            (MsgpackValue::from("max"), MsgpackValue::from(u64::MAX)),
+            (MsgpackValue::from("nan"), MsgpackValue::F32(f32::NAN)),
+            (MsgpackValue::from("items"), MsgpackValue::Array(vec![MsgpackValue::Boolean(false), MsgpackValue::Nil])),
+        ]));
+        assert_eq!(decode(&payload).unwrap(), json!({
+            "temperature": 21.5,
+            "1": "AQID",
+            "ext": {"ext": 5, "data": "/w=="},
+            "max": u64::MAX,
+            "nan": null,
+            "items": [false, null],
+        }));
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_data() {
+        let payload = encode(&MsgpackValue::from("hello"));
+        assert!(decode(&payload[..payload.len() - 1]).is_err());
+        let mut trailing = payload.clone();
+        trailing.push(0xc0);
+        assert_eq!(decode(&trailing).unwrap_err(), "MessagePack の値の後ろに 1 バイトの余分なデータがあります");
+    }
+}
@@ -0,0 +1,658 @@
+// 受信メッセージのコンソール出力用の整形処理
+use super::config_utils::{Config, ConnectPropertiesConfig, OutputFormat, PayloadEncoding, PayloadFormat, RawDelimiter, WrapWidth};
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::{ProtocolVersion, ReceivedMessage};
+use super::qos_utils::qos_number;
+use super::template_utils::Template;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rumqttc::v5::mqttbytes::v5::ConnAckProperties;
+use std::{borrow::Cow, fmt, io::{IsTerminal, Write}, sync::{atomic::{AtomicBool, Ordering}, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+/// CSV 出力 (output_format: csv) のヘッダー行
+pub const CSV_HEADER: &str = "timestamp,topic,qos,retain,payload_len,payload";
+
+// 状態メッセージを標準エラー出力に出すか (JSON / CSV の出力に混ざらないようにする)
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// 接続状況などの状態メッセージの出力先を標準エラー出力に切り替える
+pub fn set_status_to_stderr(enabled: bool) {
+    STATUS_TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+// 詳細を表示するか (コマンドラインの --verbose)
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// 詳細の表示 (--verbose) を有効にする
+pub fn set_verbose(enabled: bool) {
+    VERBOSE.store(enabled, Ordering::Relaxed);
+}
+
+/// 詳細を表示するか
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// 状態メッセージを出力する。`set_status_to_stderr(true)` の場合は標準エラー出力に出す。
+/// 標準出力に書き込めない場合は無視する (閉じられたことはメッセージの出力で検出する)
+pub fn print_status(args: fmt::Arguments) {
+    if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", args);
+    } else {
+        let _ = writeln!(std::io::stdout().lock(), "{}", args);
+    }
+}
+
+// output_format: json-array で終了時に出力するまで保持する、受信したメッセージの JSON (すべてのセッションで共有する)
+static JSON_ARRAY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// output_format: json-array の場合に、終了時に出力する配列にメッセージ (format_message の出力) を追加する
+pub fn push_json_array(output: String) {
+    JSON_ARRAY.lock().unwrap().push(output);
+}
+
+/// 保持しているメッセージを 1 つの JSON の配列として標準出力に出力する (`pretty` の場合は字下げする)
+pub fn print_json_array(pretty: bool) {
+    let messages = std::mem::take(&mut *JSON_ARRAY.lock().unwrap());
+    if !pretty {
+        println!("[{}]", messages.join(","));
+        return;
+    }
+    let values: Vec<serde_json::Value> = messages.iter()
+        .map(|message| serde_json::from_str(message).expect("format_json の出力は JSON"))
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&values).expect("JSON の値は文字列に変換できる"));
+}
+
+/// 状態メッセージを `println!` と同じ書式で出力する (出力先は `output_utils::print_status` を参照)
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::common::output_utils::print_status(format_args!($($arg)*))
+    };
+}
+
+/// ペイロードを表示用の文字列に変換する。
+///
+/// `preview_bytes` が指定されていてペイロードがそれより長い場合は、先頭 N バイトのみを表示し、
+/// 末尾に全体のバイト数を付加する。
+pub fn format_payload(payload: &[u8], preview_bytes: Option<usize>) -> String {
+    match preview_bytes {
+        Some(limit) if payload.len() > limit => {
+            format!("{}… ({} total bytes)", String::from_utf8_lossy(&payload[..limit]), payload.len())
+        }
+        _ => String::from_utf8_lossy(payload).into_owned(),
+    }
+}
+
+/// 相関データなどのバイナリ値を表示用の文字列に変換する。
+///
+/// UTF-8 として正しい場合はそのまま、それ以外は 16 進数 (`0x...`) で表示する。
+pub fn format_binary(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(s) => s.to_string(),
+        Err(_) => {
+            let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}", hex)
+        }
+    }
+}
+
+/// payload_format (format_rules の payload_format を含む) に対応するフィーチャーを有効にしてビルドされているか確認する (されていない場合は終了する)
+pub fn check_payload_format(config: &Config) {
+    let rules = config.format_rules.iter().flatten().filter_map(|rule| rule.payload_format);
+    for format in config.payload_format.into_iter().chain(rules) {
+        if format == PayloadFormat::SparkplugB && !cfg!(feature = "sparkplug") {
+            exit_with(AppError::Config("payload_format: sparkplug_b を使用するには sparkplug フィーチャーを有効にしてビルドしてください (cargo build --features sparkplug)。".to_string()));
+        }
+        if format == PayloadFormat::Cbor && !cfg!(feature = "cbor") {
+            exit_with(AppError::Config("payload_format: cbor を使用するには cbor フィーチャーを有効にしてビルドしてください (cargo build --features cbor)。".to_string()));
+        }
+        if format == PayloadFormat::Msgpack && !cfg!(feature = "msgpack") {
+            exit_with(AppError::Config("payload_format: msgpack を使用するには msgpack フィーチャーを有効にしてビルドしてください (cargo build --features msgpack)。".to_string()));
+        }
+    }
+}
+
+/// output_template を解析する (不正な場合は設定エラーで終了する)。output_format: text 以外の場合は警告を表示して `None` を返す
+pub fn output_template(config: &Config) -> Option<Template> {
+    let template = config.output_template.as_deref()?;
+    let template = Template::parse(template)
+        .unwrap_or_else(|e| exit_with(AppError::Config(format!("output_template が不正です: {}", e))));
+    if config.output_format.unwrap_or_default() != OutputFormat::Text {
+        eprintln!("警告: output_template は output_format: text の場合のみ使用されます。");
+        return None;
+    }
+    Some(template)
+}
+
+/// payload_format のペイロードをデコードして JSON の値に変換するデコーダ。
+///
+/// フィーチャーを有効にしてビルドした形式だけを実装する (無効な形式は `check_payload_format` で起動時に終了する)。
+pub trait PayloadDecoder: Sync {
+    /// 警告に表示する形式の名前
+    fn name(&self) -> &'static str;
+
+    /// デコードするメッセージか。既定では空のペイロード (retain メッセージの削除など) はデコードしない
+    fn applies_to(&self, message: &ReceivedMessage) -> bool {
+        !message.payload.is_empty()
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<serde_json::Value, String>;
+}
+
+#[cfg(feature = "sparkplug")]
+struct SparkplugDecoder;
+
+#[cfg(feature = "sparkplug")]
+impl PayloadDecoder for SparkplugDecoder {
+    fn name(&self) -> &'static str {
+        "Sparkplug B"
+    }
+
+    // Sparkplug B のトピック以外 (STATE トピックを含む) のメッセージはそのまま表示する
+    fn applies_to(&self, message: &ReceivedMessage) -> bool {
+        super::sparkplug_utils::is_sparkplug_topic(&message.topic)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<serde_json::Value, String> {
+        super::sparkplug_utils::decode(payload)
+    }
+}
+
+#[cfg(feature = "cbor")]
+struct CborDecoder;
+
+#[cfg(feature = "cbor")]
+impl PayloadDecoder for CborDecoder {
+    fn name(&self) -> &'static str {
+        "CBOR"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<serde_json::Value, String> {
+        super::cbor_utils::decode(payload)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+struct MsgpackDecoder;
+
+#[cfg(feature = "msgpack")]
+impl PayloadDecoder for MsgpackDecoder {
+    fn name(&self) -> &'static str {
+        "MessagePack"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<serde_json::Value, String> {
+        super::msgpack_utils::decode(payload)
+    }
+}
+
+/// `format` のデコーダ (payload_format: raw と、フィーチャーを有効にせずにビルドした形式は `None`)
+pub fn payload_decoder(format: PayloadFormat) -> Option<&'static dyn PayloadDecoder> {
+    match format {
+        #[cfg(feature = "sparkplug")]
+        PayloadFormat::SparkplugB => Some(&SparkplugDecoder),
+        #[cfg(feature = "cbor")]
+        PayloadFormat::Cbor => Some(&CborDecoder),
+        #[cfg(feature = "msgpack")]
+        PayloadFormat::Msgpack => Some(&MsgpackDecoder),
+        _ => None,
+    }
+}
+
+/// payload_format に従って、表示するメッセージのペイロードをデコードする。
+/// デコードできない場合は警告を表示し、受信したままのペイロードを表示する
+pub fn decode_payload(message: ReceivedMessage, config: &Config) -> ReceivedMessage {
+    decode_payload_as(message, config.payload_format.unwrap_or_default())
+}
+
+/// `format` に従って、表示するメッセージのペイロードをデコードする (format_rules でトピックごとに指定した場合)
+pub fn decode_payload_as(mut message: ReceivedMessage, format: PayloadFormat) -> ReceivedMessage {
+    let Some(decoder) = payload_decoder(format).filter(|decoder| decoder.applies_to(&message)) else {
+        return message;
+    };
+    match decoder.decode(&message.payload) {
+        Ok(decoded) => message.payload = decoded.to_string().into(),
+        Err(e) => eprintln!("警告: トピック '{}' のペイロードを {} としてデコードできませんでした: {}", status_topic(&message.topic), decoder.name(), e),
+    }
+    message
+}
+
+/// 受信メッセージを output_format に従って 1 件分の出力に変換する (末尾の改行は含まない)。
+/// `template` は output_template を `output_template` で解析したもの
+pub fn format_message(message: &ReceivedMessage, config: &Config, template: Option<&Template>) -> String {
+    format_message_at(message, config, template, SystemTime::now())
+}
+
+/// `format_message` と同じだが、出力の時刻 (timestamp) を受信した時刻 `time` にする (記録の再生用)
+pub fn format_message_at(message: &ReceivedMessage, config: &Config, template: Option<&Template>, time: SystemTime) -> String {
+    match config.output_format.unwrap_or_default() {
+        OutputFormat::Text => match template {
+            Some(template) => template.render(message, config, time),
+            None => format_text(message, config.payload_preview_bytes),
+        },
+        OutputFormat::Json | OutputFormat::JsonArray => format_json_at(message, time),
+        OutputFormat::Csv => format_csv(message, config.csv_payload_encoding.unwrap_or_default(), time),
+        // 標準出力にはバイト列のまま書き込む (write_raw)。stream_port への配信などには UTF-8 として読めない部分を置換して渡す
+        OutputFormat::Raw => String::from_utf8_lossy(&message.payload).into_owned(),
+    }
+}
+
+/// output_format: raw の場合に、ペイロードを受信したまま raw_delimiter の区切り (未指定の場合は改行) を付けて標準出力に書き込む
+pub fn write_raw(payload: &[u8], delimiter: Option<&RawDelimiter>) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    write_delimited(&mut stdout, payload, delimiter.unwrap_or(&RawDelimiter::Newline))?;
+    // 改行で終わらない区切りでも、パイプの先のプロセスにメッセージごとに届くようにする
+    stdout.flush()
+}
+
+/// ペイロードを `delimiter` の区切り (length-prefix の場合は前に付ける長さ) とともに書き込む
+pub fn write_delimited(writer: &mut impl Write, payload: &[u8], delimiter: &RawDelimiter) -> std::io::Result<()> {
+    match delimiter {
+        RawDelimiter::Newline => writer.write_all(payload).and_then(|_| writer.write_all(b"\n")),
+        RawDelimiter::Null => writer.write_all(payload).and_then(|_| writer.write_all(b"\0")),
+        RawDelimiter::LengthPrefix => writer.write_all(&(payload.len() as u32).to_be_bytes()).and_then(|_| writer.write_all(payload)),
+        RawDelimiter::Custom(bytes) => writer.write_all(payload).and_then(|_| writer.write_all(bytes)),
+    }
+}
+
+/// retain フラグ付きの空のペイロード (ブローカーに保持されている retain メッセージの削除) かを判定する
+pub fn is_retained_delete(message: &ReceivedMessage) -> bool {
+    message.retain && message.payload.is_empty()
+}
+
+/// 受信メッセージをコンソール (およびログファイル) に出力する複数行の文字列に変換する
+pub fn format_text(message: &ReceivedMessage, preview_bytes: Option<usize>) -> String {
+    if is_retained_delete(message) {
+        return format!("トピック {} の retain メッセージの削除 (QoS: {:?})", message.topic, message.qos);
+    }
+    let mut lines = vec![
+        format!("トピック: {}", message.topic),
+        format!("ペイロード: {}", format_payload(&message.payload, preview_bytes)),
+        format!("QoS: {:?}", message.qos),
+    ];
+    // MQTT 5 のリクエスト/レスポンス用のプロパティ (応答先の確認や手動での応答に使う)
+    if let Some(properties) = &message.properties {
+        if let Some(response_topic) = &properties.response_topic {
+            lines.push(format!("レスポンストピック: {}", response_topic));
+        }
+        if let Some(correlation_data) = &properties.correlation_data {
+            lines.push(format!("相関データ: {}", format_binary(correlation_data)));
+        }
+    }
+    lines.join("\n")
+}
+
+/// 1 行 1 オブジェクトの JSON (JSON Lines) に変換する。payload_preview_bytes は適用しない
+pub fn format_json(message: &ReceivedMessage) -> String {
+    format_json_at(message, SystemTime::now())
+}
+
+fn format_json_at(message: &ReceivedMessage, time: SystemTime) -> String {
+    let mut object = serde_json::json!({
+        "timestamp": format_timestamp(time),
+        "topic": message.topic,
+        "qos": qos_number(message.qos),
+        "retain": message.retain,
+        "payload_len": message.payload.len(),
+        "payload": String::from_utf8_lossy(&message.payload),
+    });
+    if is_retained_delete(message) {
+        object["retained_delete"] = true.into();
+    }
+    if let Some(properties) = &message.properties {
+        if let Some(response_topic) = &properties.response_topic {
+            object["response_topic"] = response_topic.clone().into();
+        }
+        if let Some(correlation_data) = &properties.correlation_data {
+            object["correlation_data"] = format_binary(correlation_data).into();
+        }
+    }
+    object.to_string()
+}
+
+// CSV_HEADER の列順の 1 行に変換する。payload_preview_bytes は適用しない
+fn format_csv(message: &ReceivedMessage, encoding: PayloadEncoding, time: SystemTime) -> String {
+    let payload = match encoding {
+        PayloadEncoding::Text => String::from_utf8_lossy(&message.payload).into_owned(),
+        PayloadEncoding::Base64 => BASE64.encode(&message.payload),
+    };
+    [
+        format_timestamp(time),
+        csv_field(&message.topic),
+        qos_number(message.qos).to_string(),
+        message.retain.to_string(),
+        message.payload.len().to_string(),
+        csv_field(&payload),
+    ].join(",")
+}
+
+/// CSV のフィールドを必要に応じて引用符で囲む (RFC 4180)
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// ブローカーが CONNACK で通知した機能と制限 (MQTT 5 の CONNACK プロパティ) を表形式で表示する。
+///
+/// プロパティが省略されている項目は、仕様で定められた既定値を表示する。
+pub fn print_capabilities(version: ProtocolVersion, properties: Option<&ConnAckProperties>) {
+    if version == ProtocolVersion::V311 {
+        crate::status!("MQTT 3.1.1 の CONNACK にはブローカーの機能の情報が含まれません (protocol_version: 5 で接続すると表示できます)。");
+        return;
+    }
+    let empty = empty_connack_properties();
+    let p = properties.unwrap_or(&empty);
+    // 省略された場合の既定値を付けて表示する
+    let or_default = |value: Option<String>, default: &str| value.unwrap_or_else(|| format!("{} (既定値)", default));
+    let flag = |value: Option<u8>| or_default(value.map(|v| if v == 0 { "なし".to_string() } else { "あり".to_string() }), "あり");
+    let text = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+
+    let mut rows = vec![
+        ("最大 QoS (Maximum QoS)", or_default(p.max_qos.map(|v| v.to_string()), "2")),
+        ("retain メッセージ (Retain Available)", flag(p.retain_available)),
+        ("ワイルドカード購読 (Wildcard Subscription Available)", flag(p.wildcard_subscription_available)),
+        ("購読 ID (Subscription Identifiers Available)", flag(p.subscription_identifiers_available)),
+        ("共有購読 (Shared Subscription Available)", flag(p.shared_subscription_available)),
+        ("最大パケットサイズ (Maximum Packet Size)", or_default(p.max_packet_size.map(|v| format!("{} バイト", v)), "制限なし")),
+        ("受信最大数 (Receive Maximum)", or_default(p.receive_max.map(|v| v.to_string()), "65535")),
+        ("トピックエイリアス最大数 (Topic Alias Maximum)", or_default(p.topic_alias_max.map(|v| v.to_string()), "0")),
+        ("サーバーのキープアライブ (Server Keep Alive)", or_default(p.server_keep_alive.map(|v| format!("{} 秒", v)), "クライアントの指定どおり")),
+        ("セッション有効期間 (Session Expiry Interval)", or_default(p.session_expiry_interval.map(|v| format!("{} 秒", v)), "クライアントの指定どおり")),
+        ("割り当てられたクライアント ID (Assigned Client Identifier)", text(&p.assigned_client_identifier)),
+        ("レスポンス情報 (Response Information)", text(&p.response_information)),
+        ("サーバー参照 (Server Reference)", text(&p.server_reference)),
+        ("認証方式 (Authentication Method)", text(&p.authentication_method)),
+        ("理由 (Reason String)", text(&p.reason_string)),
+    ];
+    for (key, value) in &p.user_properties {
+        rows.push(("ユーザープロパティ", format!("{} = {}", key, value)));
+    }
+    print_table("ブローカーの機能 (CONNACK プロパティ):", &rows);
+}
+
+/// connect_properties で CONNECT に指定したプロパティと、ブローカーが CONNACK で通知した対応する値を並べて表示する
+pub fn print_connect_properties(requested: &ConnectPropertiesConfig, properties: Option<&ConnAckProperties>) {
+    let empty = empty_connack_properties();
+    let p = properties.unwrap_or(&empty);
+    let mut rows = Vec::new();
+    if let Some(interval) = requested.session_expiry_interval {
+        // ブローカーが別の値を使う場合のみ CONNACK に含まれる
+        let granted = p.session_expiry_interval.unwrap_or(interval);
+        rows.push(("セッション有効期間 (Session Expiry Interval)", format!("要求 {} 秒 / 適用 {} 秒", interval, granted)));
+    }
+    if let Some(maximum) = requested.receive_maximum {
+        // CONNACK の Receive Maximum はブローカーが受け付ける数 (クライアントから送信する方向の上限)
+        rows.push(("受信最大数 (Receive Maximum)", format!("要求 {} / ブローカー側 {}", maximum,
+            p.receive_max.map_or("65535 (既定値)".to_string(), |v| v.to_string()))));
+    }
+    if let Some(size) = requested.maximum_packet_size {
+        rows.push(("最大パケットサイズ (Maximum Packet Size)", format!("要求 {} バイト / ブローカー側 {}", size,
+            p.max_packet_size.map_or("制限なし (既定値)".to_string(), |v| format!("{} バイト", v)))));
+    }
+    if let Some(request) = requested.request_response_information {
+        let response = match (&p.response_information, request) {
+            (Some(info), _) => info.clone(),
+            (None, true) => "ブローカーは提供しませんでした".to_string(),
+            (None, false) => "-".to_string(),
+        };
+        rows.push(("レスポンス情報 (Request / Response Information)", format!("要求 {} / 応答 {}", if request { "あり" } else { "なし" }, response)));
+    }
+    if !rows.is_empty() {
+        print_table("CONNECT プロパティ (要求した値とブローカーの応答):", &rows);
+    }
+}
+
+fn print_table(title: &str, rows: &[(&str, String)]) {
+    let width = rows.iter().map(|(name, _)| display_width(name)).max().unwrap_or(0);
+    crate::status!("{}", title);
+    for (name, value) in rows {
+        crate::status!("  {}{}  {}", name, " ".repeat(width - display_width(name)), value);
+    }
+}
+
+/// すべての項目が省略された CONNACK のプロパティ (プロパティがない場合はこれとして扱う)
+pub fn empty_connack_properties() -> ConnAckProperties {
+    ConnAckProperties {
+        session_expiry_interval: None,
+        receive_max: None,
+        max_qos: None,
+        retain_available: None,
+        max_packet_size: None,
+        assigned_client_identifier: None,
+        topic_alias_max: None,
+        reason_string: None,
+        user_properties: Vec::new(),
+        wildcard_subscription_available: None,
+        subscription_identifiers_available: None,
+        shared_subscription_available: None,
+        server_keep_alive: None,
+        response_information: None,
+        server_reference: None,
+        authentication_method: None,
+        authentication_data: None,
+    }
+}
+
+// auto で端末の幅が取得できない場合の折り返し幅
+const DEFAULT_TERMINAL_COLUMNS: usize = 80;
+
+/// 表示するトピック名をエスケープするか (escape_topics。未指定の場合は標準出力が端末かどうかで決める)
+pub fn escape_topics(config: &Config) -> bool {
+    config.escape_topics.unwrap_or_else(|| std::io::stdout().is_terminal())
+}
+
+/// トピック名の制御文字 (エスケープシーケンスの ESC を含む)、文字の向きを変える書式文字、'%' を UTF-8 のバイトごとに
+/// パーセントエンコードする (悪意のあるトピック名で端末の表示が書き換えられないようにする)。エンコードする文字がなければそのまま返す
+pub fn escape_topic(topic: &str) -> Cow<'_, str> {
+    let needs_escape = |c: char| c == '%' || c.is_control() || matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}');
+    if !topic.contains(needs_escape) {
+        return Cow::Borrowed(topic);
+    }
+    let mut escaped = String::with_capacity(topic.len());
+    for c in topic.chars() {
+        if needs_escape(c) {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// 警告や状態の表示に含めるトピック名をエスケープするか (設定を読み込む前はエスケープする)
+static ESCAPE_STATUS_TOPICS: AtomicBool = AtomicBool::new(true);
+
+/// 警告や状態の表示 (`status!` / `eprintln!`) に含める受信したトピック名をエスケープするかを設定する
+/// (escape_topics。未指定の場合は標準エラー出力が端末かどうかで決める)
+pub fn configure_status_topics(config: &Config) {
+    ESCAPE_STATUS_TOPICS.store(config.escape_topics.unwrap_or_else(|| std::io::stderr().is_terminal()), Ordering::Relaxed);
+}
+
+/// 警告や状態の表示に含める受信したトピック名 (`configure_status_topics` の設定に従って `escape_topic` でエスケープする)
+pub fn status_topic(topic: &str) -> Cow<'_, str> {
+    if ESCAPE_STATUS_TOPICS.load(Ordering::Relaxed) { escape_topic(topic) } else { Cow::Borrowed(topic) }
+}
+
+/// テキスト出力を端末に表示する場合に、設定の折り返し幅を返す (JSON / CSV の出力やパイプへの出力では折り返さない)
+pub fn wrap_width(config: &Config) -> Option<WrapWidth> {
+    if config.output_format.unwrap_or_default() != OutputFormat::Text || !std::io::stdout().is_terminal() {
+        return None;
+    }
+    config.wrap_width
+}
+
+/// 折り返す桁数。auto の場合は表示するたびに端末の幅を取得する (端末のサイズ変更に追従するため)
+pub fn wrap_columns(width: WrapWidth) -> usize {
+    match width {
+        WrapWidth::Columns(columns) => columns,
+        WrapWidth::Auto => terminal_columns().unwrap_or(DEFAULT_TERMINAL_COLUMNS),
+    }
+}
+
+/// 各行を表示幅 `columns` で折り返す。
+///
+/// 続きの行は `ペイロード: ` のような見出しの幅だけ字下げする (見出しが長すぎる場合は 2 桁)。
+pub fn wrap_text(text: &str, columns: usize) -> String {
+    text.split('\n').map(|line| wrap_line(line, columns)).collect::<Vec<_>>().join("\n")
+}
+
+fn wrap_line(line: &str, columns: usize) -> String {
+    if display_width(line) <= columns {
+        return line.to_string();
+    }
+    let indent = match line.find(": ") {
+        Some(pos) if display_width(&line[..pos + 2]) <= columns / 2 => display_width(&line[..pos + 2]),
+        _ => 2.min(columns.saturating_sub(1)),
+    };
+    let mut wrapped = String::new();
+    let mut width = 0;
+    for c in line.chars() {
+        let char_width = display_width(c.encode_utf8(&mut [0; 4]));
+        if width > indent && width + char_width > columns {
+            wrapped.push('\n');
+            wrapped.push_str(&" ".repeat(indent));
+            width = indent;
+        }
+        wrapped.push(c);
+        width += char_width;
+    }
+    wrapped
+}
+
+// 標準出力の端末の桁数 (環境変数 COLUMNS が指定されていればそれを優先する)
+fn terminal_columns() -> Option<usize> {
+    if let Some(columns) = std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()).filter(|c| *c > 0) {
+        return Some(columns);
+    }
+    ioctl_columns()
+}
+
+#[cfg(unix)]
+fn ioctl_columns() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    // SAFETY: TIOCGWINSZ は winsize 構造体に端末のサイズを書き込むだけ
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_col > 0).then_some(size.ws_col as usize)
+}
+
+#[cfg(not(unix))]
+fn ioctl_columns() -> Option<usize> {
+    None
+}
+
+// 端末での表示幅 (ASCII 以外の文字は全角として 2 桁と数える)
+fn display_width(text: &str) -> usize {
+    text.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum()
+}
+
+/// 時刻を UTC の ISO 8601 形式 (例: 2024-01-02T03:04:05.678Z) に変換する
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60, since_epoch.subsec_millis())
+}
+
+/// `format_timestamp` の形式 (ミリ秒は省略可) の時刻を読む。読めない場合は `None` を返す
+pub fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    let text = text.strip_suffix('Z')?;
+    let (date, time) = text.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (time, millis) = match time.split_once('.') {
+        Some((time, fraction)) if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) => {
+            // ミリ秒より細かい桁は切り捨てる
+            let digits = &fraction[..fraction.len().min(3)];
+            (time, digits.parse::<u64>().ok()? * 10u64.pow(3 - digits.len() as u32))
+        }
+        Some(_) => return None,
+        None => (time, 0),
+    };
+    let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month as u32, day as u32)).ok()?;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + std::time::Duration::from_millis(secs * 1000 + millis))
+}
+
+// グレゴリオ暦の年月日を 1970-01-01 からの日数に変換する (Howard Hinnant の days_from_civil)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from(if month > 2 { month - 3 } else { month + 9 });
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// 1970-01-01 からの日数をグレゴリオ暦の年月日に変換する (Howard Hinnant の civil_from_days)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use rumqttc::QoS;
+
+    fn message(topic: &str, payload: &[u8]) -> ReceivedMessage {
+        ReceivedMessage { topic: topic.to_string(), payload: Bytes::copy_from_slice(payload), qos: QoS::AtMostOnce, retain: false, pkid: 0, properties: None, acker: None }
+    }
+
+    #[test]
+    fn raw_has_no_decoder() {
+        assert!(payload_decoder(PayloadFormat::Raw).is_none());
+        assert_eq!(decode_payload_as(message("a", b"\xa1"), PayloadFormat::Raw).payload, &b"\xa1"[..]);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn decodes_cbor_and_falls_back_to_the_received_payload() {
+        // {"a": 1}
+        assert_eq!(decode_payload_as(message("a", b"\xa1\x61a\x01"), PayloadFormat::Cbor).payload, r#"{"a":1}"#);
+        assert_eq!(decode_payload_as(message("a", b"\xa1\x61"), PayloadFormat::Cbor).payload, &b"\xa1\x61"[..]);
+        assert!(decode_payload_as(message("a", b""), PayloadFormat::Cbor).payload.is_empty());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn decodes_msgpack_and_falls_back_to_the_received_payload() {
+        // {"a": 1}
+        assert_eq!(decode_payload_as(message("a", b"\x81\xa1a\x01"), PayloadFormat::Msgpack).payload, r#"{"a":1}"#);
+        assert_eq!(decode_payload_as(message("a", b"\x81\xa1"), PayloadFormat::Msgpack).payload, &b"\x81\xa1"[..]);
+        assert!(decode_payload_as(message("a", b""), PayloadFormat::Msgpack).payload.is_empty());
+    }
+
+    #[cfg(feature = "sparkplug")]
+    #[test]
+    fn decodes_only_sparkplug_topics() {
+        // seq: 3 (フィールド 3、varint)
+        let decoded = decode_payload_as(message("spBv1.0/group/DDATA/node/device", b"\x18\x03"), PayloadFormat::SparkplugB);
+        assert_eq!(decoded.payload, r#"{"seq":3,"metrics":[]}"#);
+        let state = decode_payload_as(message("spBv1.0/STATE/host", b"ONLINE"), PayloadFormat::SparkplugB);
+        assert_eq!(state.payload, &b"ONLINE"[..]);
+    }
+}
@@ -0,0 +1,301 @@
+// 送信するパケットの 16 進ダンプ (--dump-packets / dump_packets)
+//
+// rumqttc は送信するパケットのバイト列を公開していないため、rumqttc と同じ値からパケットを組み立て、
+// rumqttc のシリアライザーで書き出したバイト列を表示する。パケット ID は rumqttc が送信時に割り当てるため、仮の値 (1) で表示する。
+use super::config_utils::Config;
+use bytes::BytesMut;
+use rumqttc::v5::mqttbytes::v5 as v5_packet;
+use rumqttc::{v5, MqttOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// 1 行に表示するバイト数
+const BYTES_PER_LINE: usize = 16;
+// PUBLISH のペイロードを表示する最大バイト数
+const MAX_PAYLOAD_DUMP_BYTES: usize = 256;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SHOW_CREDENTIALS: AtomicBool = AtomicBool::new(false);
+
+/// dump_packets と dump_packets_show_credentials の設定を反映する
+pub fn configure(config: &Config) {
+    ENABLED.store(config.dump_packets.unwrap_or(false), Ordering::Relaxed);
+    SHOW_CREDENTIALS.store(config.dump_packets_show_credentials.unwrap_or(false), Ordering::Relaxed);
+}
+
+/// パケットのダンプが有効か (無効な場合はダンプ用のパケットを組み立てない)
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// MQTT 3.1.1 の接続で送信する CONNECT を表示する
+pub fn connect(options: &MqttOptions) {
+    if !enabled() {
+        return;
+    }
+    let mut connect = rumqttc::Connect::new(options.client_id());
+    connect.keep_alive = options.keep_alive().as_secs() as u16;
+    connect.clean_session = options.clean_session();
+    connect.last_will = options.last_will();
+    connect.login = options.credentials().map(|(username, password)| rumqttc::Login::new(username, password));
+    let mut buffer = BytesMut::new();
+    print(connect.write(&mut buffer).map(|_| buffer).map_err(|e| format!("{:?}", e)), false);
+}
+
+/// MQTT 5 の接続で送信する CONNECT を表示する
+pub fn connect_v5(options: &v5::MqttOptions) {
+    if !enabled() {
+        return;
+    }
+    let connect = v5_packet::Connect {
+        keep_alive: options.keep_alive().as_secs() as u16,
+        client_id: options.client_id(),
+        clean_start: options.clean_start(),
+        properties: options.connect_properties(),
+    };
+    let login = options.credentials().map(|(username, password)| v5_packet::Login { username, password });
+    let mut buffer = BytesMut::new();
+    let packet = v5_packet::Packet::Connect(connect, options.last_will(), login);
+    print(packet.write(&mut buffer).map(|_| buffer).map_err(|e| format!("{:?}", e)), true);
+}
+
+/// MQTT 3.1.1 の SUBSCRIBE を表示する
+pub fn subscribe(packet: &rumqttc::Subscribe) {
+    let mut buffer = BytesMut::new();
+    print(packet.write(&mut buffer).map(|_| buffer).map_err(|e| format!("{:?}", e)), false);
+}
+
+/// MQTT 5 の SUBSCRIBE を表示する
+pub fn subscribe_v5(packet: &v5_packet::Subscribe) {
+    let mut buffer = BytesMut::new();
+    print(packet.write(&mut buffer).map(|_| buffer).map_err(|e| format!("{:?}", e)), true);
+}
+
+/// MQTT 3.1.1 の PUBLISH を表示する
+pub fn publish(packet: &rumqttc::Publish) {
+    let mut buffer = BytesMut::new();
+    print(packet.write(&mut buffer).map(|_| buffer).map_err(|e| format!("{:?}", e)), false);
+}
+
+/// MQTT 5 の PUBLISH を表示する
+pub fn publish_v5(packet: &v5_packet::Publish) {
+    let mut buffer = BytesMut::new();
+    print(packet.write(&mut buffer).map(|_| buffer).map_err(|e| format!("{:?}", e)), true);
+}
+
+// ダンプを 1 回の出力で標準エラー出力に書き出す (他の出力と行が混ざらないようにする)
+fn print(bytes: Result<BytesMut, String>, v5: bool) {
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("警告: ダンプ用のパケットを組み立てられませんでした: {}", e);
+            return;
+        }
+    };
+    let name = packet_name(bytes.first().copied().unwrap_or_default() >> 4);
+    let mut output = format!("[パケットダンプ] {} ({}、{} バイト)\n", name, if v5 { "MQTT 5" } else { "MQTT 3.1.1" }, bytes.len());
+    for segment in annotate(&bytes, v5) {
+        output.push_str(&format!("  {}\n", segment.label));
+        let data = &bytes[segment.start..segment.end];
+        if segment.secret && !SHOW_CREDENTIALS.load(Ordering::Relaxed) {
+            output.push_str(&format!("    ({} バイトを伏せています。表示するには dump_packets_show_credentials: true を指定してください)\n", data.len()));
+            continue;
+        }
+        let shown = if segment.payload { data.len().min(MAX_PAYLOAD_DUMP_BYTES) } else { data.len() };
+        hex_lines(&mut output, segment.start, &data[..shown]);
+        if shown < data.len() {
+            output.push_str(&format!("    … 残りの {} バイトは省略しました\n", data.len() - shown));
+        }
+    }
+    eprint!("{}", output);
+}
+
+fn packet_name(packet_type: u8) -> &'static str {
+    match packet_type {
+        1 => "CONNECT",
+        3 => "PUBLISH",
+        8 => "SUBSCRIBE",
+        _ => "パケット",
+    }
+}
+
+// `offset` から始まるバイト列を、オフセット・16 進数・ASCII の行にして追加する
+fn hex_lines(output: &mut String, offset: usize, data: &[u8]) {
+    for (i, line) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        output.push_str(&format!("    {:04x}  {:<width$}  |{}|\n", offset + i * BYTES_PER_LINE, hex.join(" "), ascii,
+            width = BYTES_PER_LINE * 3 - 1));
+    }
+}
+
+// パケットの各部分の説明と範囲
+struct Segment {
+    label: String,
+    start: usize,
+    end: usize,
+    // ユーザー名・パスワード (dump_packets_show_credentials が指定されていなければ伏せる)
+    secret: bool,
+    // PUBLISH のペイロード (MAX_PAYLOAD_DUMP_BYTES までに切り詰める)
+    payload: bool,
+}
+
+// パケットを先頭から読み、各部分に説明を付ける
+struct Annotator<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    segments: Vec<Segment>,
+}
+
+impl<'a> Annotator<'a> {
+    fn push(&mut self, len: usize, label: String) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(len).filter(|&end| end <= self.bytes.len())?;
+        let data = &self.bytes[self.position..end];
+        self.segments.push(Segment { label, start: self.position, end, secret: false, payload: false });
+        self.position = end;
+        Some(data)
+    }
+
+    // 可変長のバイト整数 (残りの長さ・プロパティの長さ) の値とバイト数
+    fn peek_variable_int(&self, from: usize) -> Option<(usize, usize)> {
+        let mut value = 0usize;
+        for (i, &byte) in self.bytes.get(from..)?.iter().take(4).enumerate() {
+            value |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Some((value, i + 1));
+            }
+        }
+        None
+    }
+
+    // 2 バイトの長さが前に付いた文字列 (またはバイナリ)
+    fn length_prefixed(&mut self, label: &str, text: bool) -> Option<()> {
+        let len = u16::from_be_bytes(self.bytes.get(self.position..self.position + 2)?.try_into().ok()?) as usize;
+        let value = self.bytes.get(self.position + 2..self.position + 2 + len)?;
+        let label = if text {
+            format!("{} \"{}\" (長さ {})", label, String::from_utf8_lossy(value), len)
+        } else {
+            format!("{} (長さ {})", label, len)
+        };
+        self.push(2 + len, label).map(|_| ())
+    }
+
+    fn u16_field(&mut self, label: &str) -> Option<u16> {
+        let position = self.position;
+        let value = u16::from_be_bytes(self.bytes.get(position..position + 2)?.try_into().ok()?);
+        self.push(2, format!("{} {}", label, value))?;
+        Some(value)
+    }
+
+    fn properties(&mut self, label: &str) -> Option<()> {
+        let (len, count) = self.peek_variable_int(self.position)?;
+        self.push(count + len, format!("{} ({} バイト)", label, len)).map(|_| ())
+    }
+
+    fn mark_last(&mut self, secret: bool, payload: bool) {
+        if let Some(segment) = self.segments.last_mut() {
+            segment.secret = secret;
+            segment.payload = payload;
+        }
+    }
+}
+
+fn annotate(bytes: &[u8], v5: bool) -> Vec<Segment> {
+    let mut annotator = Annotator { bytes, position: 0, segments: Vec::new() };
+    let parsed = annotate_packet(&mut annotator, v5);
+    // 読み取れなかった部分は説明なしで表示する
+    if parsed.is_none() || annotator.position < bytes.len() {
+        let start = annotator.position;
+        annotator.segments.push(Segment { label: "(残りのデータ)".to_string(), start, end: bytes.len(), secret: false, payload: false });
+    }
+    annotator.segments
+}
+
+fn annotate_packet(a: &mut Annotator, v5: bool) -> Option<()> {
+    let first = *a.bytes.first()?;
+    let (remaining, count) = a.peek_variable_int(1)?;
+    let packet_type = first >> 4;
+    let qos = (first >> 1) & 0x03;
+    let flags = match packet_type {
+        3 => format!("、QoS {}{}{}", qos, if first & 0x01 != 0 { "、retain" } else { "" }, if first & 0x08 != 0 { "、DUP" } else { "" }),
+        _ => String::new(),
+    };
+    a.push(1 + count, format!("固定ヘッダー ({}{}、残りの長さ {})", packet_name(packet_type), flags, remaining))?;
+    match packet_type {
+        1 => annotate_connect(a, v5),
+        3 => {
+            a.length_prefixed("トピック名", true)?;
+            if qos > 0 {
+                a.push(2, "パケット ID (送信時に割り当てられるため仮の値 1 で表示)".to_string())?;
+            }
+            if v5 {
+                a.properties("プロパティ")?;
+            }
+            let len = a.bytes.len() - a.position;
+            a.push(len, format!("ペイロード ({} バイト)", len))?;
+            a.mark_last(false, true);
+            Some(())
+        }
+        8 => {
+            a.push(2, "パケット ID (送信時に割り当てられるため仮の値 1 で表示)".to_string())?;
+            if v5 {
+                a.properties("プロパティ")?;
+            }
+            while a.position < a.bytes.len() {
+                a.length_prefixed("トピックフィルター", true)?;
+                let options = *a.bytes.get(a.position)?;
+                let label = if v5 {
+                    format!("購読オプション 0x{:02x} (QoS {}、No Local {}、Retain As Published {}、Retain Handling {})",
+                        options, options & 0x03, options >> 2 & 1, options >> 3 & 1, options >> 4 & 0x03)
+                } else {
+                    format!("要求する QoS {}", options & 0x03)
+                };
+                a.push(1, label)?;
+            }
+            Some(())
+        }
+        _ => None,
+    }
+}
+
+fn annotate_connect(a: &mut Annotator, v5: bool) -> Option<()> {
+    a.length_prefixed("プロトコル名", true)?;
+    let level = *a.bytes.get(a.position)?;
+    a.push(1, format!("プロトコルレベル {}", level))?;
+    let flags = *a.bytes.get(a.position)?;
+    let mut names = Vec::new();
+    if flags & 0x02 != 0 {
+        names.push(if v5 { "Clean Start".to_string() } else { "クリーンセッション".to_string() });
+    }
+    if flags & 0x04 != 0 {
+        names.push(format!("Will (QoS {}{})", flags >> 3 & 0x03, if flags & 0x20 != 0 { "、retain" } else { "" }));
+    }
+    if flags & 0x80 != 0 {
+        names.push("ユーザー名".to_string());
+    }
+    if flags & 0x40 != 0 {
+        names.push("パスワード".to_string());
+    }
+    let names = if names.is_empty() { "なし".to_string() } else { names.join("、") };
+    a.push(1, format!("接続フラグ 0x{:02x} ({})", flags, names))?;
+    a.u16_field("キープアライブ (秒)")?;
+    if v5 {
+        a.properties("プロパティ")?;
+    }
+    a.length_prefixed("クライアント ID", true)?;
+    if flags & 0x04 != 0 {
+        if v5 {
+            a.properties("Will プロパティ")?;
+        }
+        a.length_prefixed("Will トピック", true)?;
+        a.length_prefixed("Will メッセージ", false)?;
+    }
+    if flags & 0x80 != 0 {
+        a.length_prefixed("ユーザー名", false)?;
+        a.mark_last(true, false);
+    }
+    if flags & 0x40 != 0 {
+        a.length_prefixed("パスワード", false)?;
+        a.mark_last(true, false);
+    }
+    Some(())
+}
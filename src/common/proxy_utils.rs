@@ -0,0 +1,225 @@
+// プロキシ (SOCKS5 / HTTP CONNECT) を経由したブローカーへの接続と、rumqttc からプロキシへの接続の中継 (proxy_url)
+use super::config_utils::Config;
+use super::dscp_utils;
+use super::error_utils::{exit_with, AppError};
+use super::tls_utils;
+use super::url_utils::{percent_decode, split_host_port};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rumqttc::tokio_rustls::TlsConnector;
+use rustls_pki_types::ServerName;
+use std::{io, time::Duration};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+// 接続を受け付けられなかった場合 (ファイルディスクリプタの上限など) に、次に受け付けるまで待つ時間
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// プロキシの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// SOCKS5 (socks5:// または socks5h://。ブローカーのホスト名はプロキシが解決する)
+    Socks5,
+    /// HTTP の CONNECT メソッドによるトンネル (http://)
+    HttpConnect,
+}
+
+/// proxy_url を解析したプロキシの接続先と認証情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    /// URL のユーザー情報 (user:password@) で指定したユーザー名とパスワード
+    pub credentials: Option<(String, String)>,
+}
+
+/// `socks5://[user:password@]host[:port]` または `http://[user:password@]host[:port]` 形式の URL を解析する。
+/// ポートを省略した場合は SOCKS5 は 1080、HTTP は 8080 を使用する
+pub fn parse_proxy_url(url: &str) -> Result<Proxy, String> {
+    let (scheme, rest) = url.split_once("://").ok_or_else(|| format!("proxy_url '{}' にスキーム (socks5:// または http://) がありません", url))?;
+    let (kind, default_port) = match scheme.to_ascii_lowercase().as_str() {
+        "socks5" | "socks5h" => (ProxyKind::Socks5, 1080),
+        "http" => (ProxyKind::HttpConnect, 8080),
+        other => return Err(format!("proxy_url のスキーム '{}' には対応していません (socks5 / socks5h / http を指定してください)", other)),
+    };
+    let authority = rest.trim_end_matches('/');
+    let (credentials, host_port) = match authority.rsplit_once('@') {
+        Some((userinfo, host_port)) => {
+            let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            let decode = |text| percent_decode(text).map_err(|e| format!("proxy_url のユーザー情報: {}", e));
+            (Some((decode(user)?, decode(password)?)), host_port)
+        }
+        None => (None, authority),
+    };
+    let (host, port) = split_host_port(host_port, default_port).map_err(|e| format!("proxy_url '{}': {}", url, e))?;
+    Ok(Proxy { kind, host, port, credentials })
+}
+
+/// rumqttc が接続するブローカーのアドレスとポート。
+///
+/// proxy_url が指定されている場合は、プロキシ経由でブローカーに中継するローカルのリスナー (127.0.0.1) を起動し、
+/// そのアドレスを返す。rumqttc にはソケットの接続処理を差し替える手段がないため、rumqttc からの接続ごとに
+/// プロキシのトンネルを確立して中継する。SSL/TLS 接続 (scheme: mqtts / ssl) の場合は、
+/// rumqttc は TLS なしで中継に接続し、中継がブローカーとの間で TLS 接続する (SNI とサーバー証明書の検証には broker_address を使う)。
+/// tcp_nodelay が false でなければ中継の両側のソケットに TCP_NODELAY を設定し、socket_dscp はプロキシとの接続のソケットに設定する。
+pub fn broker_endpoint(config: &Config) -> (String, u16) {
+    let Some(url) = &config.proxy_url else {
+        return (config.broker_address.clone(), config.broker_port);
+    };
+    let proxy = parse_proxy_url(url).unwrap_or_else(|e| exit_with(AppError::Config(e)));
+    let dscp = dscp_utils::socket_dscp(config);
+    let nodelay = config.tcp_nodelay.unwrap_or(true);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .and_then(TcpListener::from_std)
+        .unwrap_or_else(|e| exit_with(AppError::Config(format!("ブローカーへの中継用のポートを開けませんでした: {}", e))));
+    let port = listener.local_addr().map(|addr| addr.port()).unwrap_or_default();
+    let target = (config.broker_address.clone(), config.broker_port);
+    let tls = tls_utils::build_client_config(config).map(|client_config| (TlsConnector::from(client_config), tls_utils::broker_server_name(config)));
+    tokio::spawn(relay(listener, proxy, target, tls, dscp, nodelay));
+    ("127.0.0.1".to_string(), port)
+}
+
+/// rumqttc がブローカーではなく中継用のローカルのアドレスに接続するか (proxy_url)
+pub fn is_relayed(config: &Config) -> bool {
+    config.proxy_url.is_some()
+}
+
+// rumqttc からの接続を受け付け、プロキシ経由のブローカーとの接続との間でデータを中継する。
+// `tls` が指定されている場合は、ブローカーとの間で TLS 接続してから中継する
+async fn relay(listener: TcpListener, proxy: Proxy, target: (String, u16), tls: Option<(TlsConnector, ServerName<'static>)>, dscp: Option<u8>, nodelay: bool) {
+    loop {
+        let local = match listener.accept().await {
+            Ok((local, _)) => local,
+            Err(e) => {
+                eprintln!("警告: ブローカーへの中継用のポートで接続を受け付けられませんでした: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let proxy = proxy.clone();
+        let tls = tls.clone();
+        let (host, port) = target.clone();
+        tokio::spawn(async move {
+            let remote = match connect(&proxy, &host, port, dscp).await {
+                Ok(remote) => remote,
+                // 中継用の接続を閉じると rumqttc の接続エラーになり、通常の再接続処理が行われる
+                Err(e) => {
+                    eprintln!("プロキシ {}:{} 経由でブローカー {}:{} に接続できませんでした: {}", proxy.host, proxy.port, host, port, e);
+                    return;
+                }
+            };
+            // 小さなパケット (PUBLISH / PUBACK) を Nagle アルゴリズムで遅らせない
+            if nodelay && let Err(e) = local.set_nodelay(true).and_then(|_| remote.set_nodelay(true)) {
+                eprintln!("警告: ソケットに TCP_NODELAY を設定できませんでした: {}", e);
+            }
+            match tls {
+                Some((connector, server_name)) => match connector.connect(server_name, remote).await {
+                    Ok(remote) => pipe(local, remote).await,
+                    Err(e) => eprintln!("ブローカー {}:{} との TLS 接続に失敗しました: {}", host, port, e),
+                },
+                None => pipe(local, remote).await,
+            }
+        });
+    }
+}
+
+// どちらかの接続が閉じるまでデータを双方向にそのまま転送する
+async fn pipe<S: AsyncRead + AsyncWrite + Unpin>(mut local: TcpStream, mut remote: S) {
+    let _ = tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+}
+
+/// プロキシに接続し、`host:port` へのトンネルを確立したストリームを返す。`dscp` はプロキシとの接続のソケットに設定する
+pub async fn connect(proxy: &Proxy, host: &str, port: u16, dscp: Option<u8>) -> io::Result<TcpStream> {
+    let mut stream = dscp_utils::connect(&proxy.host, proxy.port, dscp).await?;
+    match proxy.kind {
+        ProxyKind::Socks5 => socks5_handshake(&mut stream, proxy, host, port).await?,
+        ProxyKind::HttpConnect => http_connect(&mut stream, proxy, host, port).await?,
+    }
+    Ok(stream)
+}
+
+fn proxy_error(message: String) -> io::Error {
+    io::Error::other(message)
+}
+
+// SOCKS5 (RFC 1928) の CONNECT。認証はユーザー名とパスワード (RFC 1929) に対応する
+async fn socks5_handshake(stream: &mut TcpStream, proxy: &Proxy, host: &str, port: u16) -> io::Result<()> {
+    // 認証方式の交渉 (0x00 = 認証なし、0x02 = ユーザー名とパスワード)
+    let methods: &[u8] = if proxy.credentials.is_some() { &[0x00, 0x02] } else { &[0x00] };
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    match (reply[1], &proxy.credentials) {
+        (0x00, _) => {}
+        (0x02, Some((user, password))) => {
+            if user.len() > 255 || password.len() > 255 {
+                return Err(proxy_error("SOCKS5 のユーザー名とパスワードは 255 バイト以下にしてください".to_string()));
+            }
+            let mut auth = vec![0x01, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(proxy_error("SOCKS5 プロキシの認証に失敗しました".to_string()));
+            }
+        }
+        (method, _) => return Err(proxy_error(format!("SOCKS5 プロキシが対応していない認証方式を要求しました (0x{:02x})", method))),
+    }
+
+    // ホスト名のまま送信し、名前解決はプロキシに任せる
+    if host.len() > 255 {
+        return Err(proxy_error("SOCKS5 で接続するホスト名は 255 バイト以下にしてください".to_string()));
+    }
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != 0x00 {
+        return Err(proxy_error(format!("SOCKS5 プロキシが接続を拒否しました (応答コード 0x{:02x})", header[1])));
+    }
+    // 応答のバインドアドレスとポートを読み捨てる
+    let address_len = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        other => return Err(proxy_error(format!("SOCKS5 プロキシの応答のアドレス種別が不正です (0x{:02x})", other))),
+    };
+    let mut rest = vec![0u8; address_len + 2];
+    stream.read_exact(&mut rest).await?;
+    Ok(())
+}
+
+// HTTP の CONNECT メソッドでトンネルを確立する。認証は Basic 認証 (Proxy-Authorization) に対応する
+async fn http_connect(stream: &mut TcpStream, proxy: &Proxy, host: &str, port: u16) -> io::Result<()> {
+    let authority = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some((user, password)) = &proxy.credentials {
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", BASE64.encode(format!("{}:{}", user, password))));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // ステータス行とヘッダーを空行まで読む (トンネル確立後のデータを読み込まないよう 1 バイトずつ読む)
+    let mut reader = BufReader::with_capacity(1, stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).await?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(proxy_error(format!("HTTP プロキシがトンネルの確立を拒否しました ({})", status_line.trim_end())));
+    }
+    Ok(())
+}
@@ -0,0 +1,737 @@
+use super::benchmark_utils;
+use super::config_utils::{Config, Qos2TimeoutPolicy};
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::{self, ClientError, MqttClient, MqttEvent, MqttEventLoop};
+use super::qos_utils;
+use super::reconnect_utils::{ReconnectDeadline, StartupRetry};
+use super::schema_utils::PublishedSchema;
+use super::sequence_utils;
+use super::token_utils;
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+use rumqttc::{Outgoing, QoS};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, BufReader}, sync::{mpsc, Mutex}, time};
+
+/// 発行するメッセージのペイロードの取得元
+pub enum PayloadSource {
+    /// 設定ファイルで指定された固定のペイロード
+    Fixed(Vec<u8>),
+    /// 標準入力の 1 行を 1 メッセージとして発行する
+    StdinLines,
+    /// 標準入力全体を 1 つのバイナリメッセージとして発行する
+    StdinRaw,
+    /// 設定ファイルの messages の各メッセージを、それぞれのペイロードで発行する
+    Messages(Vec<Vec<u8>>),
+}
+
+// 発行先のトピックと、そのトピックに発行するメッセージの QoS / retain フラグ
+struct PublishTarget {
+    topic: String,
+    qos: QoS,
+    retain: bool,
+}
+
+/// 設定ファイルで指定されたトピックにメッセージを発行するパブリッシャー
+pub struct Publisher {
+    client: MqttClient,
+    eventloop: MqttEventLoop,
+    config: Config,
+    qos: Vec<QoS>,
+    schema: Option<PublishedSchema>,
+}
+
+impl Publisher {
+    pub fn new(config: Config) -> Publisher {
+        let actual_qos = qos_utils::resolve_qos(&config);
+        let schema = PublishedSchema::new(&config);
+        let (client, eventloop) = mqtt_utils::create_client(&config, 10); // 10 はイベントループのチャネル容量
+
+        Publisher { client, eventloop, config, qos: actual_qos, schema }
+    }
+
+    /// ペイロードをすべて発行し、ブローカーからの確認応答を受け取ってから切断する
+    pub async fn run(&mut self, source: PayloadSource) {
+        // auth_token_url が指定されている場合は、接続前にトークンを取得して password として使用する
+        // (パブリッシャーは発行が終わると切断するため、トークンの更新は行わない)
+        if let Some(url) = &self.config.auth_token_url {
+            let token = token_utils::fetch_token_with_retry(&self.config, url).await;
+            self.eventloop.set_credentials(self.config.username.as_deref().unwrap_or(""), &token.token);
+        }
+
+        // ペイロードの読み込みと発行はイベントループの処理と並行して行う
+        let (done_tx, mut done_rx) = mpsc::channel::<usize>(1);
+        let mut tracker = Qos2Tracker::new(&self.config);
+        let client = PublishClient { client: self.client.clone(), log: tracker.as_ref().map(|tracker| tracker.log.clone()) };
+        let retain = self.config.retain.unwrap_or(false);
+        let targets: Vec<PublishTarget> = match &self.config.messages {
+            Some(messages) => messages.iter().map(|message| PublishTarget {
+                topic: message.topic.clone(),
+                qos: message.qos.unwrap_or_else(|| qos_utils::resolve_message_qos(&self.config, &message.topic)),
+                retain: message.retain.unwrap_or(retain),
+            }).collect(),
+            None => self.config.topics.iter().cloned().zip(self.qos.iter().copied())
+                .map(|(topic, qos)| PublishTarget { topic, qos, retain })
+                .collect(),
+        };
+        // verify_exactly_once が有効な場合は、トピックごとに 1 から順にシーケンス番号を付ける
+        let mut sequences = self.config.verify_exactly_once.unwrap_or(false).then(|| vec![0u64; targets.len()]);
+        let benchmark = self.config.benchmark.unwrap_or(false);
+        if sequences.is_some() && targets.iter().any(|target| target.qos != QoS::ExactlyOnce) {
+            eprintln!("警告: verify_exactly_once はサブスクライバーが QoS 2 で受信したメッセージのみを検証します。");
+        }
+        let mut pacer = Pacer::new(&self.config);
+        let publishing = client.clone();
+        let schema = self.schema.clone();
+        tokio::spawn(async move {
+            let options = PublishOptions { benchmark };
+            // 説明はデータより先に発行する (同じ接続の PUBLISH は要求した順に送信される)
+            let mut published = match &schema {
+                Some(schema) => publish_schemas(&publishing, &targets, schema).await,
+                None => 0,
+            };
+            published += publish_from_source(&publishing, &targets, options, &mut sequences, &mut pacer, source).await;
+            pacer.report();
+            let _ = done_tx.send(published).await;
+        });
+
+        // 発行済みで確認応答 (QoS 0 は送信完了) を受け取っていないメッセージを追跡する
+        let mut total: Option<usize> = None;
+        let mut completed = 0usize;
+        // completed のうち、ブローカーが発行を拒否した件数 (MQTT 5 のみ)
+        let mut rejected = 0usize;
+        let mut disconnecting = false;
+        let mut reconnect_deadline = ReconnectDeadline::new(&self.config);
+        let mut startup_retry = StartupRetry::new(&self.config);
+        let mut keep_alive_rejections = 0;
+        loop {
+            let deadline = tracker.as_ref().and_then(Qos2Tracker::next_deadline);
+            tokio::select! {
+                Some(published) = done_rx.recv(), if total.is_none() => {
+                    total = Some(published);
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)), if deadline.is_some() => {
+                    if let Some(tracker) = &mut tracker {
+                        completed += tracker.expire(&mut self.eventloop, &client);
+                    }
+                }
+                result = self.eventloop.poll() => match result {
+                    Ok(MqttEvent::Outgoing(Outgoing::Publish(pkid))) => {
+                        if let Some(tracker) = &mut tracker {
+                            tracker.sent(pkid);
+                        }
+                        if pkid == 0 {
+                            completed += 1; // QoS 0 はパケット ID が 0
+                        }
+                    }
+                    Ok(MqttEvent::PubRec(pkid)) => {
+                        if let Some(tracker) = &mut tracker {
+                            tracker.received_pubrec(pkid);
+                        }
+                    }
+                    Ok(MqttEvent::PubAck(pkid)) | Ok(MqttEvent::PubComp(pkid)) => {
+                        if tracker.as_mut().is_none_or(|tracker| tracker.completed(pkid)) {
+                            completed += 1;
+                        }
+                    }
+                    Ok(MqttEvent::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(MqttEvent::ConnAck { properties, .. }) => {
+                        if let Some(deadline) = &mut reconnect_deadline {
+                            deadline.connected();
+                        }
+                        if let Some(startup) = &mut startup_retry {
+                            startup.connected();
+                        }
+                        if !mqtt_utils::check_server_keep_alive(&self.config, properties.as_deref(), &mut keep_alive_rejections) {
+                            self.eventloop.reconnect();
+                            time::sleep(Duration::from_secs(5)).await;
+                        }
+                    }
+                    Ok(MqttEvent::Disconnect(reason)) => {
+                        eprintln!("ブローカーから切断されました。理由コード: {:?} (0x{:02X}), 理由: {}",
+                            reason.code, reason.code as u8, reason.reason_string.as_deref().unwrap_or("(なし)"));
+                        if let Some(deadline) = &mut reconnect_deadline {
+                            deadline.failed(&format!("ブローカーからの切断 (理由コード: {:?})", reason.code));
+                        }
+                        // 次の poll で再接続し、確認応答を受け取っていないメッセージを再送する
+                        time::sleep(Duration::from_secs(1)).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        if e.is_requests_done() {
+                            exit_with(AppError::Unavailable("イベントループへの要求チャネルが閉じられたため終了します。".to_string()));
+                        }
+                        let refused = mqtt_utils::report_connect_refusal(&e, &self.config);
+                        if self.config.fail_fast.unwrap_or(false) {
+                            exit_with(e.to_app_error(format!("ブローカーへの接続でエラーが発生したため終了します (fail_fast): {:?}", e)));
+                        }
+                        // 起動時の接続の失敗は startup_retry_delay_ms の間隔で再試行する (一度接続した後は以下の通常の再接続)。
+                        // 起動時は startup_max_attempts の上限 (終了コード 69) を優先し、max_reconnect_duration_secs の時間を計らない
+                        if let Some(delay) = startup_retry.as_mut().and_then(|startup| startup.failed(&e.to_string())) {
+                            time::sleep(delay).await;
+                            continue;
+                        }
+                        // 発行の拒否はブローカーに接続できないことによるエラーではないため、接続エラーとして数えない
+                        if let Some(deadline) = reconnect_deadline.as_mut().filter(|_| e.rejected_publish_qos().is_none()) {
+                            deadline.failed(&e.to_string());
+                        }
+                        if let Some(rejection) = e.rejection() {
+                            eprintln!("エラー: {}。", rejection);
+                        } else if !refused {
+                            eprintln!("イベントループでエラーが発生しました: {:?}", e);
+                        }
+                        // 拒否されたメッセージは再送されないため、確認応答を受け取ったものとして数える
+                        if let Some(qos) = e.rejected_publish_qos() {
+                            let unacked = self.eventloop.unacked_publishes();
+                            if tracker.as_mut().is_none_or(|tracker| tracker.rejected(qos, &unacked)) {
+                                completed += 1;
+                            }
+                            rejected += 1;
+                        }
+                        time::sleep(Duration::from_secs(1)).await;
+                    }
+                },
+            }
+
+            if !disconnecting && total.is_some_and(|t| completed >= t) {
+                println!("{} 件のメッセージを発行しました。", completed);
+                if rejected > 0 {
+                    eprintln!("警告: うち {} 件の発行がブローカーに拒否されました。", rejected);
+                }
+                if let Some(tracker) = tracker.as_ref().filter(|tracker| tracker.stuck > 0) {
+                    eprintln!("警告: QoS 2 のハンドシェイクが {} 秒以内に完了しなかったメッセージが {} 件ありました。",
+                        tracker.timeout.as_secs(), tracker.stuck);
+                }
+                disconnecting = true;
+                if let Err(e) = self.client.disconnect().await {
+                    eprintln!("切断要求の送信中にエラーが発生しました: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// 別の接続 (サブスクライバー) のイベントループを共有してメッセージを発行するハンドル。
+///
+/// 受信したメッセージに応答を発行する場合など、購読と発行を 1 つのプロセスで行うときに、ブローカーへの接続
+/// (CONNECT とセッション) を 1 つにする。`Subscriber::shared_publisher` で取得する。
+///
+/// スレッドモデル: ハンドルは `Clone` / `Send` / `Sync` で、複数のタスクから同時に発行できる。発行の要求は
+/// クライアントの要求チャネル (容量 10) に積まれ、サブスクライバーの `run` / `run_with_handler` を実行している
+/// タスクのイベントループが PUBLISH を送信する。確認応答 (PUBACK / PUBCOMP) の受信と、再接続時の未確認の
+/// メッセージの再送もそのイベントループが行うため、イベントループが動いていない間 (`run` の開始前や終了後) は
+/// 送信されない。`publish` はチャネルに空きができるまで待つため、イベントループを処理しているタスクからは呼ばずに
+/// `run_with_handler` のハンドラーなど別のタスクから呼ぶ。`handler_overflow: block` でハンドラーのキューが満杯の場合は
+/// イベントループもハンドラーを待つため、ハンドラーからはチャネルに空きがなくても待たない `try_publish` を使う。
+#[derive(Clone)]
+pub struct SharedPublisher {
+    client: MqttClient,
+    config: Arc<Config>,
+}
+
+impl SharedPublisher {
+    /// `client` のイベントループを共有する。QoS と retain フラグの既定値は `config` から決める
+    pub fn new(client: MqttClient, config: &Config) -> SharedPublisher {
+        SharedPublisher { client, config: Arc::new(config.clone()) }
+    }
+
+    /// `topic` にメッセージを発行する。QoS は messages で省略した場合と同じ順 (qos_override・qos_rules・qos の先頭) で決め、
+    /// retain フラグは retain の設定に従う。要求チャネルに空きができるまで待つ
+    pub async fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<(), ClientError> {
+        let qos = qos_utils::resolve_message_qos(&self.config, topic);
+        self.client.publish(topic, qos, self.config.retain.unwrap_or(false), payload).await
+    }
+
+    /// `publish` と同様だが、要求チャネルに空きがない場合は待たずにエラーを返す
+    pub fn try_publish(&self, topic: &str, payload: Vec<u8>) -> Result<(), ClientError> {
+        let qos = qos_utils::resolve_message_qos(&self.config, topic);
+        self.client.try_publish(topic, qos, self.config.retain.unwrap_or(false), payload)
+    }
+
+    /// QoS と retain フラグを指定して発行する
+    pub async fn publish_with(&self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), ClientError> {
+        self.client.publish(topic, qos, retain, payload).await
+    }
+}
+
+// 発行するメッセージに共通の設定
+#[derive(Clone, Copy)]
+struct PublishOptions {
+    // ペイロードの先頭に発行時刻を付ける (benchmark)
+    benchmark: bool,
+}
+
+// 発行に使うクライアント。qos2_completion_timeout_secs が指定されている場合は、発行を要求したメッセージを Qos2Tracker に記録する
+#[derive(Clone)]
+struct PublishClient {
+    client: MqttClient,
+    log: Option<PublishLog>,
+}
+
+#[derive(Clone)]
+struct PublishLog {
+    // 記録とイベントループへの要求の順が入れ替わらないよう、要求を送るまでロックする
+    tx: Arc<Mutex<mpsc::UnboundedSender<SentPublish>>>,
+    // downgrade-to-qos1 で発行し直すため、QoS 2 のメッセージのペイロードを保持する
+    keep_payloads: bool,
+}
+
+// 発行を要求したメッセージ
+struct SentPublish {
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    payload: Vec<u8>,
+}
+
+impl PublishClient {
+    async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), ClientError> {
+        let Some(log) = &self.log else {
+            return self.client.publish(&topic, qos, retain, payload).await;
+        };
+        let tx = log.tx.lock().await;
+        let kept = if log.keep_payloads && qos == QoS::ExactlyOnce { payload.clone() } else { Vec::new() };
+        let _ = tx.send(SentPublish { topic: topic.clone(), qos, retain, payload: kept });
+        self.client.publish(&topic, qos, retain, payload).await
+    }
+}
+
+// qos2_completion_timeout_secs が指定されている場合に、確認応答を待っている QoS 1 / 2 のメッセージを追跡し、
+// 期限内にハンドシェイクが完了しなかった QoS 2 のメッセージに qos2_timeout_policy を適用する。
+// 追跡はパケット ID ごとに行い、PUBLISH の送信 (Outgoing::Publish) でパケット ID を割り当て、PUBREC / PUBACK / PUBCOMP で
+// 状態を進める。rumqttc は送信した PUBLISH のパケット ID しか通知しないため、パケット ID に対応するメッセージ (トピックとペイロード) は、
+// 発行の要求をチャネルから受け取った順に送信することを利用して求める (再接続後の再送は、確認応答を待っているパケット ID で区別する)
+struct Qos2Tracker {
+    timeout: Duration,
+    policy: Qos2TimeoutPolicy,
+    log: PublishLog,
+    sent_rx: mpsc::UnboundedReceiver<SentPublish>,
+    // 確認応答を待っているメッセージ (パケット ID ごと)
+    inflight: HashMap<u16, Inflight>,
+    // 最後に送信したメッセージの通し番号
+    last_order: u64,
+    // 期限内にハンドシェイクが完了しなかったメッセージ数
+    stuck: u64,
+}
+
+struct Inflight {
+    message: SentPublish,
+    order: u64,
+    // PUBREC を受信したか (QoS 2 のみ)
+    released: bool,
+    // ハンドシェイクの期限 (QoS 2 のみ。完了を待たなくなった場合は `None`)
+    deadline: Option<time::Instant>,
+    // downgrade-to-qos1 で完了を待たなくなった (この後に PUBCOMP を受信しても完了として数えない)
+    abandoned: bool,
+}
+
+impl Qos2Tracker {
+    // qos2_completion_timeout_secs が指定されていない場合は `None` を返す
+    fn new(config: &Config) -> Option<Qos2Tracker> {
+        let timeout = Duration::from_secs(config.qos2_completion_timeout_secs?);
+        let policy = config.qos2_timeout_policy.unwrap_or_default();
+        let (tx, sent_rx) = mpsc::unbounded_channel();
+        let log = PublishLog { tx: Arc::new(Mutex::new(tx)), keep_payloads: policy == Qos2TimeoutPolicy::DowngradeToQos1 };
+        Some(Qos2Tracker { timeout, policy, log, sent_rx, inflight: HashMap::new(), last_order: 0, stuck: 0 })
+    }
+
+    fn next_deadline(&self) -> Option<time::Instant> {
+        self.inflight.values().filter_map(|inflight| inflight.deadline).min()
+    }
+
+    // rumqttc が PUBLISH を送信した。再送の場合は最初に送信したときの期限のまま待つ
+    fn sent(&mut self, pkid: u16) {
+        if pkid != 0 && self.inflight.contains_key(&pkid) {
+            return;
+        }
+        let Ok(message) = self.sent_rx.try_recv() else {
+            return;
+        };
+        if pkid == 0 {
+            return;
+        }
+        let deadline = (message.qos == QoS::ExactlyOnce).then(|| time::Instant::now() + self.timeout);
+        self.last_order += 1;
+        self.inflight.insert(pkid, Inflight { message, order: self.last_order, released: false, deadline, abandoned: false });
+    }
+
+    fn received_pubrec(&mut self, pkid: u16) {
+        if let Some(inflight) = self.inflight.get_mut(&pkid) {
+            inflight.released = true;
+        }
+    }
+
+    // PUBACK / PUBCOMP を受信した。完了として数える場合は true を返す
+    fn completed(&mut self, pkid: u16) -> bool {
+        self.inflight.remove(&pkid).is_none_or(|inflight| !inflight.abandoned)
+    }
+
+    // 発行が拒否された。拒否のエラーにはパケット ID が含まれないため、PUBACK / PUBREC を待っていたメッセージのうち、
+    // エラーで接続を破棄した rumqttc がまだ確認応答を待っているパケット ID (`unacked`) にないものを拒否されたものとする。
+    // 完了として数える場合は true を返す
+    fn rejected(&mut self, qos: QoS, unacked: &HashSet<u16>) -> bool {
+        let rejected = self.inflight.iter()
+            .find(|(pkid, inflight)| inflight.message.qos == qos && !inflight.released && !unacked.contains(pkid))
+            .map(|(pkid, _)| *pkid);
+        rejected.is_none_or(|pkid| self.completed(pkid))
+    }
+
+    // 期限を過ぎたメッセージに qos2_timeout_policy を適用し、完了として数えるメッセージ数を返す
+    fn expire(&mut self, eventloop: &mut MqttEventLoop, client: &PublishClient) -> usize {
+        let now = time::Instant::now();
+        let mut expired: Vec<(u16, u64)> = self.inflight.iter()
+            .filter(|(_, inflight)| inflight.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(pkid, inflight)| (*pkid, inflight.order))
+            .collect();
+        expired.sort_by_key(|(_, order)| *order);
+        let mut completed = 0;
+        // downgrade-to-qos1 で rumqttc の再送の対象から外すメッセージと、QoS 1 で発行し直すメッセージ
+        let mut discarded = Vec::new();
+        for (pkid, _) in &expired {
+            let Some(inflight) = self.inflight.get_mut(pkid) else {
+                continue;
+            };
+            self.stuck += 1;
+            eprintln!("警告: QoS 2 のメッセージ (パケット ID {}、トピック '{}') のハンドシェイクが {} 秒以内に完了しませんでした ({} を受信していません)。",
+                pkid, inflight.message.topic, self.timeout.as_secs(), if inflight.released { "PUBCOMP" } else { "PUBREC" });
+            match self.policy {
+                Qos2TimeoutPolicy::Retry => inflight.deadline = Some(now + self.timeout),
+                Qos2TimeoutPolicy::Fail => exit_with(AppError::NotReceived(
+                    "QoS 2 のハンドシェイクが完了しなかったため終了します (qos2_timeout_policy: fail)。".to_string())),
+                Qos2TimeoutPolicy::DowngradeToQos1 => {
+                    if inflight.released {
+                        // PUBREC を受信していればブローカーはメッセージを受け取っているため、発行し直さない。
+                        // rumqttc は PUBREL の再送を続けるため、この後に PUBCOMP を受信しても完了として数えないようにする
+                        inflight.deadline = None;
+                        inflight.abandoned = true;
+                        completed += 1;
+                        crate::status!("PUBREC を受信しているため、パケット ID {} のメッセージは発行済みとして扱います (qos2_timeout_policy: downgrade-to-qos1)。", pkid);
+                        continue;
+                    }
+                    // rumqttc から取り除いたパケット ID は別のメッセージに割り当てられるため、追跡もやめる
+                    if let Some(inflight) = self.inflight.remove(pkid) {
+                        discarded.push((*pkid, inflight.message));
+                    }
+                }
+            }
+        }
+        if self.policy == Qos2TimeoutPolicy::Retry && !expired.is_empty() {
+            // rumqttc は再接続時に、PUBREC を受信していないメッセージの PUBLISH と PUBCOMP を受信していないメッセージの PUBREL を再送する
+            crate::status!("ブローカーに再接続して、ハンドシェイクが完了していないメッセージを再送します (qos2_timeout_policy: retry)。");
+            eventloop.reconnect();
+        }
+        if !discarded.is_empty() {
+            // rumqttc は確認応答を待っている PUBLISH を再接続時の再送のために保持し続けるため、再送の待ち行列に移してから取り除く
+            // (QoS 2 の PUBLISH の再送は再接続時にしか行えないため、ほかのメッセージの再送と PUBREL の送信は再接続後に行われる)
+            crate::status!("ブローカーに再接続して、ハンドシェイクが完了していない QoS 2 のメッセージを再送の対象から外します (qos2_timeout_policy: downgrade-to-qos1)。");
+            eventloop.reconnect();
+            for (pkid, message) in discarded {
+                eventloop.discard_pending_publish(pkid);
+                crate::status!("トピック '{}' のメッセージを QoS 1 で発行し直します (qos2_timeout_policy: downgrade-to-qos1)。", message.topic);
+                // イベントループを処理しているタスクからは要求チャネルの空きを待てないため、別のタスクで発行する
+                let client = client.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = client.publish(message.topic.clone(), QoS::AtLeastOnce, message.retain, message.payload).await {
+                        eprintln!("トピック '{}' への発行中にエラーが発生しました: {:?}", message.topic, e);
+                    }
+                });
+            }
+        }
+        completed
+    }
+}
+
+// publish_count / publish_interval_ms に従って、発行を繰り返す回数と間隔を管理する
+struct Pacer {
+    count: Option<u64>,
+    // 発行の時刻の刻み。遅れた場合も次の時刻は最初の発行からの間隔で決める (遅れが積み重ならないようにする)
+    interval: Option<time::Interval>,
+    period: Duration,
+    // 最初と最後の回を発行し始めた時刻
+    started: Option<time::Instant>,
+    last: Option<time::Instant>,
+    rounds: u64,
+    // 予定の時刻から 1 間隔以上遅れて発行した回数
+    late: u64,
+    // 1 回に発行するメッセージ数
+    messages_per_round: usize,
+}
+
+impl Pacer {
+    fn new(config: &Config) -> Pacer {
+        let period = Duration::from_millis(config.publish_interval_ms.unwrap_or_default());
+        let interval = (!period.is_zero()).then(|| {
+            let mut interval = time::interval(period);
+            // 追いつけなかった刻みはまとめて発行せずに飛ばし、以降は本来の刻みで発行する
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+            interval
+        });
+        Pacer { count: config.publish_count, interval, period, started: None, last: None, rounds: 0, late: 0, messages_per_round: 0 }
+    }
+
+    // publish_count の回数をすべて発行したか (未指定の場合は `default` 回)
+    fn finished(&self, default: Option<u64>) -> bool {
+        self.count.or(default).is_some_and(|count| self.rounds >= count)
+    }
+
+    // 次の回を発行する時刻まで待つ
+    async fn wait(&mut self) {
+        if let Some(interval) = &mut self.interval {
+            let scheduled = interval.tick().await;
+            if scheduled.elapsed() >= self.period {
+                self.late += 1;
+            }
+        }
+        let now = time::Instant::now();
+        self.started.get_or_insert(now);
+        self.last = Some(now);
+        self.rounds += 1;
+    }
+
+    // publish_interval_ms を指定した場合に、指定した発行レートと実際のレートを表示する
+    fn report(&self) {
+        if self.interval.is_none() || self.rounds < 2 {
+            return;
+        }
+        let (Some(started), Some(last)) = (self.started, self.last) else {
+            return;
+        };
+        let elapsed = last.duration_since(started).as_secs_f64();
+        let actual = if elapsed > 0.0 { (self.rounds - 1) as f64 / elapsed } else { f64::INFINITY };
+        crate::status!("発行レート: 実測 {:.2} 回/秒 (指定 {:.2} 回/秒、1 回あたり {} 件、{} 回を {:.3} 秒で発行)",
+            actual, 1.0 / self.period.as_secs_f64(), self.messages_per_round, self.rounds, elapsed);
+        if self.late > 0 {
+            eprintln!("警告: 発行が publish_interval_ms ({} ms) に追いつかず、{} 回は予定の時刻より遅れて発行しました \
+                (確認応答を待っていないメッセージの上限やブローカーの応答が追いついていない可能性があります)。",
+                self.period.as_millis(), self.late);
+        }
+    }
+}
+
+// ペイロードを読み込んで全トピックに発行し、発行したメッセージ数を返す。固定のペイロードは publish_count の回数だけ繰り返す
+async fn publish_from_source(client: &PublishClient, targets: &[PublishTarget], options: PublishOptions, sequences: &mut Option<Vec<u64>>, pacer: &mut Pacer, source: PayloadSource) -> usize {
+    let mut published = 0;
+    match source {
+        PayloadSource::Fixed(payload) => {
+            pacer.messages_per_round = targets.len();
+            while !pacer.finished(Some(1)) {
+                pacer.wait().await;
+                published += publish_to_all(client, targets, options, sequences, payload.clone()).await;
+            }
+        }
+        PayloadSource::StdinRaw => {
+            let mut payload = Vec::new();
+            if let Err(e) = tokio::io::stdin().read_to_end(&mut payload).await {
+                eprintln!("標準入力の読み込み中にエラーが発生しました: {}", e);
+            }
+            pacer.messages_per_round = targets.len();
+            while !pacer.finished(Some(1)) {
+                pacer.wait().await;
+                published += publish_to_all(client, targets, options, sequences, payload.clone()).await;
+            }
+        }
+        PayloadSource::Messages(payloads) => {
+            pacer.messages_per_round = payloads.len();
+            while !pacer.finished(Some(1)) {
+                pacer.wait().await;
+                // messages の i 番目のペイロードは i 番目の発行先にのみ発行する
+                for (i, payload) in payloads.iter().enumerate() {
+                    let sequence = sequences.as_mut().map(|sequences| &mut sequences[i]);
+                    published += usize::from(publish_one(client, &targets[i], options, sequence, payload.clone()).await);
+                }
+            }
+        }
+        PayloadSource::StdinLines => {
+            pacer.messages_per_round = targets.len();
+            let mut lines = BufReader::new(tokio::io::stdin()).lines();
+            // 標準入力の場合、publish_count は発行する行数の上限
+            while !pacer.finished(None) {
+                match lines.next_line().await {
+                    Ok(Some(line)) => {
+                        pacer.wait().await;
+                        published += publish_to_all(client, targets, options, sequences, line.into_bytes()).await;
+                    }
+                    Ok(None) => break, // EOF
+                    Err(e) => {
+                        eprintln!("標準入力の読み込み中にエラーが発生しました: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    published
+}
+
+// publish_schema の説明を、発行先の各トピック (重複を除く) の説明のトピックに retain で発行し、発行したメッセージ数を返す
+async fn publish_schemas(client: &PublishClient, targets: &[PublishTarget], schema: &PublishedSchema) -> usize {
+    let mut published = 0;
+    let mut seen = HashSet::new();
+    for target in targets.iter().filter(|target| seen.insert(target.topic.as_str())) {
+        let topic = schema.topic_for(&target.topic);
+        match client.publish(topic.clone(), target.qos, true, schema.payload.clone()).await {
+            Ok(()) => {
+                crate::status!("トピック '{}' のペイロードの説明を '{}' に発行しました。", target.topic, topic);
+                published += 1;
+            }
+            Err(e) => eprintln!("トピック '{}' への説明の発行中にエラーが発生しました: {:?}", topic, e),
+        }
+    }
+    published
+}
+
+async fn publish_to_all(client: &PublishClient, targets: &[PublishTarget], options: PublishOptions, sequences: &mut Option<Vec<u64>>, payload: Vec<u8>) -> usize {
+    let mut published = 0;
+    for (i, target) in targets.iter().enumerate() {
+        let sequence = sequences.as_mut().map(|sequences| &mut sequences[i]);
+        published += usize::from(publish_one(client, target, options, sequence, payload.clone()).await);
+    }
+    published
+}
+
+// 1 件のメッセージを発行し、発行要求を送れた場合は true を返す
+async fn publish_one(client: &PublishClient, target: &PublishTarget, options: PublishOptions, sequence: Option<&mut u64>, payload: Vec<u8>) -> bool {
+    let payload = match sequence {
+        Some(sequence) => {
+            *sequence += 1;
+            sequence_utils::with_sequence(*sequence, &payload)
+        }
+        None => payload,
+    };
+    // 発行時刻はシーケンス番号より外側に付け、発行の直前に取得する
+    let payload = if options.benchmark { benchmark_utils::with_timestamp(&payload) } else { payload };
+    match client.publish(target.topic.clone(), target.qos, target.retain, payload).await {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("トピック '{}' への発行中にエラーが発生しました: {:?}", target.topic, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("client_id: publisher\nbroker_address: localhost\nbroker_port: 1883\nqos2_completion_timeout_secs: 5\n{}", yaml)).unwrap()
+    }
+
+    fn log(tracker: &Qos2Tracker, topic: &str, qos: QoS) {
+        let _ = tracker.log.tx.try_lock().unwrap().send(SentPublish { topic: topic.to_string(), qos, retain: false, payload: topic.as_bytes().to_vec() });
+    }
+
+    #[tokio::test]
+    async fn packet_ids_are_assigned_in_request_order_and_resends_keep_theirs() {
+        let mut tracker = Qos2Tracker::new(&config("")).unwrap();
+        for (topic, qos) in [("a", QoS::ExactlyOnce), ("b", QoS::AtMostOnce), ("c", QoS::AtLeastOnce)] {
+            log(&tracker, topic, qos);
+        }
+        tracker.sent(1);
+        tracker.sent(0);
+        // 再接続後の再送は、発行を要求したメッセージを消費しない
+        tracker.sent(1);
+        tracker.sent(2);
+        assert_eq!(tracker.inflight[&1].message.topic, "a");
+        assert_eq!(tracker.inflight[&2].message.topic, "c");
+        assert_eq!(tracker.inflight.len(), 2);
+        assert!(tracker.sent_rx.try_recv().is_err());
+
+        tracker.received_pubrec(1);
+        assert!(tracker.inflight[&1].released);
+        assert!(tracker.completed(1));
+        assert!(tracker.completed(2));
+        assert!(tracker.inflight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_rejected_message_is_the_one_rumqttc_no_longer_awaits() {
+        let mut tracker = Qos2Tracker::new(&config("")).unwrap();
+        for topic in ["a", "b", "c"] {
+            log(&tracker, topic, QoS::ExactlyOnce);
+        }
+        for pkid in 1..=3 {
+            tracker.sent(pkid);
+        }
+        tracker.received_pubrec(1);
+        // 最も古い 1 は PUBREC を受信済み、3 はまだ確認応答を待っているため、2 が拒否されたもの
+        assert!(tracker.rejected(QoS::ExactlyOnce, &HashSet::from([3])));
+        assert!(!tracker.inflight.contains_key(&2));
+        assert!(tracker.inflight.contains_key(&1) && tracker.inflight.contains_key(&3));
+        // QoS が異なる拒否は QoS 2 のメッセージに当てはめない
+        assert!(tracker.rejected(QoS::AtLeastOnce, &HashSet::new()));
+        assert!(tracker.inflight.contains_key(&3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn downgrade_stops_tracking_unreleased_messages_and_republishes_them_with_qos_1() {
+        let config = config("qos2_timeout_policy: downgrade-to-qos1\n");
+        let (client, mut eventloop) = mqtt_utils::create_client(&config, 10);
+        let mut tracker = Qos2Tracker::new(&config).unwrap();
+        let client = PublishClient { client, log: Some(tracker.log.clone()) };
+        for topic in ["released", "stuck"] {
+            log(&tracker, topic, QoS::ExactlyOnce);
+        }
+        tracker.sent(1);
+        tracker.sent(2);
+        tracker.received_pubrec(1);
+        assert_eq!(tracker.next_deadline(), Some(time::Instant::now() + Duration::from_secs(5)));
+
+        time::advance(Duration::from_secs(5)).await;
+        // PUBREC を受信した 1 は完了として数え、この後の PUBCOMP は数えない
+        assert_eq!(tracker.expire(&mut eventloop, &client), 1);
+        assert_eq!(tracker.stuck, 2);
+        assert_eq!(tracker.next_deadline(), None);
+        assert!(!tracker.completed(1));
+        // PUBREC を受信していない 2 は追跡をやめ、QoS 1 で発行し直す
+        assert!(!tracker.inflight.contains_key(&2));
+        tokio::task::yield_now().await;
+        let republished = tracker.sent_rx.try_recv().unwrap();
+        assert_eq!((republished.topic.as_str(), republished.qos), ("stuck", QoS::AtLeastOnce));
+    }
+
+    #[test]
+    fn pacer_finishes_after_publish_count_or_the_default() {
+        let mut pacer = Pacer::new(&config(""));
+        pacer.rounds = 1;
+        assert!(!pacer.finished(None));
+        assert!(pacer.finished(Some(1)));
+        let mut pacer = Pacer::new(&config("publish_count: 3\n"));
+        pacer.rounds = 2;
+        assert!(!pacer.finished(Some(1)));
+        pacer.rounds = 3;
+        assert!(pacer.finished(Some(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pacer_waits_for_each_interval_and_counts_late_rounds() {
+        let mut pacer = Pacer::new(&config("publish_interval_ms: 100\n"));
+        let start = time::Instant::now();
+        pacer.wait().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        pacer.wait().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        // 予定の時刻 (200 ms) から 1 間隔未満の遅れは数えない
+        time::advance(Duration::from_millis(150)).await;
+        pacer.wait().await;
+        assert_eq!(pacer.late, 0);
+        // 予定の時刻 (300 ms) から 1 間隔以上遅れた回を数え、飛ばした刻みはまとめて発行しない
+        time::advance(Duration::from_millis(250)).await;
+        pacer.wait().await;
+        assert_eq!(pacer.late, 1);
+        pacer.wait().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(600));
+        assert_eq!((pacer.rounds, pacer.late), (5, 1));
+        assert_eq!(pacer.last.unwrap().duration_since(pacer.started.unwrap()), Duration::from_millis(600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pacer_without_interval_does_not_wait() {
+        let mut pacer = Pacer::new(&config(""));
+        let start = time::Instant::now();
+        for _ in 0..3 {
+            pacer.wait().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!((pacer.rounds, pacer.late), (3, 0));
+    }
+}
@@ -0,0 +1,169 @@
+use super::config_utils::{Config, QosRule};
+use super::topic_utils::topic_matches;
+use rumqttc::QoS;
+use serde::{de, Deserialize, Deserializer};
+
+// 設定ファイルの QoS 値 (0〜2) を rumqttc::QoS 型に変換する
+pub fn to_qos(value: i32) -> Option<QoS> {
+    match value {
+        0 => Some(QoS::AtMostOnce),
+        1 => Some(QoS::AtLeastOnce),
+        2 => Some(QoS::ExactlyOnce),
+        _ => None,
+    }
+}
+
+/// QoS の文字列表現 ("at_most_once" / "at_least_once" / "exactly_once" または "0" / "1" / "2") を変換する
+pub fn parse_qos(value: &str) -> Option<QoS> {
+    match value {
+        "0" | "at_most_once" => Some(QoS::AtMostOnce),
+        "1" | "at_least_once" => Some(QoS::AtLeastOnce),
+        "2" | "exactly_once" => Some(QoS::ExactlyOnce),
+        _ => None,
+    }
+}
+
+/// QoS を数値 (0〜2) で表す
+pub fn qos_number(qos: QoS) -> u8 {
+    match qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+        QoS::ExactlyOnce => 2,
+    }
+}
+
+// 設定ファイルに記述された QoS (整数または文字列)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawQos {
+    Number(i64),
+    Name(String),
+}
+
+impl RawQos {
+    fn to_qos(&self) -> Option<QoS> {
+        match self {
+            RawQos::Number(n) => i32::try_from(*n).ok().and_then(to_qos),
+            RawQos::Name(name) => parse_qos(name),
+        }
+    }
+}
+
+impl std::fmt::Display for RawQos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RawQos::Number(n) => write!(f, "{}", n),
+            RawQos::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+fn invalid_qos<E: de::Error>(value: &RawQos, position: &str) -> E {
+    E::custom(format!("{}不正な QoS 値 '{}' (0〜2 または at_most_once / at_least_once / exactly_once を指定してください)", position, value))
+}
+
+/// 設定ファイルの QoS (整数または文字列) を読み込む serde のデシリアライザー
+pub fn deserialize_qos<'de, D: Deserializer<'de>>(deserializer: D) -> Result<QoS, D::Error> {
+    let raw = RawQos::deserialize(deserializer)?;
+    raw.to_qos().ok_or_else(|| invalid_qos(&raw, ""))
+}
+
+/// `Option<QoS>` 用の `deserialize_qos`
+pub fn deserialize_optional_qos<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<QoS>, D::Error> {
+    match Option::<RawQos>::deserialize(deserializer)? {
+        Some(raw) => raw.to_qos().map(Some).ok_or_else(|| invalid_qos(&raw, "")),
+        None => Ok(None),
+    }
+}
+
+/// `Option<Vec<QoS>>` 用の `deserialize_qos`
+pub fn deserialize_optional_qos_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<QoS>>, D::Error> {
+    match Option::<Vec<RawQos>>::deserialize(deserializer)? {
+        Some(list) => list.iter().map(|raw| raw.to_qos().ok_or_else(|| invalid_qos(raw, ""))).collect::<Result<_, _>>().map(Some),
+        None => Ok(None),
+    }
+}
+
+/// QoS のリスト用の `deserialize_qos`。不正な値はリスト内の位置 (0 始まり) とともにエラーにする
+pub fn deserialize_qos_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<QoS>, D::Error> {
+    Vec::<RawQos>::deserialize(deserializer)?
+        .iter()
+        .enumerate()
+        .map(|(i, raw)| raw.to_qos().ok_or_else(|| invalid_qos(raw, &format!("qos[{}]: ", i))))
+        .collect()
+}
+
+/// 購読する各トピックの QoS を決定する。
+///
+/// `qos_override` (コマンドラインの `--qos`) が指定されている場合は、すべてのトピックにその QoS を適用する。
+/// `qos_rules` が指定されている場合はルールを上から順に評価し、最初に一致したルールの QoS を使用する
+/// (一致しない場合は `default_qos`、未指定なら QoS 0)。指定されていない場合は `qos` リストを使用する。
+pub fn resolve_qos(config: &Config) -> Vec<QoS> {
+    if let Some(qos) = config.qos_override {
+        return vec![qos; config.topics.len()];
+    }
+    match &config.qos_rules {
+        Some(rules) => resolve_qos_by_rules(config, rules),
+        None => resolve_qos_by_list(config),
+    }
+}
+
+fn resolve_qos_by_rules(config: &Config, rules: &[QosRule]) -> Vec<QoS> {
+    let default_qos = config.default_qos.unwrap_or(QoS::AtMostOnce);
+
+    for rule in rules {
+        if !config.topics.iter().any(|topic| topic_matches(&rule.pattern, topic)) {
+            eprintln!("警告: qos_rules のパターン '{}' はどのトピックにも一致しません。", rule.pattern);
+        }
+    }
+
+    config.topics.iter().map(|topic| {
+        rules.iter()
+            .find(|rule| topic_matches(&rule.pattern, topic))
+            .map(|rule| rule.qos)
+            .unwrap_or(default_qos)
+    }).collect()
+}
+
+// qos の数は Config::validate で検査済み (topics と同じ数、1 つ、または空)
+fn resolve_qos_by_list(config: &Config) -> Vec<QoS> {
+    match config.qos.as_slice() {
+        [] => vec![QoS::AtMostOnce; config.topics.len()], // デフォルトで QoS 0 を適用
+        [qos] => vec![*qos; config.topics.len()],
+        qos => qos.to_vec(),
+    }
+}
+
+/// messages で QoS を省略したメッセージを発行する QoS を決定する。
+///
+/// `qos_override`、`qos_rules` (一致しない場合は `default_qos`)、`qos` の先頭の順に使用し、いずれもなければ QoS 0 とする。
+pub fn resolve_message_qos(config: &Config, topic: &str) -> QoS {
+    if let Some(qos) = config.qos_override {
+        return qos;
+    }
+    match &config.qos_rules {
+        Some(rules) => rules.iter()
+            .find(|rule| topic_matches(&rule.pattern, topic))
+            .map(|rule| rule.qos)
+            .unwrap_or(config.default_qos.unwrap_or(QoS::AtMostOnce)),
+        None => config.qos.first().copied().unwrap_or(QoS::AtMostOnce),
+    }
+}
+
+/// enforce_min_qos のルールでトピックに求められる QoS の下限 (一致するルールが複数ある場合は最も高いもの)
+pub fn min_qos_for(config: &Config, topic: &str) -> Option<QoS> {
+    config.enforce_min_qos.as_ref()?.iter()
+        .filter(|rule| topic_matches(&rule.pattern, topic))
+        .map(|rule| rule.min_qos)
+        .max_by_key(|qos| qos_number(*qos))
+}
+
+/// 購読するトピックと QoS のうち、enforce_min_qos の下限より低いものについてエラーメッセージを返す
+pub fn min_qos_violations(config: &Config, filters: &[(String, QoS)]) -> Vec<String> {
+    filters.iter().filter_map(|(topic, qos)| {
+        let min_qos = min_qos_for(config, topic)?;
+        (qos_number(*qos) < qos_number(min_qos)).then(|| format!(
+            "トピック '{}' は QoS {} で購読する設定ですが、enforce_min_qos により QoS {} 以上が必要です。",
+            topic, qos_number(*qos), qos_number(min_qos)))
+    }).collect()
+}
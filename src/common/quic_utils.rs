@@ -0,0 +1,155 @@
+// 実験的な MQTT over QUIC (scheme: quic、quic フィーチャー)
+//
+// rumqttc には QUIC のトランスポートがないため、ローカルの TCP のリスナー (127.0.0.1) で rumqttc からの接続を受け付け、
+// 接続ごとにブローカーとの QUIC 接続を確立して、1 つの双方向ストリームで MQTT のパケットをそのまま中継する。
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use super::tls_utils;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Endpoint};
+use rustls::client::{danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, WebPkiServerVerifier};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
+
+// tls_alpn_protocols が未指定の場合に提示する ALPN プロトコル (MQTT over QUIC に対応するブローカーの多くが使用する)
+const DEFAULT_QUIC_ALPN: &str = "mqtt";
+// 接続を受け付けられなかった場合 (ファイルディスクリプタの上限など) に、次に受け付けるまで待つ時間
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// QUIC でブローカーに中継するローカルのリスナーを起動し、rumqttc が接続するアドレスとポートを返す
+pub fn quic_endpoint(config: &Config) -> (String, u16) {
+    eprintln!("警告: scheme: quic は実験的な機能です。対応しているブローカーと接続方式が限られます。");
+    let client_config = build_client_config(config);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .and_then(TcpListener::from_std)
+        .unwrap_or_else(|e| exit_with(AppError::Config(format!("QUIC の中継用のポートを開けませんでした: {}", e))));
+    let port = listener.local_addr().map(|addr| addr.port()).unwrap_or_default();
+    let target = (config.broker_address.clone(), config.broker_port);
+    tokio::spawn(relay(listener, client_config, target));
+    ("127.0.0.1".to_string(), port)
+}
+
+// TLS の設定 (CA証明書・クライアント証明書・ALPN) は scheme: mqtts と同じ項目から構築する。QUIC では TLS 1.3 が必須
+fn build_client_config(config: &Config) -> ClientConfig {
+    let mut root_store = rustls::RootCertStore::empty();
+    for cert in tls_utils::load_ca_certs(config) {
+        root_store.add(cert).unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("CA証明書の追加中にエラーが発生しました: {}", e)));
+        });
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let pins = tls_utils::pinned_cert_sha256(config);
+    let builder = rustls::ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap_or_else(|e| exit_with(AppError::Config(format!("QUIC の TLS 設定に失敗しました: {}", e))));
+    let builder = if pins.is_empty() {
+        builder.with_root_certificates(root_store)
+    } else {
+        let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(root_store), provider).build().unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("QUIC の TLS 接続のサーバー証明書の検証を準備できませんでした (CA 証明書を確認してください): {}", e)));
+        });
+        builder.dangerous().with_custom_certificate_verifier(Arc::new(PinnedCertVerifier { inner, pins }))
+    };
+    let mut tls_config = match tls_utils::load_client_identity(config) {
+        Some((certs, key)) => builder.with_client_auth_cert(certs, key).unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("クライアント認証の設定に失敗しました: {}", e)));
+        }),
+        None => builder.with_no_client_auth(),
+    };
+    tls_config.alpn_protocols = match &config.tls_alpn_protocols {
+        Some(protocols) => protocols.iter().map(|p| p.as_bytes().to_vec()).collect(),
+        None => vec![DEFAULT_QUIC_ALPN.as_bytes().to_vec()],
+    };
+    let crypto = QuicClientConfig::try_from(tls_config)
+        .unwrap_or_else(|e| exit_with(AppError::Config(format!("QUIC の TLS 設定に失敗しました: {}", e))));
+    ClientConfig::new(Arc::new(crypto))
+}
+
+// tls_pinned_cert_sha256 が指定されている場合、CA による検証に成功した後にリーフ証明書のフィンガープリントを照合する
+// (scheme: mqtts の検証と同じだが、quinn が使う rustls は rumqttc と版が異なるため別に実装している)
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        tls_utils::check_pinned_cert(&self.pins, end_entity).map_err(rustls::Error::General)?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+// rumqttc からの接続を受け付け、ブローカーとの QUIC のストリームとの間でデータを中継する
+async fn relay(listener: TcpListener, client_config: ClientConfig, target: (String, u16)) {
+    loop {
+        let local = match listener.accept().await {
+            Ok((local, _)) => local,
+            Err(e) => {
+                eprintln!("警告: QUIC の中継用のポートで接続を受け付けられませんでした: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let client_config = client_config.clone();
+        let (host, port) = target.clone();
+        tokio::spawn(async move {
+            // 中継用の接続を閉じると rumqttc の接続エラーになり、通常の再接続処理が行われる
+            if let Err(e) = relay_connection(local, client_config, &host, port).await {
+                eprintln!("QUIC でブローカー {}:{} に接続できませんでした: {}", host, port, e);
+            }
+        });
+    }
+}
+
+async fn relay_connection(mut local: TcpStream, client_config: ClientConfig, host: &str, port: u16) -> io::Result<()> {
+    let addr: SocketAddr = tokio::net::lookup_host((host, port)).await?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{} の名前解決に失敗しました", host)))?;
+    let bind: SocketAddr = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse().expect("固定のアドレス");
+    let mut endpoint = Endpoint::client(bind)?;
+    endpoint.set_default_client_config(client_config);
+    let connection = endpoint.connect(addr, host)
+        .map_err(io::Error::other)?
+        .await
+        .map_err(io::Error::other)?;
+    let (mut send, mut recv) = connection.open_bi().await.map_err(io::Error::other)?;
+
+    let (mut local_read, mut local_write) = local.split();
+    let upstream = async {
+        tokio::io::copy(&mut local_read, &mut send).await?;
+        // rumqttc が接続を閉じたらストリームの送信を終える
+        send.finish().map_err(io::Error::other)
+    };
+    let downstream = tokio::io::copy(&mut recv, &mut local_write);
+    let result = tokio::select! {
+        result = upstream => result,
+        result = downstream => result.map(|_| ()),
+    };
+    connection.close(0u32.into(), b"");
+    endpoint.wait_idle().await;
+    result
+}
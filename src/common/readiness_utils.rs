@@ -0,0 +1,138 @@
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use super::history_utils::MessageHistory;
+use super::url_utils;
+use std::{fs, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, time};
+
+// readiness_bind_address が未指定の場合に待ち受けるアドレス (/history で受信したメッセージを返すため、ローカルホストのみ)
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+// 接続を受け付けられなかった場合 (ファイルディスクリプタの上限など) に、次に受け付けるまで待つ時間
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Kubernetes などの readiness probe に準備完了状態を公開する。
+///
+/// すべてのセッションがブローカーに接続し、すべてのトピックの購読が完了している間だけ準備完了とし、
+/// `readiness_file` にはファイルを作成し、`readiness_port` では HTTP 200 を返す (それ以外は 503)。
+/// history_size が指定されている場合は、`readiness_port` の `/history` で直近に受信したメッセージも返す。
+pub struct Readiness {
+    file: Option<PathBuf>,
+    ready: Arc<AtomicBool>,
+    // セッションごとの準備完了状態
+    sessions: Mutex<Vec<bool>>,
+}
+
+impl Readiness {
+    /// readiness_file / readiness_port のどちらも指定されていない場合は `None` を返す
+    pub async fn start(config: &Config, sessions: usize, history: Option<Arc<MessageHistory>>) -> Option<Arc<Readiness>> {
+        if config.readiness_file.is_none() && config.readiness_port.is_none() {
+            return None;
+        }
+
+        let readiness = Arc::new(Readiness {
+            file: config.readiness_file.as_ref().map(PathBuf::from),
+            ready: Arc::new(AtomicBool::new(false)),
+            sessions: Mutex::new(vec![false; sessions]),
+        });
+        // 前回の実行で残ったファイルを削除する
+        readiness.remove_file();
+
+        if let Some(port) = config.readiness_port {
+            let address = config.readiness_bind_address.as_deref().unwrap_or(DEFAULT_BIND_ADDRESS);
+            let listener = TcpListener::bind((address, port)).await.unwrap_or_else(|e| {
+                exit_with(AppError::Config(format!("readiness_port {} での待ち受けを {} で開始できませんでした: {}", port, address, e)));
+            });
+            crate::status!("readiness probe を {}:{} で待ち受けています。", address, port);
+            tokio::spawn(serve(listener, Arc::clone(&readiness.ready), history));
+        }
+
+        Some(readiness)
+    }
+
+    /// セッション (0 始まりの番号) の準備完了状態を更新する
+    pub fn set(&self, session: usize, ready: bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions[session] = ready;
+        let all_ready = sessions.iter().all(|r| *r);
+        if self.ready.swap(all_ready, Ordering::Relaxed) == all_ready {
+            return;
+        }
+        if all_ready {
+            if let Some(file) = &self.file
+                && let Err(e) = fs::write(file, b"") {
+                eprintln!("readiness_file '{}' の作成中にエラーが発生しました: {}", file.display(), e);
+            }
+        } else {
+            self.remove_file();
+        }
+    }
+
+    /// 終了時に準備完了状態を解除する
+    pub fn shutdown(&self) {
+        self.ready.store(false, Ordering::Relaxed);
+        self.remove_file();
+    }
+
+    fn remove_file(&self) {
+        if let Some(file) = &self.file
+            && let Err(e) = fs::remove_file(file)
+            && e.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("readiness_file '{}' の削除中にエラーが発生しました: {}", file.display(), e);
+        }
+    }
+}
+
+// /history (history_size が指定されている場合) 以外のパスには、準備完了なら 200、それ以外は 503 を返す
+async fn serve(listener: TcpListener, ready: Arc<AtomicBool>, history: Option<Arc<MessageHistory>>) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("警告: readiness_port への接続を受け付けられませんでした: {}", e);
+                time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let ready = Arc::clone(&ready);
+        let history = history.clone();
+        tokio::spawn(async move {
+            // リクエストを読まずに閉じると接続がリセットされることがあるため、先頭は必ず読む
+            let mut buf = [0u8; 1024];
+            let read = time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await.ok().and_then(Result::ok).unwrap_or(0);
+            let target = request_target(&buf[..read]);
+            let (status, content_type, body) = match (&history, target.as_deref().and_then(|t| t.strip_prefix("/history"))) {
+                (Some(history), Some(query)) if query.is_empty() || query.starts_with('?') => match history_filter(query) {
+                    Ok(filter) => ("200 OK", "application/json", history.to_json(filter.as_deref()) + "\n"),
+                    Err(e) => ("400 Bad Request", "text/plain", e + "\n"),
+                },
+                _ if ready.load(Ordering::Relaxed) => ("200 OK", "text/plain", "ready\n".to_string()),
+                _ => ("503 Service Unavailable", "text/plain", "not ready\n".to_string()),
+            };
+            let response = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, content_type, body.len(), body);
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+// リクエスト行 (GET /path?query HTTP/1.1) のパスとクエリ
+fn request_target(request: &[u8]) -> Option<String> {
+    let line = request.split(|b| *b == b'\n').next()?;
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split_whitespace();
+    parts.next()?;
+    parts.next().map(str::to_string)
+}
+
+// /history のクエリの topic (% エンコードをデコードする。ワイルドカードも指定できる)
+fn history_filter(query: &str) -> Result<Option<String>, String> {
+    let query = query.trim_start_matches('?');
+    for pair in query.split('&') {
+        if let Some(value) = pair.strip_prefix("topic=") {
+            return url_utils::percent_decode(value).map(Some).map_err(|e| format!("topic が不正です: {}", e));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,160 @@
+// 再接続を試行し続ける時間の上限 (max_reconnect_duration_secs) と、起動時の接続の試行回数の上限 (startup_max_attempts)
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// 接続エラーが続いている時間を最初のエラーから計り、max_reconnect_duration_secs を超えたら終了する。
+/// 接続に成功する (CONNACK を受信する) と計り直す。時間は単調増加の時計で計るため、システムの時刻の変更の影響を受けない
+#[derive(Debug)]
+pub struct ReconnectDeadline {
+    limit: Duration,
+    // 接続に成功してから最初の接続エラーの時刻 (接続中は `None`)
+    failing_since: Option<Instant>,
+}
+
+impl ReconnectDeadline {
+    /// max_reconnect_duration_secs が指定されていない場合は `None` を返す
+    pub fn new(config: &Config) -> Option<ReconnectDeadline> {
+        let secs = config.max_reconnect_duration_secs?;
+        Some(ReconnectDeadline { limit: Duration::from_secs(secs), failing_since: None })
+    }
+
+    /// 接続に成功した
+    pub fn connected(&mut self) {
+        self.failing_since = None;
+    }
+
+    /// 接続エラー (`error`) が発生した。最初のエラーから max_reconnect_duration_secs を超えている場合は終了コード 75 で終了する
+    pub fn failed(&mut self, error: &str) {
+        if let Some(elapsed) = self.expired() {
+            exit_with(AppError::RetriesExhausted(format!(
+                "最初の接続エラーから {} 秒間 (max_reconnect_duration_secs: {}) 再接続できなかったため終了します。最後のエラー: {}",
+                elapsed.as_secs(), self.limit.as_secs(), error)));
+        }
+    }
+
+    // 接続エラーを記録し、最初のエラーから上限を超えている場合はその経過時間を返す
+    fn expired(&mut self) -> Option<Duration> {
+        let since = *self.failing_since.get_or_insert_with(Instant::now);
+        let elapsed = since.elapsed();
+        (elapsed >= self.limit).then_some(elapsed)
+    }
+}
+
+/// startup_retry_delay_ms の既定値
+pub const DEFAULT_STARTUP_RETRY_DELAY_MS: u64 = 1000;
+
+/// 起動時の最初の接続を startup_max_attempts 回まで試行し、すべて失敗した場合は終了する。
+/// 一度接続に成功した後の接続エラーは通常の再接続として扱う (この上限は適用しない)
+#[derive(Debug)]
+pub struct StartupRetry {
+    max_attempts: u32,
+    delay: Duration,
+    attempts: u32,
+    connected: bool,
+}
+
+impl StartupRetry {
+    /// startup_max_attempts が指定されていない場合は `None` を返す
+    pub fn new(config: &Config) -> Option<StartupRetry> {
+        let max_attempts = config.startup_max_attempts?;
+        let delay = Duration::from_millis(config.startup_retry_delay_ms.unwrap_or(DEFAULT_STARTUP_RETRY_DELAY_MS));
+        Some(StartupRetry { max_attempts, delay, attempts: 0, connected: false })
+    }
+
+    /// 接続に成功した
+    pub fn connected(&mut self) {
+        self.connected = true;
+    }
+
+    /// 接続エラー (`error`) が発生した。起動時の接続の失敗であれば、次の試行までの待ち時間を返す
+    /// (一度接続した後の場合は `None`)。試行回数の上限に達した場合は終了コード 69 で終了する
+    pub fn failed(&mut self, error: &str) -> Option<Duration> {
+        if !self.count_failure() {
+            return None;
+        }
+        if self.attempts >= self.max_attempts {
+            exit_with(AppError::Unavailable(format!(
+                "起動時にブローカーへの接続を {} 回試行しましたが接続できなかったため終了します (startup_max_attempts)。最後のエラー: {}",
+                self.attempts, error)));
+        }
+        eprintln!("起動時の接続に失敗しました ({}/{} 回目): {}。{} ms 後に再試行します。",
+            self.attempts, self.max_attempts, error, self.delay.as_millis());
+        Some(self.delay)
+    }
+
+    // 起動時の接続の失敗であれば試行回数に数えて `true` を返す
+    fn count_failure(&mut self) -> bool {
+        if self.connected {
+            return false;
+        }
+        self.attempts += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("client_id: reconnect
+broker_address: localhost
+broker_port: 1883
+{}", yaml)).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_expires_at_the_limit_from_the_first_error() {
+        let mut deadline = ReconnectDeadline::new(&config("max_reconnect_duration_secs: 10\n")).unwrap();
+        assert_eq!(deadline.expired(), None);
+        tokio::time::advance(Duration::from_millis(9_999)).await;
+        assert_eq!(deadline.expired(), None);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(deadline.expired(), Some(Duration::from_secs(10)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_restarts_after_connecting() {
+        let mut deadline = ReconnectDeadline::new(&config("max_reconnect_duration_secs: 10\n")).unwrap();
+        assert_eq!(deadline.expired(), None);
+        tokio::time::advance(Duration::from_secs(8)).await;
+        deadline.connected();
+        tokio::time::advance(Duration::from_secs(8)).await;
+        // 接続した後の最初のエラーから計り直す
+        assert_eq!(deadline.expired(), None);
+        tokio::time::advance(Duration::from_secs(9)).await;
+        assert_eq!(deadline.expired(), None);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(deadline.expired().is_some());
+    }
+
+    #[test]
+    fn deadline_is_disabled_without_max_reconnect_duration_secs() {
+        assert!(ReconnectDeadline::new(&config("")).is_none());
+    }
+
+    #[test]
+    fn startup_retry_counts_attempts_until_connected() {
+        let mut startup = StartupRetry::new(&config("startup_max_attempts: 3\nstartup_retry_delay_ms: 250\n")).unwrap();
+        assert_eq!(startup.delay, Duration::from_millis(250));
+        assert_eq!(startup.failed("connection refused"), Some(Duration::from_millis(250)));
+        assert_eq!(startup.failed("connection refused"), Some(Duration::from_millis(250)));
+        assert_eq!(startup.attempts, 2);
+        // 次 (3 回目) の失敗で上限に達して終了する
+        assert_eq!(startup.attempts + 1, startup.max_attempts);
+        startup.connected();
+        // 一度接続した後の失敗は数えず、再試行の待ち時間も返さない
+        assert!(!startup.count_failure());
+        assert_eq!(startup.failed("connection refused"), None);
+        assert_eq!(startup.attempts, 2);
+    }
+
+    #[test]
+    fn startup_retry_uses_the_default_delay() {
+        let startup = StartupRetry::new(&config("startup_max_attempts: 2\n")).unwrap();
+        assert_eq!(startup.delay, Duration::from_millis(DEFAULT_STARTUP_RETRY_DELAY_MS));
+        assert!(StartupRetry::new(&config("")).is_none());
+    }
+}
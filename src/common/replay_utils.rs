@@ -0,0 +1,136 @@
+// 受信したメッセージの記録の標準出力への再生 (sub --replay)
+//
+// 記録は output_format: json (1 行に 1 メッセージの JSON Lines) または json-array で出力したファイルとし、
+// ブローカーには接続せずに、各メッセージを記録した時刻 (timestamp) の間隔を再現して output_format の形式で標準出力に出力する。
+use super::config_utils::Config;
+use super::error_utils::AppError;
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils;
+use super::qos_utils;
+use super::sink_utils::{self, Sinks};
+use super::template_utils::Template;
+use bytes::Bytes;
+use serde::Deserialize;
+use std::{fs::File, io::{BufRead, BufReader, Read}, time::{Duration, SystemTime}};
+use tokio::time::{self, Instant};
+
+/// 再生した結果
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplaySummary {
+    pub replayed: u64,
+    /// 読めなかったため読み飛ばした記録の数
+    pub skipped: u64,
+}
+
+// 記録の 1 件 (format_json の出力)。payload_len などの再生に使わない項目は無視する
+#[derive(Debug, Deserialize)]
+struct Record {
+    timestamp: Option<String>,
+    topic: String,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+    #[serde(default)]
+    payload: String,
+}
+
+/// `path` の記録を標準出力に再生する。
+///
+/// `speed` が指定されている場合は記録の時刻の間隔をその倍率で縮めて (2.0 なら 2 倍の速さで) 待ち、`None` の場合は待たずに出力する。
+/// 時刻が読めない記録や前の記録より古い時刻の記録は待たずに出力する。出力の timestamp は記録した時刻のままにする。
+pub async fn replay(config: &Config, path: &str, speed: Option<f64>) -> Result<ReplaySummary, AppError> {
+    let file = File::open(path).map_err(|e| AppError::Config(format!("記録のファイル '{}' を開けませんでした: {}", path, e)))?;
+    let mut reader = BufReader::new(file);
+    let read_error = |e: std::io::Error| AppError::Config(format!("記録のファイル '{}' の読み込み中にエラーが発生しました: {}", path, e));
+
+    let mut player = Player { config, sinks: Sinks::new(config), template: output_utils::output_template(config), speed, origin: None, summary: ReplaySummary::default() };
+    let mut first = String::new();
+    reader.read_line(&mut first).map_err(read_error)?;
+    if first.trim_start().starts_with('[') {
+        // json-array の記録は全体で 1 つの配列のため、まとめて読み込む
+        reader.read_to_string(&mut first).map_err(read_error)?;
+        let records: Vec<serde_json::Value> = serde_json::from_str(&first)
+            .map_err(|e| AppError::Config(format!("記録のファイル '{}' を JSON の配列として読めませんでした: {}", path, e)))?;
+        for (index, record) in records.into_iter().enumerate() {
+            if let Err(e) = player.play(serde_json::from_value(record), format!("{} 件目", index + 1)).await {
+                return stop(e, player.summary);
+            }
+        }
+    } else {
+        let mut line_number = 1;
+        let mut line = first;
+        loop {
+            if !line.trim().is_empty()
+                && let Err(e) = player.play(serde_json::from_str(&line), format!("{} 行目", line_number)).await {
+                return stop(e, player.summary);
+            }
+            line.clear();
+            if reader.read_line(&mut line).map_err(read_error)? == 0 {
+                break;
+            }
+            line_number += 1;
+        }
+    }
+    Ok(player.summary)
+}
+
+// 標準出力に書き込めなくなったため再生を終える。読み取り側のプロセスが終了した場合は、それまでの再生の結果を返す
+fn stop(e: std::io::Error, summary: ReplaySummary) -> Result<ReplaySummary, AppError> {
+    if sink_utils::is_stdout_closed(&e) {
+        eprintln!("標準出力の読み取り側が閉じられたため、再生を終了します。");
+        return Ok(summary);
+    }
+    Err(AppError::Unavailable(format!("標準出力に書き込めませんでした: {}", e)))
+}
+
+struct Player<'a> {
+    config: &'a Config,
+    sinks: Sinks,
+    template: Option<Template>,
+    speed: Option<f64>,
+    // 最初の記録の時刻と、それを出力した時刻 (以降の記録はこの時刻からの経過で待つ)
+    origin: Option<(SystemTime, Instant)>,
+    summary: ReplaySummary,
+}
+
+impl Player<'_> {
+    // `position` は警告に表示する記録の位置 (JSON Lines の場合は行番号、json-array の場合は配列の何件目か)
+    // 標準出力に書き込めなかった場合はエラーを返す
+    async fn play(&mut self, record: Result<Record, serde_json::Error>, position: String) -> std::io::Result<()> {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("警告: 記録の {}を読めないため読み飛ばします: {}", position, e);
+                self.summary.skipped += 1;
+                return Ok(());
+            }
+        };
+        let recorded_at = record.timestamp.as_deref().and_then(output_utils::parse_timestamp);
+        if let (Some(speed), Some(recorded_at)) = (self.speed, recorded_at) {
+            match self.origin {
+                Some((first, started)) => {
+                    // 記録の時刻が前後している場合は待たない
+                    if let Ok(offset) = recorded_at.duration_since(first) {
+                        time::sleep_until(started + Duration::from_secs_f64(offset.as_secs_f64() / speed)).await;
+                    }
+                }
+                None => self.origin = Some((recorded_at, Instant::now())),
+            }
+        }
+
+        let message = ReceivedMessage {
+            topic: record.topic,
+            payload: Bytes::from(record.payload),
+            qos: qos_utils::to_qos(i32::from(record.qos)).unwrap_or(rumqttc::QoS::AtMostOnce),
+            retain: record.retain,
+            pkid: 0,
+            properties: None,
+            acker: None,
+        };
+        let output = output_utils::format_message_at(&message, self.config, self.template.as_ref(), recorded_at.unwrap_or_else(SystemTime::now));
+        self.sinks.write(&message, &output, self.config)?;
+        self.summary.replayed += 1;
+        Ok(())
+    }
+}
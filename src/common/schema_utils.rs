@@ -0,0 +1,229 @@
+// 受信した JSON のペイロードの JSON Schema による検証 (schema、schema フィーチャーが必要) と、
+// パブリッシャーが発行するペイロードの形式の説明 (publish_schema)
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::ReceivedMessage;
+use super::topic_utils::topic_matches;
+
+// 1 件のメッセージについて表示・記録する検証エラーの最大数
+#[cfg(feature = "schema")]
+const MAX_REPORTED_ERRORS: usize = 10;
+
+/// スキーマの検証に失敗した箇所
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    /// 失敗した値の位置 (JSON Pointer。ペイロード全体の場合は空文字列)
+    pub path: String,
+    pub message: String,
+}
+
+/// スキーマに適合しなかったメッセージの検証結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaFailure {
+    /// 一致したルールのスキーマファイル
+    pub schema: String,
+    pub violations: Vec<SchemaViolation>,
+}
+
+/// schema のルール (トピックパターンと JSON Schema のファイル) ごとのバリデーター。
+///
+/// ルールは上から順に評価し、最初に一致したルールのスキーマで検証する。どのルールにも一致しないトピックは検証しない。
+pub struct SchemaValidator {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    pattern: String,
+    file: String,
+    #[cfg(feature = "schema")]
+    validator: jsonschema::Validator,
+}
+
+impl SchemaValidator {
+    /// schema が指定されていない場合は `None` を返す。スキーマファイルを読み込めない場合はエラーを表示して終了する
+    pub fn new(config: &Config) -> Option<SchemaValidator> {
+        let rules = config.schema.as_ref().filter(|rules| !rules.is_empty())?;
+        if !cfg!(feature = "schema") {
+            exit_with(AppError::Config("schema を使用するには schema フィーチャーを有効にしてビルドしてください (cargo build --features schema)。".to_string()));
+        }
+        let rules = rules.iter().map(|rule| Rule {
+            pattern: rule.pattern.clone(),
+            file: rule.file.clone(),
+            #[cfg(feature = "schema")]
+            validator: compile(&rule.file),
+        }).collect();
+        Some(SchemaValidator { rules })
+    }
+
+    /// メッセージのトピックに一致するルールのスキーマで検証し、適合しなかった場合はその内容を返す
+    /// (JSON として解析できないペイロードも適合しないものとする)
+    pub fn validate(&self, message: &ReceivedMessage) -> Option<SchemaFailure> {
+        let rule = self.rules.iter().find(|rule| topic_matches(&rule.pattern, &message.topic))?;
+        let violations = rule.violations(&message.payload);
+        (!violations.is_empty()).then(|| SchemaFailure { schema: rule.file.clone(), violations })
+    }
+}
+
+impl Rule {
+    #[cfg(feature = "schema")]
+    fn violations(&self, payload: &[u8]) -> Vec<SchemaViolation> {
+        let value: serde_json::Value = match serde_json::from_slice(payload) {
+            Ok(value) => value,
+            Err(e) => return vec![SchemaViolation { path: String::new(), message: format!("ペイロードが JSON ではありません: {}", e) }],
+        };
+        self.validator.iter_errors(&value)
+            .take(MAX_REPORTED_ERRORS)
+            .map(|error| SchemaViolation { path: error.instance_path().to_string(), message: error.to_string() })
+            .collect()
+    }
+
+    #[cfg(not(feature = "schema"))]
+    fn violations(&self, _payload: &[u8]) -> Vec<SchemaViolation> {
+        unreachable!("schema フィーチャーが無効な場合は SchemaValidator を生成しない")
+    }
+}
+
+#[cfg(feature = "schema")]
+fn compile(file: &str) -> jsonschema::Validator {
+    let text = std::fs::read_to_string(file)
+        .unwrap_or_else(|e| exit_with(AppError::Config(format!("スキーマファイル '{}' を読み込めませんでした: {}", file, e))));
+    let schema: serde_json::Value = serde_json::from_str(&text)
+        .unwrap_or_else(|e| exit_with(AppError::Config(format!("スキーマファイル '{}' が JSON として不正です: {}", file, e))));
+    jsonschema::validator_for(&schema)
+        .unwrap_or_else(|e| exit_with(AppError::Config(format!("スキーマファイル '{}' が JSON Schema として不正です: {}", file, e))))
+}
+
+/// schema_deadletter_topic に発行する、スキーマに適合しなかったメッセージの JSON
+/// (元のトピック、スキーマファイル、検証エラー、UTF-8 として読めない部分を置換したペイロード)
+pub fn deadletter_payload(message: &ReceivedMessage, failure: &SchemaFailure) -> String {
+    let errors: Vec<serde_json::Value> = failure.violations.iter()
+        .map(|violation| serde_json::json!({ "path": violation.path, "message": violation.message }))
+        .collect();
+    serde_json::json!({
+        "topic": message.topic,
+        "schema": failure.schema,
+        "errors": errors,
+        "payload": String::from_utf8_lossy(&message.payload),
+    }).to_string()
+}
+
+/// publish_schema の説明を発行するトピックの接尾辞の既定値
+pub const DEFAULT_SCHEMA_TOPIC_SUFFIX: &str = "/$schema";
+
+/// パブリッシャーが発行先のトピックごとに retain で発行する、ペイロードの形式の説明
+#[derive(Debug, Clone)]
+pub struct PublishedSchema {
+    pub topic_suffix: String,
+    /// 整形せずに 1 行にした説明の JSON
+    pub payload: Vec<u8>,
+}
+
+impl PublishedSchema {
+    /// publish_schema が指定されていない場合は `None` を返す。説明を読み込めない場合や JSON (schema フィーチャーが有効な場合は
+    /// JSON Schema) として不正な場合は、発行する前に設定エラーで終了する
+    pub fn new(config: &Config) -> Option<PublishedSchema> {
+        let settings = config.publish_schema.as_ref()?;
+        let (text, source) = match (&settings.schema, &settings.file) {
+            (Some(_), Some(_)) => exit_with(AppError::Config("publish_schema の schema と file は同時に指定できません。".to_string())),
+            (Some(text), None) => (text.clone(), "publish_schema.schema".to_string()),
+            (None, Some(file)) => {
+                let text = std::fs::read_to_string(file).unwrap_or_else(|e| {
+                    exit_with(AppError::Config(format!("publish_schema のファイル '{}' を読み込めませんでした: {}", file, e)))
+                });
+                (text, format!("publish_schema のファイル '{}'", file))
+            }
+            (None, None) => exit_with(AppError::Config("publish_schema には schema または file を指定してください。".to_string())),
+        };
+        let value: serde_json::Value = serde_json::from_str(&text)
+            .unwrap_or_else(|e| exit_with(AppError::Config(format!("{} が JSON として不正です: {}", source, e))));
+        // JSON Schema はオブジェクトまたは真偽値 (content-type などの記述子もオブジェクトとする)
+        if !value.is_object() && !value.is_boolean() {
+            exit_with(AppError::Config(format!("{} には JSON のオブジェクト (JSON Schema または記述子) を指定してください。", source)));
+        }
+        #[cfg(feature = "schema")]
+        if let Err(e) = jsonschema::validator_for(&value) {
+            exit_with(AppError::Config(format!("{} が JSON Schema として不正です: {}", source, e)));
+        }
+        let topic_suffix = settings.topic_suffix.clone().unwrap_or_else(|| DEFAULT_SCHEMA_TOPIC_SUFFIX.to_string());
+        if topic_suffix.is_empty() || topic_suffix.contains(['+', '#']) {
+            exit_with(AppError::Config(format!("publish_schema.topic_suffix '{}' が不正です (空またはワイルドカードを含む接尾辞は指定できません)。", topic_suffix)));
+        }
+        Some(PublishedSchema { topic_suffix, payload: value.to_string().into_bytes() })
+    }
+
+    /// 発行先のトピック `topic` の説明を発行するトピック
+    pub fn topic_for(&self, topic: &str) -> String {
+        format!("{}{}", topic, self.topic_suffix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use rumqttc::QoS;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("client_id: schema\nbroker_address: localhost\nbroker_port: 1883\n{}", yaml)).unwrap()
+    }
+
+    fn message(topic: &str, payload: &'static [u8]) -> ReceivedMessage {
+        ReceivedMessage { topic: topic.to_string(), payload: Bytes::from_static(payload), qos: QoS::AtMostOnce, retain: false, pkid: 0, properties: None, acker: None }
+    }
+
+    #[test]
+    fn deadletter_payload_carries_the_topic_schema_and_errors() {
+        let failure = SchemaFailure {
+            schema: "sensor.json".to_string(),
+            violations: vec![SchemaViolation { path: "/temp".to_string(), message: "\"hot\" is not of type \"number\"".to_string() }],
+        };
+        let payload: serde_json::Value = serde_json::from_str(&deadletter_payload(&message("sensors/1", b"{\"temp\":\"hot\xff\"}"), &failure)).unwrap();
+        assert_eq!(payload, serde_json::json!({
+            "topic": "sensors/1",
+            "schema": "sensor.json",
+            "errors": [{ "path": "/temp", "message": "\"hot\" is not of type \"number\"" }],
+            "payload": "{\"temp\":\"hot\u{FFFD}\"}",
+        }));
+    }
+
+    #[test]
+    fn published_schema_is_compacted_and_uses_the_topic_suffix() {
+        assert!(PublishedSchema::new(&config("")).is_none());
+        let schema = PublishedSchema::new(&config("publish_schema:\n  schema: '{ \"type\": \"object\" }'\n")).unwrap();
+        assert_eq!(schema.payload, br#"{"type":"object"}"#);
+        assert_eq!(schema.topic_for("sensors/1"), "sensors/1/$schema");
+        let schema = PublishedSchema::new(&config("publish_schema:\n  schema: 'true'\n  topic_suffix: /meta\n")).unwrap();
+        assert_eq!(schema.topic_for("sensors/1"), "sensors/1/meta");
+    }
+
+    #[test]
+    fn validator_is_created_only_for_configured_rules() {
+        assert!(SchemaValidator::new(&config("")).is_none());
+        assert!(SchemaValidator::new(&config("schema: []\n")).is_none());
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn messages_are_validated_with_the_first_matching_rule() {
+        let dir = std::env::temp_dir().join(format!("mqtt-client-schema-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let number = dir.join("number.json");
+        let any = dir.join("any.json");
+        std::fs::write(&number, r#"{"type": "object", "properties": {"temp": {"type": "number"}}, "required": ["temp"]}"#).unwrap();
+        std::fs::write(&any, "true").unwrap();
+        let validator = SchemaValidator::new(&config(&format!(
+            "schema:\n  - pattern: sensors/+\n    file: {}\n  - pattern: \"#\"\n    file: {}\n", number.display(), any.display()))).unwrap();
+
+        assert_eq!(validator.validate(&message("sensors/1", br#"{"temp": 21.5}"#)), None);
+        let failure = validator.validate(&message("sensors/1", br#"{"temp": "hot"}"#)).unwrap();
+        assert_eq!(failure.schema, number.display().to_string());
+        assert_eq!(failure.violations.iter().map(|violation| violation.path.as_str()).collect::<Vec<_>>(), ["/temp"]);
+        // JSON として読めないペイロードも適合しない
+        let failure = validator.validate(&message("sensors/1", b"not json")).unwrap();
+        assert_eq!(failure.violations[0].path, "");
+        assert!(failure.violations[0].message.starts_with("ペイロードが JSON ではありません"));
+        // 先に一致したルールだけで検証し、ほかのトピックは後のルールのスキーマで検証する
+        assert!(validator.validate(&message("other/topic", br#"{"temp": "hot"}"#)).is_none());
+        assert_eq!(validator.validate(&message("other/topic", b"not json")).unwrap().schema, any.display().to_string());
+    }
+}
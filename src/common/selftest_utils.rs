@@ -0,0 +1,128 @@
+// 設定ファイルの接続設定 (TLS・認証を含む) を確認するセルフテスト (--selftest)
+use super::config_utils::Config;
+use super::error_utils::AppError;
+use super::mqtt_utils::{self, MqttClient, MqttEvent};
+use super::token_utils;
+use rumqttc::QoS;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+// selftest_timeout_secs が未指定の場合に、往復を待つ秒数
+const DEFAULT_SELFTEST_TIMEOUT_SECS: u64 = 10;
+// selftest_topic_prefix が未指定の場合のトピックの接頭辞
+const DEFAULT_SELFTEST_TOPIC_PREFIX: &str = "$selftest";
+// 切断要求の送信を待つ時間
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// 他のプロセスやセルフテストと重ならない ID (時刻とプロセス ID から生成する)
+fn unique_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+    format!("{:x}-{:x}", nanos, std::process::id())
+}
+
+/// ブローカーに接続し、一時的なトピック (`$selftest/<ID>`) を QoS 1 で購読して発行したメッセージが
+/// 戻ってくることを確認する。各手順の結果を表示し、往復に成功した場合はその所要時間を返す。
+///
+/// クライアント ID は実行中のサブスクライバーの接続を切断しないよう `<client_id>-selftest` とし、
+/// セッションを残さないようクリーンセッションで接続する。
+pub async fn run_selftest(config: &Config) -> Result<Duration, AppError> {
+    let id = unique_id();
+    let prefix = config.selftest_topic_prefix.as_deref().unwrap_or(DEFAULT_SELFTEST_TOPIC_PREFIX);
+    let topic = format!("{}/{}", prefix, id);
+    let payload = format!("selftest {}", id).into_bytes();
+    let timeout = Duration::from_secs(config.selftest_timeout_secs.unwrap_or(DEFAULT_SELFTEST_TIMEOUT_SECS));
+
+    let mut config = config.clone();
+    config.client_id = format!("{}-selftest", config.client_id);
+    config.clean_session = Some(true);
+    config.manual_ack = Some(false);
+    let (client, mut eventloop) = mqtt_utils::create_client(&config, 10);
+    if let Some(url) = &config.auth_token_url {
+        let token = token_utils::fetch_token_with_retry(&config, url).await;
+        eventloop.set_credentials(config.username.as_deref().unwrap_or_default(), &token.token);
+        crate::status!("セルフテスト: 認証トークンを取得しました。");
+    }
+
+    crate::status!("セルフテスト: {}:{} に接続します (トピック '{}')。", config.broker_address, config.broker_port, topic);
+    let started = time::Instant::now();
+    let deadline = time::Instant::now() + timeout;
+    let mut published_at = None;
+    let mut connected = false;
+    let result = loop {
+        let event = match time::timeout_at(deadline, eventloop.poll()).await {
+            Err(_) => break Err(AppError::Unavailable(format!(
+                "セルフテストに失敗しました: {} 秒以内に{}。", timeout.as_secs(), match published_at {
+                    Some(_) => "発行したメッセージが戻ってきませんでした",
+                    None => "購読と発行が完了しませんでした",
+                }))),
+            Ok(Err(e)) => {
+                // 接続後に切断された場合は、`$` で始まるトピックをクライアントに使わせないブローカーの可能性がある
+                let hint = if connected && topic.starts_with('$') {
+                    " (ブローカーが $ で始まるトピックを拒否している可能性があります。selftest_topic_prefix で別の接頭辞を指定してください)"
+                } else {
+                    ""
+                };
+                if let Some((refusal, code)) = e.connect_refusal() {
+                    break Err(e.to_app_error(format!("セルフテストに失敗しました: {}", refusal.describe(&config, &code))));
+                }
+                if let Some(rejection) = e.rejection() {
+                    break Err(AppError::Unavailable(format!("セルフテストに失敗しました: {}{}。", rejection, hint)));
+                }
+                break Err(e.to_app_error(format!("セルフテストに失敗しました: ブローカーとの通信でエラーが発生しました: {}{}", e, hint)));
+            }
+            Ok(Ok(event)) => event,
+        };
+        match event {
+            MqttEvent::ConnAck { .. } => {
+                crate::status!("セルフテスト: 接続しました ({} ms)。", started.elapsed().as_millis());
+                connected = true;
+                if let Err(e) = request(client.subscribe(&topic, QoS::AtLeastOnce, Default::default()).await) {
+                    break Err(e);
+                }
+            }
+            MqttEvent::SubAck { granted, .. } => {
+                if granted.first().copied().flatten().is_none() {
+                    break Err(AppError::Unavailable(format!("セルフテストに失敗しました: トピック '{}' の購読が拒否されました。", topic)));
+                }
+                crate::status!("セルフテスト: 購読しました ({} ms)。", started.elapsed().as_millis());
+                if let Err(e) = request(client.publish(&topic, QoS::AtLeastOnce, false, payload.clone()).await) {
+                    break Err(e);
+                }
+                published_at = Some(time::Instant::now());
+            }
+            MqttEvent::PubAck(_) => {
+                crate::status!("セルフテスト: 発行したメッセージがブローカーに受け付けられました ({} ms)。", started.elapsed().as_millis());
+            }
+            MqttEvent::Publish(message) if message.topic == topic && message.payload[..] == payload[..] => {
+                break Ok(published_at.map(|at| at.elapsed()).unwrap_or_default());
+            }
+            MqttEvent::Disconnect(reason) => {
+                break Err(AppError::Unavailable(format!("セルフテストに失敗しました: ブローカーから切断されました ({:?}{})。",
+                    reason.code, reason.reason_string.map(|r| format!(": {}", r)).unwrap_or_default())));
+            }
+            _ => {}
+        }
+    };
+
+    disconnect(&client, &mut eventloop).await;
+    result
+}
+
+// 購読・発行の要求をイベントループに送れなかった場合のエラー
+fn request(result: Result<(), mqtt_utils::ClientError>) -> Result<(), AppError> {
+    result.map_err(|e| AppError::Unavailable(format!("セルフテストに失敗しました: 要求を送信できませんでした: {}", e)))
+}
+
+// 切断要求を送信し、送信されるまで (または一定時間) イベントループを処理する
+async fn disconnect(client: &MqttClient, eventloop: &mut mqtt_utils::MqttEventLoop) {
+    if client.disconnect().await.is_err() {
+        return;
+    }
+    let _ = time::timeout(DISCONNECT_TIMEOUT, async {
+        while let Ok(event) = eventloop.poll().await {
+            if matches!(event, MqttEvent::Outgoing(rumqttc::Outgoing::Disconnect)) {
+                break;
+            }
+        }
+    }).await;
+}
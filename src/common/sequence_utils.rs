@@ -0,0 +1,146 @@
+// QoS 2 の exactly-once 配信を検証するためのシーケンス番号の付与と照合
+use super::output_utils;
+use rumqttc::QoS;
+use std::{collections::{HashMap, HashSet}, sync::Mutex};
+
+// 重複・欠番として表示する番号の最大数 (件数はすべて数える)
+const MAX_LISTED: usize = 20;
+
+/// ペイロードの先頭にシーケンス番号を付ける (`<番号>:<ペイロード>`)
+pub fn with_sequence(sequence: u64, payload: &[u8]) -> Vec<u8> {
+    let mut data = format!("{}:", sequence).into_bytes();
+    data.extend_from_slice(payload);
+    data
+}
+
+/// `with_sequence` で付けたシーケンス番号を取り出す。番号が付いていない場合は `None` を返す
+pub fn parse_sequence(payload: &[u8]) -> Option<u64> {
+    let end = payload.iter().position(|b| *b == b':')?;
+    std::str::from_utf8(&payload[..end]).ok()?.parse().ok()
+}
+
+/// 受信した QoS 2 のメッセージのシーケンス番号をトピックごとに記録し、重複と欠番を検出する。
+///
+/// パブリッシャーは各トピックで 1 から順に番号を付けるため、受信した最大の番号までに
+/// 抜けている番号を欠番とする (最後の方のメッセージがまとめて届かなかった場合は検出できない)。
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    topics: Mutex<HashMap<String, TopicSequences>>,
+}
+
+#[derive(Debug, Default)]
+struct TopicSequences {
+    seen: HashSet<u64>,
+    max: u64,
+    duplicates: Vec<u64>,
+    duplicate_count: u64,
+    // シーケンス番号が付いていなかったメッセージ数
+    unnumbered: u64,
+}
+
+/// トピックごとの検証結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceReport {
+    pub topic: String,
+    pub received: u64,
+    pub duplicate_count: u64,
+    /// 重複した番号 (先頭の MAX_LISTED 件)
+    pub duplicates: Vec<u64>,
+    pub missing_count: u64,
+    /// 欠番 (先頭の MAX_LISTED 件)
+    pub missing: Vec<u64>,
+    pub unnumbered: u64,
+}
+
+impl SequenceReport {
+    /// 重複・欠番・番号のないメッセージがなければ `true`
+    pub fn is_ok(&self) -> bool {
+        self.duplicate_count == 0 && self.missing_count == 0 && self.unnumbered == 0
+    }
+}
+
+impl SequenceTracker {
+    /// 受信したメッセージを記録する。QoS 2 以外のメッセージと retain メッセージは検証の対象外
+    pub fn record(&self, topic: &str, qos: QoS, retain: bool, payload: &[u8]) {
+        if qos != QoS::ExactlyOnce || retain {
+            return;
+        }
+        let mut topics = self.topics.lock().unwrap();
+        let entry = topics.entry(topic.to_string()).or_default();
+        let Some(sequence) = parse_sequence(payload) else {
+            entry.unnumbered += 1;
+            return;
+        };
+        if !entry.seen.insert(sequence) {
+            entry.duplicate_count += 1;
+            if entry.duplicates.len() < MAX_LISTED {
+                entry.duplicates.push(sequence);
+            }
+        }
+        entry.max = entry.max.max(sequence);
+    }
+
+    /// トピック名順の検証結果を返す
+    pub fn report(&self) -> Vec<SequenceReport> {
+        let topics = self.topics.lock().unwrap();
+        let mut reports: Vec<SequenceReport> = topics.iter().map(|(topic, entry)| {
+            let mut missing = Vec::new();
+            let mut missing_count = 0;
+            for sequence in 1..=entry.max {
+                if !entry.seen.contains(&sequence) {
+                    missing_count += 1;
+                    if missing.len() < MAX_LISTED {
+                        missing.push(sequence);
+                    }
+                }
+            }
+            SequenceReport {
+                topic: topic.clone(),
+                received: entry.seen.len() as u64 + entry.duplicate_count,
+                duplicate_count: entry.duplicate_count,
+                duplicates: entry.duplicates.clone(),
+                missing_count,
+                missing,
+                unnumbered: entry.unnumbered,
+            }
+        }).collect();
+        reports.sort_by(|a, b| a.topic.cmp(&b.topic));
+        reports
+    }
+}
+
+/// 検証結果を表示し、すべてのトピックで問題がなければ `true` を返す
+pub fn print_report(label: &str, reports: &[SequenceReport]) -> bool {
+    if reports.is_empty() {
+        crate::status!("[{}] exactly-once の検証: QoS 2 のメッセージを受信しませんでした。", label);
+        return true;
+    }
+    let mut all_ok = true;
+    for report in reports {
+        if report.is_ok() {
+            crate::status!("[{}] exactly-once の検証: トピック '{}' OK ({} 件)", label, output_utils::status_topic(&report.topic), report.received);
+            continue;
+        }
+        all_ok = false;
+        crate::status!("[{}] exactly-once の検証: トピック '{}' NG (受信 {} 件)", label, output_utils::status_topic(&report.topic), report.received);
+        if report.duplicate_count > 0 {
+            crate::status!("  重複: {} 件 {}", report.duplicate_count, format_list(&report.duplicates, report.duplicate_count));
+        }
+        if report.missing_count > 0 {
+            crate::status!("  欠番: {} 件 {}", report.missing_count, format_list(&report.missing, report.missing_count));
+        }
+        if report.unnumbered > 0 {
+            crate::status!("  シーケンス番号のないメッセージ: {} 件", report.unnumbered);
+        }
+    }
+    all_ok
+}
+
+fn format_list(sequences: &[u64], total: u64) -> String {
+    let list: Vec<String> = sequences.iter().map(u64::to_string).collect();
+    if total as usize > sequences.len() {
+        format!("[{}, ...]", list.join(", "))
+    } else {
+        format!("[{}]", list.join(", "))
+    }
+}
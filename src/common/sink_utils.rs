@@ -0,0 +1,187 @@
+// 受信したメッセージの出力先 (標準出力・ログファイル・syslog・stream_port・output_fifo) を 1 つの一覧として扱う
+use super::config_utils::{Config, OutputFormat, WrapWidth};
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils;
+use std::{io::{self, Write}, sync::Arc};
+
+/// 受信したメッセージの出力先。
+///
+/// 表示するメッセージ (デコード・エスケープ済み) と、それを output_format に従って変換した出力 (`format_message` の結果) を受け取る。
+/// 書き込みに失敗した場合はエラーを返し、他の出力先への出力は `Sinks` が続ける。
+pub trait Sink: Send + Sync {
+    /// 警告に表示する出力先の名前
+    fn name(&self) -> String;
+    fn write(&self, message: &ReceivedMessage, output: &str, config: &Config) -> Result<(), String>;
+}
+
+// すべてのセッションで共有する出力先 (stream_port・output_fifo) は Arc のまま追加できる
+impl<T: Sink + ?Sized> Sink for Arc<T> {
+    fn name(&self) -> String {
+        (**self).name()
+    }
+
+    fn write(&self, message: &ReceivedMessage, output: &str, config: &Config) -> Result<(), String> {
+        (**self).write(message, output, config)
+    }
+}
+
+/// 設定された出力先の一覧。受信したメッセージを標準出力と、追加した順に他のすべての出力先に書き込む。
+///
+/// ある出力先への書き込みに失敗しても (ディスクの空きがない場合など) 他の出力先への書き込みは続ける。
+/// 失敗は出力先ごとに書き込めるようになるまで 1 回だけ表示する。標準出力への書き込みの失敗は `write` が返し、
+/// 終了するかどうかは呼び出し側が決める。
+///
+/// 別のブローカーへの転送 (bridge) は表示用に変換する前のメッセージを送るため、この一覧ではなく `forward_utils::Bridge` が
+/// 受信したメッセージを受け取る (転送先に接続できない間も、この一覧への出力は止まらない)。
+pub struct Sinks {
+    stdout: StdoutSink,
+    sinks: Vec<Box<dyn Sink>>,
+    // 出力先ごとの、前回の書き込みに失敗したか
+    failing: Vec<bool>,
+}
+
+impl Sinks {
+    /// 設定から標準出力の出力先を作成する。他の出力先は `push` で追加する
+    pub fn new(config: &Config) -> Sinks {
+        Sinks { stdout: StdoutSink { wrap_width: output_utils::wrap_width(config) }, sinks: Vec::new(), failing: Vec::new() }
+    }
+
+    /// 出力先を追加する
+    pub fn push(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+        self.failing.push(false);
+    }
+
+    /// メッセージをすべての出力先に書き込む。標準出力に書き込めなかった場合は、他の出力先に書き込んだ後にそのエラーを返す
+    pub fn write(&mut self, message: &ReceivedMessage, output: &str, config: &Config) -> io::Result<()> {
+        let stdout = self.stdout.write(message, output, config);
+        for (sink, failing) in self.sinks.iter().zip(self.failing.iter_mut()) {
+            match sink.write(message, output, config) {
+                Ok(()) if *failing => {
+                    *failing = false;
+                    crate::status!("{} への出力を再開しました。", sink.name());
+                }
+                Ok(()) => {}
+                Err(e) if !*failing => {
+                    *failing = true;
+                    eprintln!("警告: {} への出力に失敗しました: {}。他の出力先への出力は続けます (書き込めるようになるまで以降の失敗は表示しません)。", sink.name(), e);
+                }
+                Err(_) => {}
+            }
+        }
+        stdout
+    }
+}
+
+/// 標準出力への書き込みのエラーが、読み取り側のプロセスが終了した (`| head` など、BrokenPipe) ことによるものか。
+/// その場合は出力を止めて正常に終了する
+pub fn is_stdout_closed(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::BrokenPipe
+}
+
+// 標準出力 (output_format に従い、text の場合は wrap_width で折り返す)。
+// 他の出力先と異なり、書き込めない場合 (パイプの先のプロセスが終了した場合など) のエラーは `Sinks::write` から返す
+struct StdoutSink {
+    // 端末に表示するテキスト出力の折り返し幅 (折り返さない場合は None)
+    wrap_width: Option<WrapWidth>,
+}
+
+impl StdoutSink {
+    fn write(&self, message: &ReceivedMessage, output: &str, config: &Config) -> io::Result<()> {
+        if config.output_format == Some(OutputFormat::JsonArray) {
+            // json-array は終了時にまとめて出力する
+            output_utils::push_json_array(output.to_string());
+            Ok(())
+        } else if config.output_format == Some(OutputFormat::Raw) {
+            output_utils::write_raw(&message.payload, config.raw_delimiter.as_ref())
+        } else if let Some(width) = self.wrap_width {
+            writeln!(io::stdout().lock(), "{}", output_utils::wrap_text(output, output_utils::wrap_columns(width)))
+        } else {
+            writeln!(io::stdout().lock(), "{}", output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use rumqttc::QoS;
+    use std::sync::{Mutex, atomic::{AtomicBool, Ordering}};
+
+    // 書き込まれた出力を記録する出力先 (`fail` が true の間は書き込みに失敗する)
+    #[derive(Clone, Default)]
+    struct Recorder {
+        outputs: Arc<Mutex<Vec<String>>>,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl Sink for Recorder {
+        fn name(&self) -> String {
+            "recorder".to_string()
+        }
+
+        fn write(&self, _message: &ReceivedMessage, output: &str, _config: &Config) -> Result<(), String> {
+            if self.fail.load(Ordering::Relaxed) {
+                return Err("disk full".to_string());
+            }
+            self.outputs.lock().unwrap().push(output.to_string());
+            Ok(())
+        }
+    }
+
+    impl Recorder {
+        fn outputs(&self) -> Vec<String> {
+            self.outputs.lock().unwrap().clone()
+        }
+    }
+
+    // 標準出力の出力先はテストの出力に書き込まないよう、json-array (終了時にまとめて出力する) にする
+    fn config() -> Config {
+        serde_yaml::from_str("client_id: sinks\noutput_format: json-array\n").unwrap()
+    }
+
+    fn message() -> ReceivedMessage {
+        ReceivedMessage {
+            topic: "sinks/test".to_string(),
+            payload: Bytes::from_static(b"payload"),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pkid: 0,
+            properties: None,
+            acker: None,
+        }
+    }
+
+    #[test]
+    fn writes_every_message_to_every_sink_in_order() {
+        let config = config();
+        let (first, second) = (Recorder::default(), Recorder::default());
+        let mut sinks = Sinks::new(&config);
+        sinks.push(Box::new(first.clone()));
+        sinks.push(Box::new(second.clone()));
+        for output in ["1", "2"] {
+            sinks.write(&message(), output, &config).unwrap();
+        }
+        assert_eq!(first.outputs(), ["1", "2"]);
+        assert_eq!(second.outputs(), ["1", "2"]);
+    }
+
+    #[test]
+    fn a_failing_sink_does_not_stop_the_others() {
+        let config = config();
+        let (failing, healthy) = (Recorder::default(), Recorder::default());
+        let mut sinks = Sinks::new(&config);
+        sinks.push(Box::new(failing.clone()));
+        sinks.push(Box::new(healthy.clone()));
+        failing.fail.store(true, Ordering::Relaxed);
+        sinks.write(&message(), "1", &config).unwrap();
+        sinks.write(&message(), "2", &config).unwrap();
+        assert_eq!(sinks.failing, [true, false]);
+        failing.fail.store(false, Ordering::Relaxed);
+        sinks.write(&message(), "3", &config).unwrap();
+        assert_eq!(sinks.failing, [false, false]);
+        assert_eq!(failing.outputs(), ["3"]);
+        assert_eq!(healthy.outputs(), ["1", "2", "3"]);
+    }
+}
@@ -0,0 +1,193 @@
+// rumqttc が作成したブローカーとの接続のソケットへのオプションの設定 (tcp_nodelay / socket_dscp)
+//
+// rumqttc は接続のたびにソケットを内部で作成し、ソケットやそのオプションを設定する手段を公開していないため、
+// CONNACK を受信した時点で、プロセスが開いているファイルディスクリプタからブローカーのアドレスとポートに接続している
+// TCP のソケットを探して設定する。proxy_url の場合は、中継がプロキシとの接続のソケットに設定する。
+// socket_dscp は CONNACK 以降のパケットに付き、CONNECT までのパケット (TCP / TLS のハンドシェイクなど) には付かない。
+use super::{config_utils::Config, dscp_utils};
+use std::{io, net::{IpAddr, SocketAddr}, sync::atomic::{AtomicBool, Ordering}};
+
+// ソケットを設定できなかった警告を 1 回だけ表示する
+static SOCKET_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// ブローカーとの接続のソケットに設定するオプション
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    host: String,
+    port: u16,
+    nodelay: bool,
+    dscp: Option<u8>,
+}
+
+impl SocketOptions {
+    /// 設定するオプションがない場合と、rumqttc がブローカーに直接 TCP で接続しない場合 (proxy_url / scheme: quic) は `None` を返す
+    pub fn new(config: &Config) -> Option<SocketOptions> {
+        // scheme: quic で socket_dscp が指定されている場合の警告はここで表示する
+        let dscp = dscp_utils::socket_dscp(config);
+        if config.proxy_url.is_some() || config.scheme.as_deref() == Some("quic") {
+            return None;
+        }
+        let nodelay = config.tcp_nodelay.unwrap_or(true);
+        if !nodelay && dscp.is_none() {
+            return None;
+        }
+        let host = config.broker_address.trim_start_matches('[').trim_end_matches(']').to_string();
+        Some(SocketOptions { host, port: config.broker_port, nodelay, dscp })
+    }
+
+    /// ブローカーのアドレスとポートに接続しているソケットを探してオプションを設定する。
+    /// 設定できない場合 (ソケットが見つからない、OS が対応していないなど) は警告を 1 回表示する
+    pub async fn apply(&self) {
+        if let Err(e) = self.try_apply().await
+            && !SOCKET_WARNED.swap(true, Ordering::Relaxed) {
+            eprintln!("警告: ブローカーとの接続のソケットを設定できませんでした ({}): {}", self.describe(), e);
+        }
+    }
+
+    // 警告に表示する、設定するオプションの一覧
+    fn describe(&self) -> String {
+        let mut names = Vec::new();
+        if self.nodelay {
+            names.push("TCP_NODELAY".to_string());
+        }
+        if let Some(dscp) = self.dscp {
+            names.push(format!("DSCP {}", dscp));
+        }
+        names.join(", ")
+    }
+
+    async fn try_apply(&self) -> io::Result<()> {
+        // rumqttc と同じく接続のたびに名前解決し、解決したいずれかのアドレスに接続しているソケットを探す
+        let addresses: Vec<IpAddr> = match self.host.parse::<IpAddr>() {
+            Ok(ip) => vec![ip],
+            Err(_) => tokio::net::lookup_host((self.host.as_str(), self.port)).await?.map(|addr| addr.ip()).collect(),
+        };
+        let sockets = broker_sockets(&addresses, self.port)?;
+        if sockets.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}:{} に接続しているソケットが見つかりません", self.host, self.port)));
+        }
+        for socket in &sockets {
+            if self.nodelay {
+                socket.set_nodelay(true)?;
+            }
+            if let Some(dscp) = self.dscp {
+                dscp_utils::set_dscp(&**socket, socket.peer_addr()?, dscp)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `addresses` のいずれかに接続している TCP のソケットの接続先 (見つからない場合は `None`)。
+/// アドレスのポートはすべて同じ (同じ名前とポートを名前解決したもの)
+pub fn connected_peer(addresses: &[SocketAddr]) -> Option<SocketAddr> {
+    let port = addresses.first()?.port();
+    let ips: Vec<IpAddr> = addresses.iter().map(SocketAddr::ip).collect();
+    broker_sockets(&ips, port).ok()?.iter().find_map(|socket| socket.peer_addr().ok())
+}
+
+// 開いているファイルディスクリプタのうち、`addresses` のいずれかの `port` に接続している TCP のソケット。
+// ファイルディスクリプタは rumqttc が所有しているため、閉じないように ManuallyDrop で包む
+#[cfg(unix)]
+fn broker_sockets(addresses: &[IpAddr], port: u16) -> io::Result<Vec<std::mem::ManuallyDrop<std::net::TcpStream>>> {
+    use std::os::fd::FromRawFd;
+    // Linux は /proc/self/fd、macOS などは /dev/fd に開いているファイルディスクリプタの一覧がある
+    let entries = std::fs::read_dir("/proc/self/fd").or_else(|_| std::fs::read_dir("/dev/fd"))?;
+    let mut sockets = Vec::new();
+    for entry in entries.flatten() {
+        let Some(fd) = entry.file_name().to_str().and_then(|name| name.parse::<i32>().ok()) else {
+            continue;
+        };
+        if !is_stream_socket(fd) {
+            continue;
+        }
+        // SAFETY: TCP のソケットであることを確認したファイルディスクリプタを、閉じずに参照するためだけに包む
+        let socket = std::mem::ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(fd) });
+        if socket.peer_addr().is_ok_and(|peer: SocketAddr| peer.port() == port && addresses.contains(&peer.ip())) {
+            sockets.push(socket);
+        }
+    }
+    Ok(sockets)
+}
+
+#[cfg(not(unix))]
+fn broker_sockets(_addresses: &[IpAddr], _port: u16) -> io::Result<Vec<std::mem::ManuallyDrop<std::net::TcpStream>>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "この OS ではブローカーとの接続のソケットを設定できません"))
+}
+
+// SOCK_STREAM のソケットか (ソケットでないファイルディスクリプタや UDP のソケットを除く)
+#[cfg(unix)]
+fn is_stream_socket(fd: i32) -> bool {
+    let mut socket_type: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: サイズを指定した c_int の領域に SO_TYPE を読み込むだけで、ファイルディスクリプタが無効な場合はエラーを返す
+    let result = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, (&mut socket_type as *mut libc::c_int).cast(), &mut len) };
+    result == 0 && socket_type == libc::SOCK_STREAM
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn sets_options_only_on_sockets_connected_to_the_broker() {
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let to_broker = TcpStream::connect(broker.local_addr().unwrap()).unwrap();
+        let to_other = TcpStream::connect(other.local_addr().unwrap()).unwrap();
+        let (accepted, _) = broker.accept().unwrap();
+        for stream in [&to_broker, &to_other, &accepted] {
+            stream.set_nodelay(false).unwrap();
+        }
+
+        let options = SocketOptions { host: "127.0.0.1".to_string(), port: broker.local_addr().unwrap().port(), nodelay: true, dscp: None };
+        options.try_apply().await.unwrap();
+        assert!(to_broker.nodelay().unwrap());
+        assert!(!to_other.nodelay().unwrap());
+        // ブローカー側で受け付けた接続は、接続先のポートがブローカーのポートではない
+        assert!(!accepted.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn reports_when_no_socket_is_connected_to_the_broker() {
+        let options = SocketOptions { host: "127.0.0.1".to_string(), port: 9, nodelay: true, dscp: None };
+        assert_eq!(options.try_apply().await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn finds_the_peer_of_the_broker_connection() {
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = broker.local_addr().unwrap();
+        let _to_broker = TcpStream::connect(address).unwrap();
+        let other: SocketAddr = format!("127.0.0.2:{}", address.port()).parse().unwrap();
+        assert_eq!(connected_peer(&[other, address]), Some(address));
+        assert_eq!(connected_peer(&[other]), None);
+        assert_eq!(connected_peer(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn sets_dscp_on_the_broker_socket() {
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let to_broker = TcpStream::connect(broker.local_addr().unwrap()).unwrap();
+        let options = SocketOptions { host: "127.0.0.1".to_string(), port: broker.local_addr().unwrap().port(), nodelay: false, dscp: Some(46) };
+        options.try_apply().await.unwrap();
+        // ToS のバイトの上位 6 ビットが DSCP
+        use std::os::fd::AsRawFd;
+        let mut tos: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe { libc::getsockopt(to_broker.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TOS, (&mut tos as *mut libc::c_int).cast(), &mut len) };
+        assert_eq!(result, 0);
+        assert_eq!(tos >> 2, 46);
+    }
+
+    #[test]
+    fn proxy_and_disabled_nodelay_leave_the_socket_alone() {
+        let config = |yaml: &str| -> Config { serde_yaml::from_str(&format!("client_id: socket\nbroker_address: localhost\nbroker_port: 1883\n{}", yaml)).unwrap() };
+        assert!(SocketOptions::new(&config("")).is_some_and(|options| options.nodelay));
+        assert!(SocketOptions::new(&config("tcp_nodelay: false\n")).is_none());
+        assert!(SocketOptions::new(&config("proxy_url: socks5://127.0.0.1:1080\n")).is_none());
+        assert!(SocketOptions::new(&config("tcp_nodelay: false\nsocket_dscp: 46\n")).is_some_and(|options| !options.nodelay && options.dscp == Some(46)));
+        assert!(SocketOptions::new(&config("scheme: ws\nsocket_dscp: 46\n")).is_some_and(|options| options.dscp == Some(46)));
+    }
+}
@@ -0,0 +1,151 @@
+// Sparkplug B のペイロード (protobuf) のデコード (payload_format: sparkplug_b、sparkplug フィーチャー)
+//
+// Eclipse Sparkplug B の sparkplug_b.proto のうち、表示に使うフィールドだけを定義する。
+// DataSet / Template などの入れ子のメッセージは展開せず、バイト数のみを表示する。
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use prost::Message;
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Payload {
+    #[prost(uint64, optional, tag = "1")]
+    timestamp: Option<u64>,
+    #[prost(message, repeated, tag = "2")]
+    metrics: Vec<Metric>,
+    #[prost(uint64, optional, tag = "3")]
+    seq: Option<u64>,
+    #[prost(string, optional, tag = "4")]
+    uuid: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "5")]
+    body: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Metric {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    alias: Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    datatype: Option<u32>,
+    #[prost(bool, optional, tag = "5")]
+    is_historical: Option<bool>,
+    #[prost(bool, optional, tag = "6")]
+    is_transient: Option<bool>,
+    #[prost(bool, optional, tag = "7")]
+    is_null: Option<bool>,
+    #[prost(oneof = "MetricValue", tags = "10, 11, 12, 13, 14, 15, 16, 17, 18, 19")]
+    value: Option<MetricValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum MetricValue {
+    #[prost(uint32, tag = "10")]
+    Int(u32),
+    #[prost(uint64, tag = "11")]
+    Long(u64),
+    #[prost(float, tag = "12")]
+    Float(f32),
+    #[prost(double, tag = "13")]
+    Double(f64),
+    #[prost(bool, tag = "14")]
+    Boolean(bool),
+    #[prost(string, tag = "15")]
+    String(String),
+    #[prost(bytes = "vec", tag = "16")]
+    Bytes(Vec<u8>),
+    // 以下は入れ子のメッセージ (DataSet / Template / 拡張) をバイト列のまま受け取る
+    #[prost(bytes = "vec", tag = "17")]
+    DataSet(Vec<u8>),
+    #[prost(bytes = "vec", tag = "18")]
+    Template(Vec<u8>),
+    #[prost(bytes = "vec", tag = "19")]
+    Extension(Vec<u8>),
+}
+
+/// Sparkplug B のトピック (`spBv1.0/...`) のうち、protobuf のペイロードを持つトピックかを判定する
+/// (ホストアプリケーションの状態を通知する STATE トピックは JSON またはテキストのため除く)
+pub fn is_sparkplug_topic(topic: &str) -> bool {
+    topic.strip_prefix("spBv1.0/").is_some_and(|rest| !rest.starts_with("STATE/"))
+}
+
+/// Sparkplug B のペイロードを、メトリクスの名前・データ型・値・タイムスタンプを含む JSON に変換する
+pub fn decode(payload: &[u8]) -> Result<Value, String> {
+    let payload = Payload::decode(payload).map_err(|e| e.to_string())?;
+    let mut object = Map::new();
+    if let Some(timestamp) = payload.timestamp {
+        object.insert("timestamp".to_string(), timestamp.into());
+    }
+    if let Some(seq) = payload.seq {
+        object.insert("seq".to_string(), seq.into());
+    }
+    if let Some(uuid) = payload.uuid {
+        object.insert("uuid".to_string(), uuid.into());
+    }
+    object.insert("metrics".to_string(), payload.metrics.iter().map(metric_to_json).collect());
+    if let Some(body) = payload.body {
+        object.insert("body".to_string(), BASE64.encode(body).into());
+    }
+    Ok(Value::Object(object))
+}
+
+fn metric_to_json(metric: &Metric) -> Value {
+    let mut object = Map::new();
+    if let Some(name) = &metric.name {
+        object.insert("name".to_string(), name.clone().into());
+    }
+    if let Some(alias) = metric.alias {
+        object.insert("alias".to_string(), alias.into());
+    }
+    if let Some(timestamp) = metric.timestamp {
+        object.insert("timestamp".to_string(), timestamp.into());
+    }
+    if let Some(datatype) = metric.datatype {
+        object.insert("datatype".to_string(), datatype_name(datatype).map_or_else(|| datatype.into(), Value::from));
+    }
+    for (key, flag) in [("is_historical", metric.is_historical), ("is_transient", metric.is_transient)] {
+        if flag == Some(true) {
+            object.insert(key.to_string(), true.into());
+        }
+    }
+    let value = if metric.is_null == Some(true) {
+        Value::Null
+    } else {
+        metric.value.as_ref().map_or(Value::Null, |value| value_to_json(value, metric.datatype))
+    };
+    object.insert("value".to_string(), value);
+    Value::Object(object)
+}
+
+// 符号付き整数は 2 の補数のまま符号なしのフィールドに格納されているため、データ型に従って戻す
+fn value_to_json(value: &MetricValue, datatype: Option<u32>) -> Value {
+    match (value, datatype) {
+        (MetricValue::Int(v), Some(1)) => (*v as u8 as i8).into(),
+        (MetricValue::Int(v), Some(2)) => (*v as u16 as i16).into(),
+        (MetricValue::Int(v), Some(3)) => (*v as i32).into(),
+        (MetricValue::Int(v), _) => (*v).into(),
+        (MetricValue::Long(v), Some(4)) => (*v as i64).into(),
+        (MetricValue::Long(v), _) => (*v).into(),
+        (MetricValue::Float(v), _) => serde_json::Number::from_f64(f64::from(*v)).map_or(Value::Null, Value::Number),
+        (MetricValue::Double(v), _) => serde_json::Number::from_f64(*v).map_or(Value::Null, Value::Number),
+        (MetricValue::Boolean(v), _) => (*v).into(),
+        (MetricValue::String(v), _) => v.clone().into(),
+        (MetricValue::Bytes(v), _) => BASE64.encode(v).into(),
+        (MetricValue::DataSet(v), _) => json!({ "dataset_bytes": v.len() }),
+        (MetricValue::Template(v), _) => json!({ "template_bytes": v.len() }),
+        (MetricValue::Extension(v), _) => json!({ "extension_bytes": v.len() }),
+    }
+}
+
+// Sparkplug B のデータ型の番号と名前
+fn datatype_name(datatype: u32) -> Option<&'static str> {
+    const NAMES: [&str; 35] = [
+        "Unknown", "Int8", "Int16", "Int32", "Int64", "UInt8", "UInt16", "UInt32", "UInt64", "Float", "Double",
+        "Boolean", "String", "DateTime", "Text", "UUID", "DataSet", "Bytes", "File", "Template", "PropertySet",
+        "PropertySetList", "Int8Array", "Int16Array", "Int32Array", "Int64Array", "UInt8Array", "UInt16Array",
+        "UInt32Array", "UInt64Array", "FloatArray", "DoubleArray", "BooleanArray", "StringArray", "DateTimeArray",
+    ];
+    NAMES.get(datatype as usize).copied()
+}
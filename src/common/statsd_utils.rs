@@ -0,0 +1,115 @@
+// 受信統計の StatsD / DogStatsD への送信 (statsd_address)
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use super::metrics_utils::{Metrics, MetricsSnapshot};
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+use tokio::{net::UdpSocket, time};
+
+// statsd_interval_secs が未指定の場合の送信間隔
+const DEFAULT_STATSD_INTERVAL_SECS: u64 = 10;
+// statsd_prefix が未指定の場合のメトリクス名の接頭辞
+const DEFAULT_STATSD_PREFIX: &str = "mqtt";
+// 1 つの UDP データグラムにまとめる最大バイト数 (一般的な MTU を超えないようにする)
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+/// セッションごとの受信統計を一定間隔で StatsD に UDP で送信する。
+///
+/// カウンター (`<prefix>.messages.received` / `<prefix>.bytes.received` / `<prefix>.reconnects`) は前回の送信からの増分を送り、
+/// タグ (DogStatsD 形式) には client_id と broker、statsd_tags で指定したタグを付ける。
+/// 送信はノンブロッキングで行い、送信できなかったデータは破棄する (イベントループを待たせない)。
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<String>,
+    // セッションの client_id と受信統計、前回送信した時点の値
+    sessions: Mutex<Vec<(String, Arc<Metrics>, MetricsSnapshot)>>,
+}
+
+impl StatsdExporter {
+    /// statsd_address が指定されていない場合は `None` を返す
+    pub async fn start(config: &Config, sessions: Vec<(String, Arc<Metrics>)>) -> Option<Arc<StatsdExporter>> {
+        let address = config.statsd_address.as_ref()?;
+        let target: SocketAddr = tokio::net::lookup_host(address.as_str()).await.ok()
+            .and_then(|mut addrs| addrs.next())
+            .unwrap_or_else(|| exit_with(AppError::Config(format!("statsd_address '{}' を解決できませんでした (host:port の形式で指定してください)", address))));
+        let bind = if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = match UdpSocket::bind(bind).await {
+            Ok(socket) => socket,
+            Err(e) => exit_with(AppError::Config(format!("StatsD への送信用のソケットを開けませんでした: {}", e))),
+        };
+        if let Err(e) = socket.connect(target).await {
+            exit_with(AppError::Config(format!("statsd_address '{}' に接続できませんでした: {}", address, e)));
+        }
+
+        let mut tags = vec![format!("broker:{}:{}", config.broker_address, config.broker_port)];
+        tags.extend(config.statsd_tags.iter().flatten().cloned());
+        let exporter = Arc::new(StatsdExporter {
+            socket,
+            prefix: config.statsd_prefix.clone().unwrap_or_else(|| DEFAULT_STATSD_PREFIX.to_string()),
+            tags,
+            sessions: Mutex::new(sessions.into_iter().map(|(client_id, metrics)| (client_id, metrics, MetricsSnapshot::default())).collect()),
+        });
+        let interval = Duration::from_secs(config.statsd_interval_secs.unwrap_or(DEFAULT_STATSD_INTERVAL_SECS).max(1));
+        crate::status!("受信統計を {} 秒ごとに StatsD ({}) に送信します。", interval.as_secs(), target);
+
+        let periodic = Arc::clone(&exporter);
+        tokio::spawn(async move {
+            let mut ticker = time::interval_at(time::Instant::now() + interval, interval);
+            loop {
+                ticker.tick().await;
+                periodic.flush();
+            }
+        });
+        Some(exporter)
+    }
+
+    /// 前回の送信からの増分を送信する。終了時にも呼び出し、最後の増分を送る
+    pub fn flush(&self) {
+        let mut lines = Vec::new();
+        for (client_id, metrics, last) in self.sessions.lock().unwrap().iter_mut() {
+            let snapshot = metrics.snapshot();
+            let tags = self.format_tags(client_id);
+            let counters = [
+                ("messages.received", snapshot.messages_received - last.messages_received),
+                ("bytes.received", snapshot.bytes_received - last.bytes_received),
+                ("reconnects", snapshot.reconnects - last.reconnects),
+            ];
+            for (name, delta) in counters {
+                lines.push(format!("{}.{}:{}|c{}", self.prefix, name, delta, tags));
+            }
+            // 起動してからのペイロードのサイズのパーセンタイル (payload_size_histogram が指定されている場合)
+            if let Some(summary) = metrics.payload_size_summary() {
+                let gauges = [("p50", summary.p50), ("p90", summary.p90), ("p99", summary.p99), ("max", summary.max)];
+                for (name, value) in gauges {
+                    lines.push(format!("{}.payload_size.{}:{}|g{}", self.prefix, name, value, tags));
+                }
+            }
+            *last = snapshot;
+        }
+        for datagram in pack_datagrams(&lines) {
+            // 送信バッファが満杯などで送れない場合は待たずに破棄する (UDP のため到達も保証されない)
+            let _ = self.socket.try_send(datagram.as_bytes());
+        }
+    }
+
+    fn format_tags(&self, client_id: &str) -> String {
+        let mut tags = vec![format!("client_id:{}", client_id)];
+        tags.extend(self.tags.iter().cloned());
+        format!("|#{}", tags.join(","))
+    }
+}
+
+// 改行区切りで MAX_DATAGRAM_SIZE 以下のデータグラムにまとめる
+fn pack_datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM_SIZE => {
+                datagram.push('\n');
+                datagram.push_str(line);
+            }
+            _ => datagrams.push(line.clone()),
+        }
+    }
+    datagrams
+}
@@ -0,0 +1,101 @@
+use super::config_utils::{Config, OutputFormat};
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils;
+use super::sink_utils::Sink;
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}, sync::mpsc, time};
+
+// 接続ごとに送信を待てるメッセージの数 (これを超えて溜まった接続は切断する)
+const CLIENT_BUFFER_SIZE: usize = 256;
+// 接続を受け付けられなかった場合 (ファイルディスクリプタの上限など) に、次に受け付けるまで待つ時間
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// 受信したメッセージを `stream_port` に接続したすべてのクライアントに送信する (`nc localhost 9000` などで確認する用途)。
+///
+/// 各メッセージは output_format の形式で 1 件ごとに改行を付けて送信する。送信が追いつかず、
+/// 未送信のメッセージが溜まったクライアントは切断し、他のクライアントやメッセージの受信を待たせない。
+pub struct MessageStream {
+    clients: Mutex<Vec<StreamClient>>,
+}
+
+struct StreamClient {
+    addr: SocketAddr,
+    tx: mpsc::Sender<Arc<str>>,
+}
+
+impl MessageStream {
+    /// stream_port が指定されていない場合は `None` を返す。ローカルホストからの接続のみ受け付ける
+    pub async fn start(config: &Config) -> Option<Arc<MessageStream>> {
+        let port = config.stream_port?;
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("stream_port {} での待ち受けを開始できませんでした: {}", port, e)));
+        });
+        crate::status!("受信メッセージをポート {} で配信しています。", port);
+
+        let stream = Arc::new(MessageStream { clients: Mutex::new(Vec::new()) });
+        // CSV の場合は接続ごとに先頭でヘッダー行を送る
+        let header = (config.output_format.unwrap_or_default() == OutputFormat::Csv).then_some(output_utils::CSV_HEADER);
+        tokio::spawn(accept(listener, Arc::clone(&stream), header));
+        Some(stream)
+    }
+
+    /// 接続中のすべてのクライアントにメッセージ (末尾の改行は含まない) を送信する
+    pub fn broadcast(&self, text: &str) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return;
+        }
+        let line: Arc<str> = Arc::from(format!("{}\n", text));
+        clients.retain(|client| match client.tx.try_send(Arc::clone(&line)) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                eprintln!("stream_port のクライアント {} は受信が追いつかないため切断します。", client.addr);
+                false
+            }
+            // 接続が閉じられた
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+    }
+}
+
+// 受信が追いつかないクライアントは broadcast で切断するため、出力は失敗しない
+impl Sink for MessageStream {
+    fn name(&self) -> String {
+        "stream_port".to_string()
+    }
+
+    fn write(&self, _message: &ReceivedMessage, output: &str, _config: &Config) -> Result<(), String> {
+        self.broadcast(output);
+        Ok(())
+    }
+}
+
+async fn accept(listener: TcpListener, stream: Arc<MessageStream>, header: Option<&'static str>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("警告: stream_port への接続を受け付けられませんでした: {}", e);
+                time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let (tx, rx) = mpsc::channel(CLIENT_BUFFER_SIZE);
+        if let Some(header) = header {
+            let _ = tx.try_send(Arc::from(format!("{}\n", header)));
+        }
+        stream.clients.lock().unwrap().push(StreamClient { addr, tx });
+        tokio::spawn(send_lines(socket, rx));
+    }
+}
+
+// 送信側が破棄される (切断される) か書き込みに失敗するまでメッセージを書き込む
+async fn send_lines(mut socket: TcpStream, mut rx: mpsc::Receiver<Arc<str>>) {
+    while let Some(line) = rx.recv().await {
+        if socket.write_all(line.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.shutdown().await;
+}
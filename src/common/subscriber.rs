@@ -0,0 +1,1693 @@
+use super::benchmark_utils::{self, LatencyRecorder};
+use super::clock_utils::{ClockJump, ClockWatch};
+use super::config_utils::{Config, IdleAction, MinQosPolicy, OversizedPacketPolicy, TopicCountMode};
+use super::dedup_utils::{Deduplicator, DEFAULT_DEDUP_MAX_ENTRIES};
+use super::discover_utils::TopicDiscovery;
+use super::dns_utils::BrokerResolver;
+use super::error_utils::{exit_with, AppError};
+use super::format_rule_utils::FormatRules;
+use super::forward_utils::Bridge;
+use super::gap_utils::GapTracker;
+use super::handler_utils::HandlerQueue;
+use super::history_utils::MessageHistory;
+use super::idle_subscription_utils::IdleSubscriptions;
+use super::log_utils::RotatingLog;
+use super::metrics_utils::Metrics;
+use super::output_utils;
+use super::publisher::SharedPublisher;
+use super::qos_utils;
+use super::reconnect_utils::{ReconnectDeadline, StartupRetry};
+use super::schema_utils::{self, SchemaValidator};
+use super::sequence_utils::SequenceTracker;
+use super::sink_utils::{self, Sink, Sinks};
+use super::syslog_utils::SyslogSink;
+use super::template_utils::Template;
+use super::throttle_utils::{Admission, Throttle};
+use super::token_utils;
+use super::topic_utils::{self, topic_matches};
+use super::wait_utils::WaitFor;
+use super::webhook_utils::ReconnectWebhook;
+use super::mqtt_utils::{self, ConnectionError, DisconnectReason, MqttClient, MqttEvent, MqttEventLoop, ProtocolVersion, ReceivedMessage, SubscribeOptions};
+use std::{borrow::Cow, collections::{HashMap, HashSet, VecDeque}, future::Future, sync::Arc, time::Duration};
+use bytes::Bytes;
+use regex::RegexSet;
+use rumqttc::{Outgoing, QoS};
+use tokio::{sync::{mpsc, watch}, time};
+
+// handler_queue_size が未指定の場合のキュー容量
+const DEFAULT_HANDLER_QUEUE_SIZE: usize = 100;
+// subscribe_batch_size が未指定の場合に 1 つの SUBSCRIBE パケットにまとめるトピック数
+const DEFAULT_SUBSCRIBE_BATCH_SIZE: usize = 1;
+// subscribe_timeout_secs が未指定の場合に SUBACK を待つ秒数
+const DEFAULT_SUBSCRIBE_TIMEOUT_SECS: u64 = 10;
+// heartbeat_interval_secs が未指定の場合に heartbeat_topic に発行する間隔の秒数
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 60;
+// subscribe_retry の max_attempts / delay_ms が未指定の場合の値
+const DEFAULT_SUBSCRIBE_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_SUBSCRIBE_RETRY_DELAY_MS: u64 = 1000;
+// 購読の再試行の待ち時間の上限
+const MAX_SUBSCRIBE_RETRY_DELAY: Duration = Duration::from_secs(60);
+// ベンチマークのループバックのメッセージを発行する間隔
+const LOOPBACK_INTERVAL: Duration = Duration::from_secs(1);
+// max_packet_size を超えるパケットで続けて切断された場合に、再接続を諦めるまでの回数 (oversized_packet_policy: skip)
+const MAX_OVERSIZED_PACKET_RETRIES: u32 = 3;
+// 重複メッセージを抑制した件数を表示する間隔
+const DEDUP_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// ブローカーとの接続状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// 初回接続を試行中
+    Connecting,
+    /// ブローカーに接続済み (ConnAck を受信)
+    Connected,
+    /// ブローカーから切断された。ブローカーが DISCONNECT (MQTT 5) で理由を通知した場合はその内容を保持する
+    Disconnected(Option<DisconnectReason>),
+    /// 切断後の再接続を試行中
+    Reconnecting,
+}
+
+/// 設定ファイルに従ってトピックを購読し、受信したメッセージを出力するサブスクライバー
+pub struct Subscriber {
+    client: MqttClient,
+    eventloop: MqttEventLoop,
+    config: Config,
+    qos: Vec<QoS>,
+    online_qos: QoS,
+    state_tx: watch::Sender<ConnectionState>,
+    ready_tx: watch::Sender<bool>,
+    // SUBACK を待っている SUBSCRIBE パケットの数
+    pending_subacks: usize,
+    // 送信を要求したが、まだパケット ID が割り当てられていない SUBSCRIBE のトピック (送信順)
+    unsent_subscribes: VecDeque<Vec<(String, QoS)>>,
+    // SUBACK を待っている SUBSCRIBE のパケット ID と、そのトピックと要求した QoS (送信順。拒否・引き下げられたトピックの特定に使う)
+    inflight_subscribes: VecDeque<(u16, Vec<(String, QoS)>)>,
+    // ブローカーに拒否された購読があるか
+    subscribe_rejected: bool,
+    // 送信した SUBSCRIBE の SUBACK をすべて受信する期限 (subscribe_timeout_secs)
+    suback_deadline: Option<time::Instant>,
+    // SUBACK がすべて届いたときに表示する、トピックごとの購読結果 (許可された QoS。拒否された場合は None)
+    subscribe_results: Vec<(String, Option<QoS>)>,
+    // MQTT v5 で SUBACK の拒否により接続がリセットされた場合の、拒否された SUBSCRIBE のパケット ID
+    // (同時に受信した SUBACK のイベントは再接続後に渡されるため、拒否されたトピックは再接続の CONNACK の時点で除外する)
+    subscribe_failed: Option<u16>,
+    // 拒否された SUBSCRIBE にまとめていたため、どのトピックが拒否されたか分からず、1 件ずつ購読し直すトピック
+    isolated_subscriptions: HashSet<String>,
+    // その拒否が subscribe_retry で再試行する一時的な失敗か
+    subscribe_failure_transient: bool,
+    // subscribe_retry で再試行を待っている購読 (トピック、QoS、再試行する時刻)
+    subscribe_retries: Vec<(String, QoS, time::Instant)>,
+    // トピックごとの購読の再試行回数 (許可されたら取り除く)
+    subscribe_attempts: HashMap<String, u32>,
+    // MQTT v5 で SUBACK の拒否により接続がリセットされたトピック (再接続後は購読しない)
+    failed_subscriptions: Vec<String>,
+    metrics: Arc<Metrics>,
+    dedup: Option<Deduplicator>,
+    // sample_rate で表示しなかったメッセージの記録にも使うため、出力先の一覧とは別にも保持する
+    log: Option<Arc<RotatingLog>>,
+    // 表示するメッセージの出力先 (標準出力・ログファイル・syslog・stream_port・output_fifo)
+    sinks: Sinks,
+    // 標準出力の読み取り側が閉じられたため、切断を要求したか
+    stdout_closed: bool,
+    throttle: Option<Throttle>,
+    schema: Option<SchemaValidator>,
+    history: Option<Arc<MessageHistory>>,
+    bridge: Option<Arc<Bridge>>,
+    wait_for: Option<Arc<WaitFor>>,
+    sequences: Option<Arc<SequenceTracker>>,
+    latency: Option<Arc<LatencyRecorder>>,
+    gaps: Option<Arc<GapTracker>>,
+    // --verbose の場合に、受信したメッセージの間のシステムの時刻の変更を検出する
+    clock: Option<ClockWatch>,
+    // format_rules が指定されている場合の、トピックごとの表示するペイロードの書式
+    format_rules: Option<FormatRules>,
+    // 起動時に解析した output_template (output_format: text の場合のみ)
+    template: Option<Template>,
+    // display_regex の正規表現 (いずれにも一致しないトピックのメッセージは表示しない)
+    display_regex: Option<RegexSet>,
+    // sample_rate が指定されている場合の、トピックごとの受信数 (表示するメッセージの判定に使う)
+    sample_counts: HashMap<String, u64>,
+    // 前回重複の件数を表示した時刻と、それ以降に抑制した件数
+    dedup_reported_at: time::Instant,
+    dedup_unreported: u64,
+    // 一度でも接続に成功したか (2 回目以降の ConnAck を再接続として数える)
+    connected_once: bool,
+    // 再接続の通知 (reconnect_webhook_url)
+    webhook: Option<ReconnectWebhook>,
+    // 接続が切れてからの接続の試行回数と、切断の原因となったエラー (接続中は None)
+    reconnect_attempts: u32,
+    disconnect_error: Option<String>,
+    // ブローカーが購読していないシステムトピックを配信したことを警告したか
+    system_topic_warned: bool,
+    // accept_qos にない QoS のメッセージを受信したことを警告したトピック
+    qos_rejected_topics: HashSet<String>,
+    // 最後にメッセージを受信してから、max_packet_size を超えるパケットで続けて切断された回数
+    oversized_packets: u32,
+    // 表示・出力するトピック名をエスケープするか (escape_topics)
+    escape_topics: bool,
+    // discover が指定されている場合の、トピックの一覧から検出したトピックの管理
+    discovery: Option<TopicDiscovery>,
+    // topic_idle_unsub_secs が指定されている場合の、購読ごとの最後の配信の記録
+    idle_subscriptions: Option<IdleSubscriptions>,
+    // max_reconnect_duration_secs が指定されている場合の、接続エラーが続いている時間
+    reconnect_deadline: Option<ReconnectDeadline>,
+    // startup_max_attempts が指定されている場合の、起動時の接続の試行回数
+    startup_retry: Option<StartupRetry>,
+    // ブローカーの名前解決の結果 (接続のたびに解決し直して表示する)
+    resolver: BrokerResolver,
+    // 設定の再読み込み (購読するトピックと QoS の変更) の要求
+    reload_tx: mpsc::Sender<Config>,
+    reload_rx: mpsc::Receiver<Config>,
+}
+
+impl Subscriber {
+    pub fn new(config: Config) -> Subscriber {
+        output_utils::check_payload_format(&config);
+        let template = output_utils::output_template(&config);
+        let actual_qos = qos_utils::resolve_qos(&config);
+        let online_qos = config.online_qos.unwrap_or(QoS::AtMostOnce);
+        let (client, eventloop) = mqtt_utils::create_client(&config, 10); // 10 はイベントループのチャネル容量
+        let (state_tx, _) = watch::channel(ConnectionState::Connecting);
+        let (ready_tx, _) = watch::channel(false);
+        let (reload_tx, reload_rx) = mpsc::channel(1);
+        let log = RotatingLog::open(&config).map(Arc::new);
+        let display_regex = topic_utils::display_regex(&config);
+        let mut sinks = Sinks::new(&config);
+        if let Some(log) = &log {
+            sinks.push(Box::new(Arc::clone(log)));
+        }
+        if let Some(syslog) = SyslogSink::open(&config) {
+            sinks.push(Box::new(syslog));
+        }
+        let metrics = Arc::new(Metrics::new(&config));
+        let throttle = config.throttle.as_ref().map(|rules| Throttle::new(rules, config.throttle_policy.unwrap_or_default(), Arc::clone(&metrics)));
+        let schema = SchemaValidator::new(&config);
+        let latency = config.benchmark.unwrap_or(false).then(|| Arc::new(LatencyRecorder::default()));
+        let gaps = GapTracker::new(&config).map(Arc::new);
+        let sequences = config.verify_exactly_once.unwrap_or(false).then(|| Arc::new(SequenceTracker::default()));
+        let dedup = config.dedup_window_secs.map(|secs| Deduplicator::new(
+            Duration::from_secs(secs),
+            config.dedup_include_topic.unwrap_or(true),
+            config.dedup_max_entries.unwrap_or(DEFAULT_DEDUP_MAX_ENTRIES),
+        ));
+        let escape_topics = output_utils::escape_topics(&config);
+        output_utils::configure_status_topics(&config);
+        let discovery = config.discover.clone().map(TopicDiscovery::new);
+        let idle_subscriptions = IdleSubscriptions::new(&config);
+        let reconnect_deadline = ReconnectDeadline::new(&config);
+        let startup_retry = StartupRetry::new(&config);
+        let format_rules = FormatRules::new(&config);
+        let resolver = BrokerResolver::new(&config);
+        let webhook = ReconnectWebhook::new(&config);
+
+        Subscriber {
+            client,
+            eventloop,
+            config,
+            qos: actual_qos,
+            online_qos,
+            state_tx,
+            ready_tx,
+            pending_subacks: 0,
+            unsent_subscribes: VecDeque::new(),
+            inflight_subscribes: VecDeque::new(),
+            subscribe_rejected: false,
+            suback_deadline: None,
+            subscribe_results: Vec::new(),
+            subscribe_failed: None,
+            isolated_subscriptions: HashSet::new(),
+            subscribe_failure_transient: false,
+            subscribe_retries: Vec::new(),
+            subscribe_attempts: HashMap::new(),
+            failed_subscriptions: Vec::new(),
+            metrics,
+            dedup,
+            log,
+            sinks,
+            stdout_closed: false,
+            throttle,
+            schema,
+            history: None,
+            bridge: None,
+            wait_for: None,
+            sequences,
+            latency,
+            gaps,
+            clock: output_utils::is_verbose().then(ClockWatch::default),
+            format_rules,
+            template,
+            display_regex,
+            sample_counts: HashMap::new(),
+            dedup_reported_at: time::Instant::now(),
+            dedup_unreported: 0,
+            connected_once: false,
+            webhook,
+            reconnect_attempts: 0,
+            disconnect_error: None,
+            system_topic_warned: false,
+            qos_rejected_topics: HashSet::new(),
+            oversized_packets: 0,
+            escape_topics,
+            discovery,
+            idle_subscriptions,
+            reconnect_deadline,
+            startup_retry,
+            resolver,
+            reload_tx,
+            reload_rx,
+        }
+    }
+
+    /// 実行中のサブスクライバーに再読み込みした設定を送るための送信側を返す。
+    ///
+    /// 反映されるのは購読するトピックと QoS の設定 (topics, qos, qos_rules, default_qos, qos_override) のみで、
+    /// 追加されたトピックを購読し、削除されたトピックの購読を解除し、QoS が変わったトピックを新しい QoS で購読し直す。
+    pub fn reload_sender(&self) -> mpsc::Sender<Config> {
+        self.reload_tx.clone()
+    }
+
+    /// benchmark が有効な場合に、受信したメッセージの遅延の記録を返す
+    pub fn latency(&self) -> Option<Arc<LatencyRecorder>> {
+        self.latency.clone()
+    }
+
+    /// max_gap_secs または burst_max_rate が指定されている場合に、メッセージの到着間隔の記録を返す
+    pub fn gaps(&self) -> Option<Arc<GapTracker>> {
+        self.gaps.clone()
+    }
+
+    /// verify_exactly_once が有効な場合に、受信したシーケンス番号の記録を返す
+    pub fn sequences(&self) -> Option<Arc<SequenceTracker>> {
+        self.sequences.clone()
+    }
+
+    /// 表示するメッセージの出力先を追加する (stream_port・output_fifo など、すべてのセッションで共有する出力先用)。
+    /// 標準出力・ログファイル・syslog の出力先は設定から作成済み
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+
+    /// 受信したメッセージを `history` にも記録する (history_size 用)
+    pub fn set_history(&mut self, history: Arc<MessageHistory>) {
+        self.history = Some(history);
+    }
+
+    /// 受信したメッセージを `bridge` で別のブローカーにも転送する (bridge 用)
+    pub fn set_bridge(&mut self, bridge: Arc<Bridge>) {
+        self.bridge = Some(bridge);
+    }
+
+    /// 受信したメッセージが `wait_for` の条件に一致するかも調べる (wait_for 用)
+    pub fn set_wait_for(&mut self, wait_for: Arc<WaitFor>) {
+        self.wait_for = Some(wait_for);
+    }
+
+    /// 接続状態の変化を受け取るレシーバーを返す。
+    ///
+    /// レシーバーは `clone()` して複数箇所で保持でき、`changed().await` で次の状態遷移を待機できる。
+    /// 状態はイベントループ (`run`) の中で更新されるため、`run` を実行していない間は変化しない。
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state_tx.subscribe()
+    }
+
+    /// 準備完了状態 (接続済みで、すべてのトピックの購読がブローカーに受け入れられている) の変化を受け取るレシーバーを返す
+    pub fn readiness(&self) -> watch::Receiver<bool> {
+        self.ready_tx.subscribe()
+    }
+
+    /// ブローカーへの要求を送信するクライアント (終了時の切断の要求などに使う)
+    pub fn client(&self) -> MqttClient {
+        self.client.clone()
+    }
+
+    /// このサブスクライバーの接続 (CONNECT とセッション) を共有してメッセージを発行するハンドルを返す。
+    /// 発行はこのサブスクライバーの `run` / `run_with_handler` のイベントループが処理する (スレッドモデルは `SharedPublisher` を参照)
+    pub fn shared_publisher(&self) -> SharedPublisher {
+        SharedPublisher::new(self.client.clone(), &self.config)
+    }
+
+    /// 受信統計を返す。`run` の実行中も別のタスクから参照できる
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// トピックを購読し、切断されるまでイベントループを処理する。受信したメッセージはコンソールに表示する
+    pub async fn run(&mut self) {
+        self.run_inner(None).await;
+    }
+
+    /// `run` と同様にイベントループを処理するが、受信したメッセージをコンソールに表示せず `handler` に渡す。
+    ///
+    /// ハンドラーはイベントループとは別のタスクで実行され、両者の間には `handler_queue_size` 件までの
+    /// キューが置かれる。ハンドラーの処理が遅くても MQTT のプロトコル処理 (確認応答やキープアライブ) は継続し、
+    /// キューが満杯になった場合は `handler_overflow` に従って待機 (`block`) するか、古いメッセージ (`drop-oldest`)
+    /// または新しいメッセージ (`drop-newest`) を破棄する。破棄した件数は `metrics()` で参照できる。
+    /// イベントループの終了後、キューに残ったメッセージをすべて処理してから戻る。
+    pub async fn run_with_handler<F, Fut>(&mut self, handler: F)
+    where
+        F: Fn(ReceivedMessage) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let capacity = self.config.handler_queue_size.unwrap_or(DEFAULT_HANDLER_QUEUE_SIZE);
+        let policy = self.config.handler_overflow.unwrap_or_default();
+        let queue = Arc::new(HandlerQueue::new(capacity, policy));
+
+        let runner_queue = Arc::clone(&queue);
+        let runner = tokio::spawn(async move {
+            while let Some(message) = runner_queue.pop().await {
+                handler(message).await;
+            }
+        });
+
+        self.run_inner(Some(&queue)).await;
+
+        queue.close();
+        if let Err(e) = runner.await {
+            eprintln!("メッセージハンドラーの実行中にエラーが発生しました: {:?}", e);
+        }
+    }
+
+    async fn run_inner(&mut self, handler: Option<&HandlerQueue>) {
+        // トピックの購読
+        // tail モードでは購読時点の retain メッセージを受け取らない
+        let tail = self.config.tail.unwrap_or(false);
+        let options = SubscribeOptions { skip_retained: tail };
+        // auth_token_url が指定されている場合は、接続前にトークンを取得して password として使用する
+        let mut token_refresh_at = self.apply_auth_token().await;
+        // 購読は CONNACK の受信後にイベントループの中で行う (接続の確認前に SUBSCRIBE を送らず、SUBACK で拒否を検出できるようにする)
+        if self.config.manual_ack.unwrap_or(false) && self.qos.contains(&QoS::AtMostOnce) {
+            eprintln!("警告: manual_ack は QoS 1 以上で購読したメッセージにのみ有効です。QoS 0 のトピックのメッセージには確認応答がありません。");
+        }
+
+        // ping_response_timeout_secs が指定されている場合、PINGREQ の送信後この時刻までに PINGRESP がなければ再接続する
+        let ping_timeout = self.config.ping_response_timeout_secs.map(Duration::from_secs);
+        let mut ping_deadline: Option<time::Instant> = None;
+        let mut keep_alive_rejections = 0;
+        // idle_timeout_secs が指定されている場合、接続中にこの時刻までメッセージを受信しなければ idle_action を行う
+        let idle_timeout = self.config.idle_timeout_secs.map(|secs| Duration::from_secs(secs.max(1)));
+        let mut idle_deadline: Option<time::Instant> = None;
+        // idle_action: reconnect-then-exit で、無受信のために再接続してからまだメッセージを受信していないか
+        let mut idle_reconnected = false;
+        // ベンチマークのループバックのメッセージを次に発行する時刻
+        let mut loopback_at = self.latency.as_ref()
+            .and(self.config.benchmark_loopback_topic.as_ref())
+            .map(|_| time::Instant::now() + LOOPBACK_INTERVAL);
+        // heartbeat_topic が指定されている場合、接続中はこの時刻ごとにハートビートのメッセージを発行する (CONNACK の受信から数える)
+        let heartbeat_interval = self.config.heartbeat_topic.as_ref()
+            .map(|_| Duration::from_secs(self.config.heartbeat_interval_secs.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS).max(1)));
+        let mut heartbeat_at: Option<time::Instant> = None;
+        // dns_refresh_secs が指定されている場合、接続中もこの間隔でブローカーの名前を解決し直す
+        let dns_refresh = self.config.dns_refresh_secs.map(|secs| Duration::from_secs(secs.max(1)));
+        let mut dns_refresh_at = dns_refresh.map(|interval| time::Instant::now() + interval);
+        // max_gap_secs が指定されている場合、この間隔でメッセージが届いていないトピックを確認する
+        let silence_check = self.gaps.as_ref().and_then(|gaps| gaps.silence_check_interval());
+        let mut silence_check_at = silence_check.map(|interval| time::Instant::now() + interval);
+        self.resolver.refresh().await;
+
+        crate::status!("MQTT イベントを処理中...");
+        loop {
+            let throttle_at = self.throttle.as_ref().and_then(Throttle::next_release);
+            let suback_deadline = self.suback_deadline;
+            let retry_at = self.subscribe_retries.iter().map(|(_, _, at)| *at).min();
+            // 接続していない間はメッセージが配信されないため、購読を解除しない
+            let topic_idle_at = self.idle_subscriptions.as_ref()
+                .filter(|_| *self.state_tx.borrow() == ConnectionState::Connected)
+                .and_then(IdleSubscriptions::next_deadline);
+            let gaps_at = silence_check_at.filter(|_| *self.state_tx.borrow() == ConnectionState::Connected);
+            let wake = tokio::select! {
+                result = self.eventloop.poll() => Wake::Event(result),
+                _ = sleep_until_or_pending(token_refresh_at) => Wake::TokenRefresh,
+                _ = sleep_until_or_pending(ping_deadline) => Wake::PingTimeout,
+                Some(config) = self.reload_rx.recv() => Wake::Reload(Box::new(config)),
+                _ = sleep_until_or_pending(loopback_at) => Wake::LoopbackPublish,
+                _ = sleep_until_or_pending(dns_refresh_at) => Wake::DnsRefresh,
+                _ = sleep_until_or_pending(throttle_at) => Wake::ThrottleRelease,
+                _ = sleep_until_or_pending(suback_deadline) => Wake::SubAckTimeout,
+                _ = sleep_until_or_pending(retry_at) => Wake::SubscribeRetry,
+                _ = sleep_until_or_pending(idle_deadline) => Wake::IdleTimeout,
+                _ = sleep_until_or_pending(heartbeat_at) => Wake::Heartbeat,
+                _ = sleep_until_or_pending(topic_idle_at) => Wake::TopicIdle,
+                _ = sleep_until_or_pending(gaps_at) => Wake::SilenceCheck,
+            };
+            let result = match wake {
+                Wake::Event(result) => result,
+                Wake::TokenRefresh => {
+                    // トークンの有効期限が切れる前に新しいトークンで接続し直す
+                    token_refresh_at = self.apply_auth_token().await;
+                    crate::status!("認証トークンを更新しました。ブローカーに再接続します。");
+                    ping_deadline = None;
+                    idle_deadline = None;
+                    self.eventloop.reconnect();
+                    continue;
+                }
+                Wake::LoopbackPublish => {
+                    self.publish_loopback_message();
+                    loopback_at = Some(time::Instant::now() + LOOPBACK_INTERVAL);
+                    continue;
+                }
+                Wake::ThrottleRelease => {
+                    let released = self.throttle.as_mut().map(Throttle::release).unwrap_or_default();
+                    for p in released {
+                        self.deliver(p, handler).await;
+                    }
+                    continue;
+                }
+                Wake::SubAckTimeout => {
+                    self.suback_deadline = None;
+                    self.report_suback_timeout();
+                    continue;
+                }
+                Wake::SubscribeRetry => {
+                    self.retry_subscriptions(options);
+                    continue;
+                }
+                Wake::Heartbeat => {
+                    self.publish_heartbeat_message();
+                    heartbeat_at = heartbeat_interval.map(|interval| time::Instant::now() + interval);
+                    continue;
+                }
+                Wake::TopicIdle => {
+                    self.unsubscribe_idle_topics();
+                    continue;
+                }
+                Wake::SilenceCheck => {
+                    silence_check_at = silence_check.map(|interval| time::Instant::now() + interval);
+                    if let Some(gaps) = &self.gaps {
+                        gaps.check_silent();
+                    }
+                    continue;
+                }
+                Wake::DnsRefresh => {
+                    dns_refresh_at = dns_refresh.map(|interval| time::Instant::now() + interval);
+                    // 接続中のアドレスが名前解決の結果からなくなった場合は、新しいアドレスに接続し直す
+                    if self.resolver.refresh().await {
+                        crate::status!("ブローカーのアドレスが変わったため再接続します (dns_refresh_secs)。");
+                        ping_deadline = None;
+                        idle_deadline = None;
+                        self.set_state(ConnectionState::Reconnecting);
+                        self.eventloop.reconnect();
+                    }
+                    continue;
+                }
+                Wake::Reload(config) => {
+                    self.apply_reload(*config, options);
+                    continue;
+                }
+                Wake::PingTimeout => {
+                    eprintln!("PINGRESP が {} 秒以内に届かないため、ブローカーに再接続します。",
+                        ping_timeout.unwrap_or_default().as_secs());
+                    ping_deadline = None;
+                    idle_deadline = None;
+                    self.set_state(ConnectionState::Reconnecting);
+                    self.eventloop.reconnect();
+                    continue;
+                }
+                Wake::IdleTimeout => {
+                    // 接続が失われたことを検出できていない (半開きの) 場合と、ブローカーにメッセージがないだけの場合を
+                    // 区別するため、reconnect / reconnect-then-exit では再接続してから判断する
+                    idle_deadline = None;
+                    let secs = idle_timeout.unwrap_or_default().as_secs();
+                    let action = self.config.idle_action.unwrap_or_default();
+                    if action == IdleAction::Exit || (action == IdleAction::ReconnectThenExit && idle_reconnected) {
+                        let after = if idle_reconnected { "再接続後も " } else { "" };
+                        exit_with(AppError::NotReceived(format!("{}{} 秒間メッセージを受信しなかったため終了します (idle_timeout_secs)。", after, secs)));
+                    }
+                    eprintln!("警告: {} 秒間メッセージを受信しなかったため、ブローカーに再接続します (idle_timeout_secs)。", secs);
+                    idle_reconnected = true;
+                    ping_deadline = None;
+                    self.set_state(ConnectionState::Reconnecting);
+                    self.eventloop.reconnect();
+                    continue;
+                }
+            };
+            match result {
+                Ok(event) => {
+                    // println!("受信イベント: {:?}", event); // 詳細なイベントログが必要な場合にコメントを外す
+                    match event {
+                        MqttEvent::Publish(mut p) => {
+                            self.oversized_packets = 0;
+                            self.record_subscription_activity(&p.topic, options);
+                            self.check_clock();
+                            // 購読のたびに届く retain メッセージは数えない (表示しない重複などのメッセージは受信として扱う)
+                            if !p.retain {
+                                idle_deadline = idle_timeout.map(|timeout| time::Instant::now() + timeout);
+                                idle_reconnected = false;
+                            }
+                            // manual_ack ではハンドラーに渡したメッセージの確認応答をハンドラーが送信する
+                            if self.config.manual_ack.unwrap_or(false) {
+                                p.acker = Some(self.client.clone());
+                                if handler.is_none() {
+                                    send_ack(&mut p);
+                                }
+                            }
+                            if let Some(discovery) = &mut self.discovery
+                                && discovery.is_discovery_topic(&p.topic) {
+                                let discovered = discovery.discover(&p.payload, &self.config.topics);
+                                if !discovered.is_empty() {
+                                    self.subscribe(discovered, options);
+                                }
+                                send_ack(&mut p);
+                                continue;
+                            }
+                            if self.is_unsubscribed_system_topic(&p.topic) {
+                                send_ack(&mut p);
+                                continue;
+                            }
+                            // 受け付けない QoS のメッセージもプロトコル上は確認応答する
+                            if self.is_rejected_qos(&p) {
+                                send_ack(&mut p);
+                                continue;
+                            }
+                            self.metrics.record_message(p.payload.len());
+                            self.record_topic(&p.topic);
+                            self.record_payload_size(&p.topic, p.payload.len());
+                            // 購読時にまとめて届く retain メッセージは到着間隔に含めない
+                            if let Some(gaps) = &self.gaps
+                                && !p.retain {
+                                gaps.record(&p.topic);
+                            }
+                            // ベンチマークの発行時刻はシーケンス番号より外側に付いている
+                            let (timestamp, payload) = match &self.latency {
+                                Some(_) => benchmark_utils::split_timestamp(&p.payload).map_or((None, &p.payload[..]), |(t, rest)| (Some(t), rest)),
+                                None => (None, &p.payload[..]),
+                            };
+                            if let (Some(latency), Some(timestamp)) = (&self.latency, timestamp) {
+                                // ループバックのメッセージは遅延の比較用のため、検証や表示の対象にしない
+                                let loopback = self.config.benchmark_loopback_topic.as_deref() == Some(p.topic.as_str());
+                                latency.record(timestamp, loopback);
+                                if loopback {
+                                    send_ack(&mut p);
+                                    continue;
+                                }
+                            }
+                            // 重複の検出のため、retain や重複の除外より前に記録する
+                            if let Some(sequences) = &self.sequences {
+                                sequences.record(&p.topic, p.qos, p.retain, payload);
+                            }
+                            // MQTT 3.1.1 では Retain Handling を指定できないため、retain メッセージを破棄する
+                            if tail && p.retain {
+                                send_ack(&mut p);
+                                continue;
+                            }
+                            // 確認応答はイベントループが行うため、重複メッセージも ack される
+                            if self.is_duplicate(&p) {
+                                send_ack(&mut p);
+                                continue;
+                            }
+                            self.validate_utf8(&p);
+                            // スキーマに適合しないメッセージは表示せず、schema_deadletter_topic に発行する
+                            if self.is_schema_invalid(&p) {
+                                send_ack(&mut p);
+                                continue;
+                            }
+                            // 最大レートを超えたメッセージも、ブローカーへの確認応答はすぐに行う
+                            if let Some(throttle) = &mut self.throttle {
+                                match throttle.admit(&p) {
+                                    Admission::Pass => {}
+                                    Admission::Drop => {
+                                        send_ack(&mut p);
+                                        continue;
+                                    }
+                                    Admission::Delay => {
+                                        send_ack(&mut p);
+                                        throttle.delay(p);
+                                        continue;
+                                    }
+                                }
+                            }
+                            self.deliver(p, handler).await;
+                        }
+                        MqttEvent::ConnAck { session_present, properties } => {
+                            if !mqtt_utils::check_server_keep_alive(&self.config, properties.as_deref(), &mut keep_alive_rejections) {
+                                ping_deadline = None;
+                                self.set_state(ConnectionState::Reconnecting);
+                                self.eventloop.reconnect();
+                                time::sleep(Duration::from_secs(5)).await;
+                                continue;
+                            }
+                            self.resolver.connected();
+                            match self.resolver.describe() {
+                                Some(addresses) => crate::status!("ブローカーに接続しました ({})。", addresses),
+                                None => crate::status!("ブローカーに接続しました。"),
+                            }
+                            if !self.connected_once && self.config.show_capabilities.unwrap_or(false) {
+                                output_utils::print_capabilities(mqtt_utils::protocol_version(&self.config), properties.as_deref());
+                            }
+                            if !self.connected_once
+                                && let Some(requested) = &self.config.connect_properties
+                                && mqtt_utils::protocol_version(&self.config) == ProtocolVersion::V5 {
+                                output_utils::print_connect_properties(requested, properties.as_deref());
+                            }
+                            ping_deadline = None;
+                            idle_deadline = idle_timeout.map(|timeout| time::Instant::now() + timeout);
+                            heartbeat_at = heartbeat_interval.map(|interval| time::Instant::now() + interval);
+                            if let Some(idle) = &mut self.idle_subscriptions {
+                                idle.restart();
+                            }
+                            if let Some(error) = self.disconnect_error.take()
+                                && let Some(webhook) = &self.webhook {
+                                webhook.reconnected(self.reconnect_attempts, &error);
+                            }
+                            self.reconnect_attempts = 0;
+                            if let Some(deadline) = &mut self.reconnect_deadline {
+                                deadline.connected();
+                            }
+                            if let Some(startup) = &mut self.startup_retry {
+                                startup.connected();
+                            }
+                            let isolated = match self.subscribe_failed.take() {
+                                Some(pkid) => self.exclude_failed_subscription(pkid),
+                                None => Vec::new(),
+                            };
+                            if self.connected_once {
+                                self.metrics.record_reconnect();
+                                // ブローカーにセッションが残っていない場合は購読も失われているため、購読し直す
+                                if !session_present {
+                                    self.subscribe_all(options);
+                                } else if !isolated.is_empty() {
+                                    self.subscribe(isolated, options);
+                                }
+                            } else {
+                                self.subscribe_all(options);
+                            }
+                            self.connected_once = true;
+                            self.set_state(ConnectionState::Connected);
+                            // クリーンセッションではクライアントの状態が残らないため、接続のたびに発行する
+                            self.publish_online_message();
+                        }
+                        MqttEvent::Outgoing(Outgoing::PingReq) => {
+                            // 応答を待っている間に次の PINGREQ が送られても期限は延ばさない
+                            if let Some(timeout) = ping_timeout
+                                && ping_deadline.is_none() {
+                                ping_deadline = Some(time::Instant::now() + timeout);
+                            }
+                        }
+                        MqttEvent::PingResp => ping_deadline = None,
+                        MqttEvent::Outgoing(Outgoing::Subscribe(pkid)) => {
+                            if let Some(topics) = self.unsent_subscribes.pop_front() {
+                                self.inflight_subscribes.push_back((pkid, topics));
+                            }
+                        }
+                        MqttEvent::SubAck { pkid, granted } => {
+                            let topics = self.inflight_subscribes.iter().position(|(id, _)| *id == pkid)
+                                .and_then(|i| self.inflight_subscribes.remove(i))
+                                .map(|(_, topics)| topics)
+                                .unwrap_or_default();
+                            let mut rejected = Vec::new();
+                            for (i, granted_qos) in granted.iter().enumerate() {
+                                let topic = topics.get(i);
+                                // MQTT 3.1.1 の拒否 (0x80) は理由を区別できないため、一時的な失敗として再試行する
+                                if let (None, Some((topic, qos))) = (granted_qos, topic)
+                                    && self.schedule_subscribe_retry(topic, *qos) {
+                                    continue;
+                                }
+                                if let (Some(_), Some((topic, _))) = (granted_qos, topic)
+                                    && let Some(attempts) = self.subscribe_attempts.remove(topic) {
+                                    crate::status!("トピック '{}' の購読は {} 回目の再試行で許可されました。", topic, attempts);
+                                }
+                                let name = topic.map(|(topic, _)| topic.clone()).unwrap_or_else(|| "(不明)".to_string());
+                                self.subscribe_results.push((name, *granted_qos));
+                                match (granted_qos, topic) {
+                                    (None, _) => rejected.push(topic.map(|(topic, _)| topic.as_str()).unwrap_or("(不明)")),
+                                    // ブローカーは要求より低い QoS で購読を受け付けることがある
+                                    (Some(granted_qos), Some((topic, requested))) if qos_utils::qos_number(*granted_qos) < qos_utils::qos_number(*requested) => {
+                                        eprintln!("警告: トピック '{}' の購読は QoS {} を要求しましたが、ブローカーは QoS {} を許可しました。",
+                                            topic, qos_utils::qos_number(*requested), qos_utils::qos_number(*granted_qos));
+                                        self.check_granted_min_qos(topic, *granted_qos);
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            if !rejected.is_empty() {
+                                eprintln!("警告: 次のトピックの購読がブローカーに拒否されました (パケット ID: {}): {}", pkid, rejected.join(", "));
+                                self.subscribe_rejected = true;
+                            }
+                            self.pending_subacks = self.pending_subacks.saturating_sub(1);
+                            if self.pending_subacks == 0 {
+                                self.suback_deadline = None;
+                                // 再試行を待っている購読がある場合は、結果の表示を再試行の後にする
+                                if self.subscribe_retries.is_empty() {
+                                    self.report_subscribe_results();
+                                }
+                            }
+                            self.update_readiness();
+                        }
+                        MqttEvent::Disconnect(reason) => {
+                            ping_deadline = None;
+                            idle_deadline = None;
+                            heartbeat_at = None;
+                            eprintln!("ブローカーから切断されました。理由コード: {:?} (0x{:02X}), 理由: {}",
+                                reason.code, reason.code as u8, reason.reason_string.as_deref().unwrap_or("(なし)"));
+                            let reconnect = reason.should_reconnect();
+                            let error = format!("ブローカーからの切断 (理由コード: {:?}, 理由: {})",
+                                reason.code, reason.reason_string.as_deref().unwrap_or("(なし)"));
+                            self.record_disconnect(error.clone());
+                            self.set_state(ConnectionState::Disconnected(Some(reason)));
+                            if !reconnect {
+                                // 再接続すると他のクライアントとセッションを奪い合うことになるため終了する
+                                eprintln!("この理由による切断では再接続を行いません。");
+                                break;
+                            }
+                            if let Some(deadline) = &mut self.reconnect_deadline {
+                                deadline.failed(&error);
+                            }
+                            // 接続は破棄されているため、次の poll で再接続が行われる
+                            time::sleep(Duration::from_secs(1)).await;
+                        }
+                        MqttEvent::Outgoing(Outgoing::Disconnect) => {
+                            crate::status!("ブローカーから切断しました。");
+                            self.set_state(ConnectionState::Disconnected(None));
+                            break;  // イベントループを終了
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    ping_deadline = None;
+                    // 接続していない間は監視しない (再接続後の CONNACK から数え直す)
+                    idle_deadline = None;
+                    heartbeat_at = None;
+                    // 接続が切れた場合は SUBACK も届かないため、再接続後の購読で期限を設定し直す
+                    self.suback_deadline = None;
+                    // ブローカーから通知された切断理由がある場合はそれを残す
+                    if !matches!(*self.state_tx.borrow(), ConnectionState::Disconnected(_)) {
+                        self.set_state(ConnectionState::Disconnected(None));
+                    }
+                    if e.is_requests_done() {
+                        exit_with(AppError::Unavailable("イベントループへの要求チャネルが閉じられたため終了します。".to_string()));
+                    }
+                    self.record_disconnect(e.to_string());
+                    let refused = mqtt_utils::report_connect_refusal(&e, &self.config);
+                    if self.config.fail_fast.unwrap_or(false) {
+                        exit_with(e.to_app_error(format!("ブローカーへの接続でエラーが発生したため終了します (fail_fast): {:?}", e)));
+                    }
+                    // 起動時の接続の失敗は startup_retry_delay_ms の間隔で再試行する (一度接続した後は以下の通常の再接続)。
+                    // 起動時は startup_max_attempts の上限 (終了コード 69) を優先し、max_reconnect_duration_secs の時間を計らない
+                    if let Some(delay) = self.startup_retry.as_mut().and_then(|startup| startup.failed(&e.to_string())) {
+                        time::sleep(delay).await;
+                        self.resolver.refresh().await;
+                        self.set_state(ConnectionState::Reconnecting);
+                        continue;
+                    }
+                    if let Some(deadline) = &mut self.reconnect_deadline {
+                        deadline.failed(&e.to_string());
+                    }
+                    if let Some(size) = e.oversized_packet() {
+                        self.handle_oversized_packet(size);
+                        time::sleep(Duration::from_secs(1)).await;
+                        self.set_state(ConnectionState::Reconnecting);
+                        continue;
+                    }
+                    let err_str = e.to_string();
+                    if let Some(rejection) = e.rejection() {
+                        eprintln!("エラー: {}。再接続します。", rejection);
+                        if e.is_subscribe_rejected() {
+                            self.subscribe_rejected = true;
+                            let buffered = self.eventloop.buffered_subacks();
+                            self.handle_subscribe_failure(e.is_transient_subscribe_failure(), &buffered);
+                            self.update_readiness();
+                        }
+                        time::sleep(Duration::from_secs(1)).await;
+                    } else if refused {
+                        time::sleep(Duration::from_secs(5)).await;
+                    } else if err_str.contains("disconnected") {
+                        eprintln!("ブローカーへの接続が閉じられました。再接続を試行中...");
+                        time::sleep(Duration::from_secs(5)).await;
+                    } else {
+                        eprintln!("イベントループでエラーが発生しました: {:?}", e);
+                        time::sleep(Duration::from_secs(1)).await;
+                    }
+                    // 次の poll での再接続では rumqttc も名前を解決し直す (DNS によるフェイルオーバーでアドレスが変わった場合に追従する)
+                    self.resolver.refresh().await;
+                    // 次の poll で再接続が行われる
+                    self.set_state(ConnectionState::Reconnecting);
+                }
+            }
+        }
+    }
+
+    // 受信したメッセージを転送し、ハンドラーに渡すか、表示して stream / FIFO / syslog / ログファイルに出力する
+    async fn deliver(&mut self, p: ReceivedMessage, handler: Option<&HandlerQueue>) {
+        if let Some(history) = &self.history {
+            history.record(&p);
+        }
+        // 表示の有無にかかわらず、受信したままのメッセージを転送する
+        if let Some(bridge) = &self.bridge {
+            bridge.forward(&p).await;
+        }
+        // 表示の有無 (sample_rate など) にかかわらず、受信したままのメッセージで判定する
+        if let Some(wait_for) = &self.wait_for {
+            wait_for.check(&p);
+        }
+        if let Some(queue) = handler {
+            if queue.push(p).await {
+                self.metrics.record_handler_drop();
+            }
+            return;
+        }
+        // display_regex に一致しないメッセージは表示やログへの出力をしない (確認応答は済んでいる)
+        if let Some(regex) = &self.display_regex
+            && !regex.is_match(&p.topic) {
+            return;
+        }
+        // ハンドラーには受信したままのペイロードを渡し、表示とログにはデコードしたペイロードを使う
+        let mut p = match &self.format_rules {
+            Some(rules) => rules.apply(p),
+            None => output_utils::decode_payload(p, &self.config),
+        };
+        if self.escape_topics
+            && let Cow::Owned(escaped) = output_utils::escape_topic(&p.topic) {
+            p.topic = escaped;
+        }
+        if self.config.hide_retained_deletes.unwrap_or(false) && output_utils::is_retained_delete(&p) {
+            return;
+        }
+        if self.is_sampled_out(&p) {
+            return;
+        }
+        let output = output_utils::format_message(&p, &self.config, self.template.as_ref());
+        if let Err(e) = self.sinks.write(&p, &output, &self.config) {
+            self.handle_stdout_error(e);
+        }
+    }
+
+    // 標準出力に書き込めない場合は終了する。読み取り側のプロセスが終了した場合は、ブローカーから切断して正常に終了する
+    // (切断の DISCONNECT を送信するとイベントループを終了する)
+    fn handle_stdout_error(&mut self, e: std::io::Error) {
+        if !sink_utils::is_stdout_closed(&e) {
+            exit_with(AppError::Unavailable(format!("標準出力に書き込めませんでした: {}", e)));
+        }
+        if self.stdout_closed {
+            return;
+        }
+        self.stdout_closed = true;
+        eprintln!("標準出力の読み取り側が閉じられたため、ブローカーから切断して終了します。");
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client.disconnect().await {
+                eprintln!("ブローカーからの切断中にエラーが発生しました: {:?}", e);
+            }
+        });
+    }
+
+    // max_packet_size を超えるパケットを受信して切断された場合の処理 (oversized_packet_policy)。
+    // パケットは解析前に破棄されるためトピックは分からない。ブローカーが同じメッセージ (retain メッセージや
+    // 永続セッションの未配信のメッセージ) を再接続のたびに送ってくる場合に、再接続を繰り返し続けないようにする
+    fn handle_oversized_packet(&mut self, size: usize) {
+        let limit = self.eventloop.max_packet_size();
+        eprintln!("エラー: max_packet_size ({} バイト) を超えるパケット ({} バイト) を受信したため、接続が切断されました。", limit, size);
+        let policy = self.config.oversized_packet_policy.unwrap_or_default();
+        if policy == OversizedPacketPolicy::Raise && size <= mqtt_utils::MAX_PROTOCOL_PACKET_SIZE {
+            // MQTT 5 の Maximum Packet Size は固定ヘッダー (最大 5 バイト) を含むパケット全体のサイズ
+            let raised = size + 5;
+            eprintln!("警告: max_packet_size を {} バイトに引き上げて再接続します (oversized_packet_policy: raise)。", raised);
+            self.eventloop.set_max_packet_size(raised);
+            return;
+        }
+        self.oversized_packets += 1;
+        if self.oversized_packets >= MAX_OVERSIZED_PACKET_RETRIES {
+            exit_with(AppError::Unavailable(format!(
+                "max_packet_size を超えるパケットで {} 回続けて切断されたため終了します。ブローカーが同じメッセージを再送している可能性があります。\
+                 max_packet_size を引き上げるか、oversized_packet_policy: raise を指定してください。", self.oversized_packets)));
+        }
+        crate::status!("このパケットを読み飛ばすため再接続します。");
+    }
+
+    // auth_token_url からトークンを取得して次回の接続の認証情報に設定し、トークンを更新すべき時刻を返す
+    async fn apply_auth_token(&mut self) -> Option<time::Instant> {
+        let url = self.config.auth_token_url.clone()?;
+        let token = token_utils::fetch_token_with_retry(&self.config, &url).await;
+        let username = self.config.username.clone().unwrap_or_default();
+        self.eventloop.set_credentials(&username, &token.token);
+        token.refresh_after(&self.config).map(|after| time::Instant::now() + after)
+    }
+
+    // すべてのトピックを subscribe_batch_size 件ずつ 1 つの SUBSCRIBE パケットにまとめて購読する (再接続後の購読し直しにも使う)
+    fn subscribe_all(&mut self, options: SubscribeOptions) {
+        let mut filters: Vec<(String, QoS)> = self.config.topics.iter().enumerate()
+            // QoS が指定されていない場合は QoS::AtMostOnce (QoS 0) をデフォルトとする
+            .map(|(i, topic)| (topic.clone(), self.qos.get(i).copied().unwrap_or(QoS::AtMostOnce)))
+            .collect();
+        if self.config.include_system_topics.unwrap_or(false) {
+            topic_utils::add_system_topics(&mut filters);
+        }
+        // 検出したトピックは再接続時にも購読し直す
+        if let Some(discovery) = &self.discovery {
+            filters.extend(discovery.filters());
+        }
+        // 無配信のため自動で解除した購読は、再接続時にも購読し直さない
+        if let Some(idle) = &self.idle_subscriptions {
+            filters.retain(|(topic, _)| !idle.is_unsubscribed(topic));
+        }
+
+        self.pending_subacks = 0;
+        self.subscribe_rejected = !self.failed_subscriptions.is_empty();
+        self.unsent_subscribes.clear();
+        self.inflight_subscribes.clear();
+        self.subscribe_results.clear();
+        self.suback_deadline = None;
+        let delay = self.subscribe_delay();
+        if let Some(delay) = delay {
+            crate::status!("{} ms 待ってから購読します (subscribe_delay_ms)。", delay.as_millis());
+        }
+        self.subscribe_after(filters, options, delay);
+    }
+
+    // CONNACK の受信後、購読を開始するまで待つ時間 (subscribe_delay_ms)
+    fn subscribe_delay(&self) -> Option<Duration> {
+        self.config.subscribe_delay_ms.filter(|&ms| ms > 0).map(Duration::from_millis)
+    }
+
+    // トピックを subscribe_batch_size 件ずつ 1 つの SUBSCRIBE パケットにまとめて購読する。
+    // イベントループのチャネルが埋まっても止まらないよう、購読要求の送信は別のタスクで行う
+    fn subscribe(&mut self, filters: Vec<(String, QoS)>, options: SubscribeOptions) {
+        self.subscribe_after(filters, options, None);
+    }
+
+    // delay が指定されている場合は、その時間だけ待ってから購読要求を送信する
+    fn subscribe_after(&mut self, mut filters: Vec<(String, QoS)>, options: SubscribeOptions, delay: Option<Duration>) {
+        // 再試行を待っている購読は、再試行の時刻になってから購読する
+        filters.retain(|(topic, _)| !self.failed_subscriptions.contains(topic)
+            && !self.subscribe_retries.iter().any(|(retrying, _, _)| retrying == topic));
+        if let Some(idle) = &mut self.idle_subscriptions {
+            idle.subscribed(&filters);
+        }
+        let batch_size = self.config.subscribe_batch_size.unwrap_or(DEFAULT_SUBSCRIBE_BATCH_SIZE).max(1);
+        // まとめた SUBSCRIBE が拒否されたトピックは、拒否されたトピックを特定できるよう 1 件ずつ購読する
+        let (isolated, filters): (Vec<_>, Vec<_>) = filters.into_iter().partition(|(topic, _)| self.isolated_subscriptions.contains(topic));
+        let batches: Vec<Vec<(String, QoS)>> = filters.chunks(batch_size).map(<[_]>::to_vec)
+            .chain(isolated.into_iter().map(|filter| vec![filter]))
+            .collect();
+
+        self.pending_subacks += batches.len();
+        self.unsent_subscribes.extend(batches.iter().cloned());
+        self.update_readiness();
+        if !batches.is_empty() && self.suback_deadline.is_none() {
+            let timeout = Duration::from_secs(self.config.subscribe_timeout_secs.unwrap_or(DEFAULT_SUBSCRIBE_TIMEOUT_SECS).max(1));
+            self.suback_deadline = Some(time::Instant::now() + delay.unwrap_or_default() + timeout);
+        }
+
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Some(delay) = delay {
+                time::sleep(delay).await;
+            }
+            subscribe_batches(&client, &batches, options).await;
+        });
+    }
+
+    // 再読み込みした設定と現在の購読を比べ、追加・削除・QoS が変わったトピックだけを購読し直す
+    fn apply_reload(&mut self, config: Config, options: SubscribeOptions) {
+        let mut current: Vec<(String, QoS)> = self.config.topics.iter().cloned().zip(self.qos.iter().copied()).collect();
+        let qos = qos_utils::resolve_qos(&config);
+        let mut reloaded: Vec<(String, QoS)> = config.topics.iter().cloned().zip(qos.iter().copied()).collect();
+        if self.config.include_system_topics.unwrap_or(false) {
+            topic_utils::add_system_topics(&mut current);
+            topic_utils::add_system_topics(&mut reloaded);
+        }
+
+        // enforce_min_qos は起動時の設定のルールで検査する
+        let Some(ReloadPlan { changed, removed }) = plan_reload(&self.config, &current, &reloaded) else {
+            eprintln!("設定の再読み込み: enforce_min_qos に違反しているため、変更を反映しません。");
+            return;
+        };
+
+        self.config.topics = config.topics;
+        self.config.qos = config.qos;
+        self.config.qos_rules = config.qos_rules;
+        self.config.default_qos = config.default_qos;
+        self.config.qos_override = config.qos_override;
+        self.qos = qos;
+
+        if let Some(idle) = &mut self.idle_subscriptions {
+            idle.remove(&removed);
+        }
+        if !removed.is_empty() {
+            let client = self.client.clone();
+            tokio::spawn(async move {
+                for topic in removed {
+                    if let Err(e) = client.unsubscribe(&topic).await {
+                        eprintln!("トピック '{}' の購読解除中にエラーが発生しました: {:?}", topic, e);
+                    }
+                }
+            });
+        }
+        if !changed.is_empty() {
+            // 同じトピックフィルターへの SUBSCRIBE は既存の購読を置き換えるため、QoS の変更も購読し直すだけでよい
+            self.subscribe(changed, options);
+        }
+    }
+
+    // topic_idle_unsub_secs の間メッセージを配信しなかった購読を解除する
+    fn unsubscribe_idle_topics(&mut self) {
+        let Some(idle) = &mut self.idle_subscriptions else {
+            return;
+        };
+        let secs = idle.timeout().as_secs();
+        let expired = idle.expire();
+        if expired.is_empty() {
+            return;
+        }
+        for topic in &expired {
+            crate::status!("トピック '{}' は {} 秒間メッセージを配信しなかったため、購読を解除します (topic_idle_unsub_secs)。", topic, secs);
+        }
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            for topic in expired {
+                if let Err(e) = client.unsubscribe(&topic).await {
+                    eprintln!("トピック '{}' の購読解除中にエラーが発生しました: {:?}", topic, e);
+                }
+            }
+        });
+    }
+
+    // 受信したメッセージを購読ごとの最後の配信として記録し、topic_idle_resubscribe の場合は一致する解除済みの購読を購読し直す
+    fn record_subscription_activity(&mut self, topic: &str, options: SubscribeOptions) {
+        let Some(idle) = &mut self.idle_subscriptions else {
+            return;
+        };
+        let filters = idle.record(topic);
+        if filters.is_empty() {
+            return;
+        }
+        for (filter, _) in &filters {
+            crate::status!("購読を解除したトピック '{}' に一致するメッセージ ('{}') を受信したため、購読し直します (topic_idle_resubscribe)。", filter, output_utils::status_topic(topic));
+        }
+        self.subscribe(filters, options);
+    }
+
+    // 前回のメッセージからシステムの時刻が変わった場合に、受信時刻の差が実際の間隔と一致しないことを表示する (--verbose)
+    fn check_clock(&mut self) {
+        let Some(jump) = self.clock.as_mut().and_then(|clock| clock.record(ClockWatch::now())) else {
+            return;
+        };
+        let (direction, amount) = match jump {
+            ClockJump::Forward(amount) => ("進み", amount),
+            ClockJump::Backward(amount) => ("戻り", amount),
+        };
+        eprintln!("注意: 前のメッセージの受信からシステムの時刻が約 {:.1} 秒{}ました (NTP による補正など)。\
+            前後のメッセージの受信時刻の差は実際の間隔と一致しません (再接続や idle_timeout_secs などの間隔には影響しません)。",
+            amount.as_secs_f64(), direction);
+    }
+
+    // 接続が切れたこと、または再接続に失敗したことを記録する (reconnect_webhook_url で通知する試行回数と切断の原因)。
+    // 一度も接続していない間の失敗は再接続として扱わない
+    fn record_disconnect(&mut self, error: String) {
+        if !self.connected_once {
+            return;
+        }
+        self.reconnect_attempts += 1;
+        if self.disconnect_error.is_none() {
+            if let Some(webhook) = &self.webhook {
+                webhook.disconnected(&error);
+            }
+            self.disconnect_error = Some(error);
+        }
+    }
+
+    // subscribe_timeout_secs 以内に SUBACK が届かなかった購読を表示する (require_all_subscriptions の場合は終了する)。
+    // 準備完了にはならないまま、SUBACK を待ち続ける
+    fn report_suback_timeout(&self) {
+        if self.pending_subacks == 0 {
+            return;
+        }
+        let topics: Vec<&str> = self.unsent_subscribes.iter()
+            .chain(self.inflight_subscribes.iter().map(|(_, topics)| topics))
+            .flatten()
+            .map(|(topic, _)| topic.as_str())
+            .collect();
+        let timeout = self.config.subscribe_timeout_secs.unwrap_or(DEFAULT_SUBSCRIBE_TIMEOUT_SECS).max(1);
+        let message = format!("{} 秒以内に SUBACK が届かなかった購読があります: {}", timeout, topics.join(", "));
+        if self.config.require_all_subscriptions.unwrap_or(false) {
+            exit_with(AppError::Unavailable(format!("{} 終了します (require_all_subscriptions)。", message)));
+        }
+        eprintln!("警告: {}。準備完了にはならないまま応答を待ちます。", message);
+    }
+
+    // SUBACK がすべて届いたときに、許可・拒否されたトピックの一覧を表示する (require_all_subscriptions の場合は拒否があれば終了する)
+    fn report_subscribe_results(&mut self) {
+        let results = std::mem::take(&mut self.subscribe_results);
+        if results.is_empty() && self.failed_subscriptions.is_empty() {
+            return;
+        }
+        let granted: Vec<String> = results.iter()
+            .filter_map(|(topic, qos)| qos.map(|qos| format!("{} (QoS {})", topic, qos_utils::qos_number(qos))))
+            .collect();
+        let mut denied: Vec<&str> = results.iter().filter(|(_, qos)| qos.is_none()).map(|(topic, _)| topic.as_str()).collect();
+        for topic in &self.failed_subscriptions {
+            if !denied.contains(&topic.as_str()) {
+                denied.push(topic);
+            }
+        }
+        crate::status!("購読結果: 許可 {} 件、拒否 {} 件", granted.len(), denied.len());
+        if !granted.is_empty() {
+            crate::status!("  許可: {}", granted.join(", "));
+        }
+        if denied.is_empty() {
+            return;
+        }
+        crate::status!("  拒否: {}", denied.join(", "));
+        if self.config.require_all_subscriptions.unwrap_or(false) {
+            exit_with(AppError::Auth(format!("購読が拒否されたトピックがあるため終了します (require_all_subscriptions): {}", denied.join(", "))));
+        }
+        if granted.is_empty() {
+            eprintln!("警告: 許可された購読がないため、メッセージを受信できません。");
+        }
+    }
+
+    // MQTT v5 で SUBACK の拒否によって接続がリセットされた場合の処理。
+    // `transient` は一時的な失敗の理由コードか (subscribe_retry が指定されていれば再接続後に再試行する)。
+    // rumqttc は拒否された SUBACK のパケット ID を通知しないが、ブローカーは SUBACK を SUBSCRIBE を受信した順に返すため、
+    // SUBACK を待っている SUBSCRIBE のうち、エラーより前に受信した SUBACK (`buffered`) がない最も古いものが拒否されたもの
+    fn handle_subscribe_failure(&mut self, transient: bool, buffered: &HashSet<u16>) {
+        self.subscribe_failed = self.inflight_subscribes.iter().map(|(pkid, _)| *pkid).find(|pkid| !buffered.contains(pkid));
+        self.subscribe_failure_transient = transient && self.config.subscribe_retry.is_some();
+        if !self.subscribe_failure_transient && self.config.require_all_subscriptions.unwrap_or(false) {
+            exit_with(AppError::Auth("購読がブローカーに拒否されたため終了します (require_all_subscriptions)。".to_string()));
+        }
+    }
+
+    // 再接続の CONNACK の時点で、パケット ID が `pkid` の拒否された SUBSCRIBE のトピックを以降購読しないよう除外する。
+    // 一時的な失敗の場合は、除外する代わりに subscribe_retry に従って再試行する。
+    // rumqttc は拒否を含む SUBACK のトピックごとの理由コードを通知しないため、subscribe_batch_size で複数のトピックを
+    // まとめていた場合は除外せずに 1 件ずつ購読し直し、それぞれの SUBACK で拒否されたトピックを特定する。
+    // 1 件ずつ購読し直すトピック (ブローカーにセッションが残っている場合に呼び出し側が購読する) を返す
+    fn exclude_failed_subscription(&mut self, pkid: u16) -> Vec<(String, QoS)> {
+        let Some(topics) = self.inflight_subscribes.iter().position(|(id, _)| *id == pkid)
+            .and_then(|i| self.inflight_subscribes.remove(i))
+            .map(|(_, topics)| topics) else {
+            return Vec::new();
+        };
+        // 拒否された SUBSCRIBE の SUBACK は届かないため、待つのをやめる
+        self.pending_subacks = self.pending_subacks.saturating_sub(1);
+        if self.pending_subacks == 0 {
+            self.suback_deadline = None;
+        }
+        let mut excluded = Vec::new();
+        let mut isolated = Vec::new();
+        let batched = topics.len() > 1;
+        for (topic, qos) in topics {
+            if self.subscribe_failure_transient && self.schedule_subscribe_retry(&topic, qos) {
+                continue;
+            }
+            if batched {
+                self.isolated_subscriptions.insert(topic.clone());
+                isolated.push((topic, qos));
+            } else {
+                excluded.push(topic);
+            }
+        }
+        if !isolated.is_empty() {
+            let topics: Vec<&str> = isolated.iter().map(|(topic, _)| topic.as_str()).collect();
+            eprintln!("警告: まとめて購読したトピックのいずれかが拒否されたため、1 件ずつ購読し直します: {}", topics.join(", "));
+        }
+        self.subscribe_rejected = !self.failed_subscriptions.is_empty() || !excluded.is_empty();
+        if excluded.is_empty() {
+            return isolated;
+        }
+        if self.config.require_all_subscriptions.unwrap_or(false) {
+            exit_with(AppError::Auth(format!("購読が拒否されたトピックがあるため終了します (require_all_subscriptions): {}", excluded.join(", "))));
+        }
+        eprintln!("警告: 拒否されたトピックを除いて購読します: {}", excluded.join(", "));
+        self.failed_subscriptions.extend(excluded);
+        isolated
+    }
+
+    // 拒否された購読の再試行を subscribe_retry に従って予約する。再試行しない場合 (未指定・回数の上限) は `false` を返す
+    fn schedule_subscribe_retry(&mut self, topic: &str, qos: QoS) -> bool {
+        let Some(retry) = &self.config.subscribe_retry else {
+            return false;
+        };
+        let max_attempts = retry.max_attempts.unwrap_or(DEFAULT_SUBSCRIBE_RETRY_ATTEMPTS);
+        let base_delay = Duration::from_millis(retry.delay_ms.unwrap_or(DEFAULT_SUBSCRIBE_RETRY_DELAY_MS));
+        let attempts = self.subscribe_attempts.entry(topic.to_string()).or_insert(0);
+        if *attempts >= max_attempts {
+            if max_attempts > 0 {
+                eprintln!("警告: トピック '{}' の購読は {} 回再試行しても許可されませんでした。", topic, max_attempts);
+            }
+            return false;
+        }
+        *attempts += 1;
+        let delay = base_delay.saturating_mul(1 << (*attempts - 1).min(16)).min(MAX_SUBSCRIBE_RETRY_DELAY);
+        eprintln!("警告: トピック '{}' の購読がブローカーに拒否されました。{:.1} 秒後に再試行します ({}/{} 回目、subscribe_retry)。",
+            topic, delay.as_secs_f64(), attempts, max_attempts);
+        self.subscribe_retries.push((topic.to_string(), qos, time::Instant::now() + delay));
+        self.update_readiness();
+        true
+    }
+
+    // 再試行の時刻になった購読を購読し直す
+    fn retry_subscriptions(&mut self, options: SubscribeOptions) {
+        let now = time::Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.subscribe_retries).into_iter().partition(|(_, _, at)| *at <= now);
+        self.subscribe_retries = waiting;
+        let filters: Vec<(String, QoS)> = due.into_iter().map(|(topic, qos, _): (String, QoS, time::Instant)| {
+            crate::status!("トピック '{}' の購読を再試行します ({} 回目)。", topic, self.subscribe_attempts.get(&topic).copied().unwrap_or(1));
+            (topic, qos)
+        }).collect();
+        self.subscribe(filters, options);
+    }
+
+    // ブローカーが許可した QoS が enforce_min_qos の下限より低い場合にエラーを表示する (refuse の場合は終了する)
+    fn check_granted_min_qos(&self, topic: &str, granted: QoS) {
+        let Some(min_qos) = qos_utils::min_qos_for(&self.config, topic) else {
+            return;
+        };
+        if qos_utils::qos_number(granted) >= qos_utils::qos_number(min_qos) {
+            return;
+        }
+        let message = format!("トピック '{}' の購読でブローカーが許可した QoS {} は、enforce_min_qos の下限 QoS {} より低くなっています。",
+            topic, qos_utils::qos_number(granted), qos_utils::qos_number(min_qos));
+        if self.config.min_qos_policy.unwrap_or_default() == MinQosPolicy::Refuse {
+            exit_with(AppError::Unavailable(format!("{} 終了します (min_qos_policy: refuse)。", message)));
+        }
+        eprintln!("エラー: {}", message);
+    }
+
+    // include_system_topics が無効で、`$` で始まるトピックが購読したフィルタに仕様上一致しない場合に `true` を返す。
+    // 一部のブローカーは `#` の購読に $SYS などのシステムトピックも配信するため、表示しないようにする
+    fn is_unsubscribed_system_topic(&mut self, topic: &str) -> bool {
+        if self.config.include_system_topics.unwrap_or(false)
+            || !topic_utils::is_system_topic(topic)
+            || self.config.topics.iter().any(|filter| topic_matches(filter, topic))
+            || self.discovery.as_ref().is_some_and(|discovery| discovery.is_subscribed(topic)) {
+            return false;
+        }
+        if !self.system_topic_warned {
+            eprintln!("警告: 購読したトピックフィルタに一致しないシステムトピック '{}' が配信されました (MQTT の仕様では # や + は $ で始まるトピックに一致しません)。システムトピックは表示しません。", output_utils::status_topic(topic));
+            self.system_topic_warned = true;
+        }
+        true
+    }
+
+    // accept_qos が指定されていてメッセージの QoS が含まれない場合に、破棄した件数を記録して `true` を返す。
+    // トピックごとに最初の 1 件だけ警告を表示する
+    fn is_rejected_qos(&mut self, message: &ReceivedMessage) -> bool {
+        let Some(accepted) = &self.config.accept_qos else {
+            return false;
+        };
+        if accepted.contains(&message.qos) {
+            return false;
+        }
+        self.metrics.record_qos_rejected();
+        if !self.qos_rejected_topics.contains(&message.topic) {
+            eprintln!("警告: トピック '{}' で accept_qos にない QoS {} のメッセージを受信したため破棄しました (このトピックでは以降警告しません)。",
+                output_utils::status_topic(&message.topic), qos_utils::qos_number(message.qos));
+            self.qos_rejected_topics.insert(message.topic.clone());
+        }
+        true
+    }
+
+    // sample_rate が指定されている場合に、トピックごとに N 件に 1 件 (最初のメッセージを含む) 以外であれば
+    // 表示しなかった件数を記録して `true` を返す。ログファイルにはすべてのメッセージを記録する。
+    // validate を通さずに Config を組み立てたライブラリの利用者が 0 を指定した場合は、すべてのメッセージを表示する
+    fn is_sampled_out(&mut self, message: &ReceivedMessage) -> bool {
+        let Some(rate) = self.config.sample_rate.map(|rate| rate.max(1)) else {
+            return false;
+        };
+        let count = match self.sample_counts.get_mut(&message.topic) {
+            Some(count) => count,
+            None => self.sample_counts.entry(message.topic.clone()).or_insert(0),
+        };
+        let skip = *count % rate != 0;
+        *count += 1;
+        if !skip {
+            return false;
+        }
+        self.metrics.record_sampled_out();
+        if let Some(log) = &self.log {
+            log.write_message(&output_utils::format_text(message, self.config.payload_preview_bytes));
+        }
+        true
+    }
+
+    // dedup_window_secs が指定されている場合に、重複メッセージであれば件数を記録して `true` を返す
+    fn is_duplicate(&mut self, message: &ReceivedMessage) -> bool {
+        let Some(dedup) = &mut self.dedup else {
+            return false;
+        };
+        let now = time::Instant::now();
+        if !dedup.is_duplicate(&message.topic, &message.payload, now) {
+            return false;
+        }
+        self.metrics.record_duplicate();
+        self.dedup_unreported += 1;
+        if now.duration_since(self.dedup_reported_at) >= DEDUP_REPORT_INTERVAL {
+            crate::status!("直近 {} 秒間に {} 件の重複メッセージを表示しませんでした。",
+                now.duration_since(self.dedup_reported_at).as_secs(), self.dedup_unreported);
+            self.dedup_reported_at = now;
+            self.dedup_unreported = 0;
+        }
+        true
+    }
+
+    // ペイロードが UTF-8 であるべき場合に、不正なバイト列が含まれていれば警告する。
+    // MQTT 5 の Payload Format Indicator が UTF-8 (1) の場合は常に、それ以外は validate_utf8 が有効な場合に検査する。
+    // 表示は検査結果にかかわらず不正なバイト列を置換して行う。
+    fn validate_utf8(&self, message: &ReceivedMessage) {
+        let indicated = message.properties.as_ref().and_then(|p| p.payload_format_indicator) == Some(1);
+        if !indicated && !self.config.validate_utf8.unwrap_or(false) {
+            return;
+        }
+        if let Err(e) = std::str::from_utf8(&message.payload) {
+            eprintln!("警告: トピック '{}' のペイロードに不正な UTF-8 のバイト列が含まれています (オフセット: {} バイト)。",
+                output_utils::status_topic(&message.topic), e.valid_up_to());
+        }
+    }
+
+    // schema のルールに一致するトピックのペイロードを検証し、適合しなかった場合は失敗した箇所を警告して true を返す。
+    // schema_deadletter_topic 自体のメッセージは、発行したものを再び検証しないよう対象外にする
+    fn is_schema_invalid(&self, message: &ReceivedMessage) -> bool {
+        let Some(schema) = &self.schema else {
+            return false;
+        };
+        let deadletter = self.config.schema_deadletter_topic.as_deref();
+        if deadletter == Some(message.topic.as_str()) {
+            return false;
+        }
+        let Some(failure) = schema.validate(message) else {
+            return false;
+        };
+        self.metrics.record_schema_invalid();
+        let first = &failure.violations[0];
+        let others = match failure.violations.len() - 1 {
+            0 => String::new(),
+            n => format!(" (他 {} 件)", n),
+        };
+        eprintln!("警告: トピック '{}' のペイロードがスキーマ '{}' に適合しません: {}: {}{}",
+            output_utils::status_topic(&message.topic), failure.schema, if first.path.is_empty() { "/" } else { &first.path }, first.message, others);
+        if let Some(topic) = deadletter {
+            let payload = schema_utils::deadletter_payload(message, &failure);
+            if let Err(e) = self.client.try_publish(topic, QoS::AtLeastOnce, false, payload.into_bytes()) {
+                eprintln!("スキーマに適合しなかったメッセージの {} への発行中にエラーが発生しました: {:?}", topic, e);
+            }
+        }
+        true
+    }
+
+    // topic_counts が指定されていれば、トピック (または一致した購読トピックフィルタ) ごとの受信数を数える
+    fn record_topic(&self, topic: &str) {
+        if let Some(mode) = self.config.topic_counts {
+            self.metrics.record_topic(self.count_key(mode, topic));
+        }
+    }
+
+    // payload_size_histogram が指定されていれば、ペイロードのサイズを per_topic の単位で記録する
+    fn record_payload_size(&self, topic: &str, size: usize) {
+        let Some(histogram) = &self.config.payload_size_histogram else {
+            return;
+        };
+        let key = histogram.per_topic.map_or(topic, |mode| self.count_key(mode, topic));
+        self.metrics.record_payload_size(key, size);
+    }
+
+    // 集計の単位に従ったトピックのキー。一致するフィルタが見つからない場合 (共有購読など) は実際のトピックで数える
+    fn count_key<'a>(&'a self, mode: TopicCountMode, topic: &'a str) -> &'a str {
+        match mode {
+            TopicCountMode::Topic => topic,
+            TopicCountMode::Filter => self.config.topics.iter()
+                .find(|filter| topic_matches(filter, topic))
+                .map_or(topic, String::as_str),
+        }
+    }
+
+    // 遅延の比較用に、発行時刻を付けたメッセージを benchmark_loopback_topic に発行する
+    fn publish_loopback_message(&self) {
+        let Some(topic) = &self.config.benchmark_loopback_topic else {
+            return;
+        };
+        let payload = benchmark_utils::with_timestamp(b"");
+        if let Err(e) = self.client.try_publish(topic, QoS::AtMostOnce, false, payload) {
+            eprintln!("ループバックのメッセージの発行中にエラーが発生しました: {:?}", e);
+        }
+    }
+
+    // ハートビートのメッセージ (heartbeat_topic) を発行する。ペイロードは発行時刻 (UNIX 時間の秒) の小さなメッセージにする
+    fn publish_heartbeat_message(&self) {
+        let Some(topic) = &self.config.heartbeat_topic else {
+            return;
+        };
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        // イベントループの処理中にチャネルの空きを待つとデッドロックするため、待機しない try_publish を使う
+        if let Err(e) = self.client.try_publish(topic, QoS::AtMostOnce, false, now.to_string().into_bytes()) {
+            eprintln!("ハートビートのメッセージの発行中にエラーが発生しました: {:?}", e);
+        }
+    }
+
+    // 設定されていれば接続通知メッセージ (online_topic) を発行する
+    fn publish_online_message(&self) {
+        let Some(topic) = &self.config.online_topic else {
+            return;
+        };
+        let payload = self.config.online_payload.clone().unwrap_or_default().into_bytes();
+        let retain = self.config.online_retain.unwrap_or(false);
+        // イベントループの処理中にチャネルの空きを待つとデッドロックするため、待機しない try_publish を使う
+        match self.client.try_publish(topic, self.online_qos, retain, payload) {
+            Ok(()) => crate::status!("接続通知メッセージをトピック '{}' に発行しました。", topic),
+            Err(e) => eprintln!("接続通知メッセージの発行中にエラーが発生しました: {:?}", e),
+        }
+    }
+
+    // 状態が変化した場合のみ通知する
+    fn set_state(&self, state: ConnectionState) {
+        self.state_tx.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            *current = state;
+            true
+        });
+        self.update_readiness();
+    }
+
+    fn update_readiness(&self) {
+        let ready = *self.state_tx.borrow() == ConnectionState::Connected
+            && self.pending_subacks == 0
+            && self.subscribe_retries.is_empty()
+            && !self.subscribe_rejected;
+        self.ready_tx.send_if_modified(|current| {
+            if *current == ready {
+                return false;
+            }
+            *current = ready;
+            true
+        });
+    }
+}
+
+// イベントループの待機を中断した理由
+enum Wake {
+    Event(Result<MqttEvent, ConnectionError>),
+    TokenRefresh,
+    PingTimeout,
+    Reload(Box<Config>),
+    LoopbackPublish,
+    DnsRefresh,
+    ThrottleRelease,
+    SubAckTimeout,
+    SubscribeRetry,
+    IdleTimeout,
+    Heartbeat,
+    TopicIdle,
+    SilenceCheck,
+}
+
+// manual_ack が有効で、まだ確認応答していないメッセージの確認応答を送信する。
+// イベントループの処理中に要求チャネルの空きを待たないよう、別のタスクで送信する
+fn send_ack(message: &mut ReceivedMessage) {
+    let Some(client) = message.acker.take() else {
+        return;
+    };
+    let ack = ReceivedMessage {
+        topic: message.topic.clone(),
+        payload: Bytes::new(),
+        qos: message.qos,
+        retain: message.retain,
+        pkid: message.pkid,
+        properties: None,
+        acker: None,
+    };
+    tokio::spawn(async move {
+        if let Err(e) = client.ack(&ack).await {
+            eprintln!("トピック '{}' のメッセージの確認応答の送信中にエラーが発生しました: {:?}", output_utils::status_topic(&ack.topic), e);
+        }
+    });
+}
+
+// `at` が `None` の場合は完了しない
+async fn sleep_until_or_pending(at: Option<time::Instant>) {
+    match at {
+        Some(at) => time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+// トピックのまとまりごとに SUBSCRIBE を送信する
+async fn subscribe_batches(cli: &MqttClient, batches: &[Vec<(String, QoS)>], options: SubscribeOptions) {
+    for batch in batches {
+        if let Err(e) = cli.subscribe_many(batch, options).await {
+            let topics: Vec<&str> = batch.iter().map(|(topic, _)| topic.as_str()).collect();
+            exit_with(AppError::Unavailable(format!("トピック {} の購読中にエラーが発生しました: {:?}", topics.join(", "), e)));
+        }
+        for (topic, qos) in batch {
+            crate::status!("トピック: '{}' (QoS {:?}) を購読しました。", topic, qos);
+        }
+    }
+}
+
+// 設定の再読み込みで送信する SUBSCRIBE (追加、または QoS を変更するトピック) と UNSUBSCRIBE (削除したトピック)
+#[derive(Debug, PartialEq, Eq)]
+struct ReloadPlan {
+    changed: Vec<(String, QoS)>,
+    removed: Vec<String>,
+}
+
+// 再読み込みの前後の購読を比べて、変更内容を表示して返す。
+// `config` (起動時の設定) の enforce_min_qos に違反し、min_qos_policy: refuse の場合は変更を反映しないため `None` を返す
+fn plan_reload(config: &Config, current: &[(String, QoS)], reloaded: &[(String, QoS)]) -> Option<ReloadPlan> {
+    let violations = qos_utils::min_qos_violations(config, reloaded);
+    for violation in &violations {
+        eprintln!("エラー: {}", violation);
+    }
+    if !violations.is_empty() && config.min_qos_policy.unwrap_or_default() == MinQosPolicy::Refuse {
+        return None;
+    }
+
+    let mut changed = Vec::new();
+    for (topic, new_qos) in reloaded {
+        match current.iter().find(|(t, _)| t == topic).map(|(_, qos)| qos) {
+            None => {
+                crate::status!("設定の再読み込み: トピック '{}' (QoS {}) を追加します。", topic, qos_utils::qos_number(*new_qos));
+                changed.push((topic.clone(), *new_qos));
+            }
+            Some(old_qos) if old_qos != new_qos => {
+                let direction = if qos_utils::qos_number(*new_qos) > qos_utils::qos_number(*old_qos) { "引き上げ" } else { "引き下げ" };
+                crate::status!("設定の再読み込み: トピック '{}' の QoS を {} から {} に{}ます。",
+                    topic, qos_utils::qos_number(*old_qos), qos_utils::qos_number(*new_qos), direction);
+                changed.push((topic.clone(), *new_qos));
+            }
+            Some(_) => {}
+        }
+    }
+    let removed: Vec<String> = current.iter()
+        .filter(|(topic, _)| !reloaded.iter().any(|(t, _)| t == topic))
+        .map(|(topic, _)| topic.clone())
+        .collect();
+    for topic in &removed {
+        crate::status!("設定の再読み込み: トピック '{}' の購読を解除します。", topic);
+    }
+    if changed.is_empty() && removed.is_empty() {
+        crate::status!("設定の再読み込み: 購読するトピックと QoS に変更はありません。");
+    }
+    Some(ReloadPlan { changed, removed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("client_id: reload\n{}", yaml)).unwrap()
+    }
+
+    fn subscriptions(entries: &[(&str, QoS)]) -> Vec<(String, QoS)> {
+        entries.iter().map(|(topic, qos)| (topic.to_string(), *qos)).collect()
+    }
+
+    #[test]
+    fn reload_resubscribes_a_topic_whose_qos_changed() {
+        let current = subscriptions(&[("a/#", QoS::AtMostOnce), ("b/#", QoS::AtLeastOnce)]);
+        let reloaded = subscriptions(&[("a/#", QoS::ExactlyOnce), ("b/#", QoS::AtLeastOnce)]);
+        let plan = plan_reload(&config(""), &current, &reloaded).unwrap();
+        assert_eq!(plan, ReloadPlan { changed: subscriptions(&[("a/#", QoS::ExactlyOnce)]), removed: Vec::new() });
+    }
+
+    #[test]
+    fn reload_subscribes_added_and_unsubscribes_removed_topics() {
+        let current = subscriptions(&[("a/#", QoS::AtLeastOnce), ("b/#", QoS::AtLeastOnce)]);
+        let reloaded = subscriptions(&[("b/#", QoS::AtLeastOnce), ("c/#", QoS::AtMostOnce)]);
+        let plan = plan_reload(&config(""), &current, &reloaded).unwrap();
+        assert_eq!(plan, ReloadPlan { changed: subscriptions(&[("c/#", QoS::AtMostOnce)]), removed: vec!["a/#".to_string()] });
+
+        let unchanged = plan_reload(&config(""), &current, &current).unwrap();
+        assert!(unchanged.changed.is_empty() && unchanged.removed.is_empty());
+    }
+
+    #[test]
+    fn reload_below_min_qos_is_refused_only_with_refuse_policy() {
+        let rules = "enforce_min_qos:\n  - pattern: alerts/#\n    min_qos: 1\n";
+        let current = subscriptions(&[("alerts/#", QoS::AtLeastOnce)]);
+        let reloaded = subscriptions(&[("alerts/#", QoS::AtMostOnce)]);
+        assert_eq!(plan_reload(&config(&format!("{}min_qos_policy: refuse\n", rules)), &current, &reloaded), None);
+
+        let plan = plan_reload(&config(rules), &current, &reloaded).unwrap();
+        assert_eq!(plan.changed, reloaded);
+    }
+
+    #[tokio::test]
+    async fn sample_rate_shows_every_nth_message_per_topic_and_zero_shows_all() {
+        let message = |topic: &str| ReceivedMessage {
+            topic: topic.to_string(), payload: Bytes::new(), qos: QoS::AtMostOnce, retain: false, pkid: 0, properties: None, acker: None,
+        };
+        let base = "broker_address: localhost\nbroker_port: 1883\ntopics: [a]\nqos: [0]\n";
+        let mut subscriber = Subscriber::new(config(&format!("{}sample_rate: 3\n", base)));
+        let shown: Vec<bool> = ["a", "a", "b", "a", "a", "b"].iter().map(|topic| !subscriber.is_sampled_out(&message(topic))).collect();
+        assert_eq!(shown, [true, false, true, false, true, false]);
+
+        // validate を通さずに 0 を指定しても、すべてのメッセージを表示する
+        let mut subscriber = Subscriber::new(config(&format!("{}sample_rate: 0\n", base)));
+        assert!((0..3).all(|_| !subscriber.is_sampled_out(&message("a"))));
+    }
+
+    #[tokio::test]
+    async fn reload_sends_subscribe_for_changed_qos_and_unsubscribe_for_removed_topics() {
+        let mut subscriber = Subscriber::new(config("broker_address: localhost\nbroker_port: 1883\ntopics: [a/#, b/#, c/#]\nqos: [0, 1, 1]\n"));
+        let (tx, rx) = flume::bounded(10);
+        subscriber.client = MqttClient::V311(rumqttc::AsyncClient::from_senders(tx));
+
+        subscriber.apply_reload(config("topics: [a/#, b/#]\nqos: [2, 1]\n"), SubscribeOptions { skip_retained: false });
+        let mut subscribed = Vec::new();
+        let mut unsubscribed = Vec::new();
+        for _ in 0..2 {
+            match time::timeout(Duration::from_secs(1), rx.recv_async()).await.unwrap().unwrap() {
+                rumqttc::Request::Subscribe(subscribe) => subscribed.extend(subscribe.filters.into_iter().map(|filter| (filter.path, filter.qos))),
+                rumqttc::Request::Unsubscribe(unsubscribe) => unsubscribed.extend(unsubscribe.topics),
+                request => panic!("想定外の要求: {:?}", request),
+            }
+        }
+        // QoS が変わらない b/# は購読し直さない
+        assert_eq!(subscribed, [("a/#".to_string(), QoS::ExactlyOnce)]);
+        assert_eq!(unsubscribed, ["c/#"]);
+        assert!(rx.is_empty());
+        assert_eq!(subscriber.qos, [QoS::ExactlyOnce, QoS::AtLeastOnce]);
+    }
+
+    #[tokio::test]
+    async fn a_rejected_batch_is_resubscribed_one_topic_at_a_time_and_a_rejected_single_topic_is_excluded() {
+        let mut subscriber = Subscriber::new(config("broker_address: localhost\nbroker_port: 1883\ntopics: [a]\nqos: [0]\nsubscribe_batch_size: 10\n"));
+        let batch = |topics: &[&str]| topics.iter().map(|topic| (topic.to_string(), QoS::AtLeastOnce)).collect::<Vec<_>>();
+        subscriber.inflight_subscribes = VecDeque::from([(1, batch(&["a"])), (2, batch(&["b", "c"])), (3, batch(&["d"]))]);
+        subscriber.pending_subacks = 3;
+
+        // 1 の SUBACK はエラーより前に受信しているため、拒否されたのは 2
+        subscriber.handle_subscribe_failure(false, &HashSet::from([1]));
+        assert_eq!(subscriber.subscribe_failed, Some(2));
+        let isolated = subscriber.exclude_failed_subscription(2);
+        assert_eq!(isolated, batch(&["b", "c"]));
+        assert!(subscriber.failed_subscriptions.is_empty());
+        assert_eq!(subscriber.inflight_subscribes.iter().map(|(pkid, _)| *pkid).collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(subscriber.pending_subacks, 2);
+
+        // 1 件ずつ購読し直すトピックは、ほかのトピックとまとめない
+        subscriber.subscribe(batch(&["b", "x", "c", "y"]), SubscribeOptions { skip_retained: false });
+        assert_eq!(subscriber.unsent_subscribes, [batch(&["x", "y"]), batch(&["b"]), batch(&["c"])]);
+
+        // 1 件だけの SUBSCRIBE が拒否された場合は、そのトピックを除外する
+        subscriber.inflight_subscribes = VecDeque::from([(4, batch(&["b"])), (5, batch(&["c"]))]);
+        subscriber.handle_subscribe_failure(false, &HashSet::new());
+        assert_eq!(subscriber.subscribe_failed, Some(4));
+        assert!(subscriber.exclude_failed_subscription(4).is_empty());
+        assert_eq!(subscriber.failed_subscriptions, ["b"]);
+        assert!(subscriber.subscribe_rejected);
+    }
+}
@@ -0,0 +1,224 @@
+// 受信したメッセージの syslog への送信 (syslog)
+use super::config_utils::{Config, OutputFormat, SyslogFormat, SyslogSeverity};
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils;
+use super::qos_utils;
+use super::sink_utils::Sink;
+use std::{collections::BTreeMap, str::FromStr, sync::mpsc, thread, time::{Duration, Instant}};
+use syslog::{Facility, Formatter3164, Formatter5424, LogFormat, LoggerBackend, Severity};
+
+// 送信待ちの記録の最大数 (超えた分は破棄し、イベントループを待たせない)
+const SYSLOG_QUEUE_CAPACITY: usize = 1024;
+// 接続 (送信) に失敗した後、接続し直すまでの間隔 (その間の記録は破棄する)
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+// RFC 5424 の構造化データの ID (IANA のドキュメント用の私企業番号を使う)
+const STRUCTURED_DATA_ID: &str = "mqtt@32473";
+
+/// 受信したメッセージを 1 件ずつ syslog の記録として送信する。
+///
+/// 送信は専用のスレッドで行い、送信先に接続できない場合や送信に失敗した場合はその記録を破棄して
+/// 一定時間後に接続し直す (受信したメッセージの処理は止めない)。
+pub struct SyslogSink {
+    tx: mpsc::SyncSender<SyslogRecord>,
+    // 一覧表示と同じ 1 行の JSON を本文にする (output_format: json / json-array の場合)
+    json: bool,
+    // 送信先の表示用の名前
+    target: String,
+}
+
+struct SyslogRecord {
+    topic: String,
+    qos: u8,
+    retain: bool,
+    text: String,
+}
+
+// syslog_address で指定した送信先
+#[derive(Debug, Clone)]
+enum SyslogTarget {
+    // ローカルの syslog のソケット (/dev/log など)
+    Local,
+    Unix(String),
+    Udp(String),
+    Tcp(String),
+}
+
+impl SyslogSink {
+    /// syslog が有効でない場合は `None` を返す
+    pub fn open(config: &Config) -> Option<SyslogSink> {
+        if !config.syslog.unwrap_or(false) {
+            return None;
+        }
+        let target = parse_target(config.syslog_address.as_deref())
+            .unwrap_or_else(|e| exit_with(AppError::Config(e)));
+        let facility = match &config.syslog_facility {
+            Some(name) => Facility::from_str(name).unwrap_or_else(|_| exit_with(AppError::Config(format!(
+                "syslog_facility '{}' が不正です (user / daemon / local0〜local7 などを指定してください)", name)))),
+            None => Facility::LOG_USER,
+        };
+        let severity = severity(config.syslog_severity.unwrap_or_default());
+        let formatter = match config.syslog_format.unwrap_or_default() {
+            SyslogFormat::Rfc3164 => Formatter::Rfc3164(Formatter3164 { facility, ..Formatter3164::default() }),
+            SyslogFormat::Rfc5424 => Formatter::Rfc5424(Formatter5424 { facility, ..Formatter5424::default() }),
+        };
+
+        let (tx, rx) = mpsc::sync_channel(SYSLOG_QUEUE_CAPACITY);
+        let mut sender = Sender { target: target.clone(), formatter, severity, backend: None, retry_at: None };
+        sender.connect();
+        thread::spawn(move || {
+            for record in rx {
+                sender.send(&record);
+            }
+        });
+        let target = describe_target(&target);
+        crate::status!("受信したメッセージを syslog ({}) に送信します。", target);
+        Some(SyslogSink { tx, json: matches!(config.output_format, Some(OutputFormat::Json | OutputFormat::JsonArray)), target })
+    }
+}
+
+impl Sink for SyslogSink {
+    fn name(&self) -> String {
+        format!("syslog ({})", self.target)
+    }
+
+    /// 受信したメッセージを送信する。送信待ちの記録が多すぎる場合は破棄してエラーを返す
+    /// (送信先に接続できない場合は送信用のスレッドが表示する)
+    fn write(&self, message: &ReceivedMessage, output: &str, config: &Config) -> Result<(), String> {
+        let text = if self.json {
+            output.to_string()
+        } else {
+            // syslog の記録は 1 行にする
+            output_utils::format_payload(&message.payload, config.payload_preview_bytes).replace(['\r', '\n'], " ")
+        };
+        let record = SyslogRecord {
+            topic: message.topic.clone(),
+            qos: qos_utils::qos_number(message.qos),
+            retain: message.retain,
+            text,
+        };
+        match self.tx.try_send(record) {
+            Err(mpsc::TrySendError::Full(_)) => Err("送信待ちの記録が多すぎるため破棄しました".to_string()),
+            // 送信用のスレッドは終了しない
+            _ => Ok(()),
+        }
+    }
+}
+
+// 記録の形式 (ホスト名とプロセス名は起動時に取得する)
+enum Formatter {
+    Rfc3164(Formatter3164),
+    Rfc5424(Formatter5424),
+}
+
+// 送信用のスレッドが保持する接続と送信の設定
+struct Sender {
+    target: SyslogTarget,
+    formatter: Formatter,
+    severity: Severity,
+    backend: Option<LoggerBackend>,
+    // 接続に失敗した場合に、次に接続を試す時刻
+    retry_at: Option<Instant>,
+}
+
+impl Sender {
+    fn connect(&mut self) {
+        let result = match &self.target {
+            SyslogTarget::Local => syslog::unix(()),
+            SyslogTarget::Unix(path) => syslog::unix_custom((), path),
+            SyslogTarget::Udp(address) => {
+                let local = if address.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+                syslog::udp((), local, address.as_str())
+            }
+            SyslogTarget::Tcp(address) => syslog::tcp((), address.as_str()),
+        };
+        match result {
+            Ok(logger) => {
+                if self.retry_at.take().is_some() {
+                    crate::status!("syslog ({}) に接続しました。", describe_target(&self.target));
+                }
+                self.backend = Some(logger.backend);
+            }
+            Err(e) => self.fail(format!("syslog ({}) に接続できませんでした: {}", describe_target(&self.target), e)),
+        }
+    }
+
+    fn send(&mut self, record: &SyslogRecord) {
+        if self.backend.is_none() {
+            if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                return;
+            }
+            self.connect();
+        }
+        let Some(backend) = &mut self.backend else {
+            return;
+        };
+        let result = match &self.formatter {
+            // RFC 3164 には構造化データがないため、トピックを本文の先頭に付ける
+            Formatter::Rfc3164(formatter) => formatter.format(backend, self.severity, format!("{}: {}", record.topic, record.text)),
+            Formatter::Rfc5424(formatter) => {
+                let params = BTreeMap::from([
+                    ("topic".to_string(), record.topic.clone()),
+                    ("qos".to_string(), record.qos.to_string()),
+                    ("retain".to_string(), record.retain.to_string()),
+                ]);
+                let data = BTreeMap::from([(STRUCTURED_DATA_ID.to_string(), params)]);
+                formatter.format(backend, self.severity, (0, data, record.text.as_str()))
+            }
+        };
+        if let Err(e) = result {
+            self.backend = None;
+            self.fail(format!("syslog ({}) への送信に失敗しました: {}", describe_target(&self.target), e));
+        }
+    }
+
+    // 失敗は接続し直すまで 1 回だけ表示する
+    fn fail(&mut self, message: String) {
+        if self.retry_at.is_none() {
+            eprintln!("警告: {}。{} 秒後に接続し直します (それまでの記録は破棄します)。", message, RECONNECT_INTERVAL.as_secs());
+        }
+        self.retry_at = Some(Instant::now() + RECONNECT_INTERVAL);
+    }
+}
+
+// `unix:<パス>` / `udp://host:port` / `tcp://host:port` / `host:port` (UDP) を解析する。未指定の場合はローカルのソケット
+fn parse_target(address: Option<&str>) -> Result<SyslogTarget, String> {
+    let Some(address) = address else {
+        return Ok(SyslogTarget::Local);
+    };
+    if let Some(path) = address.strip_prefix("unix:") {
+        return Ok(SyslogTarget::Unix(path.trim_start_matches("//").to_string()));
+    }
+    let (target, host_port): (fn(String) -> SyslogTarget, &str) = match address.split_once("://") {
+        Some(("udp", rest)) => (SyslogTarget::Udp, rest),
+        Some(("tcp", rest)) => (SyslogTarget::Tcp, rest),
+        Some((scheme, _)) => return Err(format!("syslog_address のスキーム '{}' には対応していません (unix / udp / tcp を指定してください)", scheme)),
+        None => (SyslogTarget::Udp, address),
+    };
+    if !host_port.contains(':') {
+        return Err(format!("syslog_address '{}' にポートがありません (例: udp://logs.example.com:514)", address));
+    }
+    Ok(target(host_port.to_string()))
+}
+
+fn describe_target(target: &SyslogTarget) -> String {
+    match target {
+        SyslogTarget::Local => "ローカル".to_string(),
+        SyslogTarget::Unix(path) => format!("unix:{}", path),
+        SyslogTarget::Udp(address) => format!("udp://{}", address),
+        SyslogTarget::Tcp(address) => format!("tcp://{}", address),
+    }
+}
+
+fn severity(severity: SyslogSeverity) -> Severity {
+    match severity {
+        SyslogSeverity::Emerg => Severity::LOG_EMERG,
+        SyslogSeverity::Alert => Severity::LOG_ALERT,
+        SyslogSeverity::Crit => Severity::LOG_CRIT,
+        SyslogSeverity::Err => Severity::LOG_ERR,
+        SyslogSeverity::Warning => Severity::LOG_WARNING,
+        SyslogSeverity::Notice => Severity::LOG_NOTICE,
+        SyslogSeverity::Info => Severity::LOG_INFO,
+        SyslogSeverity::Debug => Severity::LOG_DEBUG,
+    }
+}
@@ -0,0 +1,143 @@
+// テキスト出力のテンプレート (output_template)
+use super::config_utils::Config;
+use super::mqtt_utils::ReceivedMessage;
+use super::output_utils::{format_payload, format_timestamp};
+use super::qos_utils::qos_number;
+use std::time::SystemTime;
+
+/// output_template で使用できるプレースホルダー
+pub const PLACEHOLDERS: &[&str] = &["topic", "payload", "qos", "retain", "timestamp", "client_id"];
+
+/// 起動時に 1 回だけ解析した output_template。メッセージごとに解析し直さずに `render` で当てはめる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+// テンプレートを区切った部分
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    // そのまま出力する文字列 (`{{` / `}}` で書かれた波括弧は `{` / `}` にしてある)
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Topic,
+    Payload,
+    Qos,
+    Retain,
+    Timestamp,
+    ClientId,
+}
+
+impl Placeholder {
+    fn from_name(name: &str) -> Option<Placeholder> {
+        match name {
+            "topic" => Some(Placeholder::Topic),
+            "payload" => Some(Placeholder::Payload),
+            "qos" => Some(Placeholder::Qos),
+            "retain" => Some(Placeholder::Retain),
+            "timestamp" => Some(Placeholder::Timestamp),
+            "client_id" => Some(Placeholder::ClientId),
+            _ => None,
+        }
+    }
+}
+
+impl Template {
+    /// `{名前}` をプレースホルダー、`{{` と `}}` をそれぞれ `{` と `}` として解析する (不正な場合はその理由を返す)
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(index) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..index]);
+            let brace = rest[index..].chars().next().unwrap_or_default();
+            let after = &rest[index + 1..];
+            if after.starts_with(brace) {
+                literal.push(brace);
+                rest = &after[1..];
+                continue;
+            }
+            if brace == '}' {
+                return Err("対応する '{' のない '}' があります ('}' を出力するには '}}' と書いてください)".to_string());
+            }
+            let end = after.find('}').ok_or_else(|| "'{' が閉じられていません ('{' を出力するには '{{' と書いてください)".to_string())?;
+            let name = &after[..end];
+            let placeholder = Placeholder::from_name(name).ok_or_else(|| format!("不明なプレースホルダー '{{{}}}' があります (使用できるのは {} です)", name,
+                PLACEHOLDERS.iter().map(|name| format!("{{{}}}", name)).collect::<Vec<_>>().join(", ")))?;
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Placeholder(placeholder));
+            rest = &after[end + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    /// メッセージをテンプレートに当てはめる ({timestamp} は受信した時刻 `time`)
+    /// (ペイロードには payload_preview_bytes を適用し、UTF-8 として読めない部分は置換する)
+    pub fn render(&self, message: &ReceivedMessage, config: &Config, time: SystemTime) -> String {
+        let mut output = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => output.push_str(text),
+                Part::Placeholder(Placeholder::Topic) => output.push_str(&message.topic),
+                Part::Placeholder(Placeholder::Payload) => output.push_str(&format_payload(&message.payload, config.payload_preview_bytes)),
+                Part::Placeholder(Placeholder::Qos) => output.push_str(&qos_number(message.qos).to_string()),
+                Part::Placeholder(Placeholder::Retain) => output.push_str(if message.retain { "true" } else { "false" }),
+                Part::Placeholder(Placeholder::Timestamp) => output.push_str(&format_timestamp(time)),
+                Part::Placeholder(Placeholder::ClientId) => output.push_str(&config.client_id),
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use rumqttc::QoS;
+
+    fn message() -> ReceivedMessage {
+        ReceivedMessage {
+            topic: "sensors/1".to_string(), payload: Bytes::from_static(b"21.5"), qos: QoS::AtLeastOnce, retain: true,
+            pkid: 0, properties: None, acker: None,
+        }
+    }
+
+    fn config() -> Config {
+        serde_yaml::from_str("client_id: template\nbroker_address: localhost\nbroker_port: 1883\n").unwrap()
+    }
+
+    #[test]
+    fn placeholders_and_escaped_braces_are_rendered() {
+        let template = Template::parse("{{{topic}}} {payload} qos={qos} retain={retain} by {client_id}}}").unwrap();
+        assert_eq!(template.render(&message(), &config(), SystemTime::now()), "{sensors/1} 21.5 qos=1 retain=true by template}");
+        // 隣り合う文字列と波括弧は 1 つにまとめる
+        assert_eq!(Template::parse("a{{b}}c").unwrap().parts, [Part::Literal("a{b}c".to_string())]);
+        assert_eq!(Template::parse("").unwrap().parts, []);
+    }
+
+    #[test]
+    fn timestamp_is_the_given_time() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(86_400);
+        let rendered = Template::parse("[{timestamp}]").unwrap().render(&message(), &config(), time);
+        assert_eq!(rendered, format!("[{}]", format_timestamp(time)));
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        for (template, expected) in [("{topic", "閉じられていません"), ("topic}", "'}' があります"), ("{name}", "不明なプレースホルダー '{name}'"), ("{}", "不明なプレースホルダー")] {
+            let error = Template::parse(template).unwrap_err();
+            assert!(error.contains(expected), "{}: {}", template, error);
+        }
+    }
+}
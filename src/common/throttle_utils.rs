@@ -0,0 +1,138 @@
+// トピックパターンごとの表示レートの制限 (throttle)
+use super::config_utils::{ThrottlePolicy, ThrottleRule};
+use super::error_utils::{exit_with, AppError};
+use super::metrics_utils::Metrics;
+use super::mqtt_utils::ReceivedMessage;
+use super::topic_utils::topic_matches;
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+// throttle_policy: delay の場合にルールごとに保持する遅延中のメッセージの最大数 (超えた分は破棄する)
+const MAX_DELAYED_MESSAGES: usize = 10_000;
+
+/// 受信したメッセージを制限の範囲内で処理するかの判定結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// 制限の範囲内 (またはどのルールにも一致しない) のため、すぐに処理する
+    Pass,
+    /// 制限を超えたため破棄する
+    Drop,
+    /// 制限を超えたため、`delay` で追加して後で処理する (`release` で取り出す)
+    Delay,
+}
+
+/// throttle のルールごとのトークンバケット。
+///
+/// ルールは上から順に評価し、最初に一致したルールのバケットからメッセージ 1 件につき 1 トークンを消費する。
+/// バケットには max_rate 件 (1 未満の場合は 1 件) までのトークンが貯まり、毎秒 max_rate 件ずつ補充される。
+pub struct Throttle {
+    buckets: Vec<Bucket>,
+    policy: ThrottlePolicy,
+    metrics: Arc<Metrics>,
+}
+
+struct Bucket {
+    pattern: String,
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+    // throttle_policy: delay の場合に、トークンが補充されるのを待っているメッセージ
+    delayed: VecDeque<ReceivedMessage>,
+    // 遅延中のメッセージが多すぎるため破棄したことを警告したか
+    overflow_warned: bool,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+impl Throttle {
+    pub fn new(rules: &[ThrottleRule], policy: ThrottlePolicy, metrics: Arc<Metrics>) -> Throttle {
+        let now = Instant::now();
+        let buckets = rules.iter().map(|rule| {
+            if !(rule.max_rate.is_finite() && rule.max_rate > 0.0) {
+                exit_with(AppError::Config(format!("throttle のパターン '{}' の max_rate には 0 より大きい値を指定してください。", rule.pattern)));
+            }
+            let capacity = rule.max_rate.max(1.0);
+            Bucket {
+                pattern: rule.pattern.clone(),
+                rate: rule.max_rate,
+                capacity,
+                tokens: capacity,
+                updated: now,
+                delayed: VecDeque::new(),
+                overflow_warned: false,
+            }
+        }).collect();
+        Throttle { buckets, policy, metrics }
+    }
+
+    /// メッセージを制限の範囲内で処理できるかを判定し、制限を超えた件数をルールごとに記録する
+    pub fn admit(&mut self, message: &ReceivedMessage) -> Admission {
+        let Some(bucket) = self.buckets.iter_mut().find(|bucket| topic_matches(&bucket.pattern, &message.topic)) else {
+            return Admission::Pass;
+        };
+        bucket.refill(Instant::now());
+        // 遅延中のメッセージがある場合は、受信した順序を保つため後ろに並べる
+        if bucket.delayed.is_empty() && bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Admission::Pass;
+        }
+        if self.policy == ThrottlePolicy::Drop {
+            self.metrics.record_throttled(&bucket.pattern, true);
+            return Admission::Drop;
+        }
+        if bucket.delayed.len() >= MAX_DELAYED_MESSAGES {
+            if !bucket.overflow_warned {
+                eprintln!("警告: throttle のパターン '{}' で遅延中のメッセージが {} 件を超えたため、超えた分を破棄します。",
+                    bucket.pattern, MAX_DELAYED_MESSAGES);
+                bucket.overflow_warned = true;
+            }
+            self.metrics.record_throttled(&bucket.pattern, true);
+            return Admission::Drop;
+        }
+        self.metrics.record_throttled(&bucket.pattern, false);
+        Admission::Delay
+    }
+
+    /// 遅延させるメッセージを追加する (`admit` が `Admission::Delay` を返した場合)
+    pub fn delay(&mut self, message: ReceivedMessage) {
+        if let Some(bucket) = self.buckets.iter_mut().find(|bucket| topic_matches(&bucket.pattern, &message.topic)) {
+            bucket.delayed.push_back(message);
+        }
+    }
+
+    /// 遅延中のメッセージを次に処理できる時刻 (遅延中のメッセージがなければ `None`)
+    pub fn next_release(&self) -> Option<Instant> {
+        self.buckets.iter()
+            .filter(|bucket| !bucket.delayed.is_empty())
+            .map(|bucket| {
+                // 非常に小さい max_rate でも時刻が溢れないよう、待ち時間は 1 時間までにする (その後も再計算される)
+                let wait = ((1.0 - bucket.tokens).max(0.0) / bucket.rate).min(3600.0);
+                bucket.updated + Duration::from_secs_f64(wait)
+            })
+            .min()
+    }
+
+    /// トークンが補充された分だけ、遅延中のメッセージを取り出す
+    pub fn release(&mut self) -> Vec<ReceivedMessage> {
+        let now = Instant::now();
+        let mut released = Vec::new();
+        for bucket in &mut self.buckets {
+            if bucket.delayed.is_empty() {
+                continue;
+            }
+            bucket.refill(now);
+            while bucket.tokens >= 1.0 && let Some(message) = bucket.delayed.pop_front() {
+                bucket.tokens -= 1.0;
+                released.push(message);
+            }
+        }
+        released
+    }
+}
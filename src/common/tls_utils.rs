@@ -0,0 +1,264 @@
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use std::{fs, sync::Arc};
+use rumqttc::{tokio_rustls::rustls::{self, ClientConfig, RootCertStore}, Transport};
+use rustls::client::{danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, WebPkiServerVerifier};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+
+/// SSL/TLS 接続 (scheme: mqtts / ssl / wss) の場合に、証明書を読み込んで TLS のトランスポートを構築する。
+/// それ以外のスキームの場合は `None` を返す。
+pub fn build_tls_transport(config: &Config) -> Option<Transport> {
+    let tls_config = rumqttc::TlsConfiguration::Rustls(build_client_config(config)?);
+    if config.scheme.as_deref() == Some("wss") {
+        return Some(Transport::wss_with_config(tls_config));
+    }
+    Some(Transport::Tls(tls_config))
+}
+
+/// SSL/TLS 接続 (scheme: mqtts / ssl / wss) の場合に、証明書を読み込んで TLS のクライアントの設定を構築する。
+/// それ以外のスキームの場合は `None` を返す (TLS の項目を指定している場合は警告を表示する)。
+pub fn build_client_config(config: &Config) -> Option<Arc<ClientConfig>> {
+    if !matches!(config.scheme.as_deref(), Some("ssl" | "mqtts" | "wss")) {
+        if config.tls_alpn_protocols.is_some() {
+            eprintln!("警告: tls_alpn_protocols は SSL/TLS 接続 (scheme: mqtts / ssl / wss) でのみ有効です。");
+        }
+        if config.tls_pinned_cert_sha256.is_some() {
+            eprintln!("警告: tls_pinned_cert_sha256 は SSL/TLS 接続 (scheme: mqtts / ssl / wss / quic) でのみ有効です。");
+        }
+        return None;
+    }
+    let mut root_store = RootCertStore::empty();
+    for cert in load_ca_certs(config) {
+        root_store.add(cert).unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("CA証明書の追加中にエラーが発生しました: {}", e)));
+        });
+    }
+
+    let root_store = Arc::new(root_store);
+    // クライアント認証の準備
+    let mut client_config = if let Some((certs, client_key)) = load_client_identity(config) {
+        // ClientConfig の構築
+        ClientConfig::builder()
+            .with_root_certificates(Arc::clone(&root_store))
+            .with_client_auth_cert(certs, client_key)
+            .unwrap_or_else(|e| {
+                exit_with(AppError::Config(format!("クライアント認証の設定に失敗しました: {}", e)));
+            })
+    } else {
+        ClientConfig::builder()
+            .with_root_certificates(Arc::clone(&root_store))
+            .with_no_client_auth()
+    };
+    let pins = pinned_cert_sha256(config);
+    if !pins.is_empty() {
+        client_config.dangerous().set_certificate_verifier(pinned_cert_verifier(root_store, pins));
+    }
+
+    // ALPN プロトコルの設定 (AWS IoT Core のポート 443 接続では x-amzn-mqtt-ca が必要)
+    if let Some(protocols) = &config.tls_alpn_protocols {
+        client_config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+    }
+    Some(Arc::new(client_config))
+}
+
+/// TLS 接続で SNI に送り、サーバー証明書を検証するブローカーの名前 (broker_address)
+pub fn broker_server_name(config: &Config) -> ServerName<'static> {
+    let host = config.broker_address.trim_start_matches('[').trim_end_matches(']').to_string();
+    ServerName::try_from(host).unwrap_or_else(|e| {
+        exit_with(AppError::Config(format!("broker_address '{}' を TLS のサーバー名として使用できません: {}", config.broker_address, e)));
+    })
+}
+
+// tls_pinned_cert_sha256 が指定されている場合に、通常の CA による検証に成功した後にリーフ証明書のフィンガープリントを照合する
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        check_pinned_cert(&self.pins, end_entity).map_err(rustls::Error::General)?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn pinned_cert_verifier(root_store: Arc<RootCertStore>, pins: Vec<[u8; 32]>) -> Arc<PinnedCertVerifier> {
+    let inner = WebPkiServerVerifier::builder(root_store).build().unwrap_or_else(|e| {
+        exit_with(AppError::Config(format!("TLS 接続のサーバー証明書の検証を準備できませんでした (CA 証明書を確認してください): {}", e)));
+    });
+    Arc::new(PinnedCertVerifier { inner, pins })
+}
+
+/// tls_pinned_cert_sha256 のフィンガープリントを読み込む (未指定の場合は空)。16 進数の 32 バイトとして読めない場合は設定エラーで終了する
+pub(crate) fn pinned_cert_sha256(config: &Config) -> Vec<[u8; 32]> {
+    let Some(pins) = &config.tls_pinned_cert_sha256 else {
+        return Vec::new();
+    };
+    if pins.is_empty() {
+        exit_with(AppError::Config("tls_pinned_cert_sha256 にフィンガープリントが 1 つも指定されていません。".to_string()));
+    }
+    pins.iter().map(|pin| parse_fingerprint(pin).unwrap_or_else(|| {
+        exit_with(AppError::Config(format!(
+            "tls_pinned_cert_sha256 の '{}' は SHA-256 のフィンガープリントとして読めません (16 進数 64 文字で指定してください。\":\" の区切りは省略できます)。", pin)));
+    })).collect()
+}
+
+// "AB:CD:..." または "abcd..." の形式の 16 進数を 32 バイトとして読む
+fn parse_fingerprint(pin: &str) -> Option<[u8; 32]> {
+    let digits: Vec<u8> = pin.bytes().filter(|b| *b != b':').collect();
+    // from_str_radix は先頭の符号 ("+a") も受け付けるため、16 進数の文字だけであることを先に確かめる
+    if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let mut fingerprint = [0u8; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(fingerprint)
+}
+
+/// 提示されたリーフ証明書 (DER) の SHA-256 がピン留めしたフィンガープリントのいずれかと一致するかを確認する (ピンがない場合は常に成功)
+pub(crate) fn check_pinned_cert(pins: &[[u8; 32]], end_entity: &[u8]) -> Result<(), String> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+    let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+    if pins.iter().any(|pin| pin.as_slice() == digest.as_ref()) {
+        return Ok(());
+    }
+    let presented = digest.as_ref().iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":");
+    Err(format!("certificate pin mismatch: ブローカーの証明書の SHA-256 ({}) が tls_pinned_cert_sha256 のいずれとも一致しません", presented))
+}
+
+// CA証明書を読み込む (ファイルパスまたは設定ファイルに直接記述した PEM)
+pub(crate) fn load_ca_certs(config: &Config) -> Vec<CertificateDer<'static>> {
+    match read_pem_source("CA証明書", config.ca_cert_path.as_deref(), config.ca_cert_pem.as_deref()) {
+        Some(ca_cert_pem) => parse_certs(ca_cert_pem),
+        None => {
+            eprintln!("警告: SSL/TLS 接続用に CA 証明書 (ca_cert_path または ca_cert_pem) が指定されていません。");
+            Vec::new()
+        }
+    }
+}
+
+// ファイルパスまたは文字列で指定された PEM の内容を取得する。両方が指定されている場合は設定エラーとする
+fn read_pem_source(name: &str, path: Option<&str>, inline: Option<&str>) -> Option<Vec<u8>> {
+    match (path, inline) {
+        (Some(_), Some(_)) => {
+            exit_with(AppError::Config(format!("{} はファイルパスと PEM 文字列の両方が指定されています。どちらか一方のみを指定してください。", name)));
+        }
+        (Some(path), None) => Some(fs::read(path).unwrap_or_else(|e| {
+            exit_with(AppError::Config(format!("{} '{}' の読み込み中にエラーが発生しました: {}", name, path, e)));
+        })),
+        (None, Some(pem)) => Some(pem.as_bytes().to_vec()),
+        (None, None) => None,
+    }
+}
+
+fn parse_certs(pem: Vec<u8>) -> Vec<CertificateDer<'static>> {
+    let mut reader = std::io::BufReader::new(std::io::Cursor::new(pem));
+    rustls_pemfile::certs(&mut reader)
+        .filter_map(Result::ok)
+        .collect::<Vec<_>>()
+}
+
+fn parse_pkcs8_key(pem: Vec<u8>) -> PrivateKeyDer<'static> {
+    let mut reader = std::io::BufReader::new(std::io::Cursor::new(pem));
+    let client_key_pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut reader)
+        .filter_map(Result::ok)
+        .next()
+        .unwrap_or_else(|| {
+            exit_with(AppError::Config("クライアントの秘密鍵が見つかりません。".to_string()));
+        });
+
+    PrivateKeyDer::Pkcs8(client_key_pkcs8)
+}
+
+// クライアント証明書と秘密鍵 (PKCS#8) を読み込む。
+// client_combined_path (証明書と秘密鍵を 1 つにまとめたファイル) または client_cert_pem / client_key_pem のどちらかで指定する
+pub(crate) fn load_client_identity(config: &Config) -> Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let inline = config.client_cert_pem.is_some() || config.client_key_pem.is_some();
+    if config.client_combined_path.is_some() && inline {
+        exit_with(AppError::Config("クライアント証明書/キーは client_combined_path と client_cert_pem / client_key_pem の両方が指定されています。どちらか一方のみを指定してください。".to_string()));
+    }
+
+    if let Some(cert_key_pem) = read_pem_source("クライアント証明書/キーファイル", config.client_combined_path.as_deref(), None) {
+        let certs = parse_certs(cert_key_pem.clone());
+        return Some((certs, parse_pkcs8_key(cert_key_pem)));
+    }
+
+    match (&config.client_cert_pem, &config.client_key_pem) {
+        (Some(cert_pem), Some(key_pem)) => {
+            let certs = parse_certs(cert_pem.as_bytes().to_vec());
+            Some((certs, parse_pkcs8_key(key_pem.as_bytes().to_vec())))
+        }
+        (None, None) => None,
+        _ => {
+            exit_with(AppError::Config("client_cert_pem と client_key_pem は両方を指定してください。".to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &[u8] = b"leaf certificate der";
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        ring::digest::digest(&ring::digest::SHA256, data).as_ref().try_into().unwrap()
+    }
+
+    fn hex(bytes: &[u8], separator: &str) -> String {
+        bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(separator)
+    }
+
+    #[test]
+    fn fingerprints_are_read_with_or_without_colons() {
+        let digest = sha256(CERT);
+        assert_eq!(parse_fingerprint(&hex(&digest, ":")), Some(digest));
+        assert_eq!(parse_fingerprint(&hex(&digest, "")), Some(digest));
+        assert_eq!(parse_fingerprint(&hex(&digest, "").to_lowercase()), Some(digest));
+    }
+
+    #[test]
+    fn malformed_fingerprints_are_rejected() {
+        let digits = hex(&sha256(CERT), "");
+        for pin in [&digits[..62], &format!("{}00", digits), "", &format!("+{}", &digits[1..]), &format!("{}zz", &digits[..62]),
+            &format!("{}é", &digits[..62])] {
+            assert_eq!(parse_fingerprint(pin), None, "{}", pin);
+        }
+    }
+
+    #[test]
+    fn pinned_cert_must_match_one_of_the_fingerprints() {
+        let other = sha256(b"another certificate");
+        assert!(check_pinned_cert(&[], CERT).is_ok());
+        assert!(check_pinned_cert(&[other, sha256(CERT)], CERT).is_ok());
+        let error = check_pinned_cert(&[other], CERT).unwrap_err();
+        assert!(error.starts_with("certificate pin mismatch"), "{}", error);
+        assert!(error.contains(&hex(&sha256(CERT), ":")), "{}", error);
+    }
+}
@@ -0,0 +1,132 @@
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time;
+
+/// token_refresh_margin_secs が未指定の場合に、有効期限の何秒前にトークンを更新するか
+pub const DEFAULT_TOKEN_REFRESH_MARGIN_SECS: u64 = 60;
+// トークンを更新するまでの最短時間
+const MIN_REFRESH_AFTER: Duration = Duration::from_secs(1);
+// トークンの取得に失敗した場合の再試行間隔の上限
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+// トークンエンドポイントへのリクエストのタイムアウト
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// トークンエンドポイントから取得した認証トークン
+#[derive(Debug, Clone)]
+pub struct AuthToken {
+    pub token: String,
+    /// トークンの有効期間 (レスポンスに expires_in が含まれない場合は `None`)
+    pub expires_in: Option<Duration>,
+}
+
+impl AuthToken {
+    /// 有効期限の `token_refresh_margin_secs` 秒前 (トークンを更新して再接続するまでの時間) を返す。
+    /// 有効期間がマージンより短いトークンで更新と再接続を繰り返さないよう、マージンは有効期間の半分までとし、最短 1 秒とする。
+    /// 有効期間が分からない場合は `None` を返す
+    pub fn refresh_after(&self, config: &Config) -> Option<Duration> {
+        let margin = Duration::from_secs(config.token_refresh_margin_secs.unwrap_or(DEFAULT_TOKEN_REFRESH_MARGIN_SECS));
+        self.expires_in.map(|expires_in| expires_in.saturating_sub(margin.min(expires_in / 2)).max(MIN_REFRESH_AFTER))
+    }
+}
+
+// OAuth 2.0 のトークンレスポンス
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// auth_token_url からトークンを取得する。失敗した場合は間隔を延ばしながら (最大 60 秒) 再試行する。
+/// fail_fast が有効な場合は再試行せずに終了する。
+pub async fn fetch_token_with_retry(config: &Config, url: &str) -> AuthToken {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match fetch_token(config, url).await {
+            Ok(token) => return token,
+            Err(e) => {
+                if config.fail_fast.unwrap_or(false) {
+                    exit_with(e);
+                }
+                eprintln!("{} {} 秒後に再試行します。", e, delay.as_secs());
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+// client_credentials グラントでトークンを要求する。
+// レスポンスが JSON でない場合は、本文全体をトークンとして扱う
+async fn fetch_token(config: &Config, url: &str) -> Result<AuthToken, AppError> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| AppError::Config(format!("HTTP クライアントの作成に失敗しました: {}", e)))?;
+    let mut request = client.post(url).form(&[("grant_type", "client_credentials")]);
+    if let Some(client_id) = &config.auth_client_id {
+        request = request.basic_auth(client_id, config.auth_client_secret.as_ref());
+    }
+
+    let response = request.send().await
+        .map_err(|e| AppError::Unavailable(format!("認証トークンの取得中にエラーが発生しました: {}", e)))?;
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(AppError::Auth(format!("トークンエンドポイントで認証に失敗しました (HTTP {})。", status)));
+    }
+    if !status.is_success() {
+        return Err(AppError::Unavailable(format!("トークンエンドポイントがエラーを返しました (HTTP {})。", status)));
+    }
+    let body = response.text().await
+        .map_err(|e| AppError::Unavailable(format!("認証トークンの読み込み中にエラーが発生しました: {}", e)))?;
+
+    let token = match serde_json::from_str::<TokenResponse>(&body) {
+        Ok(response) => AuthToken {
+            token: response.access_token,
+            expires_in: response.expires_in.map(Duration::from_secs),
+        },
+        Err(_) => AuthToken { token: body.trim().to_string(), expires_in: None },
+    };
+    if token.token.is_empty() {
+        return Err(AppError::Unavailable("トークンエンドポイントのレスポンスにトークンが含まれていません。".to_string()));
+    }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refresh_after(expires_in: u64, margin: Option<u64>) -> Duration {
+        let mut config: Config = serde_yaml::from_str("client_id: token\n").unwrap();
+        config.token_refresh_margin_secs = margin;
+        let token = AuthToken { token: "t".to_string(), expires_in: Some(Duration::from_secs(expires_in)) };
+        token.refresh_after(&config).unwrap()
+    }
+
+    #[test]
+    fn refreshes_margin_before_expiry() {
+        assert_eq!(refresh_after(3600, None), Duration::from_secs(3600 - DEFAULT_TOKEN_REFRESH_MARGIN_SECS));
+        assert_eq!(refresh_after(300, Some(30)), Duration::from_secs(270));
+    }
+
+    #[test]
+    fn short_tokens_are_refreshed_at_half_their_lifetime() {
+        // マージン (60 秒) が有効期間 (30 秒) より長くても、すぐに更新を繰り返さない
+        assert_eq!(refresh_after(30, None), Duration::from_secs(15));
+        assert_eq!(refresh_after(120, None), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn refresh_waits_at_least_one_second() {
+        assert_eq!(refresh_after(0, None), MIN_REFRESH_AFTER);
+        assert_eq!(refresh_after(1, None), MIN_REFRESH_AFTER);
+    }
+
+    #[test]
+    fn unknown_lifetime_is_not_refreshed() {
+        let config: Config = serde_yaml::from_str("client_id: token\n").unwrap();
+        assert_eq!(AuthToken { token: "t".to_string(), expires_in: None }.refresh_after(&config), None);
+    }
+}
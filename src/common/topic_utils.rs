@@ -0,0 +1,81 @@
+// トピックとトピックフィルタのマッチング処理
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use regex::RegexSet;
+
+/// MQTT のワイルドカード (`+`, `#`) を含むパターンがトピックに一致するかを判定する。
+///
+/// `topic` にワイルドカードが含まれる場合 (購読用のトピックフィルタ) は、その文字も通常のレベルとして扱う。
+/// 仕様に従い、先頭レベルのワイルドカードは `$` で始まるトピックに一致しない。
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (pattern.starts_with('+') || pattern.starts_with('#')) {
+        return false;
+    }
+
+    let mut pattern_levels = pattern.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (pattern_levels.next(), topic_levels.next()) {
+            // `#` は残りのすべてのレベル (親レベル自身を含む) に一致する
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(p), Some(t)) if p == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// トピックフィルタとして正しいか (空でない、`#` は最後のレベルのみ、`+` と `#` はレベル全体、NUL を含まない) を検査する
+pub fn validate_filter(filter: &str) -> Result<(), String> {
+    if filter.is_empty() {
+        return Err("空のトピックフィルタは指定できません".to_string());
+    }
+    if filter.contains('\0') {
+        return Err("NUL 文字は指定できません".to_string());
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (index, level) in levels.iter().enumerate() {
+        if level.contains('#') && (*level != "#" || index + 1 != levels.len()) {
+            return Err("'#' は最後のレベルに単独で指定してください".to_string());
+        }
+        if level.contains('+') && *level != "+" {
+            return Err("'+' はレベルに単独で指定してください".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// display_regex の正規表現を 1 つの RegexSet にまとめる (未指定の場合は `None`)。不正な正規表現がある場合は設定エラーで終了する
+pub fn display_regex(config: &Config) -> Option<RegexSet> {
+    let patterns = config.display_regex.as_ref()?;
+    // RegexSet のエラーはどの正規表現が不正かを示さないため、1 つずつ検査する
+    for (i, pattern) in patterns.iter().enumerate() {
+        if let Err(e) = regex::Regex::new(pattern) {
+            exit_with(AppError::Config(format!("display_regex[{}] '{}' は正規表現として不正です: {}", i, pattern, e)));
+        }
+    }
+    Some(RegexSet::new(patterns).unwrap_or_else(|e| {
+        exit_with(AppError::Config(format!("display_regex を使用できません: {}", e)));
+    }))
+}
+
+/// `$` で始まるシステムトピック (`$SYS/...` など) かを判定する
+pub fn is_system_topic(topic: &str) -> bool {
+    topic.starts_with('$')
+}
+
+/// `#` を購読している場合に、`#` と同じ QoS で `$SYS/#` も購読するよう追加する。
+///
+/// 仕様では `#` は `$` で始まるトピックに一致しないため、システムトピックは明示的に購読する必要がある。
+pub fn add_system_topics<Q: Copy>(filters: &mut Vec<(String, Q)>) {
+    let Some(qos) = filters.iter().find(|(filter, _)| filter == "#").map(|(_, qos)| *qos) else {
+        return;
+    };
+    if !filters.iter().any(|(filter, _)| filter == SYSTEM_TOPIC_FILTER) {
+        filters.push((SYSTEM_TOPIC_FILTER.to_string(), qos));
+    }
+}
+
+// include_system_topics が有効な場合に `#` とあわせて購読するフィルタ
+const SYSTEM_TOPIC_FILTER: &str = "$SYS/#";
@@ -0,0 +1,122 @@
+// 接続先の URL (url / proxy_url) の解析
+use super::config_utils::Config;
+
+/// `url` で指定した接続先を分解したもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerUrl {
+    /// scheme に設定する値 (mqtt / mqtts / ws / wss / quic)
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// WebSocket (ws / wss) の接続先のパス
+    pub path: Option<String>,
+}
+
+/// `scheme://[user[:password]@]host[:port][/path]` 形式の接続先の URL を解析する。
+///
+/// スキームは mqtt (tcp)、mqtts (ssl)、ws、wss、quic に対応し、ポートを省略した場合はそれぞれ
+/// 1883、8883、80、443、14567 を使用する。パスは ws / wss の場合のみ指定できる。
+pub fn parse_broker_url(url: &str) -> Result<BrokerUrl, String> {
+    let (scheme, rest) = url.split_once("://")
+        .ok_or_else(|| format!("url '{}' にスキームがありません (例: mqtts://broker.example.com:8883)", url))?;
+    let (scheme, default_port) = match scheme.to_ascii_lowercase().as_str() {
+        "mqtt" | "tcp" => ("mqtt", 1883),
+        "mqtts" | "ssl" => ("mqtts", 8883),
+        "ws" => ("ws", 80),
+        "wss" => ("wss", 443),
+        "quic" => ("quic", 14567),
+        other => return Err(format!("url のスキーム '{}' には対応していません (mqtt / mqtts / ws / wss / quic を指定してください)", other)),
+    };
+    if rest.contains(['?', '#']) {
+        return Err(format!("url '{}' にクエリやフラグメント (? / #) は指定できません", url));
+    }
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], Some(&rest[index..])),
+        None => (rest, None),
+    };
+    let path = path.filter(|path| *path != "/");
+    if path.is_some() && !matches!(scheme, "ws" | "wss") {
+        return Err(format!("url '{}' のパスは ws / wss の場合のみ指定できます", url));
+    }
+    let (username, password, host_port) = match authority.rsplit_once('@') {
+        Some((userinfo, host_port)) => {
+            let (user, password) = match userinfo.split_once(':') {
+                Some((user, password)) => (user, Some(password)),
+                None => (userinfo, None),
+            };
+            let user = percent_decode(user).map_err(|e| format!("url のユーザー名: {}", e))?;
+            let password = password.map(percent_decode).transpose().map_err(|e| format!("url のパスワード: {}", e))?;
+            (Some(user), password, host_port)
+        }
+        None => (None, None, authority),
+    };
+    let (host, port) = split_host_port(host_port, default_port).map_err(|e| format!("url '{}': {}", url, e))?;
+    Ok(BrokerUrl { scheme: scheme.to_string(), host, port, username, password, path: path.map(str::to_string) })
+}
+
+/// url が指定されている場合に、解析した値で scheme / broker_address / broker_port と
+/// (URL に含まれていれば) username / password / websocket_path を上書きする
+pub fn apply_broker_url(config: &mut Config) -> Result<(), String> {
+    let Some(url) = &config.url else {
+        return Ok(());
+    };
+    let url = parse_broker_url(url)?;
+    config.scheme = Some(url.scheme);
+    config.broker_address = url.host;
+    config.broker_port = url.port;
+    if url.username.is_some() {
+        config.username = url.username;
+        config.password = url.password;
+    }
+    if url.path.is_some() {
+        config.websocket_path = url.path;
+    }
+    Ok(())
+}
+
+/// `host:port` / `[IPv6]:port` / `host` を分解する。ポートを省略した場合は `default_port` を使う
+pub fn split_host_port(host_port: &str, default_port: u16) -> Result<(String, u16), String> {
+    let parse_port = |port: &str| match port.parse::<u16>() {
+        Ok(0) | Err(_) => Err(format!("ポート '{}' が不正です (1〜65535 を指定してください)", port)),
+        Ok(port) => Ok(port),
+    };
+    let (host, port) = if let Some(rest) = host_port.strip_prefix('[') {
+        let (host, after) = rest.split_once(']').ok_or_else(|| format!("IPv6 アドレス '{}' の ] がありません", host_port))?;
+        let port = match after.strip_prefix(':') {
+            Some(port) => parse_port(port)?,
+            None if after.is_empty() => default_port,
+            None => return Err(format!("IPv6 アドレスの後の '{}' が不正です", after)),
+        };
+        (host, port)
+    } else {
+        match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, parse_port(port)?),
+            None => (host_port, default_port),
+        }
+    };
+    if host.is_empty() {
+        return Err("ホストがありません".to_string());
+    }
+    Ok((host.to_string(), port))
+}
+
+/// URL のユーザー情報の %XX をデコードする
+pub fn percent_decode(text: &str) -> Result<String, String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = text.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("% エンコードが不正です ({} バイト目)", i + 1))?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| "% エンコードをデコードした結果が UTF-8 ではありません".to_string())
+}
@@ -0,0 +1,96 @@
+// 指定したメッセージを受信するまで待って終了する (wait_for / --wait-for)
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use super::mqtt_utils::ReceivedMessage;
+use super::topic_utils::topic_matches;
+use regex::Regex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+// ペイロードの条件
+enum PayloadPattern {
+    // ペイロードにこの文字列が含まれる
+    Contains(String),
+    // ペイロードがこの正規表現に一致する (`/.../` で指定)
+    Regex(Regex),
+}
+
+/// wait_for の条件 (`トピックフィルタ[:パターン]`) に一致するメッセージの受信を待つ。
+///
+/// パターンを `/` で囲んだ場合は正規表現、それ以外は部分文字列としてペイロード (UTF-8 として読めない部分は置換する) と比べる。
+/// パターンを省略した場合は、トピックが一致すればペイロードにかかわらず一致とする。
+pub struct WaitFor {
+    spec: String,
+    filter: String,
+    pattern: Option<PayloadPattern>,
+    matched: AtomicBool,
+    notify: Notify,
+}
+
+impl WaitFor {
+    /// wait_for が指定されていない場合は `None` を返す。条件が不正な場合はエラーを表示して終了する
+    pub fn new(config: &Config) -> Option<WaitFor> {
+        let spec = config.wait_for.as_ref()?;
+        // トピックフィルタには ':' を含めないものとし、最初の ':' より後をパターンとする
+        let (filter, pattern) = match spec.split_once(':') {
+            Some((filter, pattern)) => (filter, Some(pattern)),
+            None => (spec.as_str(), None),
+        };
+        if filter.is_empty() {
+            exit_with(AppError::Config(format!("wait_for '{}' にトピックフィルタを指定してください (トピックフィルタ[:パターン])。", spec)));
+        }
+        if !config.topics.iter().any(|topic| topic == filter || topic_matches(topic, filter)) {
+            eprintln!("警告: wait_for のトピックフィルタ '{}' は購読するトピック (topics) に含まれていません。", filter);
+        }
+        let pattern = pattern.filter(|pattern| !pattern.is_empty()).map(|pattern| {
+            match pattern.strip_prefix('/').and_then(|rest| rest.strip_suffix('/')) {
+                Some(expression) => PayloadPattern::Regex(Regex::new(expression).unwrap_or_else(|e| {
+                    exit_with(AppError::Config(format!("wait_for の正規表現 '{}' が不正です: {}", expression, e)))
+                })),
+                None => PayloadPattern::Contains(pattern.to_string()),
+            }
+        });
+        Some(WaitFor {
+            spec: spec.clone(),
+            filter: filter.to_string(),
+            pattern,
+            matched: AtomicBool::new(false),
+            notify: Notify::new(),
+        })
+    }
+
+    /// 指定された条件 (wait_for の値)
+    pub fn spec(&self) -> &str {
+        &self.spec
+    }
+
+    /// 受信したメッセージが条件に一致するかを調べ、一致した場合は `wait` で待っている側に知らせる
+    pub fn check(&self, message: &ReceivedMessage) {
+        if self.matched.load(Ordering::Relaxed) || !topic_matches(&self.filter, &message.topic) {
+            return;
+        }
+        let found = match &self.pattern {
+            None => true,
+            Some(pattern) => {
+                let payload = String::from_utf8_lossy(&message.payload);
+                match pattern {
+                    PayloadPattern::Contains(text) => payload.contains(text.as_str()),
+                    PayloadPattern::Regex(regex) => regex.is_match(&payload),
+                }
+            }
+        };
+        if found && !self.matched.swap(true, Ordering::Relaxed) {
+            self.notify.notify_one();
+        }
+    }
+
+    /// 条件に一致するメッセージを受信するまで待つ
+    pub async fn wait(&self) {
+        self.notify.notified().await;
+    }
+
+    /// 条件に一致するメッセージを受信したか
+    pub fn matched(&self) -> bool {
+        self.matched.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,72 @@
+// 再接続・切断の Webhook による通知 (reconnect_webhook_url)
+use super::config_utils::Config;
+use super::error_utils::{exit_with, AppError};
+use super::output_utils::format_timestamp;
+use std::time::{Duration, SystemTime};
+
+// Webhook へのリクエストのタイムアウト (応答の遅い送信先でタスクが溜まらないよう短くする)
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 再接続 (reconnect_webhook_on_disconnect の場合は最初の切断も) を reconnect_webhook_url に JSON で POST する。
+///
+/// 送信は別のタスクで行い、結果を待たない (イベントループを止めない)。送信に失敗した場合は警告を表示するだけで再送しない。
+pub struct ReconnectWebhook {
+    client: reqwest::Client,
+    url: String,
+    client_id: String,
+    broker: String,
+    on_disconnect: bool,
+}
+
+impl ReconnectWebhook {
+    /// reconnect_webhook_url が指定されていない場合は `None` を返す
+    pub fn new(config: &Config) -> Option<ReconnectWebhook> {
+        let url = config.reconnect_webhook_url.as_ref()?;
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_else(|e| exit_with(AppError::Config(format!("HTTP クライアントの作成に失敗しました: {}", e))));
+        Some(ReconnectWebhook {
+            client,
+            url: url.clone(),
+            client_id: config.client_id.clone(),
+            broker: format!("{}:{}", config.broker_address, config.broker_port),
+            on_disconnect: config.reconnect_webhook_on_disconnect.unwrap_or(false),
+        })
+    }
+
+    /// 接続が切れたことを通知する (reconnect_webhook_on_disconnect が有効な場合のみ)
+    pub fn disconnected(&self, error: &str) {
+        if self.on_disconnect {
+            self.send("disconnected", 0, error);
+        }
+    }
+
+    /// 再接続したことを通知する。`attempt` は切断してから接続に成功するまでの試行回数、`error` は切断の原因となったエラー
+    pub fn reconnected(&self, attempt: u32, error: &str) {
+        self.send("reconnected", attempt, error);
+    }
+
+    fn send(&self, event: &str, attempt: u32, error: &str) {
+        let body = serde_json::json!({
+            "event": event,
+            "timestamp": format_timestamp(SystemTime::now()),
+            "client_id": self.client_id,
+            "broker": self.broker,
+            "attempt": attempt,
+            "error": error,
+        });
+        let request = self.client.post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if !response.status().is_success() => {
+                    eprintln!("警告: 再接続の Webhook がエラーを返しました (HTTP {})。", response.status());
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("警告: 再接続の Webhook に送信できませんでした: {}", e),
+            }
+        });
+    }
+}
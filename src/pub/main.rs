@@ -1,4 +1,147 @@
-fn main() {
-    println!("Hello, world!");
+use mqtt_client::{common, status};  // 共通のモジュールをインポート
+use clap::Parser;
+use common::config_utils::{Config, ConfigFiles, MessageConfig};
+use common::error_utils::{exit_with, AppError};
+use common::mqtt_utils;
+use common::publisher::{PayloadSource, Publisher};
+use std::io::IsTerminal;
 
+// コマンドライン引数
+#[derive(Parser, Debug)]
+#[command(version, about = "MQTT ブローカーのトピックにメッセージを発行します")]
+struct Args {
+    /// 設定ファイルのパス (複数指定した場合は指定した順に結合し、後のファイルの項目で上書きする)
+    #[arg(long, default_value = "config.yaml")]
+    config: Vec<String>,
+    /// 複数の設定ファイルで指定したリストの項目 (topics など) を、置き換えずに前のファイルのリストの後ろに追加する
+    #[arg(long)]
+    append_lists: bool,
+    /// 詳細を表示する (各設定項目の値をどの設定ファイルから読み込んだか)
+    #[arg(long)]
+    verbose: bool,
+    /// 発行先のトピック (複数指定可能、指定した場合は設定ファイルの topics を上書き)
+    #[arg(long)]
+    topic: Vec<String>,
+    /// 標準入力を行ごとに分割せず、全体を 1 つのバイナリメッセージとして発行する
+    #[arg(long)]
+    raw: bool,
+    /// このファイルの内容を 1 つのメッセージとして発行する (設定ファイルの payload / payload_file を上書き)
+    #[arg(long)]
+    payload_file: Option<String>,
+    /// 接続エラー時に再接続せず、終了コード 69 (認証エラーの場合は 77) で終了する
+    #[arg(long)]
+    fail_fast: bool,
+    /// 送信する CONNECT / SUBSCRIBE / PUBLISH を注釈付きの 16 進ダンプで標準エラー出力に表示する (プロトコルの調査用)
+    #[arg(long)]
+    dump_packets: bool,
+    /// 設定ファイルに未知の項目 (項目名の誤りなど) がある場合はエラーにする (指定しない場合は無視する)
+    #[arg(long)]
+    strict_config: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::try_parse().unwrap_or_else(|e| {
+        // --help / --version 以外 (引数の誤り) は設定エラーの終了コードで終了する
+        if e.use_stderr() {
+            exit_with(AppError::Config(e.to_string().trim_end().to_string()));
+        }
+        e.exit()
+    });
+    // 設定ファイルを読み込む
+    let config_files = ConfigFiles {
+        paths: args.config,
+        strict: args.strict_config,
+        append_lists: args.append_lists,
+        verbose: args.verbose,
+    };
+    let mut config: Config = common::config_utils::get_config(&config_files);
+
+    // コマンドライン引数で設定を上書き
+    if !args.topic.is_empty() {
+        config.topics = args.topic;
+    }
+    if let Some(path) = args.payload_file {
+        config.payload = None;
+        config.payload_file = Some(path);
+    }
+    if args.fail_fast {
+        config.fail_fast = Some(true);
+    }
+    if args.dump_packets {
+        config.dump_packets = Some(true);
+    }
+    config.apply_client_id_suffix();
+    let source = match &config.messages {
+        // messages が指定されている場合は、各メッセージをそれぞれのトピックに発行する
+        Some(messages) => {
+            if !config.topics.is_empty() {
+                eprintln!("警告: messages が指定されているため、topics ({}) には発行しません。", config.topics.join(", "));
+            }
+            PayloadSource::Messages(message_payloads(&config, messages))
+        }
+        None => {
+            if config.topics.is_empty() {
+                exit_with(AppError::Config("発行先のトピックが指定されていません。".to_string()));
+            }
+            // payload / payload_file が指定されていない場合は標準入力から読み込む
+            match fixed_payload(&config, config.payload.as_ref(), config.payload_file.as_ref(), "") {
+                Some(payload) => PayloadSource::Fixed(payload),
+                None if std::io::stdin().is_terminal() => {
+                    exit_with(AppError::Config("ペイロードが指定されていません。設定ファイルの payload を指定するか、標準入力からデータを渡してください。".to_string()));
+                }
+                None if args.raw => PayloadSource::StdinRaw,
+                None => PayloadSource::StdinLines,
+            }
+        }
+    };
+
+    let mut publisher = Publisher::new(config);
+    publisher.run(source).await;
+
+    println!("終了します。");
+}
+
+// messages の各メッセージのペイロード (省略した場合は payload) を取得し、発行先のトピックを検証する
+fn message_payloads(config: &Config, messages: &[MessageConfig]) -> Vec<Vec<u8>> {
+    if messages.is_empty() {
+        exit_with(AppError::Config("messages に発行するメッセージが指定されていません。".to_string()));
+    }
+    messages.iter().enumerate().map(|(i, message)| {
+        if message.topic.is_empty() || message.topic.contains(['+', '#']) {
+            exit_with(AppError::Config(format!("messages[{}]: 発行先のトピック '{}' が不正です (空またはワイルドカードを含むトピックには発行できません)。", i, message.topic)));
+        }
+        let label = format!("messages[{}]: ", i);
+        // メッセージの payload / payload_file を優先し、どちらもなければ全体の設定を使用する
+        let payload = if message.payload.is_some() || message.payload_file.is_some() {
+            fixed_payload(config, message.payload.as_ref(), message.payload_file.as_ref(), &label)
+        } else {
+            fixed_payload(config, config.payload.as_ref(), config.payload_file.as_ref(), "")
+        };
+        payload.unwrap_or_else(|| exit_with(AppError::Config(format!(
+            "messages[{}] (トピック '{}') のペイロードが指定されていません。payload または payload_file を指定してください。", i, message.topic))))
+    }).collect()
+}
+
+// payload または payload_file (ファイルの内容をそのまま使う) で指定したペイロード。どちらも指定されていない場合は `None`
+fn fixed_payload(config: &Config, payload: Option<&String>, payload_file: Option<&String>, label: &str) -> Option<Vec<u8>> {
+    match (payload, payload_file) {
+        (Some(_), Some(_)) => exit_with(AppError::Config(format!("{}payload と payload_file は同時に指定できません。", label))),
+        (Some(payload), None) => Some(payload.clone().into_bytes()),
+        (None, Some(path)) => Some(read_payload_file(config, path, label)),
+        (None, None) => None,
+    }
+}
+
+fn read_payload_file(config: &Config, path: &str, label: &str) -> Vec<u8> {
+    let payload = std::fs::read(path).unwrap_or_else(|e| exit_with(AppError::Config(format!(
+        "{}ペイロードファイル '{}' を読み込めませんでした: {}", label, path, e))));
+    if let Some(max) = mqtt_utils::max_outgoing_packet_size(config)
+        && payload.len() > max {
+        exit_with(AppError::Config(format!(
+            "{}ペイロードファイル '{}' ({} バイト) が max_packet_size ({} バイト) を超えています。MQTT 3.1.1 では発行するメッセージの上限にもなるため、max_packet_size を大きくしてください。",
+            label, path, payload.len(), max)));
+    }
+    status!("ペイロードファイル '{}' ({} バイト) を読み込みました。", path, payload.len());
+    payload
 }
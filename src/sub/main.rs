@@ -1,165 +1,418 @@
-use mqtt_client::common;  // 共通のモジュールをインポート
-use std::{fs, io::Seek, process, sync::Arc, time::Duration};
-use rumqttc::{tokio_rustls::rustls::{ClientConfig, RootCertStore}, Client, Event, MqttOptions, Packet, QoS, Transport};
-use tokio::time; 
+use mqtt_client::{common, status};  // 共通のモジュールをインポート
+use clap::Parser;
+use common::benchmark_utils;
+use common::error_utils::{exit_with, AppError};
+use common::forward_utils::Bridge;
+use common::gap_utils;
+use common::histogram_utils::{self, PayloadSizes};
+use common::metrics_utils::{self, MetricsSnapshot, ThrottleCount};
+use common::output_utils;
+use common::qos_utils;
+use common::history_utils::MessageHistory;
+use common::readiness_utils::Readiness;
+use common::replay_utils;
+use common::selftest_utils;
+use common::sequence_utils;
+use common::statsd_utils::StatsdExporter;
+use common::stream_utils::MessageStream;
+use common::subscriber::Subscriber;
+use common::topic_utils;
+use common::wait_utils::WaitFor;
+use rumqttc::QoS;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{task::JoinSet, time};
 // TODO: ログ出力機能、ログ出力設定を追加する
 
-// 複数のトピックを購読する
-async fn subscribe_topics(cli: &mut Client, topics: &[String], qos_values: &[QoS]) {
-    for (i, topic) in topics.iter().enumerate() {
-        // QoS が指定されていない場合は QoS::AtMostOnce (QoS 0) をデフォルトとする
-        let qos = qos_values.get(i).copied().unwrap_or(QoS::AtMostOnce);
-        if let Err(e) = cli.subscribe(topic, qos) {
-            eprintln!("トピック '{}' (QoS {:?}) の購読中にエラーが発生しました: {:?}", topic, qos, e);
-            process::exit(1);
-        }
-        println!("トピック: '{}' (QoS {:?}) を購読しました。", topic, qos);
-    }
+// max_runtime_secs が経過して切断を要求してから、各セッションの切断を待つ時間
+const DISCONNECT_GRACE: Duration = Duration::from_secs(5);
+
+// コマンドライン引数
+#[derive(Parser, Debug)]
+#[command(version, about = "MQTT ブローカーのトピックを購読し、受信したメッセージを表示します")]
+struct Args {
+    /// 設定ファイルのパス (複数指定した場合は指定した順に結合し、後のファイルの項目で上書きする)
+    #[arg(long, default_value = "config.yaml")]
+    config: Vec<String>,
+    /// 複数の設定ファイルで指定したリストの項目 (topics など) を、置き換えずに前のファイルのリストの後ろに追加する
+    #[arg(long)]
+    append_lists: bool,
+    /// 詳細を表示する (各設定項目の値をどの設定ファイルから読み込んだか、受信したメッセージの間のシステムの時刻の変更、各メッセージに適用した format_rules のルール)
+    #[arg(long)]
+    verbose: bool,
+    /// 接続エラー時に再接続せず、終了コード 69 (認証エラーの場合は 77) で終了する
+    #[arg(long)]
+    fail_fast: bool,
+    /// 送信する CONNECT / SUBSCRIBE / PUBLISH を注釈付きの 16 進ダンプで標準エラー出力に表示する (プロトコルの調査用)
+    #[arg(long)]
+    dump_packets: bool,
+    /// 接続後に発行されたメッセージのみを表示する (購読時に配信される retain メッセージを表示しない)
+    #[arg(long)]
+    tail: bool,
+    /// 最初の接続時にブローカーが通知した機能と制限 (MQTT 5 の CONNACK プロパティ) を表示する
+    #[arg(long)]
+    show_capabilities: bool,
+    /// ブローカーに接続して一時的なトピックにメッセージを発行し、受信できることを確認して終了する (接続設定の確認用)
+    #[arg(long)]
+    selftest: bool,
+    /// この時間だけ実行したら切断して終了する (例: 90、30s、5m、1h。設定ファイルの max_runtime_secs より優先)
+    #[arg(long, value_parser = parse_duration_secs)]
+    duration: Option<u64>,
+    /// すべてのトピックをこの QoS で購読する (設定ファイルの qos / qos_rules より優先)
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=2))]
+    qos: Option<i32>,
+    /// 設定ファイルに未知の項目 (項目名の誤りなど) がある場合はエラーにする (指定しない場合は無視する)
+    #[arg(long)]
+    strict_config: bool,
+    /// このメッセージを受信したら終了コード 0 で終了する (トピックフィルタ[:部分文字列 または /正規表現/]。
+    /// --duration までに受信しなかった場合は終了コード 75。設定ファイルの wait_for より優先)
+    #[arg(long, value_name = "TOPIC[:PATTERN]")]
+    wait_for: Option<String>,
+    /// ブローカーに接続せず、output_format: json (または json-array) で記録したファイルのメッセージを
+    /// 記録した時刻の間隔で output_format の形式に変換して標準出力に出力する
+    #[arg(long, value_name = "FILE")]
+    replay: Option<String>,
+    /// --replay の再生速度の倍率 (例: 2 で 2 倍の速さ、0.5 で半分の速さ。指定しない場合は 1)
+    #[arg(long, requires = "replay", conflicts_with = "no_timing")]
+    speed: Option<f64>,
+    /// --replay で記録した時刻の間隔を待たずに、すべてのメッセージをすぐに出力する
+    #[arg(long, requires = "replay")]
+    no_timing: bool,
 }
 
 #[tokio::main]
 async fn main() {
-    use common::config_utils::Config; // 設定ファイルの読み込みモジュールをインポート
+    use common::config_utils::{Config, ConfigFiles, MinQosPolicy, OutputFormat}; // 設定ファイルの読み込みモジュールをインポート
+    let args = Args::try_parse().unwrap_or_else(|e| {
+        // --help / --version 以外 (引数の誤り) は設定エラーの終了コードで終了する
+        if e.use_stderr() {
+            exit_with(AppError::Config(e.to_string().trim_end().to_string()));
+        }
+        e.exit()
+    });
     // 設定ファイルを読み込む
-    let config: Config = common::config_utils::get_config();
+    let config_files = ConfigFiles {
+        paths: args.config,
+        strict: args.strict_config,
+        append_lists: args.append_lists,
+        verbose: args.verbose,
+    };
+    let mut config: Config = common::config_utils::get_config(&config_files);
 
-    let mut mqtt_options = MqttOptions::new(config.client_id, config.broker_address, config.broker_port);
-    mqtt_options.set_keep_alive(Duration::from_secs(20));
-    mqtt_options.set_clean_session(config.clean_session.unwrap_or(true));
+    // JSON / CSV で出力する場合は、状態メッセージを標準エラー出力に出してメッセージの出力と分ける
+    let output_format = config.output_format.unwrap_or_default();
+    output_utils::set_status_to_stderr(output_format != OutputFormat::Text);
+    output_utils::set_verbose(args.verbose);
 
-    // ユーザー名とパスワードが指定されていれば設定
-    if let Some(username) = &config.username {
-        let password = config.password.as_deref().unwrap_or("");
-        mqtt_options.set_credentials(username, password);
+    // コマンドライン引数で設定を上書き
+    if args.fail_fast {
+        config.fail_fast = Some(true);
     }
-
-    // SSL/TLS 設定
-    if config.scheme.as_deref() == Some("ssl") || config.scheme.as_deref() == Some("mqtts") {
-        let mut root_store = RootCertStore::empty();
-
-        // CA証明書の読み込みと追加
-        if let Some(ca_cert_path) = &config.ca_cert_path {
-            let ca_cert_pem = fs::read(ca_cert_path).unwrap_or_else(|e| {
-                eprintln!("CA証明書 '{}' の読み込み中にエラーが発生しました: {}", ca_cert_path, e);
-                process::exit(1);
-            });
-            let mut ca_certs_reader = std::io::BufReader::new(std::io::Cursor::new(ca_cert_pem));
-            let certs = rustls_pemfile::certs(&mut ca_certs_reader)
-                .filter_map(Result::ok)
-                .collect::<Vec<_>>();
-            for cert in certs {
-                root_store.add(cert).unwrap_or_else(|e| { 
-                    eprintln!("CA証明書の追加中にエラーが発生しました: {}", e);
-                    process::exit(1);
-                });
+    if args.dump_packets {
+        config.dump_packets = Some(true);
+    }
+    if args.tail {
+        config.tail = Some(true);
+    }
+    if args.show_capabilities {
+        config.show_capabilities = Some(true);
+    }
+    if let Some(secs) = args.duration {
+        config.max_runtime_secs = Some(secs);
+    }
+    if let Some(wait_for) = args.wait_for {
+        config.wait_for = Some(wait_for);
+    }
+    config.apply_client_id_suffix();
+    // 配列は終了時に出力するため、終了しない実行ではメッセージを際限なく保持することになる
+    if output_format == OutputFormat::JsonArray && config.max_runtime_secs.is_none() && !args.selftest && args.replay.is_none() {
+        exit_with(AppError::Config("output_format: json-array は実行時間の上限 (max_runtime_secs または --duration) と組み合わせて指定してください。".to_string()));
+    }
+    if args.selftest {
+        match selftest_utils::run_selftest(&config).await {
+            Ok(round_trip) => {
+                status!("セルフテストに成功しました (往復 {:.1} ms)。", round_trip.as_secs_f64() * 1000.0);
+                return;
             }
-        } else {
-            eprintln!("警告: SSL/TLS 接続用に CA 証明書のパスが指定されていません。");
+            Err(e) => exit_with(e),
+        }
+    }
+    if let Some(path) = &args.replay {
+        let speed = args.speed.unwrap_or(1.0);
+        if !(speed.is_finite() && speed > 0.0) {
+            exit_with(AppError::Config("--speed には 0 より大きい値を指定してください。".to_string()));
+        }
+        if output_format == OutputFormat::Csv {
+            println!("{}", output_utils::CSV_HEADER);
         }
+        let summary = replay_utils::replay(&config, path, (!args.no_timing).then_some(speed)).await.unwrap_or_else(|e| exit_with(e));
+        if output_format == OutputFormat::JsonArray {
+            output_utils::print_json_array(config.json_array_pretty.unwrap_or(false));
+        }
+        status!("記録のファイル '{}' のメッセージを {} 件再生しました (読み飛ばした記録 {} 件)。", path, summary.replayed, summary.skipped);
+        return;
+    }
+    if let Some(qos) = args.qos {
+        // 値の範囲は clap で検査済み
+        config.qos_override = qos_utils::to_qos(qos);
+    }
+    if let Some(qos) = config.qos_override {
+        // 設定ファイルの QoS が実際の購読 QoS と誤解されないよう明示する
+        status!("QoS の上書きが有効です: すべてのトピックを QoS {} で購読します (設定ファイルの qos / qos_rules は無視されます)。", qos_utils::qos_number(qos));
+    }
 
-        // クライアント認証の準備
-        let client_config = if let Some(client_combined_path) = &config.client_combined_path {
-            let cert_key_pem = fs::read(client_combined_path).unwrap_or_else(|e| {
-                eprintln!("クライアント証明書/キーファイル '{}' の読み込み中にエラーが発生しました: {}", client_combined_path, e);
-                process::exit(1);
-            });
+    if let Some(topic) = &config.benchmark_loopback_topic
+        && !config.topics.iter().any(|filter| topic_utils::topic_matches(filter, topic)) {
+        exit_with(AppError::Config(format!("benchmark_loopback_topic '{}' は購読するトピック (topics) に含めてください。", topic)));
+    }
 
-            let mut reader = std::io::BufReader::new(std::io::Cursor::new(cert_key_pem));
-            let certs = rustls_pemfile::certs(&mut reader)
-                .filter_map(Result::ok)
-                .collect::<Vec<_>>();
-            reader.rewind().unwrap();
-
-            let client_key_pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut reader)
-                .filter_map(Result::ok)
-                .next()
-                .unwrap_or_else(|| {
-                    eprintln!("クライアントの秘密鍵が見つかりません。");
-                    process::exit(1);
-                });
-            
-            let client_key = rustls_pki_types::PrivateKeyDer::Pkcs8(client_key_pkcs8.into());
-
-            // ClientConfig の構築
-            ClientConfig::builder()
-                .with_root_certificates(root_store)
-                .with_client_auth_cert(certs, client_key)
-                .unwrap_or_else(|e| {
-                    eprintln!("クライアント認証の設定に失敗しました: {}", e);
-                    process::exit(1);
-                })
-        } else {
-            ClientConfig::builder()
-                .with_root_certificates(root_store)
-                .with_no_client_auth()
-        };
+    // enforce_min_qos に違反する購読がないか、接続する前に検査する
+    let filters: Vec<(String, QoS)> = config.topics.iter().cloned().zip(qos_utils::resolve_qos(&config)).collect();
+    let violations = qos_utils::min_qos_violations(&config, &filters);
+    for violation in &violations {
+        eprintln!("エラー: {}", violation);
+    }
+    if !violations.is_empty() && config.min_qos_policy.unwrap_or_default() == MinQosPolicy::Refuse {
+        exit_with(AppError::Config("enforce_min_qos に違反する購読があるため終了します (min_qos_policy: refuse)。".to_string()));
+    }
 
-        let tls_config = Arc::new(client_config);
-        mqtt_options.set_transport(Transport::Tls(rumqttc::TlsConfiguration::Rustls(tls_config)));
+    let sessions = config.sessions.unwrap_or(1);
+    if sessions == 0 {
+        exit_with(AppError::Config("sessions には 1 以上の値を指定してください。".to_string()));
     }
 
-    let (mut client, mut eventloop) = Client::new(mqtt_options, 10); // 10 はイベントループのチャネル容量
+    // 直近の受信メッセージの保持 (history_size) と readiness probe (readiness_file / readiness_port) の準備
+    let history = MessageHistory::new(&config).map(Arc::new);
+    let readiness = Readiness::start(&config, sessions, history.clone()).await;
+    // 受信メッセージの TCP 配信 (stream_port) の準備
+    let stream = MessageStream::start(&config).await;
+    // 受信メッセージの名前付きパイプへの出力 (output_fifo) の準備
+    #[cfg(unix)]
+    let fifo = common::fifo_utils::FifoOutput::open(&config);
+    // 受信メッセージの別のブローカーへの転送 (bridge) の準備
+    let bridge = Bridge::start(&config);
+    // 指定したメッセージの受信の待機 (wait_for) の準備
+    let wait_for = WaitFor::new(&config).map(Arc::new);
 
-    // 設定された QoS 値を rumqttc::QoS 型に変換
-    let actual_qos: Vec<QoS> = if config.qos.len() < config.topics.len() && !config.qos.is_empty() {
-        let default_qos_val = config.qos[0];
-        let default_qos = match default_qos_val {
-            0 => QoS::AtMostOnce,
-            1 => QoS::AtLeastOnce,
-            2 => QoS::ExactlyOnce,
-            _ => {
-                eprintln!("設定ファイル内の不正な QoS 値: {}", default_qos_val);
-                process::exit(1);
-            }
-        };
-        vec![default_qos; config.topics.len()]
-    } else if config.qos.is_empty() && !config.topics.is_empty() {
-        vec![QoS::AtMostOnce; config.topics.len()] // デフォルトで QoS 0 を適用
-    } else {
-        config.qos.iter().map(|&q| {
-            match q {
-                0 => QoS::AtMostOnce,
-                1 => QoS::AtLeastOnce,
-                2 => QoS::ExactlyOnce,
-                _ => {
-                    eprintln!("設定ファイル内の不正な QoS 値: {}", q);
-                    process::exit(1);
+    if output_format == OutputFormat::Csv {
+        println!("{}", output_utils::CSV_HEADER);
+    }
+
+    // セッションごとに独立したイベントループと再接続状態を持つサブスクライバーを起動する
+    let started = time::Instant::now();
+    let mut tasks = JoinSet::new();
+    let mut session_metrics = Vec::new();
+    let mut session_sequences = Vec::new();
+    let mut session_latency = Vec::new();
+    let mut session_gaps = Vec::new();
+    let mut reloaders = Vec::new();
+    let mut clients = Vec::new();
+    for i in 1..=sessions {
+        let mut session_config = config.clone();
+        if sessions > 1 {
+            session_config.client_id = format!("{}-{}", config.client_id, i);
+        }
+        let mut subscriber = Subscriber::new(session_config.clone());
+        if let Some(latency) = subscriber.latency() {
+            session_latency.push((session_config.client_id.clone(), latency));
+        }
+        if let Some(gaps) = subscriber.gaps() {
+            session_gaps.push((session_config.client_id.clone(), gaps));
+        }
+        if let Some(sequences) = subscriber.sequences() {
+            session_sequences.push((session_config.client_id.clone(), sequences));
+        }
+        session_metrics.push((session_config.client_id, subscriber.metrics()));
+        reloaders.push(subscriber.reload_sender());
+        clients.push(subscriber.client());
+        if let Some(stream) = &stream {
+            subscriber.add_sink(Box::new(Arc::clone(stream)));
+        }
+        #[cfg(unix)]
+        if let Some(fifo) = &fifo {
+            subscriber.add_sink(Box::new(Arc::clone(fifo)));
+        }
+        if let Some(history) = &history {
+            subscriber.set_history(Arc::clone(history));
+        }
+        if let Some(bridge) = &bridge {
+            subscriber.set_bridge(Arc::clone(bridge));
+        }
+        if let Some(wait_for) = &wait_for {
+            subscriber.set_wait_for(Arc::clone(wait_for));
+        }
+        if let Some(readiness) = &readiness {
+            let readiness = Arc::clone(readiness);
+            let mut ready_rx = subscriber.readiness();
+            tokio::spawn(async move {
+                while ready_rx.changed().await.is_ok() {
+                    let ready = *ready_rx.borrow_and_update();
+                    readiness.set(i - 1, ready);
                 }
+            });
+        }
+        tasks.spawn(async move { subscriber.run().await });
+    }
+
+    // 受信統計の StatsD への送信 (statsd_address)
+    let statsd = StatsdExporter::start(&config, session_metrics.clone()).await;
+
+    // SIGHUP を受け取ったら設定ファイルを読み込み直し、購読するトピックと QoS の変更を反映する
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(config_files, args.qos, reloaders));
+
+    // すべてのセッションが終了するか、Ctrl+C で中断されるか、max_runtime_secs が経過するか、wait_for のメッセージを受信するまで待機する
+    let max_runtime = config.max_runtime_secs.map(Duration::from_secs);
+    let stopping = tokio::select! {
+        _ = async { while tasks.join_next().await.is_some() {} } => false,
+        _ = tokio::signal::ctrl_c() => {
+            status!("中断されました。");
+            false
+        }
+        _ = async {
+            match max_runtime {
+                Some(duration) => time::sleep(duration).await,
+                None => std::future::pending().await,
+            }
+        } => {
+            status!("実行時間の上限 ({} 秒) に達したため、ブローカーから切断して終了します。", config.max_runtime_secs.unwrap_or_default());
+            true
+        }
+        _ = async {
+            match &wait_for {
+                Some(wait_for) => wait_for.wait().await,
+                None => std::future::pending().await,
             }
-        }).collect()
+        } => {
+            status!("wait_for の条件 ({}) に一致するメッセージを受信したため、ブローカーから切断して終了します。", wait_for.as_ref().map(|w| w.spec()).unwrap_or_default());
+            true
+        }
     };
+    if stopping {
+        for client in &clients {
+            let _ = client.disconnect().await;
+        }
+        // 切断要求より前に受信したメッセージの確認応答を送り終え、各セッションが切断するまで待つ
+        if time::timeout(DISCONNECT_GRACE, async { while tasks.join_next().await.is_some() {} }).await.is_err() {
+            eprintln!("警告: {} 秒以内に切断が完了しなかったセッションがあります。", DISCONNECT_GRACE.as_secs());
+        }
+    }
 
-    // トピックの購読
-    subscribe_topics(&mut client, &config.topics, &actual_qos).await;
-
-    println!("MQTT イベントを処理中...");
-    loop {
-        match eventloop.eventloop.poll().await {
-            Ok(event) => {
-                // println!("受信イベント: {:?}", event); // 詳細なイベントログが必要な場合にコメントを外す
-                if let Event::Incoming(Packet::Publish(p)) = event {
-                    println!("トピック: {}", p.topic);
-                    println!("ペイロード: {}", String::from_utf8_lossy(&p.payload));
-                    println!("QoS: {:?}", p.qos);
-                } else if let Event::Incoming(Packet::ConnAck(_)) = event {
-                    println!("ブローカーに接続しました。");
-                } else if let Event::Outgoing(rumqttc::Outgoing::Disconnect) = event {
-                    println!("ブローカーから切断しました。");
-                    break;  // イベントループを終了
-                }
+    if output_format == OutputFormat::JsonArray {
+        output_utils::print_json_array(config.json_array_pretty.unwrap_or(false));
+    }
+    if let Some(readiness) = &readiness {
+        readiness.shutdown();
+    }
+    if let Some(statsd) = &statsd {
+        statsd.flush();
+    }
+
+    // 受信統計の表示 (複数セッションの場合はセッションごとの内訳と合計)
+    let elapsed = started.elapsed();
+    let mut total = MetricsSnapshot::default();
+    let mut topic_counts = HashMap::new();
+    let mut throttled: HashMap<String, ThrottleCount> = HashMap::new();
+    let mut payload_sizes: Option<PayloadSizes> = None;
+    for (client_id, metrics) in &session_metrics {
+        let snapshot = metrics.snapshot();
+        if sessions > 1 {
+            metrics_utils::print_summary(client_id, &snapshot, elapsed);
+        }
+        total = total.merge(snapshot);
+        for (key, count) in metrics.topic_counts() {
+            *topic_counts.entry(key).or_insert(0) += count;
+        }
+        for (pattern, count) in metrics.throttled_counts() {
+            let total = throttled.entry(pattern).or_default();
+            *total = total.merge(count);
+        }
+        if let Some(sizes) = metrics.payload_sizes() {
+            match &mut payload_sizes {
+                Some(total) => total.merge(&sizes),
+                None => payload_sizes = Some(sizes),
             }
+        }
+    }
+    metrics_utils::print_summary("合計", &total, elapsed);
+    if let Some(sample_rate) = config.sample_rate {
+        metrics_utils::print_sampling("合計", &total, sample_rate);
+    }
+    metrics_utils::print_topic_counts("合計", &topic_counts);
+    metrics_utils::print_throttled("合計", &throttled);
+    if let Some(sizes) = &payload_sizes {
+        histogram_utils::print_payload_sizes("合計", sizes);
+    }
+
+    // ベンチマークの遅延の統計 (セッションごと)
+    for (client_id, latency) in &session_latency {
+        benchmark_utils::print_report(client_id, latency);
+    }
+
+    // メッセージの到着間隔の統計 (セッションごと)
+    for (client_id, gaps) in &session_gaps {
+        gap_utils::print_report(client_id, gaps);
+    }
+
+    // exactly-once の検証結果 (各セッションはそれぞれすべてのメッセージを受信するため、セッションごとに検証する)
+    let mut verified = true;
+    for (client_id, sequences) in &session_sequences {
+        verified &= sequence_utils::print_report(client_id, &sequences.report());
+    }
+    if !verified {
+        exit_with(AppError::Verification("exactly-once の検証で重複または欠番が見つかりました。".to_string()));
+    }
+    if let Some(wait_for) = &wait_for
+        && !wait_for.matched() {
+        exit_with(AppError::NotReceived(format!("wait_for の条件 ({}) に一致するメッセージを受信しませんでした。", wait_for.spec())));
+    }
+
+    status!("終了します。");
+}
+
+// `90` (秒)、`30s`、`5m`、`1h` 形式の時間を秒数に変換する
+fn parse_duration_secs(text: &str) -> Result<u64, String> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("時間の単位 '{}' は不正です (s / m / h を指定してください)", unit)),
+    };
+    match number.parse::<u64>() {
+        Ok(value) if value > 0 => value.checked_mul(multiplier).ok_or_else(|| format!("時間 '{}' が大きすぎます", text)),
+        _ => Err(format!("時間 '{}' は不正です (例: 90、30s、5m、1h)", text)),
+    }
+}
+
+// SIGHUP を受け取るたびに設定ファイルを読み込み直して各セッションに送る。読み込めない場合は現在の設定のまま続行する
+#[cfg(unix)]
+async fn reload_on_hangup(config_files: common::config_utils::ConfigFiles, qos: Option<i32>, reloaders: Vec<tokio::sync::mpsc::Sender<common::config_utils::Config>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!("SIGHUP の待ち受けを開始できませんでした (設定の再読み込みは無効です): {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        status!("SIGHUP を受信しました。設定ファイル {} を読み込み直します (反映するのは購読するトピックと QoS のみです)。", config_files.quoted_paths());
+        let mut config = match common::config_utils::load_config(&config_files) {
+            Ok(config) => config,
             Err(e) => {
-                let err_str = e.to_string();
-                if err_str.contains("disconnected") {
-                    eprintln!("ブローカーへの接続が閉じられました。再接続を試行中...");
-                    time::sleep(Duration::from_secs(5)).await;
-                } else {
-                    eprintln!("イベントループでエラーが発生しました: {:?}", e);
-                    time::sleep(Duration::from_secs(1)).await;
-                }
+                eprintln!("設定の再読み込みに失敗しました。現在の設定のまま続行します: {}", e);
+                continue;
             }
+        };
+        // コマンドラインの --qos は再読み込み後も優先する
+        if let Some(qos) = qos {
+            config.qos_override = qos_utils::to_qos(qos);
+        }
+        for reloader in &reloaders {
+            let _ = reloader.send(config.clone()).await;
         }
     }
-
-    println!("終了します。");
-}
\ No newline at end of file
+}